@@ -0,0 +1,73 @@
+// Times csv queries on one thread and on several over vgsales.csv copied many times over.
+// Run with: cargo bench --bench csv_query [copies]
+use aid::csv_utils::{self, CsvOptions, QueryResults};
+use std::error::Error;
+use std::fs::{self, File};
+use std::io::{BufRead, BufReader, BufWriter, Write};
+use std::time::Instant;
+
+const QUERIES: [&str; 3] = [
+    "SELECT Name, Platform, Global_Sales FROM {table} WHERE Genre = 'Sports' AND Year >= 2005 AND Name LIKE '%FIFA%'",
+    "SELECT Platform, COUNT(*), SUM(Global_Sales), AVG(NA_Sales), MAX(Name) FROM {table} WHERE Year > 2000 GROUP BY Platform",
+    "SELECT Publisher, COUNT(DISTINCT Name), MEDIAN(Global_Sales) FROM {table} GROUP BY Publisher ORDER BY Publisher LIMIT 10",
+];
+
+// Write the header of vgsales.csv followed by its records the given number of times
+fn scale_up(copies: usize) -> Result<String, Box<dyn Error>> {
+    let path = std::env::temp_dir().join(format!("aid_bench_vgsales_x{}.csv", copies));
+    let lines = BufReader::new(File::open("./vgsales.csv")?)
+        .lines()
+        .collect::<Result<Vec<_>, _>>()?;
+
+    let mut writer = BufWriter::new(File::create(&path)?);
+    writeln!(writer, "{}", lines[0])?;
+    for _ in 0..copies {
+        for line in &lines[1..] {
+            writeln!(writer, "{}", line)?;
+        }
+    }
+    writer.flush()?;
+    Ok(path.to_string_lossy().into_owned())
+}
+
+fn main() -> Result<(), Box<dyn Error>> {
+    // cargo bench passes --bench, the first other argument is the number of copies
+    let copies = std::env::args()
+        .skip(1)
+        .find_map(|arg| arg.parse::<usize>().ok())
+        .unwrap_or(50);
+    let cores = std::thread::available_parallelism().map_or(1, |threads| threads.get());
+    let mut thread_counts = vec![1, 2, 4, 8];
+    thread_counts.retain(|threads| *threads == 1 || *threads <= cores.max(2));
+
+    let table = scale_up(copies)?;
+    println!("{} copies of vgsales.csv, {} cores available", copies, cores);
+
+    for sql in QUERIES {
+        let query = csv_utils::parse_query(&sql.replace("{table}", &format!("'{}'", table)))?;
+        println!("\n{}", sql);
+
+        let mut baseline = None;
+        for threads in &thread_counts {
+            let options = CsvOptions {
+                threads: *threads,
+                ..CsvOptions::default()
+            };
+
+            let start = Instant::now();
+            let mut results = QueryResults::default();
+            let stats = csv_utils::execute_query(&query, &options, &mut results)?;
+            let elapsed = start.elapsed().as_secs_f64();
+
+            let speedup = baseline.map_or(1.0, |baseline: f64| baseline / elapsed);
+            baseline.get_or_insert(elapsed);
+            println!(
+                "  {} threads: {:.3}s, {} rows scanned, {} returned, {:.2}x",
+                threads, elapsed, stats.rows_scanned, stats.rows_returned, speedup
+            );
+        }
+    }
+
+    fs::remove_file(&table)?;
+    Ok(())
+}
@@ -1,16 +1,99 @@
+use std::collections::BTreeSet;
+use std::error::Error;
+use std::io::{self, BufWriter, Write};
+use std::path::Path;
+use std::sync::Arc;
 use std::{fs::File, time::Instant};
 
-use csv::Writer;
+use comfy_table::Table;
+use csv::{Writer, WriterBuilder};
+use rustyline::completion::Completer;
+use rustyline::error::ReadlineError;
+use rustyline::highlight::Highlighter;
+use rustyline::hint::Hinter;
+use rustyline::history::DefaultHistory;
+use rustyline::validate::Validator;
+use rustyline::{Context, Editor, Helper};
 
-use crate::csv_utils;
+use crate::csv_utils::{
+    self, MemoryTable, OrderBy, RowSink, RowSource, SortDirection, SplitBy, Statement,
+};
+use crate::graph_utils;
 
-pub async fn sql_search(sql: String, output_path: Option<String>) {
+pub async fn sql_search(
+    sql: String,
+    output_path: Option<String>,
+    format: Option<String>,
+    delimiter: Option<String>,
+    no_header: bool,
+    threads: usize,
+) {
+    let Some(mut options) = input_options(delimiter, no_header) else {
+        return;
+    };
+    // 0 runs the query on every core
+    options.threads = match threads {
+        0 => std::thread::available_parallelism().map_or(1, |threads| threads.get()),
+        threads => threads,
+    };
+
+    run_search(sql, output_path, format, &options);
+}
+
+// Build the options for reading input files from the command line, printing any problems
+fn input_options(delimiter: Option<String>, no_header: bool) -> Option<csv_utils::CsvOptions> {
+    let delimiter = match delimiter.map(|delimiter| parse_delimiter(&delimiter)) {
+        Some(Ok(delimiter)) => Some(delimiter),
+        Some(Err(e)) => {
+            eprintln!("{}", e);
+            return None;
+        }
+        None => None,
+    };
+    Some(csv_utils::CsvOptions {
+        delimiter,
+        no_header,
+        ..csv_utils::CsvOptions::default()
+    })
+}
+
+// The output format given on the command line, otherwise picked from the output file's extension
+fn output_format(format: Option<String>, output_path: Option<&str>) -> Option<OutputFormat> {
+    match format {
+        Some(name) => {
+            let format = OutputFormat::parse(&name);
+            if format.is_none() {
+                eprintln!(
+                    "Unknown output format '{}', expected one of: csv, tsv, table, json, ndjson, markdown",
+                    name
+                );
+            }
+            format
+        }
+        None => Some(OutputFormat::from_path(output_path)),
+    }
+}
+
+// Run a query and write out the results, shared by the csv and json commands
+pub fn run_search(
+    sql: String,
+    output_path: Option<String>,
+    format: Option<String>,
+    options: &csv_utils::CsvOptions,
+) {
+    let Some(format) = output_format(format, output_path.as_deref()) else {
+        return;
+    };
 
     let start = Instant::now();
     
     // Parse the SQL query
-    let parsed = match csv_utils::parse_sql(&sql) {
-        Ok(result) => result,
+    let query = match csv_utils::parse_statement(&sql) {
+        Ok(Statement::Select(query)) => query,
+        Ok(statement) => {
+            run_statement(&statement, options);
+            return;
+        }
         Err(e) => {
             eprintln!("Failed to parse SQL query: {}", e);
             return; // Early exit on error
@@ -20,84 +103,886 @@ pub async fn sql_search(sql: String, output_path: Option<String>) {
     let parse_time = start.elapsed();
     let start = Instant::now();
 
-    let query = parsed.1;
-
-    // Load the CSV file
-    let (headers, records) = match csv_utils::load_csv(&query.table) {
-        Ok(result) => result,
+    // Stream the CSV file(s) through the query, writing out results as they are found
+    let mut output = ResultOutput::new(output_path.clone(), format);
+    let stats = match csv_utils::execute_query(&query, options, &mut output) {
+        Ok(stats) => stats,
         Err(e) => {
-            eprintln!("Failed to load CSV file '{}': {}", query.table, e);
+            eprintln!("{}", e);
             return; // Early exit on error
         }
     };
-
-    let rows = records.len();
-    let load_time = start.elapsed();
-    let start = Instant::now();
-
-    // Apply the query
-    let (result_headers, result_rows) = csv_utils::apply_query(&headers, records, &query);
     let query_time = start.elapsed();
-    let result_row_count = result_rows.len();
 
-    output_results(result_rows, &result_headers, &output_path);
+    if let Err(e) = output.finish() {
+        eprintln!("{}", e);
+        return;
+    }
 
     if output_path.is_some() {
         // Log the query statistics
         println!("Parsed {:?} in {:?}", query, parse_time);
-        println!("Loaded {} rows in {:?}", rows, load_time);
-        println!("Found {} rows in {:?}", result_row_count, query_time);
+        println!(
+            "Scanned {} rows and found {} rows in {:?}",
+            stats.rows_scanned, stats.rows_returned, query_time
+        );
     }
 }
 
+// Run a statement that writes to a file, e.g. CREATE TABLE ... AS or UPDATE
+fn run_statement(statement: &Statement, options: &csv_utils::CsvOptions) {
+    let start = Instant::now();
+    let result = match statement {
+        Statement::CreateTableAs(table, query) => csv_utils::create_table_as(table, query, options)
+            .map(|rows| format!("Created {} with {} rows", table, rows)),
+        Statement::InsertInto(table, query) => csv_utils::insert_into(table, query, options)
+            .map(|rows| format!("Inserted {} rows into {}", rows, table)),
+        Statement::Update(table, assignments, condition) => {
+            csv_utils::update_table(table, assignments, condition, options)
+                .map(|rows| format!("Updated {} rows in {}", rows, table))
+        }
+        Statement::Delete(table, condition) => csv_utils::delete_from(table, condition, options)
+            .map(|rows| format!("Deleted {} rows from {}", rows, table)),
+        Statement::Select(_) => return,
+    };
+
+    match result {
+        Ok(message) => println!("{} in {:?}", message, start.elapsed()),
+        Err(e) => eprintln!("{}", e),
+    }
+}
 
-fn output_results(results: Vec<Vec<String>>, headers: &Vec<String>, output_path: &Option<String>) {
-    if results.is_empty() {
-        println!("No results found.");
+// Number of bins in the histogram of each numeric column
+const DESCRIBE_HISTOGRAM_BINS: usize = 10;
+
+// Profile each column of a file, printed as tables and histograms or as JSON
+pub async fn describe(
+    file: String,
+    format: Option<String>,
+    top: usize,
+    delimiter: Option<String>,
+    no_header: bool,
+) {
+    let json = match format.as_deref().map(|format| format.to_lowercase()) {
+        None => false,
+        Some(format) if format == "table" => false,
+        Some(format) if format == "json" => true,
+        Some(format) => {
+            eprintln!("Unknown output format '{}', expected one of: table, json", format);
+            return;
+        }
+    };
+    let Some(options) = input_options(delimiter, no_header) else {
+        return;
+    };
+
+    let (headers, records) = match csv_utils::load_table(&file, &options) {
+        Ok(table) => table,
+        Err(e) => {
+            eprintln!("Failed to load CSV file '{}': {}", file, e);
+            return;
+        }
+    };
+    let profiles = csv_utils::describe_table(&headers, &records, top, DESCRIBE_HISTOGRAM_BINS);
+
+    if json {
+        match serde_json::to_string_pretty(&profiles) {
+            Ok(json) => println!("{}", json),
+            Err(e) => eprintln!("Failed to serialize profile: {}", e),
+        }
         return;
     }
 
-    match output_path {
-        Some(path) => {
-            match File::create(&path) {
-                Ok(file) => {
-                    let mut wtr = Writer::from_writer(file);
+    println!("{}: {} rows, {} columns", file, records.len(), headers.len());
+    let mut summary = Table::new();
+    summary.set_header(vec![
+        "Column", "Type", "Nulls", "Distinct", "Min", "Max", "Mean", "StdDev",
+    ]);
+    for profile in &profiles {
+        summary.add_row(vec![
+            profile.name.clone(),
+            profile.column_type.clone(),
+            profile.nulls.to_string(),
+            profile.distinct.to_string(),
+            profile.min.clone().unwrap_or_default(),
+            profile.max.clone().unwrap_or_default(),
+            profile.mean.map(|mean| format!("{:.4}", mean)).unwrap_or_default(),
+            profile.stddev.map(|stddev| format!("{:.4}", stddev)).unwrap_or_default(),
+        ]);
+    }
+    println!("{}", summary);
 
-                    // Write headers to the CSV if provided
-                    if let Err(e) = wtr.write_record(headers) {
-                            eprintln!("Failed to write headers to file: {}", e);
-                            return;
-                        }
+    for profile in &profiles {
+        println!("\n{} ({})", profile.name, profile.column_type);
+        let top: Vec<String> = profile
+            .top
+            .iter()
+            .map(|value| format!("{} ({})", value.value, value.count))
+            .collect();
+        println!("top: {}", top.join(", "));
 
-                    // Write each row of results to the CSV
-                    for row in results.iter() {
-                        if let Err(e) = wtr.write_record(row) {
-                            eprintln!("Failed to write row to file: {}", e);
-                            return;
-                        }
-                    }
+        if !profile.histogram.is_empty() {
+            let bins: Vec<(String, usize)> = profile
+                .histogram
+                .iter()
+                .map(|bin| (format!("{:.2} - {:.2}", bin.start, bin.end), bin.count))
+                .collect();
+            graph_utils::plot_histogram(&bins, 40);
+        }
+    }
+}
 
-                    if let Err(e) = wtr.flush() {
-                        eprintln!("Failed to flush writer: {}", e);
-                    }
+// Compare two versions of a file, matching rows by the key columns
+pub async fn diff(
+    old_file: String,
+    new_file: String,
+    keys: String,
+    format: Option<String>,
+    delimiter: Option<String>,
+    no_header: bool,
+) {
+    let json = match format.as_deref().map(|format| format.to_lowercase()) {
+        None => false,
+        Some(format) if format == "table" => false,
+        Some(format) if format == "json" => true,
+        Some(format) => {
+            eprintln!("Unknown output format '{}', expected one of: table, json", format);
+            return;
+        }
+    };
+    let Some(options) = input_options(delimiter, no_header) else {
+        return;
+    };
+
+    let mut tables = Vec::new();
+    for file in [&old_file, &new_file] {
+        match csv_utils::load_table(file, &options) {
+            Ok(table) => tables.push(table),
+            Err(e) => {
+                eprintln!("Failed to load CSV file '{}': {}", file, e);
+                return;
+            }
+        }
+    }
+    let (new_headers, new_records) = tables.pop().unwrap();
+    let (old_headers, old_records) = tables.pop().unwrap();
+
+    let keys: Vec<String> = keys.split(',').map(|key| key.trim().to_string()).collect();
+    let diff = match csv_utils::diff_tables(
+        &old_headers,
+        &old_records,
+        &new_headers,
+        &new_records,
+        &keys,
+    ) {
+        Ok(diff) => diff,
+        Err(e) => {
+            eprintln!("{}", e);
+            return;
+        }
+    };
+
+    if json {
+        match serde_json::to_string_pretty(&diff) {
+            Ok(json) => println!("{}", json),
+            Err(e) => eprintln!("Failed to serialize diff: {}", e),
+        }
+        return;
+    }
+
+    let summary = &diff.summary;
+    println!(
+        "{} -> {}: {} added, {} removed, {} changed, {} unchanged",
+        old_file, new_file, summary.added, summary.removed, summary.changed, summary.unchanged
+    );
+    if !diff.added_columns.is_empty() {
+        println!("Added columns: {}", diff.added_columns.join(", "));
+    }
+    if !diff.removed_columns.is_empty() {
+        println!("Removed columns: {}", diff.removed_columns.join(", "));
+    }
+
+    for (title, headers, rows) in [
+        ("Added rows", &new_headers, &diff.added),
+        ("Removed rows", &old_headers, &diff.removed),
+    ] {
+        if rows.is_empty() {
+            continue;
+        }
+        let mut table = Table::new();
+        table.set_header(headers);
+        for row in rows {
+            table.add_row(row.values().map(|value| value.as_str().unwrap_or_default()));
+        }
+        println!("\n{}\n{}", title, table);
+    }
+
+    if !diff.changed.is_empty() {
+        let mut table = Table::new();
+        let mut header = keys.clone();
+        header.extend(["Column", "Old", "New"].map(String::from));
+        table.set_header(header);
+        for row in &diff.changed {
+            let key: Vec<&str> = row.key.values().map(|value| value.as_str().unwrap_or_default()).collect();
+            for change in &row.changes {
+                let mut cells = key.clone();
+                cells.extend([change.column.as_str(), &change.old, &change.new]);
+                table.add_row(cells);
+            }
+        }
+        println!("\nChanged rows\n{}", table);
+    }
+}
+
+// Sort a file by one or more columns given as e.g. 'Year:desc,Name'
+pub async fn sort(
+    file: String,
+    keys: String,
+    output_path: Option<String>,
+    format: Option<String>,
+    buffer_rows: usize,
+    delimiter: Option<String>,
+    no_header: bool,
+) {
+    let keys = match parse_sort_keys(&keys) {
+        Ok(keys) => keys,
+        Err(e) => {
+            eprintln!("{}", e);
+            return;
+        }
+    };
+    let Some(options) = input_options(delimiter, no_header) else {
+        return;
+    };
+
+    transform_table(&file, &options, output_path, format, |source, output| {
+        csv_utils::sort_table(source, &keys, buffer_rows, output).map(|_| ())
+    });
+}
+
+// Sort keys are separated by commas, each column can be followed by :asc or :desc
+fn parse_sort_keys(keys: &str) -> Result<Vec<OrderBy>, String> {
+    keys.split(',')
+        .map(|key| {
+            let (column, direction) = match key.rsplit_once(':') {
+                Some((column, direction)) => (column, direction),
+                None => (key, "asc"),
+            };
+            let direction = match direction.to_lowercase().as_str() {
+                "asc" => SortDirection::Ascending,
+                "desc" => SortDirection::Descending,
+                _ => {
+                    return Err(format!(
+                        "Invalid sort direction '{}' for column '{}', expected asc or desc",
+                        direction, column
+                    ))
+                }
+            };
+            Ok(OrderBy {
+                column: column.trim().to_string(),
+                direction,
+            })
+        })
+        .collect()
+}
+
+// Remove duplicate rows, comparing just the key columns when they're given
+pub async fn dedupe(
+    file: String,
+    keys: Option<String>,
+    output_path: Option<String>,
+    format: Option<String>,
+    delimiter: Option<String>,
+    no_header: bool,
+) {
+    let keys: Vec<String> = keys
+        .iter()
+        .flat_map(|keys| keys.split(','))
+        .map(|key| key.trim().to_string())
+        .collect();
+    let Some(options) = input_options(delimiter, no_header) else {
+        return;
+    };
+
+    let log = output_path.is_some();
+    transform_table(&file, &options, output_path, format, |source, output| {
+        let (written, removed) = csv_utils::dedupe_table(source, &keys, output)?;
+        if log {
+            println!("Kept {} rows and removed {} duplicates", written, removed);
+        }
+        Ok(())
+    });
+}
+
+// Split a file into several files, either every n rows or by the value of a column
+pub async fn split(
+    file: String,
+    rows: Option<usize>,
+    by: Option<String>,
+    output_dir: Option<String>,
+    delimiter: Option<String>,
+    no_header: bool,
+) {
+    let split_by = match (rows, by) {
+        (Some(rows), None) => SplitBy::Rows(rows),
+        (None, Some(column)) => SplitBy::Column(column),
+        _ => {
+            eprintln!("Either --rows or --by has to be given");
+            return;
+        }
+    };
+    let Some(options) = input_options(delimiter, no_header) else {
+        return;
+    };
+
+    let output_dir = output_dir.unwrap_or_else(|| ".".to_string());
+    if let Err(e) = std::fs::create_dir_all(&output_dir) {
+        eprintln!("Failed to create directory {}: {}", output_dir, e);
+        return;
+    }
+
+    let source = match csv_utils::open_table(&file, &options) {
+        Ok(source) => source,
+        Err(e) => {
+            eprintln!("Failed to load CSV file '{}': {}", file, e);
+            return;
+        }
+    };
+    // Files are named after the input file e.g. sales.csv is split into sales_1.csv, sales_2.csv ...
+    let prefix = csv_utils::table_qualifier(&file, &None);
+    match csv_utils::split_table(source, &split_by, Path::new(&output_dir), &prefix) {
+        Ok(files) => {
+            for (path, rows) in &files {
+                println!("{} ({} rows)", path.display(), rows);
+            }
+            println!("Split into {} files", files.len());
+        }
+        Err(e) => eprintln!("{}", e),
+    }
+}
+
+// Join files end to end, lining up columns with the same name
+pub async fn concat(
+    files: Vec<String>,
+    output_path: Option<String>,
+    format: Option<String>,
+    delimiter: Option<String>,
+    no_header: bool,
+) {
+    let Some(options) = input_options(delimiter, no_header) else {
+        return;
+    };
+    let Some(format) = output_format(format, output_path.as_deref()) else {
+        return;
+    };
+
+    let mut sources = Vec::new();
+    for file in &files {
+        match csv_utils::open_table(file, &options) {
+            Ok(source) => sources.push(source),
+            Err(e) => {
+                eprintln!("Failed to load CSV file '{}': {}", file, e);
+                return;
+            }
+        }
+    }
+
+    let mut output = ResultOutput::new(output_path, format);
+    if let Err(e) = csv_utils::concat_tables(sources, &mut output).and_then(|_| output.finish()) {
+        eprintln!("{}", e);
+    }
+}
+
+// Write a file out in another format e.g. CSV to JSON, or NDJSON to TSV
+pub async fn convert(
+    file: String,
+    output_path: Option<String>,
+    format: Option<String>,
+    input_format: Option<String>,
+    delimiter: Option<String>,
+    no_header: bool,
+) {
+    let Some(mut options) = input_options(delimiter, no_header) else {
+        return;
+    };
+    options.input_format = match input_format.map(|format| format.to_lowercase()).as_deref() {
+        None => None,
+        Some("csv") => Some(csv_utils::InputFormat::Csv),
+        Some("json") | Some("ndjson") | Some("jsonl") => Some(csv_utils::InputFormat::Json),
+        Some(format) => {
+            eprintln!("Unknown input format '{}', expected csv or json", format);
+            return;
+        }
+    };
 
-                    println!("Results successfully written to {}", path);
+    transform_table(&file, &options, output_path, format, |source, output| {
+        csv_utils::copy_table(source, output).map(|_| ())
+    });
+}
+
+// Open a file and write it out to stdout or the output file after passing it through the transform
+fn transform_table<F>(
+    file: &str,
+    options: &csv_utils::CsvOptions,
+    output_path: Option<String>,
+    format: Option<String>,
+    transform: F,
+) where
+    F: FnOnce(Box<dyn RowSource>, &mut ResultOutput) -> Result<(), Box<dyn Error>>,
+{
+    let Some(format) = output_format(format, output_path.as_deref()) else {
+        return;
+    };
+    let source = match csv_utils::open_table(file, options) {
+        Ok(source) => source,
+        Err(e) => {
+            eprintln!("Failed to load CSV file '{}': {}", file, e);
+            return;
+        }
+    };
+
+    let mut output = ResultOutput::new(output_path, format);
+    if let Err(e) = transform(source, &mut output).and_then(|_| output.finish()) {
+        eprintln!("{}", e);
+    }
+}
+
+const REPL_HELP: &str = "Enter a query to run it against the loaded tables, e.g. SELECT * FROM orders LIMIT 10
+  .tables           List the loaded tables
+  .schema [TABLE]   Show the columns of every table, or just one
+  .format [FORMAT]  Show or set the output format: csv, tsv, table, json, ndjson or markdown
+  .help             Show this message
+  .quit             Exit (or Ctrl-D)";
+
+// Keywords offered by tab completion alongside the table and column names
+const SQL_KEYWORDS: [&str; 48] = [
+    "SELECT", "DISTINCT", "FROM", "WHERE", "GROUP BY", "HAVING", "QUALIFY", "ORDER BY", "ASC",
+    "DESC", "LIMIT", "OFFSET", "INNER JOIN", "LEFT JOIN", "ON", "AS", "AND", "OR", "NOT", "IN",
+    "IS NULL", "IS NOT NULL", "LIKE", "BETWEEN", "CASE", "WHEN", "THEN", "ELSE", "END", "CAST",
+    "COUNT", "SUM", "AVG", "MIN", "MAX", "MEDIAN", "STDDEV", "GROUP_CONCAT", "UPPER", "LOWER",
+    "COALESCE", "OVER", "PARTITION BY", "ROW_NUMBER", "RANK", "DENSE_RANK", "LAG", "LEAD",
+];
+
+// Load the files into memory once, then run queries against them until the user quits
+pub async fn repl(files: Vec<String>, delimiter: Option<String>, no_header: bool) {
+    let Some(mut options) = input_options(delimiter, no_header) else {
+        return;
+    };
+
+    // Each table can be queried by its path or by its file name without the extension
+    let mut tables: Vec<(String, String)> = Vec::new();
+    for file in files {
+        if file == "-" {
+            eprintln!("Tables can't be read from stdin in the repl");
+            return;
+        }
+
+        let start = Instant::now();
+        let table = match MemoryTable::load(&file, &options) {
+            Ok(table) => Arc::new(table),
+            Err(e) => {
+                eprintln!("Failed to load CSV file '{}': {}", file, e);
+                return;
+            }
+        };
+        let name = csv_utils::table_qualifier(&file, &None);
+        println!(
+            "Loaded {} as '{}' ({} rows, {} columns) in {:?}",
+            file,
+            name,
+            table.records.len(),
+            table.headers.len(),
+            start.elapsed()
+        );
+
+        let path = file.strip_prefix("./").unwrap_or(&file).to_string();
+        options.tables.insert(path.clone(), table.clone());
+        options.tables.entry(name.clone()).or_insert(table);
+        tables.push((name, path));
+    }
+
+    let mut editor: Editor<SqlHelper, DefaultHistory> = match Editor::new() {
+        Ok(editor) => editor,
+        Err(e) => {
+            eprintln!("Failed to start the repl: {}", e);
+            return;
+        }
+    };
+    editor.set_helper(Some(SqlHelper::new(&tables, &options)));
+
+    let history_path = std::env::var("HOME")
+        .map(|home| Path::new(&home).join(".aid_csv_history"))
+        .ok();
+    if let Some(path) = &history_path {
+        // There's no history the first time the repl is run
+        let _ = editor.load_history(path);
+    }
+
+    println!("Enter .help for usage hints.");
+    let mut format = OutputFormat::Table;
+    loop {
+        let line = match editor.readline("sql> ") {
+            Ok(line) => line,
+            // Ctrl-C abandons the current line
+            Err(ReadlineError::Interrupted) => continue,
+            Err(ReadlineError::Eof) => break,
+            Err(e) => {
+                eprintln!("{}", e);
+                break;
+            }
+        };
+
+        let line = line.trim();
+        if line.is_empty() {
+            continue;
+        }
+        let _ = editor.add_history_entry(line);
+
+        let mut args = line.split_whitespace();
+        match args.next().unwrap_or_default() {
+            ".quit" | ".exit" => break,
+            ".help" => println!("{}", REPL_HELP),
+            ".tables" => {
+                for (name, file) in &tables {
+                    let table = &options.tables[file];
+                    println!("{} ({}, {} rows)", name, file, table.records.len());
                 }
-                Err(e) => {
-                    eprintln!("Failed to create file {}: {}", path, e);
+            }
+            ".schema" => {
+                let name = args.next();
+                let mut found = false;
+                for (table_name, file) in &tables {
+                    if name.is_some_and(|name| name != table_name && name != file) {
+                        continue;
+                    }
+                    found = true;
+                    print_schema(table_name, &options.tables[file]);
                 }
+                if !found {
+                    eprintln!("No table named '{}'", name.unwrap_or_default());
+                }
+            }
+            ".format" => match args.next() {
+                Some(name) => match OutputFormat::parse(name) {
+                    Some(new_format) => format = new_format,
+                    None => eprintln!("Unknown output format '{}'", name),
+                },
+                None => println!("{:?}", format),
+            },
+            command if command.starts_with('.') => {
+                eprintln!("Unknown command '{}', enter .help for usage hints", command)
             }
+            _ => run_repl_query(line.trim_end_matches(';'), format, &options),
         }
+    }
 
-        None => {
-            // Print headers if they are available
-            println!("{}", headers.join(","));
+    if let Some(path) = &history_path {
+        let _ = editor.save_history(path);
+    }
+}
 
+fn run_repl_query(sql: &str, format: OutputFormat, options: &csv_utils::CsvOptions) {
+    let start = Instant::now();
+    let query = match csv_utils::parse_query(sql) {
+        Ok(query) => query,
+        Err(e) => {
+            eprintln!("Failed to parse SQL query: {}", e);
+            return;
+        }
+    };
+    let parse_time = start.elapsed();
+    let start = Instant::now();
 
-            // Print each row of results
-            for row in results.iter() {
-                println!("{}", row.join(","));
+    let mut output = ResultOutput::new(None, format);
+    let stats = match csv_utils::execute_query(&query, options, &mut output) {
+        Ok(stats) => stats,
+        Err(e) => {
+            eprintln!("{}", e);
+            return;
+        }
+    };
+    let query_time = start.elapsed();
+
+    if let Err(e) = output.finish() {
+        eprintln!("{}", e);
+        return;
+    }
+
+    println!(
+        "Scanned {} rows and found {} rows in {:?} (parsed in {:?})",
+        stats.rows_scanned, stats.rows_returned, query_time, parse_time
+    );
+}
+
+// List each column of a table along with the type inferred from its values
+fn print_schema(name: &str, table: &MemoryTable) {
+    let column_types = csv_utils::infer_column_types(table.headers.len(), &table.records);
+
+    let mut schema = Table::new();
+    schema.set_header(vec!["Column", "Type"]);
+    for (header, column_type) in table.headers.iter().zip(column_types) {
+        schema.add_row(vec![header.clone(), format!("{:?}", column_type)]);
+    }
+    println!("{}\n{}", name, schema);
+}
+
+// Completes SQL keywords, table names and the columns of the loaded tables
+struct SqlHelper {
+    words: BTreeSet<String>,
+}
+
+impl SqlHelper {
+    fn new(tables: &[(String, String)], options: &csv_utils::CsvOptions) -> SqlHelper {
+        let mut words: BTreeSet<String> = SQL_KEYWORDS.iter().map(|k| k.to_string()).collect();
+        for (name, file) in tables {
+            words.insert(name.clone());
+            for header in &options.tables[file].headers {
+                words.insert(header.clone());
+                words.insert(format!("{}.{}", name, header));
             }
         }
+        SqlHelper { words }
     }
 }
+
+impl Completer for SqlHelper {
+    type Candidate = String;
+
+    fn complete(
+        &self,
+        line: &str,
+        pos: usize,
+        _ctx: &Context<'_>,
+    ) -> rustyline::Result<(usize, Vec<String>)> {
+        let start = line[..pos]
+            .rfind(|c: char| !(c.is_alphanumeric() || c == '_' || c == '.'))
+            .map(|i| i + 1)
+            .unwrap_or(0);
+        let prefix = line[start..pos].to_lowercase();
+        if prefix.is_empty() {
+            return Ok((start, Vec::new()));
+        }
+
+        let candidates = self
+            .words
+            .iter()
+            .filter(|word| word.to_lowercase().starts_with(&prefix))
+            .cloned()
+            .collect();
+        Ok((start, candidates))
+    }
+}
+
+impl Hinter for SqlHelper {
+    type Hint = String;
+}
+
+impl Highlighter for SqlHelper {}
+
+impl Validator for SqlHelper {}
+
+impl Helper for SqlHelper {}
+
+// Delimiters are given as a single character, or 'tab'
+pub fn parse_delimiter(delimiter: &str) -> Result<u8, String> {
+    match delimiter {
+        "tab" | "\\t" | "\t" => Ok(b'\t'),
+        _ if delimiter.len() == 1 => Ok(delimiter.as_bytes()[0]),
+        _ => Err(format!(
+            "Invalid delimiter '{}', expected a single character or 'tab'",
+            delimiter
+        )),
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum OutputFormat {
+    Csv,
+    Tsv,
+    Table,
+    Json,
+    Ndjson,
+    Markdown,
+}
+
+impl OutputFormat {
+    pub fn parse(name: &str) -> Option<OutputFormat> {
+        match name.to_lowercase().as_str() {
+            "csv" => Some(OutputFormat::Csv),
+            "tsv" => Some(OutputFormat::Tsv),
+            "table" => Some(OutputFormat::Table),
+            "json" => Some(OutputFormat::Json),
+            "ndjson" | "jsonl" => Some(OutputFormat::Ndjson),
+            "markdown" | "md" => Some(OutputFormat::Markdown),
+            _ => None,
+        }
+    }
+
+    // The format used when none is given, based on the output file's extension
+    pub fn from_path(path: Option<&str>) -> OutputFormat {
+        path.and_then(|path| Path::new(path).extension())
+            .and_then(|extension| OutputFormat::parse(&extension.to_string_lossy()))
+            .unwrap_or(OutputFormat::Csv)
+    }
+}
+
+// Writes the formatted results to stdout or a file
+enum RowWriter {
+    Delimited(Writer<Box<dyn Write>>),
+    Text(Box<dyn Write>),
+    // Tables need every row to size their columns so are only written at the end
+    Table(Table, Box<dyn Write>),
+}
+
+// Writes results to stdout, or to a file when an output path is given.
+// Nothing is written until the first row arrives so empty results don't leave an empty file behind.
+pub struct ResultOutput {
+    path: Option<String>,
+    format: OutputFormat,
+    headers: Vec<String>,
+    writer: Option<RowWriter>,
+    rows: usize,
+}
+
+impl ResultOutput {
+    pub fn new(path: Option<String>, format: OutputFormat) -> ResultOutput {
+        ResultOutput {
+            path,
+            format,
+            headers: Vec::new(),
+            writer: None,
+            rows: 0,
+        }
+    }
+
+    pub fn finish(&mut self) -> Result<(), Box<dyn Error>> {
+        if self.rows == 0 {
+            // Keep stdout valid for tools reading JSON
+            match (&self.path, self.format) {
+                (None, OutputFormat::Json) => println!("[]"),
+                (None, OutputFormat::Ndjson) => {}
+                _ => println!("No results found."),
+            }
+            return Ok(());
+        }
+
+        let result = match self.writer.take() {
+            Some(RowWriter::Delimited(mut wtr)) => wtr.flush(),
+            Some(RowWriter::Text(mut out)) => {
+                if self.format == OutputFormat::Json {
+                    writeln!(out, "\n]").and_then(|_| out.flush())
+                } else {
+                    out.flush()
+                }
+            }
+            Some(RowWriter::Table(table, mut out)) => {
+                writeln!(out, "{}", table).and_then(|_| out.flush())
+            }
+            None => Ok(()),
+        };
+        result.map_err(|e| format!("Failed to flush writer: {}", e))?;
+
+        if let Some(path) = &self.path {
+            println!("Results successfully written to {}", path);
+        }
+
+        Ok(())
+    }
+
+    fn open(&self) -> Result<RowWriter, Box<dyn Error>> {
+        let mut out: Box<dyn Write> = match &self.path {
+            Some(path) => {
+                let file = File::create(path)
+                    .map_err(|e| format!("Failed to create file {}: {}", path, e))?;
+                Box::new(BufWriter::new(file))
+            }
+            None => Box::new(io::stdout()),
+        };
+
+        let writer = match self.format {
+            OutputFormat::Csv | OutputFormat::Tsv => {
+                let delimiter = if self.format == OutputFormat::Tsv { b'\t' } else { b',' };
+                let mut wtr = WriterBuilder::new().delimiter(delimiter).from_writer(out);
+                wtr.write_record(&self.headers)?;
+                RowWriter::Delimited(wtr)
+            }
+            OutputFormat::Table => {
+                let mut table = Table::new();
+                table.set_header(self.headers.clone());
+                RowWriter::Table(table, out)
+            }
+            OutputFormat::Markdown => {
+                writeln!(out, "{}", markdown_row(&self.headers))?;
+                writeln!(out, "|{}", " --- |".repeat(self.headers.len()))?;
+                RowWriter::Text(out)
+            }
+            OutputFormat::Json => {
+                writeln!(out, "[")?;
+                RowWriter::Text(out)
+            }
+            OutputFormat::Ndjson => RowWriter::Text(out),
+        };
+        Ok(writer)
+    }
+}
+
+impl RowSink for ResultOutput {
+    fn write_headers(&mut self, headers: &[String]) -> Result<(), Box<dyn Error>> {
+        self.headers = headers.to_vec();
+        Ok(())
+    }
+
+    fn write_row(&mut self, row: Vec<String>) -> Result<(), Box<dyn Error>> {
+        if self.writer.is_none() {
+            self.writer = Some(self.open()?);
+        }
+        self.rows += 1;
+
+        let result = match self.writer.as_mut() {
+            Some(RowWriter::Delimited(wtr)) => wtr.write_record(&row).map_err(io::Error::from),
+            Some(RowWriter::Table(table, _)) => {
+                table.add_row(row);
+                Ok(())
+            }
+            Some(RowWriter::Text(out)) => match self.format {
+                OutputFormat::Json if self.rows > 1 => {
+                    write!(out, ",\n  {}", json_object(&self.headers, &row))
+                }
+                OutputFormat::Json => write!(out, "  {}", json_object(&self.headers, &row)),
+                OutputFormat::Markdown => writeln!(out, "{}", markdown_row(&row)),
+                _ => writeln!(out, "{}", json_object(&self.headers, &row)),
+            },
+            None => Ok(()),
+        };
+        result.map_err(|e| format!("Failed to write row: {}", e).into())
+    }
+}
+
+// Build a JSON object from a row, keeping the column order. Fields that are valid JSON
+// numbers are written as numbers and empty fields as null.
+fn json_object(headers: &[String], row: &[String]) -> String {
+    let fields: Vec<String> = headers
+        .iter()
+        .zip(row)
+        .map(|(header, field)| {
+            let value = if field.is_empty() {
+                serde_json::Value::Null
+            } else {
+                match serde_json::from_str::<serde_json::Number>(field) {
+                    Ok(number) => serde_json::Value::Number(number),
+                    Err(_) => serde_json::Value::String(field.clone()),
+                }
+            };
+            format!("{}:{}", serde_json::Value::String(header.clone()), value)
+        })
+        .collect();
+    format!("{{{}}}", fields.join(","))
+}
+
+fn markdown_row(fields: &[String]) -> String {
+    let cells: Vec<String> = fields
+        .iter()
+        .map(|field| field.replace('|', "\\|").replace('\n', "<br>"))
+        .collect();
+    format!("| {} |", cells.join(" | "))
+}
@@ -2,556 +2,3795 @@ extern crate csv;
 extern crate nom;
 extern crate serde;
 
+use std::cell::OnceCell;
+use std::cmp::Ordering;
+use std::collections::{BTreeMap, BinaryHeap, HashMap, HashSet};
 use std::error::Error;
 use std::fs::File;
+use std::io::{self, BufRead, BufReader, Read, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+use std::rc::Rc;
+use std::sync::atomic::{AtomicUsize, Ordering as AtomicOrdering};
+use std::sync::{mpsc, Arc, Mutex};
+use std::thread;
 
-use csv::{ReaderBuilder, StringRecord};
+use chrono::NaiveDate;
+use csv::{ReaderBuilder, StringRecord, WriterBuilder};
+use flate2::read::GzDecoder;
+
+use crate::json_utils::JsonSource;
 use nom::{
     branch::alt,
     bytes::complete::{is_not, tag, tag_no_case},
-    character::complete::{alphanumeric1, char, digit1, one_of, space0, space1},
-    combinator::{map, opt, value},
+    character::complete::{char, digit1, multispace0, multispace1, one_of},
+    combinator::{consumed, map, not, opt, peek, recognize, value, verify},
     multi::{many0, many1, separated_list1},
-    sequence::{delimited, preceded, tuple},
+    sequence::{delimited, preceded, terminated, tuple},
     IResult,
 };
 use regex::Regex;
+use serde_derive::Serialize;
+use serde_json::{Map, Value as JsonValue};
 
-#[derive(Debug)]
+#[derive(Debug, Clone)]
 pub struct SQLQuery {
-    pub columns: Vec<String>,
+    pub columns: Vec<SelectItem>,
     pub table: String,
+    pub table_alias: Option<String>,
+    pub joins: Vec<Join>,
     pub condition: Option<Condition>,
-    pub order_by: Option<OrderBy>,
+    pub order_by: Vec<OrderBy>,
+    pub limit: Option<usize>,
+    pub offset: Option<usize>,
     pub distinct: bool,
     pub group_by: Option<Vec<String>>,
-    pub aggregate_functions: Vec<AggregateFunction>,
+    pub having: Option<Condition>,
+    // Filters rows on the results of window functions
+    pub qualify: Option<Condition>,
+}
+
+// A statement, either a query or one that writes to a file
+#[derive(Debug, Clone)]
+pub enum Statement {
+    Select(SQLQuery),
+    // CREATE TABLE 'out.csv' AS SELECT ... writes the results to a new file
+    CreateTableAs(String, SQLQuery),
+    // INSERT INTO 'out.csv' SELECT ... appends the results to an existing file
+    InsertInto(String, SQLQuery),
+    // UPDATE 'data.csv' SET column = expression, ... WHERE ...
+    Update(String, Vec<Assignment>, Option<Condition>),
+    // DELETE FROM 'data.csv' WHERE ...
+    Delete(String, Option<Condition>),
+}
+
+// A column set by an UPDATE
+#[derive(Debug, Clone)]
+pub struct Assignment {
+    pub column: String,
+    pub expr: Expr,
+}
+
+// A column in the SELECT list, along with the name given to it with AS
+#[derive(Debug, Clone)]
+pub struct SelectItem {
+    pub expr: Expr,
+    pub alias: Option<String>,
+    // The expression as written in the query
+    pub text: String,
+}
+
+impl SelectItem {
+    // The header of the column in the results
+    pub fn header(&self) -> String {
+        match (&self.alias, &self.expr) {
+            (Some(alias), _) => alias.clone(),
+            (None, Expr::Column(column)) => column.clone(),
+            (None, Expr::Aggregate(function)) => function.to_string(),
+            (None, _) => self.text.clone(),
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub enum Expr {
+    Column(String),
+    Literal(Value),
+    Aggregate(AggregateFunction),
+    Negate(Box<Expr>),
+    BinaryOp(Box<Expr>, Op, Box<Expr>),
+    Function(ScalarFunction, Vec<Expr>),
+    // Each WHEN condition with its result, followed by the ELSE result
+    Case(Vec<(Condition, Expr)>, Option<Box<Expr>>),
+    Cast(Box<Expr>, ColumnType),
+    Window(Box<WindowFunction>),
+}
+
+// A function calculated over a window of rows e.g. RANK() OVER (PARTITION BY Platform ORDER BY Sales DESC)
+#[derive(Debug, Clone)]
+pub struct WindowFunction {
+    pub function: WindowKind,
+    pub partition_by: Vec<String>,
+    pub order_by: Vec<OrderBy>,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum WindowKind {
+    RowNumber,
+    Rank,
+    DenseRank,
+    // The column, how many rows back or ahead to look and the value when there's no such row
+    Lag(String, usize, Option<Value>),
+    Lead(String, usize, Option<Value>),
+    // An aggregate over the partition, or a running total when the window has an ORDER BY
+    Aggregate(AggregateFunction),
+}
+
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub enum Op {
+    Add,
+    Sub,
+    Mul,
+    Div,
+    Mod,
+}
+
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub enum ScalarFunction {
+    Upper,
+    Lower,
+    Length,
+    Trim,
+    Substr,
+    Replace,
+    Concat,
+    Coalesce,
+}
+
+impl ScalarFunction {
+    // The minimum and maximum number of arguments the function takes
+    fn arity(&self) -> (usize, usize) {
+        match self {
+            ScalarFunction::Upper
+            | ScalarFunction::Lower
+            | ScalarFunction::Length
+            | ScalarFunction::Trim => (1, 1),
+            ScalarFunction::Substr => (2, 3),
+            ScalarFunction::Replace => (3, 3),
+            ScalarFunction::Concat | ScalarFunction::Coalesce => (1, usize::MAX),
+        }
+    }
 }
 
-#[derive(Debug, PartialEq)]
+#[derive(Debug, PartialEq, Clone)]
 pub enum AggregateFunction {
     Count(String),
+    CountDistinct(String),
     Min(String),
     Max(String),
     Sum(String),
     Avg(String),
+    Median(String),
+    StdDev(String),
+    // The column and the separator placed between values
+    GroupConcat(String, String),
+}
+
+impl AggregateFunction {
+    // The column the function is calculated over, * for COUNT(*)
+    pub fn column(&self) -> &str {
+        match self {
+            AggregateFunction::Count(column)
+            | AggregateFunction::CountDistinct(column)
+            | AggregateFunction::Min(column)
+            | AggregateFunction::Max(column)
+            | AggregateFunction::Sum(column)
+            | AggregateFunction::Avg(column)
+            | AggregateFunction::Median(column)
+            | AggregateFunction::StdDev(column)
+            | AggregateFunction::GroupConcat(column, _) => column,
+        }
+    }
+}
+
+// Aggregate functions are displayed the way they're written in a query, e.g. SUM(Global_Sales),
+// which is also used as the header of the result column
+impl std::fmt::Display for AggregateFunction {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            AggregateFunction::Count(column) => write!(f, "COUNT({})", column),
+            AggregateFunction::CountDistinct(column) => write!(f, "COUNT(DISTINCT {})", column),
+            AggregateFunction::Min(column) => write!(f, "MIN({})", column),
+            AggregateFunction::Max(column) => write!(f, "MAX({})", column),
+            AggregateFunction::Sum(column) => write!(f, "SUM({})", column),
+            AggregateFunction::Avg(column) => write!(f, "AVG({})", column),
+            AggregateFunction::Median(column) => write!(f, "MEDIAN({})", column),
+            AggregateFunction::StdDev(column) => write!(f, "STDDEV({})", column),
+            AggregateFunction::GroupConcat(column, separator) if separator == "," => {
+                write!(f, "GROUP_CONCAT({})", column)
+            }
+            AggregateFunction::GroupConcat(column, separator) => {
+                write!(f, "GROUP_CONCAT({}, '{}')", column, separator)
+            }
+        }
+    }
+}
+
+#[derive(Debug, PartialEq, Clone)]
+pub enum JoinType {
+    Inner,
+    Left,
+}
+
+#[derive(Debug, PartialEq, Clone)]
+pub struct Join {
+    pub join_type: JoinType,
+    pub table: String,
+    pub alias: Option<String>,
+    pub left_column: String,
+    pub right_column: String,
 }
 
-#[derive(Debug, PartialEq)]
+#[derive(Debug, PartialEq, Clone)]
 pub struct OrderBy {
     pub column: String,
     pub direction: SortDirection,
 }
 
-#[derive(Debug, PartialEq)]
+#[derive(Debug, PartialEq, Clone)]
 pub enum SortDirection {
     Ascending,
     Descending,
 }
 
-#[derive(Debug)]
+#[derive(Debug, Clone)]
 pub enum Condition {
-    GreaterThan(String, i32),
-    GreaterThanEqualTo(String, i32),
-    LessThan(String, i32),
-    LessThanEqualTo(String, i32),
-    Equal(String, String),
+    GreaterThan(String, Value),
+    GreaterThanEqualTo(String, Value),
+    LessThan(String, Value),
+    LessThanEqualTo(String, Value),
+    Equal(String, Value),
+    NotEqual(String, Value),
     Like(String, regex::Regex),
-    Between(String, i32, i32),
+    NotLike(String, regex::Regex),
+    Between(String, Value, Value),
+    In(String, Vec<Value>),
+    NotIn(String, Vec<Value>),
+    InSubquery(String, Subquery),
+    NotInSubquery(String, Subquery),
+    IsNull(String),
+    IsNotNull(String),
+    // A comparison against the single value returned by a subquery e.g. Global_Sales > (SELECT ...)
+    CompareSubquery(String, String, Subquery),
     And(Box<Condition>, Box<Condition>),
     Or(Box<Condition>, Box<Condition>),
     Not(Box<Condition>),
 }
 
-pub fn load_csv(file_path: &str) -> Result<(Vec<String>, Vec<StringRecord>), Box<dyn Error>> {
-    let file = File::open(file_path)?;
-    let mut reader = ReaderBuilder::new().flexible(true).from_reader(file);
+// A query nested in a condition. It's run once before the outer query reads any records
+// and the values in its first column are kept for the outer query to compare against.
+#[derive(Debug, Clone)]
+pub struct Subquery {
+    pub query: Box<SQLQuery>,
+    values: OnceCell<Vec<Value>>,
+}
+
+impl Subquery {
+    pub fn new(query: SQLQuery) -> Subquery {
+        Subquery {
+            query: Box::new(query),
+            values: OnceCell::new(),
+        }
+    }
+
+    fn run(&self, options: &CsvOptions) -> Result<(), Box<dyn Error>> {
+        if self.values.get().is_some() {
+            return Ok(());
+        }
+
+        let mut results = QueryResults::default();
+        execute_query(&self.query, options, &mut results)?;
+        let values = results
+            .rows
+            .iter()
+            .map(|row| row.first().map_or(Value::Null, |value| Value::infer(value)))
+            .collect();
+        let _ = self.values.set(values);
+        Ok(())
+    }
+
+    fn values(&self) -> &[Value] {
+        self.values.get().map_or(&[], |values| values.as_slice())
+    }
+
+    // The single value returned by a scalar subquery, NULL when it returns no rows
+    fn scalar(&self) -> Value {
+        self.values().first().cloned().unwrap_or(Value::Null)
+    }
+}
+
+// A typed value, either a literal from the query or a field read from a CSV file
+#[derive(Debug, Clone, PartialEq)]
+pub enum Value {
+    Null,
+    Integer(i64),
+    Float(f64),
+    Date(NaiveDate),
+    String(String),
+}
+
+// The type of a column, inferred from a sample of its values
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ColumnType {
+    Integer,
+    Float,
+    Date,
+    String,
+}
+
+// Number of records used to infer the type of each column
+const TYPE_INFERENCE_SAMPLE_SIZE: usize = 1000;
+
+const DATE_FORMATS: [&str; 3] = ["%Y-%m-%d", "%Y/%m/%d", "%d/%m/%Y"];
+
+fn parse_date(text: &str) -> Option<NaiveDate> {
+    DATE_FORMATS
+        .iter()
+        .find_map(|format| NaiveDate::parse_from_str(text, format).ok())
+}
+
+impl Value {
+    // Work out the type of a single value, empty fields are NULL
+    pub fn infer(text: &str) -> Value {
+        if text.is_empty() {
+            Value::Null
+        } else if let Ok(num) = text.parse::<i64>() {
+            Value::Integer(num)
+        } else if let Ok(num) = text.parse::<f64>() {
+            Value::Float(num)
+        } else if let Some(date) = parse_date(text) {
+            Value::Date(date)
+        } else {
+            Value::String(text.to_string())
+        }
+    }
+
+    // Read a field as the type of its column. Fields that don't fit the column type
+    // (e.g. 'N/A' in a numeric column) fall back to their own inferred type.
+    pub fn parse(text: &str, column_type: ColumnType) -> Value {
+        match column_type {
+            ColumnType::String if !text.is_empty() => Value::String(text.to_string()),
+            _ => Value::infer(text),
+        }
+    }
+
+    fn as_f64(&self) -> Option<f64> {
+        match self {
+            Value::Integer(num) => Some(*num as f64),
+            Value::Float(num) => Some(*num),
+            Value::String(text) => text.parse::<f64>().ok(),
+            _ => None,
+        }
+    }
+
+    // The value as a number, strings are converted if they hold a number
+    fn as_number(&self) -> Option<Value> {
+        match self {
+            Value::Integer(_) | Value::Float(_) => Some(self.clone()),
+            Value::String(text) => match Value::infer(text) {
+                number @ (Value::Integer(_) | Value::Float(_)) => Some(number),
+                _ => None,
+            },
+            _ => None,
+        }
+    }
+
+    // The text of the value, None for NULL
+    fn as_text(&self) -> Option<String> {
+        match self {
+            Value::Null => None,
+            _ => Some(self.to_string()),
+        }
+    }
+
+    // Convert a value to another type, values that can't be converted become NULL
+    pub fn cast(&self, column_type: ColumnType) -> Value {
+        match (column_type, self) {
+            (_, Value::Null) => Value::Null,
+            (ColumnType::Integer, _) => match self.as_number() {
+                Some(Value::Integer(num)) => Value::Integer(num),
+                Some(Value::Float(num)) => Value::Integer(num.trunc() as i64),
+                _ => Value::Null,
+            },
+            (ColumnType::Float, _) => self.as_f64().map_or(Value::Null, Value::Float),
+            (ColumnType::Date, Value::Date(date)) => Value::Date(*date),
+            (ColumnType::Date, Value::String(text)) => parse_date(text).map_or(Value::Null, Value::Date),
+            (ColumnType::Date, _) => Value::Null,
+            (ColumnType::String, _) => Value::String(self.to_string()),
+        }
+    }
+
+    // Compare two values, converting between types where it makes sense.
+    // Returns None when the values can't be compared, which includes anything compared to NULL.
+    pub fn compare(&self, other: &Value) -> Option<Ordering> {
+        match (self, other) {
+            (Value::Null, _) | (_, Value::Null) => None,
+            (Value::Integer(a), Value::Integer(b)) => Some(a.cmp(b)),
+            (Value::String(a), Value::String(b)) => Some(a.cmp(b)),
+            (Value::Date(a), Value::Date(b)) => Some(a.cmp(b)),
+            // Strings that aren't valid dates are compared to the ISO date text, so that
+            // e.g. '2020-01-01 10:00:00' >= '2020-01-01'
+            (Value::Date(a), Value::String(b)) => match parse_date(b) {
+                Some(b) => Some(a.cmp(&b)),
+                None => Some(a.to_string().as_str().cmp(b.as_str())),
+            },
+            (Value::String(_), Value::Date(_)) => other.compare(self).map(Ordering::reverse),
+            (Value::Date(_), _) | (_, Value::Date(_)) => None,
+            (a, b) => a.as_f64()?.partial_cmp(&b.as_f64()?),
+        }
+    }
+
+    // Values that can't be compared are never equal, unless one of them is NULL
+    // in which case the result is unknown
+    pub fn equals(&self, other: &Value) -> Option<bool> {
+        match (self, other) {
+            (Value::Null, _) | (_, Value::Null) => None,
+            _ => Some(self.compare(other) == Some(Ordering::Equal)),
+        }
+    }
+}
+
+impl std::fmt::Display for Value {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Value::Null => write!(f, "NULL"),
+            Value::Integer(num) => write!(f, "{}", num),
+            Value::Float(num) => write!(f, "{}", num),
+            Value::Date(date) => write!(f, "{}", date),
+            Value::String(text) => write!(f, "{}", text),
+        }
+    }
+}
+
+// Infer the type of each column from a sample of records. A column only gets a
+// numeric or date type when every non empty value in the sample is of that type.
+pub fn infer_column_types(column_count: usize, records: &[StringRecord]) -> Vec<ColumnType> {
+    (0..column_count)
+        .map(|index| {
+            let mut column_type: Option<ColumnType> = None;
+            for value in records.iter().filter_map(|record| record.get(index)) {
+                let value_type = match Value::infer(value) {
+                    Value::Null => continue,
+                    Value::Integer(_) => ColumnType::Integer,
+                    Value::Float(_) => ColumnType::Float,
+                    Value::Date(_) => ColumnType::Date,
+                    Value::String(_) => return ColumnType::String,
+                };
+
+                column_type = match (column_type, value_type) {
+                    (None, value_type) => Some(value_type),
+                    (Some(a), b) if a == b => Some(a),
+                    // Integers and floats mixed together make a float column
+                    (Some(ColumnType::Integer), ColumnType::Float)
+                    | (Some(ColumnType::Float), ColumnType::Integer) => Some(ColumnType::Float),
+                    _ => return ColumnType::String,
+                };
+            }
+            column_type.unwrap_or(ColumnType::String)
+        })
+        .collect()
+}
+
+// Summary statistics for a single column of a table
+#[derive(Serialize, Debug)]
+pub struct ColumnProfile {
+    pub name: String,
+    #[serde(rename = "type")]
+    pub column_type: String,
+    pub nulls: usize,
+    pub distinct: usize,
+    pub min: Option<String>,
+    pub max: Option<String>,
+    // Only set for numeric columns
+    pub mean: Option<f64>,
+    pub stddev: Option<f64>,
+    // The most common values, most frequent first
+    pub top: Vec<ValueCount>,
+    pub histogram: Vec<HistogramBin>,
+}
+
+#[derive(Serialize, Debug)]
+pub struct ValueCount {
+    pub value: String,
+    pub count: usize,
+}
+
+#[derive(Serialize, Debug)]
+pub struct HistogramBin {
+    pub start: f64,
+    pub end: f64,
+    pub count: usize,
+}
+
+// Profile each column of a table. Types are inferred from every value rather than a sample,
+// with columns holding only true/false values reported as bool.
+pub fn describe_table(
+    headers: &[String],
+    records: &[StringRecord],
+    top_values: usize,
+    histogram_bins: usize,
+) -> Vec<ColumnProfile> {
+    let column_types = infer_column_types(headers.len(), records);
+
+    headers
+        .iter()
+        .zip(column_types)
+        .enumerate()
+        .map(|(index, (name, column_type))| {
+            let values: Vec<&str> = records
+                .iter()
+                .filter_map(|record| record.get(index))
+                .filter(|value| !value.is_empty())
+                .collect();
+            describe_column(name, column_type, records.len(), &values, top_values, histogram_bins)
+        })
+        .collect()
+}
+
+fn describe_column(
+    name: &str,
+    column_type: ColumnType,
+    row_count: usize,
+    values: &[&str],
+    top_values: usize,
+    histogram_bins: usize,
+) -> ColumnProfile {
+    let is_bool = !values.is_empty()
+        && values
+            .iter()
+            .all(|value| value.eq_ignore_ascii_case("true") || value.eq_ignore_ascii_case("false"));
+    let type_name = match column_type {
+        _ if is_bool => "bool",
+        ColumnType::Integer => "int",
+        ColumnType::Float => "float",
+        ColumnType::Date => "date",
+        ColumnType::String => "string",
+    };
+
+    let mut counts: HashMap<&str, usize> = HashMap::new();
+    let mut min: Option<(Value, &str)> = None;
+    let mut max: Option<(Value, &str)> = None;
+    for value in values {
+        *counts.entry(value).or_default() += 1;
+
+        let typed = Value::parse(value, column_type);
+        if min.as_ref().is_none_or(|(min, _)| typed.compare(min) == Some(Ordering::Less)) {
+            min = Some((typed.clone(), value));
+        }
+        if max.as_ref().is_none_or(|(max, _)| typed.compare(max) == Some(Ordering::Greater)) {
+            max = Some((typed, value));
+        }
+    }
+
+    let mut top: Vec<ValueCount> = counts
+        .iter()
+        .map(|(value, count)| ValueCount {
+            value: value.to_string(),
+            count: *count,
+        })
+        .collect();
+    top.sort_by(|a, b| b.count.cmp(&a.count).then_with(|| a.value.cmp(&b.value)));
+    top.truncate(top_values);
+
+    let mut profile = ColumnProfile {
+        name: name.to_string(),
+        column_type: type_name.to_string(),
+        nulls: row_count - values.len(),
+        distinct: counts.len(),
+        min: min.map(|(_, text)| text.to_string()),
+        max: max.map(|(_, text)| text.to_string()),
+        mean: None,
+        stddev: None,
+        top,
+        histogram: Vec::new(),
+    };
+
+    if matches!(column_type, ColumnType::Integer | ColumnType::Float) && !values.is_empty() {
+        let numbers: Vec<f64> = values.iter().filter_map(|value| value.parse().ok()).collect();
+        let mean = numbers.iter().sum::<f64>() / numbers.len() as f64;
+        profile.mean = Some(mean);
+        // Sample standard deviation, as returned by STDDEV
+        if numbers.len() > 1 {
+            let squares: f64 = numbers.iter().map(|n| (n - mean).powi(2)).sum();
+            profile.stddev = Some((squares / (numbers.len() - 1) as f64).sqrt());
+        }
+        profile.histogram = histogram(&numbers, histogram_bins);
+    }
+
+    profile
+}
+
+// Count the numbers falling into equal width bins between the smallest and largest number
+fn histogram(numbers: &[f64], bins: usize) -> Vec<HistogramBin> {
+    let min = numbers.iter().cloned().fold(f64::INFINITY, f64::min);
+    let max = numbers.iter().cloned().fold(f64::NEG_INFINITY, f64::max);
+    if bins == 0 || numbers.is_empty() {
+        return Vec::new();
+    }
+    // Every number is the same so they all share a single bin
+    let bins = if min == max { 1 } else { bins };
+
+    let width = (max - min) / bins as f64;
+    let mut histogram: Vec<HistogramBin> = (0..bins)
+        .map(|i| HistogramBin {
+            start: min + width * i as f64,
+            end: if i + 1 == bins { max } else { min + width * (i + 1) as f64 },
+            count: 0,
+        })
+        .collect();
+    for number in numbers {
+        let bin = if width > 0.0 {
+            (((number - min) / width) as usize).min(bins - 1)
+        } else {
+            0
+        };
+        histogram[bin].count += 1;
+    }
+    histogram
+}
+
+// How CSV files are read, set from the command line
+#[derive(Debug, Clone, Default)]
+pub struct CsvOptions {
+    // The field delimiter, detected from each file when not given
+    pub delimiter: Option<u8>,
+    // Files have no header row, so their columns are named c1..cN
+    pub no_header: bool,
+    // How files are read, picked from each file's extension when not given
+    pub input_format: Option<InputFormat>,
+    // Tables already held in memory, used instead of reading the file they're named after
+    pub tables: HashMap<String, Arc<MemoryTable>>,
+    // Number of threads records are filtered and aggregated on, queries run on the calling
+    // thread when this is 0 or 1
+    pub threads: usize,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum InputFormat {
+    Csv,
+    // Either a JSON array of objects or newline delimited JSON objects
+    Json,
+}
+
+impl InputFormat {
+    pub fn from_path(file_path: &str) -> InputFormat {
+        let path = file_path.strip_suffix(".gz").unwrap_or(file_path);
+        match Path::new(path).extension().and_then(|extension| extension.to_str()) {
+            Some("json") | Some("ndjson") | Some("jsonl") => InputFormat::Json,
+            _ => InputFormat::Csv,
+        }
+    }
+}
+
+// A stream of records read lazily from one or more CSV files
+pub type RecordStream = Box<dyn Iterator<Item = Result<StringRecord, Box<dyn Error>>>>;
+
+// Receives the result headers followed by each result row as soon as it is produced
+pub trait RowSink {
+    fn write_headers(&mut self, headers: &[String]) -> Result<(), Box<dyn Error>>;
+    fn write_row(&mut self, row: Vec<String>) -> Result<(), Box<dyn Error>>;
+}
+
+// Collects the results of a query in memory
+#[derive(Debug, Default)]
+pub struct QueryResults {
+    pub headers: Vec<String>,
+    pub rows: Vec<Vec<String>>,
+}
+
+impl RowSink for QueryResults {
+    fn write_headers(&mut self, headers: &[String]) -> Result<(), Box<dyn Error>> {
+        self.headers = headers.to_vec();
+        Ok(())
+    }
+
+    fn write_row(&mut self, row: Vec<String>) -> Result<(), Box<dyn Error>> {
+        self.rows.push(row);
+        Ok(())
+    }
+}
+
+#[derive(Debug, Default)]
+pub struct QueryStats {
+    pub rows_scanned: usize,
+    pub rows_returned: usize,
+}
+
+// A table that queries can be run over. Each record has a field for each of the headers.
+pub trait RowSource {
+    fn headers(&self) -> &[String];
+    // Consume the source, reading its records on demand
+    fn into_records(self: Box<Self>) -> RecordStream;
+}
+
+// Open a table, choosing how to read it from the options or otherwise the file extension
+pub fn open_table(file_path: &str, options: &CsvOptions) -> Result<Box<dyn RowSource>, Box<dyn Error>> {
+    if let Some(table) = options
+        .tables
+        .get(file_path.strip_prefix("./").unwrap_or(file_path))
+    {
+        return Ok(Box::new(MemorySource::new(table.clone())));
+    }
+
+    if let Some(files) = table_files(file_path)? {
+        return Ok(Box::new(MultiFileSource::open(files, options, false)?));
+    }
+    open_file(file_path, options)
+}
+
+// Open a single file, choosing how to read it from the options or otherwise its extension
+fn open_file(file_path: &str, options: &CsvOptions) -> Result<Box<dyn RowSource>, Box<dyn Error>> {
+    let input_format = options
+        .input_format
+        .unwrap_or_else(|| InputFormat::from_path(file_path));
+
+    Ok(match input_format {
+        InputFormat::Csv => Box::new(CsvSource::open(file_path, options)?),
+        InputFormat::Json => Box::new(JsonSource::open(file_path)?),
+    })
+}
+
+// Open a table and read all of its records into memory
+pub fn load_table(
+    file_path: &str,
+    options: &CsvOptions,
+) -> Result<(Vec<String>, Vec<StringRecord>), Box<dyn Error>> {
+    let source = open_table(file_path, options)?;
+    let headers = source.headers().to_vec();
+
+    // Read all records into a vector
+    let records: Vec<StringRecord> = source.into_records().collect::<Result<Vec<_>, _>>()?;
+
+    Ok((headers, records))
+}
+
+// A table read into memory once so it can be queried many times
+#[derive(Debug)]
+pub struct MemoryTable {
+    pub headers: Vec<String>,
+    pub records: Vec<StringRecord>,
+}
+
+impl MemoryTable {
+    pub fn load(file_path: &str, options: &CsvOptions) -> Result<MemoryTable, Box<dyn Error>> {
+        let (headers, records) = load_table(file_path, options)?;
+        Ok(MemoryTable { headers, records })
+    }
+}
+
+// Reads the records of a table held in memory, copying each one as it's needed
+pub struct MemorySource {
+    table: Arc<MemoryTable>,
+}
+
+impl MemorySource {
+    pub fn new(table: Arc<MemoryTable>) -> MemorySource {
+        MemorySource { table }
+    }
+}
+
+impl RowSource for MemorySource {
+    fn headers(&self) -> &[String] {
+        &self.table.headers
+    }
+
+    fn into_records(self: Box<Self>) -> RecordStream {
+        let table = self.table;
+        Box::new((0..table.records.len()).map(move |i| Ok(table.records[i].clone())))
+    }
+}
+
+// Open a file for reading. A path of - reads from stdin and files ending in .gz are decompressed.
+pub fn open_input(file_path: &str) -> Result<BufReader<Box<dyn Read>>, Box<dyn Error>> {
+    let input: Box<dyn Read> = match file_path {
+        "-" => Box::new(io::stdin()),
+        _ => Box::new(File::open(file_path)?),
+    };
+    let input: Box<dyn Read> = if file_path.ends_with(".gz") {
+        Box::new(GzDecoder::new(input))
+    } else {
+        input
+    };
+    Ok(BufReader::new(input))
+}
+
+// A CSV file, its headers are read up front and the records on demand
+pub struct CsvSource {
+    headers: Vec<String>,
+    records: RecordStream,
+    delimiter: u8,
+}
+
+impl CsvSource {
+    pub fn open(file_path: &str, options: &CsvOptions) -> Result<CsvSource, Box<dyn Error>> {
+        let mut input = open_input(file_path)?;
+
+        let delimiter = match options.delimiter {
+            Some(delimiter) => delimiter,
+            None => detect_delimiter(file_path, &mut input)?,
+        };
+        let mut reader = ReaderBuilder::new()
+            .flexible(true)
+            .delimiter(delimiter)
+            .has_headers(!options.no_header)
+            .from_reader(input);
+
+        if options.no_header {
+            // Name the columns after the number of fields in the first record
+            let mut records = reader.into_records().peekable();
+            let column_count = match records.peek() {
+                Some(Ok(record)) => record.len(),
+                _ => 0,
+            };
+            return Ok(CsvSource {
+                headers: (1..=column_count).map(|i| format!("c{}", i)).collect(),
+                records: Box::new(records.map(|record| record.map_err(|e| e.into()))),
+                delimiter,
+            });
+        }
+
+        // Read headers from the CSV
+        let headers = reader
+            .headers()?
+            .iter()
+            .map(|h| h.to_string())
+            .collect::<Vec<String>>();
+
+        let records = reader
+            .into_records()
+            .map(|record| record.map_err(|e| e.into()));
+
+        Ok(CsvSource {
+            headers,
+            records: Box::new(records),
+            delimiter,
+        })
+    }
+
+    // The delimiter the file is read with, either given or detected
+    pub fn delimiter(&self) -> u8 {
+        self.delimiter
+    }
+}
+
+impl RowSource for CsvSource {
+    fn headers(&self) -> &[String] {
+        &self.headers
+    }
+
+    fn into_records(self: Box<Self>) -> RecordStream {
+        self.records
+    }
+}
+
+// The virtual column holding the path of the file each record was read from, added to
+// tables made up of several files. It isn't included in SELECT *.
+pub const FILE_COLUMN: &str = "_file";
+
+// Several files read one after another as a single table, e.g. every file in a directory.
+// Each file must have the same columns, although they can be in a different order.
+pub struct MultiFileSource {
+    headers: Vec<String>,
+    // Each file along with where each of the first file's columns are in it, when they differ
+    files: Vec<(String, Option<Vec<usize>>)>,
+    options: CsvOptions,
+    file_column: bool,
+}
+
+impl MultiFileSource {
+    pub fn open(
+        files: Vec<String>,
+        options: &CsvOptions,
+        file_column: bool,
+    ) -> Result<MultiFileSource, Box<dyn Error>> {
+        let mut headers: Vec<String> = Vec::new();
+        let mut positions = Vec::new();
+
+        // Only the headers are read up front, each file is opened again as its records are read
+        for (index, file) in files.iter().enumerate() {
+            let source = open_file(file, options).map_err(|e| format!("Failed to open '{}': {}", file, e))?;
+            let file_headers = source.headers();
+            if index == 0 {
+                headers = file_headers.to_vec();
+                positions.push(None);
+            } else if file_headers == headers.as_slice() {
+                positions.push(None);
+            } else if let Some(differences) = header_differences(&headers, file_headers) {
+                return Err(format!(
+                    "The columns of '{}' don't match '{}' ({}), expected {}",
+                    file,
+                    files[0],
+                    differences,
+                    headers.join(", ")
+                )
+                .into());
+            } else {
+                let columns = headers
+                    .iter()
+                    .filter_map(|header| header_position(header, file_headers))
+                    .collect();
+                positions.push(Some(columns));
+            }
+        }
+
+        if file_column {
+            headers.push(FILE_COLUMN.to_string());
+        }
+
+        Ok(MultiFileSource {
+            headers,
+            files: files.into_iter().zip(positions).collect(),
+            options: options.clone(),
+            file_column,
+        })
+    }
+}
+
+impl RowSource for MultiFileSource {
+    fn headers(&self) -> &[String] {
+        &self.headers
+    }
+
+    fn into_records(self: Box<Self>) -> RecordStream {
+        let MultiFileSource {
+            headers,
+            files,
+            options,
+            file_column,
+        } = *self;
+        let width = headers.len() - usize::from(file_column);
+
+        Box::new(files.into_iter().flat_map(move |(file, positions)| -> RecordStream {
+            let source = match open_file(&file, &options) {
+                Ok(source) => source,
+                Err(e) => return Box::new(std::iter::once(Err(format!("Failed to open '{}': {}", file, e).into()))),
+            };
+            Box::new(source.into_records().map(move |record| {
+                let record = record?;
+                let mut record = match &positions {
+                    Some(positions) => positions.iter().map(|i| field(&record, Some(*i))).collect(),
+                    None => record,
+                };
+                if file_column {
+                    // Short records are padded so the file name always lands in the last column
+                    record.truncate(width);
+                    while record.len() < width {
+                        record.push_field("");
+                    }
+                    record.push_field(&file);
+                }
+                Ok(record)
+            }))
+        }))
+    }
+}
+
+// The files a table is made up of when it's named by a glob pattern such as
+// 'exports/2026-10-*.csv' or is a directory, sorted by path. None for a single file.
+pub fn table_files(file_path: &str) -> Result<Option<Vec<String>>, Box<dyn Error>> {
+    if is_glob(file_path) {
+        let files = glob_files(file_path)?;
+        if files.is_empty() {
+            return Err(format!("No files match '{}'", file_path).into());
+        }
+        return Ok(Some(files));
+    }
+
+    let path = Path::new(file_path);
+    if file_path == "-" || !path.is_dir() {
+        return Ok(None);
+    }
+
+    // Every csv and json file in the directory, other files and hidden files are skipped
+    let mut files = Vec::new();
+    for entry in std::fs::read_dir(path)? {
+        let entry_path = entry?.path();
+        let name = entry_path.file_name().unwrap_or_default().to_string_lossy();
+        let extension = Path::new(name.strip_suffix(".gz").unwrap_or(&name))
+            .extension()
+            .map(|extension| extension.to_string_lossy().to_lowercase());
+        let is_table = matches!(
+            extension.as_deref(),
+            Some("csv" | "tsv" | "tab" | "psv" | "json" | "ndjson" | "jsonl")
+        );
+        if is_table && !name.starts_with('.') && entry_path.is_file() {
+            files.push(entry_path.to_string_lossy().into_owned());
+        }
+    }
+    if files.is_empty() {
+        return Err(format!("No csv or json files in '{}'", file_path).into());
+    }
+    files.sort();
+    Ok(Some(files))
+}
+
+fn is_glob(path: &str) -> bool {
+    path.contains(['*', '?', '['])
+}
+
+// Find the files matching a glob pattern. * and ? match within a single file or directory name,
+// [abc] and [a-z] match a set of characters and ** matches any number of directories.
+fn glob_files(pattern: &str) -> Result<Vec<String>, Box<dyn Error>> {
+    // The matched paths start the same way as the pattern, e.g. with ./ or /
+    let base = if pattern.starts_with('/') {
+        PathBuf::from("/")
+    } else if pattern.starts_with("./") {
+        PathBuf::from(".")
+    } else {
+        PathBuf::new()
+    };
+    let components: Vec<&str> = pattern
+        .split('/')
+        .filter(|component| !component.is_empty() && *component != ".")
+        .collect();
+
+    let mut files = Vec::new();
+    glob_walk(base, &components, &mut files)?;
+    files.sort();
+    files.dedup();
+    Ok(files
+        .into_iter()
+        .map(|file| file.to_string_lossy().into_owned())
+        .collect())
+}
+
+fn glob_walk(base: PathBuf, components: &[&str], files: &mut Vec<PathBuf>) -> Result<(), Box<dyn Error>> {
+    let Some((component, rest)) = components.split_first() else {
+        if base.is_file() {
+            files.push(base);
+        }
+        return Ok(());
+    };
+
+    if !is_glob(component) {
+        let path = base.join(component);
+        if path.exists() {
+            glob_walk(path, rest, files)?;
+        }
+        return Ok(());
+    }
+
+    let directory = if base.as_os_str().is_empty() { Path::new(".") } else { base.as_path() };
+    let Ok(entries) = std::fs::read_dir(directory) else {
+        return Ok(());
+    };
+    let name_pattern = glob_regex(component)?;
+    for entry in entries {
+        let entry = entry?;
+        let name = entry.file_name().to_string_lossy().into_owned();
+        // As in a shell, hidden files are only matched by a pattern starting with a dot
+        if name.starts_with('.') && !component.starts_with('.') {
+            continue;
+        }
+        if *component == "**" {
+            // Symlinks aren't followed so links back up the tree can't loop forever
+            if entry.file_type()?.is_dir() {
+                glob_walk(base.join(&name), components, files)?;
+            }
+        } else if name_pattern.is_match(&name) {
+            glob_walk(base.join(&name), rest, files)?;
+        }
+    }
+    if *component == "**" {
+        glob_walk(base, rest, files)?;
+    }
+    Ok(())
+}
+
+// Convert a glob pattern for a single file or directory name into a regex
+fn glob_regex(pattern: &str) -> Result<Regex, Box<dyn Error>> {
+    let mut regex = String::from("^");
+    let mut chars = pattern.chars();
+    while let Some(c) = chars.next() {
+        match c {
+            '*' => regex.push_str(".*"),
+            '?' => regex.push('.'),
+            '[' => {
+                let set: String = chars.by_ref().take_while(|c| *c != ']').collect();
+                let set = set.replace('\\', "\\\\").replace('[', "\\[");
+                match set.strip_prefix('!') {
+                    Some(set) => regex.push_str(&format!("[^{}]", set)),
+                    None => regex.push_str(&format!("[{}]", set)),
+                }
+            }
+            c => regex.push_str(&regex::escape(&c.to_string())),
+        }
+    }
+    regex.push('$');
+    Regex::new(&regex).map_err(|_| format!("Invalid glob pattern '{}'", pattern).into())
+}
+
+// Work out the delimiter from the file extension (ignoring .gz), otherwise use whichever
+// of comma, tab, semicolon or pipe appears most in the first line
+fn detect_delimiter(file_path: &str, input: &mut dyn BufRead) -> Result<u8, Box<dyn Error>> {
+    let path = file_path.strip_suffix(".gz").unwrap_or(file_path);
+    match Path::new(path).extension().and_then(|extension| extension.to_str()) {
+        Some("tsv") | Some("tab") => return Ok(b'\t'),
+        Some("psv") => return Ok(b'|'),
+        _ => {}
+    }
+
+    let buffer = input.fill_buf()?;
+    let first_line = buffer.split(|b| *b == b'\n').next().unwrap_or_default();
+    let mut delimiter = b',';
+    let mut most = 0;
+    for candidate in [b',', b'\t', b';', b'|'] {
+        let count = first_line.iter().filter(|b| **b == candidate).count();
+        if count > most {
+            delimiter = candidate;
+            most = count;
+        }
+    }
+    Ok(delimiter)
+}
+
+// Load the FROM table along with any joined tables into memory, see open_tables
+pub fn load_tables(
+    query: &SQLQuery,
+    options: &CsvOptions,
+) -> Result<(Vec<String>, Vec<StringRecord>), Box<dyn Error>> {
+    let (headers, records) = open_tables(query, options)?;
+    let records: Vec<StringRecord> = records.collect::<Result<Vec<_>, _>>()?;
+    Ok((headers, records))
+}
+
+// Open the FROM table along with any joined tables. Without joins the headers are returned
+// as they appear in the file, otherwise each header is qualified with its table alias
+// (e.g. 'v.Name') so that columns sharing a name can still be told apart.
+// Only the joined tables are held in memory, the FROM table is streamed.
+pub fn open_tables(
+    query: &SQLQuery,
+    options: &CsvOptions,
+) -> Result<(Vec<String>, RecordStream), Box<dyn Error>> {
+    // A table made up of several files has a column naming the file each record came from
+    let source = match table_files(&query.table) {
+        Ok(Some(files)) if !options.tables.contains_key(&query.table) => {
+            MultiFileSource::open(files, options, true).map(|source| Box::new(source) as Box<dyn RowSource>)
+        }
+        Ok(_) => open_table(&query.table, options),
+        Err(e) => Err(e),
+    }
+    .map_err(|e| format!("Failed to load CSV file '{}': {}", query.table, e))?;
+    let headers = source.headers().to_vec();
+    let records = source.into_records();
+
+    if query.joins.is_empty() {
+        return Ok((headers, records));
+    }
+
+    let mut headers = qualify_headers(&headers, &table_qualifier(&query.table, &query.table_alias));
+    let mut records = records;
+
+    for join in &query.joins {
+        let (right_headers, right_records) = load_table(&join.table, options)
+            .map_err(|e| format!("Failed to load CSV file '{}': {}", join.table, e))?;
+        let right_headers =
+            qualify_headers(&right_headers, &table_qualifier(&join.table, &join.alias));
+
+        let hash_join = HashJoin::new(&headers, &right_headers, right_records, join)?;
+        records = Box::new(records.flat_map(move |record| match record {
+            Ok(record) => hash_join.probe(record).into_iter().map(Ok).collect(),
+            Err(e) => vec![Err(e)],
+        }));
+        headers.extend(right_headers);
+    }
+
+    Ok((headers, records))
+}
+
+// A table is referred to by its alias, falling back to the file name without its extension
+pub fn table_qualifier(table: &str, alias: &Option<String>) -> String {
+    match alias {
+        Some(alias) => alias.clone(),
+        None if table == "-" => "stdin".to_string(),
+        None => Path::new(table)
+            .file_stem()
+            .map(|stem| stem.to_string_lossy().to_string())
+            .unwrap_or_else(|| table.to_string()),
+    }
+}
+
+fn qualify_headers(headers: &[String], qualifier: &str) -> Vec<String> {
+    headers
+        .iter()
+        .map(|h| format!("{}.{}", qualifier, h))
+        .collect()
+}
+
+// Joins records against a table indexed by its join key, so each record from the
+// left hand side only needs a single lookup.
+struct HashJoin {
+    join_type: JoinType,
+    left_width: usize,
+    right_width: usize,
+    left_index: usize,
+    lookup: HashMap<String, Vec<StringRecord>>,
+}
+
+impl HashJoin {
+    fn new(
+        left_headers: &[String],
+        right_headers: &[String],
+        right_records: Vec<StringRecord>,
+        join: &Join,
+    ) -> Result<HashJoin, Box<dyn Error>> {
+        // The ON clause may name the two tables in either order
+        let (left_index, right_index) = match (
+            column_index(left_headers, &join.left_column),
+            column_index(right_headers, &join.right_column),
+        ) {
+            (Some(left), Some(right)) => (left, right),
+            _ => match (
+                column_index(left_headers, &join.right_column),
+                column_index(right_headers, &join.left_column),
+            ) {
+                (Some(left), Some(right)) => (left, right),
+                _ => {
+                    return Err(format!(
+                        "Could not resolve join condition '{} = {}' for table '{}'",
+                        join.left_column, join.right_column, join.table
+                    )
+                    .into())
+                }
+            },
+        };
+
+        let mut lookup: HashMap<String, Vec<StringRecord>> = HashMap::new();
+        for record in right_records {
+            let key = record.get(right_index).unwrap_or("").to_string();
+            // Empty values are treated as NULL and never match
+            if !key.is_empty() {
+                lookup.entry(key).or_default().push(record);
+            }
+        }
+
+        Ok(HashJoin {
+            join_type: join.join_type.clone(),
+            left_width: left_headers.len(),
+            right_width: right_headers.len(),
+            left_index,
+            lookup,
+        })
+    }
+
+    fn probe(&self, mut left: StringRecord) -> Vec<StringRecord> {
+        // Pad short rows so the right hand fields line up with their headers
+        while left.len() < self.left_width {
+            left.push_field("");
+        }
+
+        match self.lookup.get(left.get(self.left_index).unwrap_or("")) {
+            Some(matches) => matches
+                .iter()
+                .map(|right| {
+                    let mut row = left.clone();
+                    for i in 0..self.right_width {
+                        row.push_field(right.get(i).unwrap_or(""));
+                    }
+                    row
+                })
+                .collect(),
+            None if self.join_type == JoinType::Left => {
+                for _ in 0..self.right_width {
+                    left.push_field("");
+                }
+                vec![left]
+            }
+            None => Vec::new(),
+        }
+    }
+}
+
+// Find the index of a column, accepting both qualified ('v.Name') and unqualified ('Name')
+// references as long as the unqualified name is unambiguous.
+pub fn column_index(headers: &[String], column: &str) -> Option<usize> {
+    if let Some(index) = headers.iter().position(|h| h == column) {
+        return Some(index);
+    }
+
+    let suffix = format!(".{}", column);
+    let mut matches = headers
+        .iter()
+        .enumerate()
+        .filter(|(_, h)| h.ends_with(&suffix));
+    if let Some((index, _)) = matches.next() {
+        return if matches.next().is_none() {
+            Some(index)
+        } else {
+            None
+        };
+    }
+
+    // Qualified reference to a table that was loaded without qualified headers
+    column
+        .split_once('.')
+        .and_then(|(_, name)| headers.iter().position(|h| h == name))
+}
+
+// Find the index of a column, failing with a hint when there's no such column
+fn find_column(headers: &[String], column: &str) -> Result<usize, Box<dyn Error>> {
+    column_index(headers, column).ok_or_else(|| unknown_column(headers, column))
+}
+
+// Describe why a column couldn't be found, suggesting the closest header when there is one
+fn unknown_column(headers: &[String], column: &str) -> Box<dyn Error> {
+    let suffix = format!(".{}", column);
+    let matches: Vec<&str> = headers
+        .iter()
+        .filter(|h| h.ends_with(&suffix))
+        .map(|h| h.as_str())
+        .collect();
+    if matches.len() > 1 {
+        return format!(
+            "Column '{}' is ambiguous, it could be any of: {}",
+            column,
+            matches.join(", ")
+        )
+        .into();
+    }
+
+    match closest_header(headers, column) {
+        Some(header) => format!("Unknown column '{}', did you mean '{}'?", column, header).into(),
+        None => format!(
+            "Unknown column '{}', the available columns are: {}",
+            column,
+            headers.join(", ")
+        )
+        .into(),
+    }
+}
+
+// The header that's the fewest edits from the column, ignoring case. Unqualified
+// columns are also compared against the unqualified part of each header.
+fn closest_header<'h>(headers: &'h [String], column: &str) -> Option<&'h str> {
+    let column = column.to_lowercase();
+    headers
+        .iter()
+        .map(|header| {
+            let name = match header.rsplit_once('.') {
+                Some((_, name)) if !column.contains('.') => name,
+                _ => header.as_str(),
+            };
+            (edit_distance(&name.to_lowercase(), &column), header.as_str())
+        })
+        // Allow roughly one mistake for every three characters
+        .filter(|(distance, _)| *distance <= (column.chars().count() / 3).max(1))
+        .min_by_key(|(distance, _)| *distance)
+        .map(|(_, header)| header)
+}
+
+// Number of insertions, deletions, substitutions or swaps of neighbouring characters
+// needed to turn one string into the other
+fn edit_distance(a: &str, b: &str) -> usize {
+    let a: Vec<char> = a.chars().collect();
+    let b: Vec<char> = b.chars().collect();
+    let mut distances = vec![vec![0; b.len() + 1]; a.len() + 1];
+    for (i, row) in distances.iter_mut().enumerate() {
+        row[0] = i;
+    }
+    distances[0] = (0..=b.len()).collect();
+
+    for i in 1..=a.len() {
+        for j in 1..=b.len() {
+            let cost = usize::from(a[i - 1] != b[j - 1]);
+            let mut distance = (distances[i - 1][j] + 1)
+                .min(distances[i][j - 1] + 1)
+                .min(distances[i - 1][j - 1] + cost);
+            if i > 1 && j > 1 && a[i - 1] == b[j - 2] && a[i - 2] == b[j - 1] {
+                distance = distance.min(distances[i - 2][j - 2] + 1);
+            }
+            distances[i][j] = distance;
+        }
+    }
+    distances[a.len()][b.len()]
+}
+
+// The fields of a record as a row with one field per header, short records are padded
+fn record_row(record: &StringRecord, width: usize) -> Vec<String> {
+    (0..width)
+        .map(|i| record.get(i).unwrap_or("").to_string())
+        .collect()
+}
+
+// Copy every record of a table to the sink, e.g. to write it out in another format
+pub fn copy_table(source: Box<dyn RowSource>, sink: &mut dyn RowSink) -> Result<usize, Box<dyn Error>> {
+    let width = source.headers().len();
+    sink.write_headers(source.headers())?;
+
+    let mut rows = 0;
+    for record in source.into_records() {
+        sink.write_row(record_row(&record?, width))?;
+        rows += 1;
+    }
+    Ok(rows)
+}
+
+// Number of rows sort_table holds in memory by default
+pub const SORT_CHUNK_SIZE: usize = 100_000;
+
+// Sort a table by the given columns. Tables with more rows than the chunk size are sorted
+// a chunk at a time, with each sorted chunk written to a temporary file and the files then
+// merged, so only the chunk and a row from each file are held in memory.
+pub fn sort_table(
+    source: Box<dyn RowSource>,
+    keys: &[OrderBy],
+    chunk_size: usize,
+    sink: &mut dyn RowSink,
+) -> Result<usize, Box<dyn Error>> {
+    let headers = source.headers().to_vec();
+    let ordering = RowOrdering {
+        keys: keys
+            .iter()
+            .map(|key| Ok((find_column(&headers, &key.column)?, key.direction.clone())))
+            .collect::<Result<_, Box<dyn Error>>>()?,
+    };
+    sink.write_headers(&headers)?;
+
+    let mut runs: Vec<SortRun> = Vec::new();
+    let mut chunk: Vec<Vec<String>> = Vec::new();
+    for record in source.into_records() {
+        chunk.push(record_row(&record?, headers.len()));
+        if chunk.len() >= chunk_size.max(1) {
+            chunk.sort_by(|a, b| ordering.compare(a, b));
+            runs.push(SortRun::write(&chunk)?);
+            chunk.clear();
+        }
+    }
+    chunk.sort_by(|a, b| ordering.compare(a, b));
+
+    // Everything fit in a single chunk so there's nothing to merge
+    if runs.is_empty() {
+        let rows = chunk.len();
+        for row in chunk {
+            sink.write_row(row)?;
+        }
+        return Ok(rows);
+    }
+    if !chunk.is_empty() {
+        runs.push(SortRun::write(&chunk)?);
+    }
+    drop(chunk);
+
+    let mut heap = BinaryHeap::new();
+    for (run, sort_run) in runs.iter_mut().enumerate() {
+        if let Some(row) = sort_run.next_row()? {
+            heap.push(MergeRow { row, run, ordering: &ordering });
+        }
+    }
+
+    let mut rows = 0;
+    while let Some(MergeRow { row, run, .. }) = heap.pop() {
+        if let Some(next) = runs[run].next_row()? {
+            heap.push(MergeRow { row: next, run, ordering: &ordering });
+        }
+        sink.write_row(row)?;
+        rows += 1;
+    }
+    Ok(rows)
+}
+
+// A sorted chunk of rows written to a temporary file, which is removed once it's been merged
+struct SortRun {
+    path: PathBuf,
+    reader: Option<csv::StringRecordsIntoIter<File>>,
+}
+
+// Gives each temporary file a different name
+static SORT_RUNS: AtomicUsize = AtomicUsize::new(0);
+
+impl SortRun {
+    fn write(rows: &[Vec<String>]) -> Result<SortRun, Box<dyn Error>> {
+        let path = std::env::temp_dir().join(format!(
+            "aid-sort-{}-{}.csv",
+            std::process::id(),
+            SORT_RUNS.fetch_add(1, AtomicOrdering::Relaxed)
+        ));
+        // Create the run straight away so the file is cleaned up if writing fails
+        let mut run = SortRun { path, reader: None };
+
+        let mut writer = csv::Writer::from_path(&run.path)?;
+        for row in rows {
+            writer.write_record(row)?;
+        }
+        writer.flush()?;
+
+        let reader = ReaderBuilder::new()
+            .has_headers(false)
+            .flexible(true)
+            .from_path(&run.path)?;
+        run.reader = Some(reader.into_records());
+        Ok(run)
+    }
+
+    fn next_row(&mut self) -> Result<Option<Vec<String>>, Box<dyn Error>> {
+        match self.reader.as_mut().and_then(|reader| reader.next()) {
+            Some(record) => Ok(Some(record?.iter().map(|field| field.to_string()).collect())),
+            None => Ok(None),
+        }
+    }
+}
+
+impl Drop for SortRun {
+    fn drop(&mut self) {
+        // Close the file before removing it
+        self.reader = None;
+        let _ = std::fs::remove_file(&self.path);
+    }
+}
+
+// The next row of a sorted run. The heap gives the smallest row first, with rows that
+// compare equal taken from the earliest run so the sort is stable.
+struct MergeRow<'o> {
+    row: Vec<String>,
+    run: usize,
+    ordering: &'o RowOrdering,
+}
+
+impl Ord for MergeRow<'_> {
+    fn cmp(&self, other: &Self) -> Ordering {
+        self.ordering
+            .compare(&other.row, &self.row)
+            .then_with(|| other.run.cmp(&self.run))
+    }
+}
+
+impl PartialOrd for MergeRow<'_> {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl PartialEq for MergeRow<'_> {
+    fn eq(&self, other: &Self) -> bool {
+        self.cmp(other) == Ordering::Equal
+    }
+}
+
+impl Eq for MergeRow<'_> {}
+
+// Write out the first row for each distinct value of the key columns, or of the whole
+// row when no key columns are given. Returns the number of rows written and removed.
+pub fn dedupe_table(
+    source: Box<dyn RowSource>,
+    keys: &[String],
+    sink: &mut dyn RowSink,
+) -> Result<(usize, usize), Box<dyn Error>> {
+    let headers = source.headers().to_vec();
+    let key_indexes: Vec<usize> = if keys.is_empty() {
+        (0..headers.len()).collect()
+    } else {
+        keys.iter()
+            .map(|key| find_column(&headers, key))
+            .collect::<Result<_, _>>()?
+    };
+    sink.write_headers(&headers)?;
+
+    let mut seen: HashSet<Vec<String>> = HashSet::new();
+    let (mut written, mut removed) = (0, 0);
+    for record in source.into_records() {
+        let row = record_row(&record?, headers.len());
+        let key = key_indexes.iter().map(|i| row[*i].clone()).collect();
+        if seen.insert(key) {
+            sink.write_row(row)?;
+            written += 1;
+        } else {
+            removed += 1;
+        }
+    }
+    Ok((written, removed))
+}
+
+// Write out the rows of each table one after the other. Columns are matched by name,
+// ignoring case and surrounding whitespace, and the result has every column from every
+// table in the order they're first seen. Rows are left empty for columns their table lacks.
+pub fn concat_tables(
+    sources: Vec<Box<dyn RowSource>>,
+    sink: &mut dyn RowSink,
+) -> Result<usize, Box<dyn Error>> {
+    let normalize = |header: &str| header.trim().to_lowercase();
+
+    let mut headers: Vec<String> = Vec::new();
+    let mut names: Vec<String> = Vec::new();
+    for source in &sources {
+        for header in source.headers() {
+            if !names.contains(&normalize(header)) {
+                names.push(normalize(header));
+                headers.push(header.trim().to_string());
+            }
+        }
+    }
+    sink.write_headers(&headers)?;
+
+    let mut rows = 0;
+    for source in sources {
+        let positions: Vec<Option<usize>> = source
+            .headers()
+            .iter()
+            .map(|header| names.iter().position(|name| *name == normalize(header)))
+            .collect();
+        for record in source.into_records() {
+            let mut row = vec![String::new(); headers.len()];
+            for (field, position) in record?.iter().zip(&positions) {
+                if let Some(position) = position {
+                    row[*position] = field.to_string();
+                }
+            }
+            sink.write_row(row)?;
+            rows += 1;
+        }
+    }
+    Ok(rows)
+}
+
+// How split_table divides up a table
+#[derive(Debug, Clone)]
+pub enum SplitBy {
+    // A file for every n rows
+    Rows(usize),
+    // A file for each value of a column
+    Column(String),
+}
+
+// Split a table into CSV files in the output directory, each with the table's headers.
+// Files are named after the prefix and either their number or the column value e.g.
+// sales_1.csv or sales_North.csv. Returns each file written along with its number of rows.
+pub fn split_table(
+    source: Box<dyn RowSource>,
+    split_by: &SplitBy,
+    output_dir: &Path,
+    prefix: &str,
+) -> Result<Vec<(PathBuf, usize)>, Box<dyn Error>> {
+    let headers = source.headers().to_vec();
+    let (column, rows_per_file) = match split_by {
+        SplitBy::Column(column) => (Some(find_column(&headers, column)?), 0),
+        SplitBy::Rows(0) => return Err("The number of rows per file must be at least 1".into()),
+        SplitBy::Rows(size) => (None, *size),
+    };
+
+    // Files stay open until the end as rows for any value can turn up at any time
+    let mut files: Vec<(PathBuf, csv::Writer<File>, usize)> = Vec::new();
+    let mut file_index: HashMap<String, usize> = HashMap::new();
+    for (rows, record) in source.into_records().enumerate() {
+        let row = record_row(&record?, headers.len());
+        let name = match column {
+            Some(column) => file_name_part(&row[column]),
+            None => (rows / rows_per_file + 1).to_string(),
+        };
+
+        let index = match file_index.get(&name) {
+            Some(index) => *index,
+            None => {
+                let path = output_dir.join(format!("{}_{}.csv", prefix, name));
+                let mut writer = csv::Writer::from_path(&path)
+                    .map_err(|e| format!("Failed to create file {}: {}", path.display(), e))?;
+                writer.write_record(&headers)?;
+                files.push((path, writer, 0));
+                file_index.insert(name, files.len() - 1);
+                files.len() - 1
+            }
+        };
+        let (_, writer, count) = &mut files[index];
+        writer.write_record(&row)?;
+        *count += 1;
+    }
+
+    let mut written = Vec::new();
+    for (path, mut writer, count) in files {
+        writer.flush()?;
+        written.push((path, count));
+    }
+    Ok(written)
+}
+
+// Make a column value safe to use in a file name
+fn file_name_part(value: &str) -> String {
+    if value.is_empty() {
+        return "empty".to_string();
+    }
+    value
+        .chars()
+        .map(|c| if c.is_alphanumeric() || c == '-' || c == '.' { c } else { '_' })
+        .collect()
+}
+
+// The differences between two versions of a table, with rows matched up by their key columns
+#[derive(Serialize, Debug, Default)]
+pub struct TableDiff {
+    pub summary: DiffSummary,
+    pub added_columns: Vec<String>,
+    pub removed_columns: Vec<String>,
+    pub added: Vec<Map<String, JsonValue>>,
+    pub removed: Vec<Map<String, JsonValue>>,
+    pub changed: Vec<ChangedRow>,
+}
+
+#[derive(Serialize, Debug, Default)]
+pub struct DiffSummary {
+    pub old_rows: usize,
+    pub new_rows: usize,
+    pub added: usize,
+    pub removed: usize,
+    pub changed: usize,
+    pub unchanged: usize,
+}
+
+#[derive(Serialize, Debug)]
+pub struct ChangedRow {
+    pub key: Map<String, JsonValue>,
+    pub changes: Vec<CellChange>,
+}
+
+#[derive(Serialize, Debug)]
+pub struct CellChange {
+    pub column: String,
+    pub old: String,
+    pub new: String,
+}
+
+// Compare two versions of a table. Rows are matched by the values of the key columns and
+// columns by name, ignoring case and surrounding whitespace, so reordering either is not a
+// change. Only the columns in both tables are compared. Changed and removed rows are listed
+// in the order of the old table, added rows in the order of the new one.
+pub fn diff_tables(
+    old_headers: &[String],
+    old_records: &[StringRecord],
+    new_headers: &[String],
+    new_records: &[StringRecord],
+    keys: &[String],
+) -> Result<TableDiff, Box<dyn Error>> {
+    if keys.is_empty() {
+        return Err("At least one key column is required".into());
+    }
+    let old_keys: Vec<usize> = keys
+        .iter()
+        .map(|key| find_column(old_headers, key))
+        .collect::<Result<_, _>>()?;
+    let new_keys: Vec<usize> = keys
+        .iter()
+        .map(|key| find_column(new_headers, key))
+        .collect::<Result<_, _>>()?;
+
+    let normalize = |header: &str| header.trim().to_lowercase();
+    // Pairs of old and new positions for the columns in both tables
+    let common: Vec<(usize, usize)> = old_headers
+        .iter()
+        .enumerate()
+        .filter_map(|(i, old)| {
+            new_headers
+                .iter()
+                .position(|new| normalize(new) == normalize(old))
+                .map(|j| (i, j))
+        })
+        .collect();
+
+    let mut diff = TableDiff {
+        added_columns: new_headers
+            .iter()
+            .filter(|new| !common.iter().any(|(_, j)| new_headers[*j] == **new))
+            .cloned()
+            .collect(),
+        removed_columns: old_headers
+            .iter()
+            .filter(|old| !common.iter().any(|(i, _)| old_headers[*i] == **old))
+            .cloned()
+            .collect(),
+        ..TableDiff::default()
+    };
+    diff.summary.old_rows = old_records.len();
+    diff.summary.new_rows = new_records.len();
+
+    let key_of = |record: &StringRecord, indexes: &[usize]| -> Vec<String> {
+        indexes
+            .iter()
+            .map(|i| record.get(*i).unwrap_or_default().to_string())
+            .collect()
+    };
+    let new_index = index_by_key(new_records, |record| key_of(record, &new_keys), "new")?;
+    let old_index = index_by_key(old_records, |record| key_of(record, &old_keys), "old")?;
+
+    for old in old_records {
+        let Some(new) = new_index.get(&key_of(old, &old_keys)).map(|i| &new_records[*i]) else {
+            diff.removed.push(row_object(old_headers, old));
+            continue;
+        };
+
+        let changes: Vec<CellChange> = common
+            .iter()
+            .filter_map(|(i, j)| {
+                let (old_value, new_value) =
+                    (old.get(*i).unwrap_or_default(), new.get(*j).unwrap_or_default());
+                (old_value != new_value).then(|| CellChange {
+                    column: new_headers[*j].clone(),
+                    old: old_value.to_string(),
+                    new: new_value.to_string(),
+                })
+            })
+            .collect();
+        if changes.is_empty() {
+            diff.summary.unchanged += 1;
+        } else {
+            let key = keys
+                .iter()
+                .zip(key_of(old, &old_keys))
+                .map(|(column, value)| (column.clone(), JsonValue::String(value)))
+                .collect();
+            diff.changed.push(ChangedRow { key, changes });
+        }
+    }
+
+    diff.added = new_records
+        .iter()
+        .filter(|new| !old_index.contains_key(&key_of(new, &new_keys)))
+        .map(|new| row_object(new_headers, new))
+        .collect();
+
+    diff.summary.added = diff.added.len();
+    diff.summary.removed = diff.removed.len();
+    diff.summary.changed = diff.changed.len();
+    Ok(diff)
+}
+
+// Map each key to the position of its record, keys have to be unique to match rows up
+fn index_by_key<F>(
+    records: &[StringRecord],
+    key_of: F,
+    table: &str,
+) -> Result<HashMap<Vec<String>, usize>, Box<dyn Error>>
+where
+    F: Fn(&StringRecord) -> Vec<String>,
+{
+    let mut index = HashMap::with_capacity(records.len());
+    for (i, record) in records.iter().enumerate() {
+        let key = key_of(record);
+        if index.contains_key(&key) {
+            return Err(format!(
+                "Duplicate key '{}' in the {} file, the key columns must identify a single row",
+                key.join(", "),
+                table
+            )
+            .into());
+        }
+        index.insert(key, i);
+    }
+    Ok(index)
+}
+
+fn row_object(headers: &[String], record: &StringRecord) -> Map<String, JsonValue> {
+    headers
+        .iter()
+        .enumerate()
+        .map(|(i, header)| {
+            let value = record.get(i).unwrap_or_default();
+            (header.clone(), JsonValue::String(value.to_string()))
+        })
+        .collect()
+}
+
+// Writes the results of a query as CSV records
+struct CsvSink<'w> {
+    writer: &'w mut csv::Writer<File>,
+}
+
+impl RowSink for CsvSink<'_> {
+    fn write_headers(&mut self, headers: &[String]) -> Result<(), Box<dyn Error>> {
+        Ok(self.writer.write_record(headers)?)
+    }
+
+    fn write_row(&mut self, row: Vec<String>) -> Result<(), Box<dyn Error>> {
+        Ok(self.writer.write_record(&row)?)
+    }
+}
+
+// Only plain CSV files can be written to, not stdin, compressed or JSON files
+fn check_writable(table: &str) -> Result<(), Box<dyn Error>> {
+    if table == "-" || table.ends_with(".gz") || InputFormat::from_path(table) != InputFormat::Csv {
+        return Err(format!("Can't write to '{}', only uncompressed CSV files can be modified", table).into());
+    }
+    if is_glob(table) || Path::new(table).is_dir() {
+        return Err(format!("Can't write to '{}', only a single file can be modified", table).into());
+    }
+    Ok(())
+}
+
+// Write a CSV file by writing a temporary file next to it, then renaming it over the top.
+// The file is either completely rewritten or left as it was.
+fn write_atomically<F>(table: &str, delimiter: u8, write: F) -> Result<(), Box<dyn Error>>
+where
+    F: FnOnce(&mut csv::Writer<File>) -> Result<(), Box<dyn Error>>,
+{
+    let path = Path::new(table);
+    let file_name = path
+        .file_name()
+        .ok_or_else(|| format!("'{}' is not a file", table))?
+        .to_string_lossy();
+    let temp_path = path.with_file_name(format!(".{}.aid-{}.tmp", file_name, std::process::id()));
+
+    let result = (|| {
+        let mut writer = WriterBuilder::new()
+            .delimiter(delimiter)
+            .flexible(true)
+            .from_writer(File::create(&temp_path)?);
+        write(&mut writer)?;
+        writer.into_inner().map_err(|e| e.to_string())?.sync_all()?;
+        std::fs::rename(&temp_path, path)?;
+        Ok(())
+    })();
+    if result.is_err() {
+        let _ = std::fs::remove_file(&temp_path);
+    }
+    result
+}
+
+// The delimiter for a new file, tab or pipe for .tsv and .psv files, otherwise a comma
+fn delimiter_for_path(table: &str) -> u8 {
+    match Path::new(table).extension().and_then(|extension| extension.to_str()) {
+        Some("tsv") | Some("tab") => b'\t',
+        Some("psv") => b'|',
+        _ => b',',
+    }
+}
+
+// Write the results of a query to a new file, returning the number of rows written
+pub fn create_table_as(
+    table: &str,
+    query: &SQLQuery,
+    options: &CsvOptions,
+) -> Result<usize, Box<dyn Error>> {
+    check_writable(table)?;
+    if Path::new(table).exists() {
+        return Err(format!("'{}' already exists, use INSERT INTO to add rows to it", table).into());
+    }
+
+    let mut rows = 0;
+    write_atomically(table, delimiter_for_path(table), |writer| {
+        rows = execute_query(query, options, &mut CsvSink { writer })?.rows_returned;
+        Ok(())
+    })?;
+    Ok(rows)
+}
+
+// Append the results of a query to an existing file. The query has to return the same columns
+// as the file, in any order. Returns the number of rows added.
+pub fn insert_into(
+    table: &str,
+    query: &SQLQuery,
+    options: &CsvOptions,
+) -> Result<usize, Box<dyn Error>> {
+    check_writable(table)?;
+    let target = CsvSource::open(table, options)
+        .map_err(|e| format!("Failed to open '{}': {}", table, e))?;
+    let (headers, delimiter) = (target.headers().to_vec(), target.delimiter());
+    drop(target);
+
+    // The results are collected before writing so a query reading the same file doesn't see them
+    let mut results = QueryResults::default();
+    execute_query(query, options, &mut results)?;
+    let positions = insert_positions(table, &headers, &results.headers, options.no_header)?;
+
+    let mut file = std::fs::OpenOptions::new().read(true).append(true).open(table)?;
+    // Start on a new line when the file doesn't end with one
+    let mut last = [0u8];
+    if file.seek(SeekFrom::End(0))? > 0 {
+        file.seek(SeekFrom::End(-1))?;
+        file.read_exact(&mut last)?;
+        if last[0] != b'\n' {
+            file.write_all(b"\n")?;
+        }
+    }
+
+    let mut writer = WriterBuilder::new()
+        .delimiter(delimiter)
+        .flexible(true)
+        .from_writer(file);
+    for row in &results.rows {
+        writer.write_record(positions.iter().map(|i| &row[*i]))?;
+    }
+    writer.flush()?;
+    Ok(results.rows.len())
+}
+
+// Line the result columns up with the columns of the file being inserted into. Columns are
+// matched by name ignoring case and surrounding whitespace, or by position when the file has
+// no header row.
+fn insert_positions(
+    table: &str,
+    headers: &[String],
+    result_headers: &[String],
+    no_header: bool,
+) -> Result<Vec<usize>, Box<dyn Error>> {
+    if no_header {
+        if headers.len() != result_headers.len() {
+            return Err(format!(
+                "The query returns {} columns but '{}' has {}",
+                result_headers.len(),
+                table,
+                headers.len()
+            )
+            .into());
+        }
+        return Ok((0..headers.len()).collect());
+    }
+
+    if let Some(differences) = header_differences(headers, result_headers) {
+        return Err(format!(
+            "The columns of the query don't match '{}' ({}), expected {}",
+            table,
+            differences,
+            headers.join(", ")
+        )
+        .into());
+    }
+    Ok(headers
+        .iter()
+        .filter_map(|header| header_position(header, result_headers))
+        .collect())
+}
+
+// Find a column by name, ignoring case and surrounding whitespace
+fn header_position(header: &str, within: &[String]) -> Option<usize> {
+    let normalize = |header: &str| header.trim().to_lowercase();
+    within.iter().position(|other| normalize(other) == normalize(header))
+}
+
+// Describe the columns missing from or unexpected in the found headers,
+// e.g. "missing Amount; unexpected Total", or None when both have the same columns
+fn header_differences(expected: &[String], found: &[String]) -> Option<String> {
+    let missing: Vec<&str> = expected
+        .iter()
+        .filter(|header| header_position(header, found).is_none())
+        .map(|header| header.as_str())
+        .collect();
+    let unexpected: Vec<&str> = found
+        .iter()
+        .filter(|header| header_position(header, expected).is_none())
+        .map(|header| header.as_str())
+        .collect();
+
+    let mut problems = Vec::new();
+    if !missing.is_empty() {
+        problems.push(format!("missing {}", missing.join(", ")));
+    }
+    if !unexpected.is_empty() {
+        problems.push(format!("unexpected {}", unexpected.join(", ")));
+    }
+    (!problems.is_empty()).then(|| problems.join("; "))
+}
+
+// Set columns of the rows matching the condition, or of every row without one.
+// Returns the number of rows updated.
+pub fn update_table(
+    table: &str,
+    assignments: &[Assignment],
+    condition: &Option<Condition>,
+    options: &CsvOptions,
+) -> Result<usize, Box<dyn Error>> {
+    let exprs: Vec<&Expr> = assignments.iter().map(|assignment| &assignment.expr).collect();
+    let targets: Vec<&str> = assignments.iter().map(|assignment| assignment.column.as_str()).collect();
+    let mut updated = 0;
+    rewrite_table(table, condition, &exprs, &targets, options, |headers, column_types, indices, record, matched| {
+        let mut row = record_row(record, headers.len());
+        if !matched {
+            return Ok(Some(row));
+        }
+        // Every expression sees the row as it was before the update
+        let lookup = record_lookup(headers, column_types, record);
+        for (assignment, index) in assignments.iter().zip(indices) {
+            row[*index] = output_field(evaluate_expr(&assignment.expr, &lookup));
+        }
+        updated += 1;
+        Ok(Some(row))
+    })?;
+    Ok(updated)
+}
+
+// Remove the rows matching the condition, or every row without one.
+// Returns the number of rows deleted.
+pub fn delete_from(
+    table: &str,
+    condition: &Option<Condition>,
+    options: &CsvOptions,
+) -> Result<usize, Box<dyn Error>> {
+    let mut deleted = 0;
+    rewrite_table(table, condition, &[], &[], options, |headers, _, _, record, matched| {
+        if matched {
+            deleted += 1;
+            return Ok(None);
+        }
+        Ok(Some(record_row(record, headers.len())))
+    })?;
+    Ok(deleted)
+}
+
+// Rewrite a file record by record. Each record is passed to the rewrite along with whether it
+// matched the condition, which returns the row to write in its place or None to drop it.
+// The rewrite also gets the index of each of the target columns it writes to.
+fn rewrite_table<F>(
+    table: &str,
+    condition: &Option<Condition>,
+    exprs: &[&Expr],
+    targets: &[&str],
+    options: &CsvOptions,
+    mut rewrite: F,
+) -> Result<(), Box<dyn Error>>
+where
+    F: FnMut(&[String], &[ColumnType], &[usize], &StringRecord, bool) -> Result<Option<Vec<String>>, Box<dyn Error>>,
+{
+    check_writable(table)?;
+    let source = CsvSource::open(table, options)
+        .map_err(|e| format!("Failed to open '{}': {}", table, e))?;
+    let headers = source.headers().to_vec();
+    let delimiter = source.delimiter();
+
+    // Every column used has to exist before anything is written
+    let mut names = Vec::new();
+    let mut conditions: Vec<&Condition> = condition.iter().collect();
+    if let Some(condition) = condition {
+        condition_columns(condition, &mut names);
+    }
+    for expr in exprs {
+        if contains_aggregate(expr) {
+            return Err("Aggregate functions can't be used to update a row".into());
+        }
+        expr_columns(expr, &mut names);
+        expr_conditions(expr, &mut conditions);
+    }
+    for name in names {
+        find_column(&headers, &name)?;
+    }
+    let indices = targets
+        .iter()
+        .map(|target| find_column(&headers, target))
+        .collect::<Result<Vec<_>, _>>()?;
+    run_condition_subqueries(conditions, options)?;
+
+    // Column types are inferred from the first records, which are then processed as normal
+    let mut records = Box::new(source).into_records();
+    let sample = records
+        .by_ref()
+        .take(TYPE_INFERENCE_SAMPLE_SIZE)
+        .collect::<Result<Vec<_>, _>>()?;
+    let column_types = infer_column_types(headers.len(), &sample);
+
+    write_atomically(table, delimiter, |writer| {
+        if !options.no_header {
+            writer.write_record(&headers)?;
+        }
+        for record in sample.into_iter().map(Ok).chain(records) {
+            let record = record?;
+            let lookup = record_lookup(&headers, &column_types, &record);
+            let matched = condition
+                .as_ref()
+                .is_none_or(|condition| evaluate_condition(condition, &lookup));
+            if let Some(row) = rewrite(&headers, &column_types, &indices, &record, matched)? {
+                writer.write_record(&row)?;
+            }
+        }
+        Ok(())
+    })
+}
+
+// Look up the text of a column in a record along with the type of the column
+fn record_lookup<'r>(
+    headers: &'r [String],
+    column_types: &'r [ColumnType],
+    record: &'r StringRecord,
+) -> impl Fn(&str) -> Option<(&'r str, ColumnType)> + 'r {
+    move |name| column_index(headers, name).map(|i| (field(record, Some(i)), column_types[i]))
+}
+
+// Run a query against its tables, streaming the results into the sink
+pub fn execute_query(
+    query: &SQLQuery,
+    options: &CsvOptions,
+    sink: &mut dyn RowSink,
+) -> Result<QueryStats, Box<dyn Error>> {
+    let (headers, records) = open_tables(query, options)?;
+    run_query(&headers, records, query, options, sink)
+}
+
+// Run a query over records that have already been loaded into memory
+pub fn apply_query(
+    headers: &[String],
+    records: Vec<StringRecord>,
+    query: &SQLQuery,
+) -> Result<QueryResults, Box<dyn Error>> {
+    let mut results = QueryResults::default();
+
+    let records = records.into_iter().map(Ok);
+    run_query(headers, records, query, &CsvOptions::default(), &mut results)?;
+
+    Ok(results)
+}
+
+// Pass each record through the query pipeline one at a time. Rows are written to the sink
+// as soon as they pass the WHERE clause, unless they need to be held back for aggregation
+// or sorting.
+pub fn run_query<I>(
+    headers: &[String],
+    records: I,
+    query: &SQLQuery,
+    options: &CsvOptions,
+    sink: &mut dyn RowSink,
+) -> Result<QueryStats, Box<dyn Error>>
+where
+    I: IntoIterator<Item = Result<StringRecord, Box<dyn Error>>>,
+{
+    run_subqueries(query, options)?;
+
+    // Column types are inferred from the first records, which are then processed as normal
+    let mut records = records.into_iter();
+    let sample = records
+        .by_ref()
+        .take(TYPE_INFERENCE_SAMPLE_SIZE)
+        .collect::<Result<Vec<_>, _>>()?;
+    let column_types = infer_column_types(headers.len(), &sample);
+
+    let mut executor = QueryExecutor::new(headers, &column_types, query)?;
+    let mut stats = QueryStats::default();
+
+    sink.write_headers(&executor.result_headers)?;
+
+    let records = sample.into_iter().map(Ok).chain(records);
+    if options.threads > 1 {
+        run_parallel(&mut executor, records, options.threads, sink, &mut stats)?;
+    } else {
+        for record in records {
+            let record = record?;
+            stats.rows_scanned += 1;
+
+            if let Some(row) = executor.push(&record) {
+                stats.rows_returned += 1;
+                sink.write_row(row)?;
+            }
+
+            // Stop reading the file once the LIMIT has been reached
+            if executor.is_complete() {
+                break;
+            }
+        }
+    }
+
+    for row in executor.finish() {
+        stats.rows_returned += 1;
+        sink.write_row(row)?;
+    }
+
+    Ok(stats)
+}
+
+// Number of records handed to a worker thread at a time when a query runs in parallel
+pub const PARALLEL_BATCH_SIZE: usize = 8192;
+
+// What a worker thread produced from a batch of records
+enum BatchResult {
+    // The projected rows that passed the WHERE clause
+    Rows(Vec<Vec<String>>),
+    // The groups the batch's records fell into, in the order they were first seen
+    Groups(Vec<(Vec<String>, Group)>),
+}
+
+// Records are read on the calling thread and handed out in batches to worker threads, which
+// filter, project and aggregate them with their own copy of the query. The batch results are
+// merged back in the order the records were read, so rows come out in the same order as when
+// the query runs on a single thread.
+fn run_parallel<I>(
+    executor: &mut QueryExecutor,
+    mut records: I,
+    threads: usize,
+    sink: &mut dyn RowSink,
+    stats: &mut QueryStats,
+) -> Result<(), Box<dyn Error>>
+where
+    I: Iterator<Item = Result<StringRecord, Box<dyn Error>>>,
+{
+    let (headers, column_types) = (executor.headers, executor.column_types);
+
+    thread::scope(|scope| {
+        // Batches are only read a little ahead of the workers so large files aren't held in memory
+        let (batch_sender, batch_receiver) = mpsc::sync_channel::<(usize, Vec<StringRecord>)>(threads * 2);
+        let batch_receiver = Arc::new(Mutex::new(batch_receiver));
+        let (result_sender, result_receiver) = mpsc::channel::<(usize, BatchResult)>();
+
+        for _ in 0..threads {
+            let batch_receiver = batch_receiver.clone();
+            let result_sender = result_sender.clone();
+            // Each worker has its own copy as subqueries can't be shared between threads
+            let query = executor.query.clone();
+            scope.spawn(move || {
+                let Ok(mut worker) = QueryExecutor::new(headers, column_types, &query) else {
+                    return;
+                };
+                loop {
+                    let batch = match batch_receiver.lock() {
+                        Ok(receiver) => receiver.recv(),
+                        Err(_) => return,
+                    };
+                    let Ok((index, batch)) = batch else {
+                        return;
+                    };
+                    if result_sender.send((index, worker.process_batch(&batch))).is_err() {
+                        return;
+                    }
+                }
+            });
+        }
+        // Only the workers hold on to these now, so the channels close if every worker stops
+        drop(batch_receiver);
+        drop(result_sender);
+
+        let mut pending = BTreeMap::new();
+        let mut sent = 0;
+        let mut merged = 0;
+        let mut write_rows = |executor: &mut QueryExecutor, result| -> Result<(), Box<dyn Error>> {
+            for row in executor.merge_batch(result) {
+                stats.rows_returned += 1;
+                sink.write_row(row)?;
+            }
+            Ok(())
+        };
+
+        while !executor.is_complete() {
+            let batch = records
+                .by_ref()
+                .take(PARALLEL_BATCH_SIZE)
+                .collect::<Result<Vec<_>, _>>()?;
+            if batch.is_empty() {
+                break;
+            }
+            stats.rows_scanned += batch.len();
+            if batch_sender.send((sent, batch)).is_err() {
+                break;
+            }
+            sent += 1;
+
+            while let Ok((index, result)) = result_receiver.try_recv() {
+                pending.insert(index, result);
+            }
+            while let Some(result) = pending.remove(&merged) {
+                write_rows(executor, result)?;
+                merged += 1;
+            }
+        }
+        drop(batch_sender);
+
+        while merged < sent && !executor.is_complete() {
+            match pending.remove(&merged) {
+                Some(result) => {
+                    write_rows(executor, result)?;
+                    merged += 1;
+                }
+                None => {
+                    let (index, result) = result_receiver
+                        .recv()
+                        .map_err(|_| "A worker thread stopped before the query finished")?;
+                    pending.insert(index, result);
+                }
+            }
+        }
+        Ok(())
+    })
+}
+
+// Where an aggregate function reads its values from
+enum AggregateInput {
+    AllRows,
+    Column(usize),
+}
+
+// Running state of an aggregate function for a single group
+enum Accumulator {
+    Count(usize),
+    CountDistinct(HashSet<String>),
+    // The lowest or highest value read as the column's type, along with its original text
+    Min(Option<(Value, String)>, ColumnType),
+    Max(Option<(Value, String)>, ColumnType),
+    Sum(f64),
+    Avg(f64, usize),
+    Median(Vec<f64>),
+    // Number of values, their mean and the sum of squared differences from the mean
+    StdDev(usize, f64, f64),
+    GroupConcat(Vec<String>, String),
+}
+
+impl Accumulator {
+    fn new(function: &AggregateFunction, column_type: ColumnType) -> Accumulator {
+        match function {
+            AggregateFunction::Count(_) => Accumulator::Count(0),
+            AggregateFunction::CountDistinct(_) => Accumulator::CountDistinct(HashSet::new()),
+            AggregateFunction::Min(_) => Accumulator::Min(None, column_type),
+            AggregateFunction::Max(_) => Accumulator::Max(None, column_type),
+            AggregateFunction::Sum(_) => Accumulator::Sum(0.0),
+            AggregateFunction::Avg(_) => Accumulator::Avg(0.0, 0),
+            AggregateFunction::Median(_) => Accumulator::Median(Vec::new()),
+            AggregateFunction::StdDev(_) => Accumulator::StdDev(0, 0.0, 0.0),
+            AggregateFunction::GroupConcat(_, separator) => {
+                Accumulator::GroupConcat(Vec::new(), separator.clone())
+            }
+        }
+    }
+
+    // Update the accumulator with a value, None represents a row for COUNT(*)
+    fn update(&mut self, value: Option<&str>) {
+        let value = match value {
+            Some("") => return,
+            Some(value) => value,
+            None => {
+                if let Accumulator::Count(count) = self {
+                    *count += 1;
+                }
+                return;
+            }
+        };
+
+        match self {
+            Accumulator::Count(count) => *count += 1,
+            Accumulator::CountDistinct(values) => {
+                if !values.contains(value) {
+                    values.insert(value.to_string());
+                }
+            }
+            Accumulator::Min(min, column_type) => {
+                let typed = Value::parse(value, *column_type);
+                if min.as_ref().is_none_or(|(min, _)| replaces(&typed, min, Ordering::Less)) {
+                    *min = Some((typed, value.to_string()));
+                }
+            }
+            Accumulator::Max(max, column_type) => {
+                let typed = Value::parse(value, *column_type);
+                if max.as_ref().is_none_or(|(max, _)| replaces(&typed, max, Ordering::Greater)) {
+                    *max = Some((typed, value.to_string()));
+                }
+            }
+            Accumulator::Sum(sum) => {
+                if let Ok(num) = value.parse::<f64>() {
+                    *sum += num;
+                }
+            }
+            Accumulator::Avg(sum, count) => {
+                if let Ok(num) = value.parse::<f64>() {
+                    *sum += num;
+                    *count += 1;
+                }
+            }
+            Accumulator::Median(values) => {
+                if let Ok(num) = value.parse::<f64>() {
+                    values.push(num);
+                }
+            }
+            Accumulator::StdDev(count, mean, squares) => {
+                // Welford's online algorithm, avoids keeping every value of the group
+                if let Ok(num) = value.parse::<f64>() {
+                    *count += 1;
+                    let delta = num - *mean;
+                    *mean += delta / *count as f64;
+                    *squares += delta * (num - *mean);
+                }
+            }
+            Accumulator::GroupConcat(values, _) => values.push(value.to_string()),
+        }
+    }
+
+    // Combine the state of the same aggregate calculated over later records
+    fn merge(&mut self, other: Accumulator) {
+        match (self, other) {
+            (Accumulator::Count(count), Accumulator::Count(other)) => *count += other,
+            (Accumulator::CountDistinct(values), Accumulator::CountDistinct(other)) => values.extend(other),
+            (Accumulator::Min(min, _), Accumulator::Min(Some(other), _))
+                if min.as_ref().is_none_or(|(min, _)| replaces(&other.0, min, Ordering::Less)) =>
+            {
+                *min = Some(other);
+            }
+            (Accumulator::Max(max, _), Accumulator::Max(Some(other), _))
+                if max.as_ref().is_none_or(|(max, _)| replaces(&other.0, max, Ordering::Greater)) =>
+            {
+                *max = Some(other);
+            }
+            (Accumulator::Sum(sum), Accumulator::Sum(other)) => *sum += other,
+            (Accumulator::Avg(sum, count), Accumulator::Avg(other_sum, other_count)) => {
+                *sum += other_sum;
+                *count += other_count;
+            }
+            (Accumulator::Median(values), Accumulator::Median(other)) => values.extend(other),
+            (Accumulator::StdDev(count, mean, squares), Accumulator::StdDev(other_count, other_mean, other_squares)) => {
+                // Chan et al.'s method for combining the running values of two sets
+                let total = *count + other_count;
+                if total == 0 {
+                    return;
+                }
+                let delta = other_mean - *mean;
+                *squares += other_squares + delta * delta * (*count * other_count) as f64 / total as f64;
+                *mean += delta * other_count as f64 / total as f64;
+                *count = total;
+            }
+            (Accumulator::GroupConcat(values, _), Accumulator::GroupConcat(other, _)) => values.extend(other),
+            _ => {}
+        }
+    }
+
+    fn result(&self) -> String {
+        match self {
+            Accumulator::Count(count) => count.to_string(),
+            Accumulator::CountDistinct(values) => values.len().to_string(),
+            Accumulator::Min(value, _) | Accumulator::Max(value, _) => value
+                .as_ref()
+                .map_or_else(|| "NULL".to_string(), |(_, text)| text.clone()),
+            Accumulator::Sum(sum) => sum.to_string(),
+            Accumulator::Avg(sum, count) => {
+                if *count > 0 {
+                    (sum / (*count as f64)).to_string()
+                } else {
+                    "NULL".to_string()
+                }
+            }
+            Accumulator::Median(values) => {
+                if values.is_empty() {
+                    return "NULL".to_string();
+                }
+                let mut values = values.clone();
+                values.sort_by(|a, b| a.partial_cmp(b).unwrap_or(Ordering::Equal));
+                let middle = values.len() / 2;
+                if values.len() % 2 == 0 {
+                    ((values[middle - 1] + values[middle]) / 2.0).to_string()
+                } else {
+                    values[middle].to_string()
+                }
+            }
+            // The sample standard deviation, which needs at least two values
+            Accumulator::StdDev(count, _, squares) => {
+                if *count > 1 {
+                    (squares / (*count - 1) as f64).sqrt().to_string()
+                } else {
+                    "NULL".to_string()
+                }
+            }
+            Accumulator::GroupConcat(values, separator) => values.join(separator),
+        }
+    }
+}
+
+// Whether a value should replace the current MIN or MAX. Values that can't be compared with it,
+// like 'N/A' in a numeric column, give way to ones that fit the column so they can't hide them.
+fn replaces(value: &Value, current: &Value, ordering: Ordering) -> bool {
+    match value.compare(current) {
+        Some(result) => result == ordering,
+        None => matches!(current, Value::String(_)) && !matches!(value, Value::String(_)),
+    }
+}
+
+// Compares result rows by each of the ORDER BY columns in turn
+struct RowOrdering {
+    keys: Vec<(usize, SortDirection)>,
+}
+
+impl RowOrdering {
+    fn compare(&self, a: &[String], b: &[String]) -> Ordering {
+        for (index, direction) in &self.keys {
+            let a_value = a.get(*index).map(|v| v.as_str()).unwrap_or("");
+            let b_value = b.get(*index).map(|v| v.as_str()).unwrap_or("");
+
+            let ordering = match direction {
+                SortDirection::Ascending => compare_values(a_value, b_value),
+                SortDirection::Descending => compare_values(b_value, a_value),
+            };
+            if ordering != Ordering::Equal {
+                return ordering;
+            }
+        }
+        Ordering::Equal
+    }
+}
+
+// A row waiting to be sorted, rows that compare equal keep the order they were read in
+struct RankedRow {
+    row: Vec<String>,
+    sequence: usize,
+    ordering: Rc<RowOrdering>,
+}
+
+impl Ord for RankedRow {
+    fn cmp(&self, other: &Self) -> Ordering {
+        self.ordering
+            .compare(&self.row, &other.row)
+            .then(self.sequence.cmp(&other.sequence))
+    }
+}
+
+impl PartialOrd for RankedRow {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl PartialEq for RankedRow {
+    fn eq(&self, other: &Self) -> bool {
+        self.cmp(other) == Ordering::Equal
+    }
+}
+
+impl Eq for RankedRow {}
+
+// Rows held back until every record has been read so they can be sorted. With a LIMIT
+// only the first OFFSET + LIMIT rows can end up in the result, so rather than keeping
+// every row a bounded max heap is used which drops the last row whenever it's full.
+enum SortBuffer {
+    All(Vec<Vec<String>>),
+    Top(BinaryHeap<RankedRow>, usize),
+}
+
+// Where the value of an output column comes from
+#[derive(Clone, Copy)]
+enum ColumnSource {
+    // A field of the record, when aggregating it's taken from the first record of the group
+    Field(Option<usize>),
+    // The result of one of the aggregate functions
+    Aggregate(usize),
+    // An expression from the SELECT list evaluated against each record
+    Expression(usize),
+    // An expression from the SELECT list that uses aggregate functions, so it's evaluated
+    // against the other columns once every record has been read
+    AggregateExpression(usize),
+    // The result of one of the window functions
+    Window(usize),
+}
+
+struct OutputColumn {
+    header: String,
+    // The column as written in the query, so it can be referred to without its alias
+    expression: String,
+    source: ColumnSource,
+}
+
+// A window function from the SELECT list, with the output columns it reads from
+struct WindowColumn {
+    // The output column the results are written to
+    column: usize,
+    function: WindowKind,
+    partition_by: Vec<usize>,
+    ordering: RowOrdering,
+    // The column read by LAG, LEAD or an aggregate function, None for COUNT(*)
+    argument: Option<usize>,
+    // The type of the argument's values, computed columns have theirs inferred from each value
+    argument_type: ColumnType,
+}
+
+impl WindowColumn {
+    // Calculate the function for each row of a partition, which is already in window order
+    fn evaluate(&self, rows: &[Vec<String>], partition: &[usize]) -> Vec<String> {
+        let peers = |a: usize, b: usize| {
+            self.ordering.compare(&rows[partition[a]], &rows[partition[b]]) == Ordering::Equal
+        };
+        let argument = |position: usize| {
+            self.argument
+                .map_or(String::new(), |column| rows[partition[position]][column].clone())
+        };
+
+        match &self.function {
+            WindowKind::RowNumber => (1..=partition.len()).map(|n| n.to_string()).collect(),
+            // Rows that tie share a rank. RANK leaves gaps after ties, DENSE_RANK doesn't.
+            WindowKind::Rank | WindowKind::DenseRank => {
+                let (mut rank, mut dense_rank) = (0, 0);
+                (0..partition.len())
+                    .map(|position| {
+                        if position == 0 || !peers(position - 1, position) {
+                            rank = position + 1;
+                            dense_rank += 1;
+                        }
+                        match self.function {
+                            WindowKind::Rank => rank.to_string(),
+                            _ => dense_rank.to_string(),
+                        }
+                    })
+                    .collect()
+            }
+            WindowKind::Lag(_, offset, default) | WindowKind::Lead(_, offset, default) => {
+                let lag = matches!(self.function, WindowKind::Lag(..));
+                (0..partition.len())
+                    .map(|position| {
+                        let target = if lag {
+                            position.checked_sub(*offset)
+                        } else {
+                            Some(position + offset).filter(|target| *target < partition.len())
+                        };
+                        match target {
+                            Some(target) => argument(target),
+                            None => default.clone().map(output_field).unwrap_or_default(),
+                        }
+                    })
+                    .collect()
+            }
+            // Each row gets the aggregate of every row up to and including it, along with any
+            // rows that tie with it. Without an ORDER BY every row ties, giving the partition total.
+            WindowKind::Aggregate(function) => {
+                let mut accumulator = Accumulator::new(function, self.argument_type);
+                let mut values = vec![String::new(); partition.len()];
+                let mut start = 0;
+                while start < partition.len() {
+                    let mut end = start + 1;
+                    while end < partition.len() && peers(start, end) {
+                        end += 1;
+                    }
+                    for position in start..end {
+                        match self.argument {
+                            Some(_) => accumulator.update(Some(&argument(position))),
+                            None => accumulator.update(None),
+                        }
+                    }
+                    let result = accumulator.result();
+                    for value in &mut values[start..end] {
+                        value.clone_from(&result);
+                    }
+                    start = end;
+                }
+                values
+            }
+        }
+    }
+}
+
+// The values of the selected columns taken from the first row of a group,
+// along with the aggregate functions calculated over every row in the group
+struct Group {
+    values: Option<Vec<String>>,
+    accumulators: Vec<Accumulator>,
+}
+
+struct QueryExecutor<'a> {
+    query: &'a SQLQuery,
+    headers: &'a [String],
+    column_types: &'a [ColumnType],
+    result_headers: Vec<String>,
+    // The selected columns followed by any hidden columns only needed by ORDER BY or HAVING,
+    // which are dropped before rows are output
+    columns: Vec<OutputColumn>,
+    aggregating: bool,
+    // The source column index of each GROUP BY column
+    group_by: Vec<Option<usize>>,
+    aggregates: Vec<(AggregateFunction, AggregateInput)>,
+    // The output column each name used by HAVING or an aggregate expression refers to
+    row_columns: HashMap<String, usize>,
+    group_index: HashMap<Vec<String>, usize>,
+    groups: Vec<Group>,
+    distinct_rows: HashSet<Vec<String>>,
+    ordering: Option<Rc<RowOrdering>>,
+    windows: Vec<WindowColumn>,
+    // Every row is held back until the window functions and QUALIFY have been applied
+    windowed: bool,
+    sort_buffer: SortBuffer,
+    sequence: usize,
+    // Rows skipped for the OFFSET and rows output so far, when results are streamed
+    skipped: usize,
+    returned: usize,
+}
+
+impl<'a> QueryExecutor<'a> {
+    fn new(
+        headers: &'a [String],
+        column_types: &'a [ColumnType],
+        query: &'a SQLQuery,
+    ) -> Result<QueryExecutor<'a>, Box<dyn Error>> {
+        let sort_buffer = match query.limit {
+            Some(limit) => SortBuffer::Top(BinaryHeap::new(), limit + query.offset.unwrap_or(0)),
+            None => SortBuffer::All(Vec::new()),
+        };
+
+        let mut executor = QueryExecutor {
+            query,
+            headers,
+            column_types,
+            result_headers: Vec::new(),
+            columns: Vec::new(),
+            aggregating: query.group_by.is_some() || query.having.is_some(),
+            group_by: query
+                .group_by
+                .iter()
+                .flatten()
+                .map(|col| find_column(headers, col).map(Some))
+                .collect::<Result<_, _>>()?,
+            aggregates: Vec::new(),
+            row_columns: HashMap::new(),
+            group_index: HashMap::new(),
+            groups: Vec::new(),
+            distinct_rows: HashSet::new(),
+            ordering: None,
+            windows: Vec::new(),
+            windowed: query.qualify.is_some(),
+            sort_buffer,
+            sequence: 0,
+            skipped: 0,
+            returned: 0,
+        };
+
+        for (item_index, item) in query.columns.iter().enumerate() {
+            match &item.expr {
+                Expr::Column(column) if column == "*" => {
+                    for (index, header) in headers.iter().enumerate() {
+                        if header != FILE_COLUMN {
+                            executor.add_column(header, header, ColumnSource::Field(Some(index)));
+                        }
+                    }
+                }
+                Expr::Column(column) if column.ends_with(".*") => {
+                    // All columns belonging to a single joined table
+                    let prefix = &column[..column.len() - 1];
+                    if !headers.iter().any(|header| header.starts_with(prefix)) {
+                        return Err(format!("Unknown table '{}'", &prefix[..prefix.len() - 1]).into());
+                    }
+                    for (index, header) in headers.iter().enumerate() {
+                        if header.starts_with(prefix) && header[prefix.len()..] != *FILE_COLUMN {
+                            executor.add_column(header, header, ColumnSource::Field(Some(index)));
+                        }
+                    }
+                }
+                Expr::Column(column) => {
+                    let source = ColumnSource::Field(Some(find_column(headers, column)?));
+                    executor.add_column(&item.header(), column, source);
+                }
+                Expr::Aggregate(function) => {
+                    let source = ColumnSource::Aggregate(executor.add_aggregate(function)?);
+                    executor.add_column(&item.header(), &function.to_string(), source);
+                    executor.aggregating = true;
+                }
+                Expr::Window(_) => {
+                    let source = ColumnSource::Window(item_index);
+                    executor.add_column(&item.header(), &item.text, source);
+                    executor.windowed = true;
+                }
+                expr if contains_window(expr) => {
+                    return Err(format!(
+                        "Window functions can't be used inside an expression: {}",
+                        item.text
+                    )
+                    .into());
+                }
+                expr if contains_aggregate(expr) => {
+                    let source = ColumnSource::AggregateExpression(item_index);
+                    executor.add_column(&item.header(), &item.text, source);
+                    executor.aggregating = true;
+                }
+                _ => {
+                    let source = ColumnSource::Expression(item_index);
+                    executor.add_column(&item.header(), &item.text, source);
+                }
+            }
+        }
+        executor.result_headers = executor.columns.iter().map(|c| c.header.clone()).collect();
+
+        // Every column read from the records has to exist, aggregates are checked as they're added
+        let mut names = Vec::new();
+        if let Some(condition) = &query.condition {
+            condition_columns(condition, &mut names);
+        }
+        for item in &query.columns {
+            if !matches!(item.expr, Expr::Column(_) | Expr::Aggregate(_)) {
+                expr_columns(&item.expr, &mut names);
+            }
+        }
+        for name in names {
+            match parse_aggregate_function(&name) {
+                Ok(("", function)) if function.column() != "*" => {
+                    find_column(headers, function.column())?;
+                }
+                Ok(("", _)) => {}
+                _ => {
+                    find_column(headers, &name)?;
+                }
+            }
+        }
+
+        let mut names = Vec::new();
+        if let Some(having) = &query.having {
+            condition_columns(having, &mut names);
+        }
+        if let Some(qualify) = &query.qualify {
+            condition_columns(qualify, &mut names);
+        }
+        for item in &query.columns {
+            if contains_aggregate(&item.expr) {
+                expr_columns(&item.expr, &mut names);
+            }
+        }
+        for name in names {
+            let index = executor.resolve_column(&name)?;
+            executor.row_columns.insert(name, index);
+        }
+
+        // The columns windows read from are resolved once the selected columns are known
+        for index in 0..executor.columns.len() {
+            let ColumnSource::Window(item) = executor.columns[index].source else {
+                continue;
+            };
+            let Expr::Window(window) = &query.columns[item].expr else {
+                continue;
+            };
+            let window = executor.window_column(index, window)?;
+            executor.windows.push(window);
+        }
+        if executor.windowed {
+            executor.sort_buffer = SortBuffer::All(Vec::new());
+        }
+
+        if !query.order_by.is_empty() {
+            let keys = query
+                .order_by
+                .iter()
+                .map(|order_by| {
+                    executor
+                        .resolve_column(&order_by.column)
+                        .map(|index| (index, order_by.direction.clone()))
+                })
+                .collect::<Result<_, _>>()?;
+            executor.ordering = Some(Rc::new(RowOrdering { keys }));
+        }
+
+        // Aggregating without a GROUP BY always produces a single row, even with no records
+        if executor.aggregating && query.group_by.is_none() {
+            executor.group(Vec::new());
+        }
+
+        Ok(executor)
+    }
+
+    fn add_column(&mut self, header: &str, expression: &str, source: ColumnSource) {
+        self.columns.push(OutputColumn {
+            header: header.to_string(),
+            expression: expression.to_string(),
+            source,
+        });
+    }
+
+    fn add_aggregate(&mut self, function: &AggregateFunction) -> Result<usize, Box<dyn Error>> {
+        let input = match function.column() {
+            "*" => AggregateInput::AllRows,
+            column_name => AggregateInput::Column(find_column(self.headers, column_name)?),
+        };
+        self.aggregates.push((function.clone(), input));
+        Ok(self.aggregates.len() - 1)
+    }
 
-    // Read headers from the CSV
-    let headers = reader
-        .headers()?
-        .iter()
-        .map(|h| h.to_string())
-        .collect::<Vec<String>>();
+    fn window_column(
+        &mut self,
+        column: usize,
+        window: &WindowFunction,
+    ) -> Result<WindowColumn, Box<dyn Error>> {
+        let partition_by = window
+            .partition_by
+            .iter()
+            .map(|name| self.resolve_column(name))
+            .collect::<Result<_, _>>()?;
+        let keys = window
+            .order_by
+            .iter()
+            .map(|order_by| {
+                self.resolve_column(&order_by.column)
+                    .map(|index| (index, order_by.direction.clone()))
+            })
+            .collect::<Result<_, _>>()?;
+        let argument = match &window.function {
+            WindowKind::Lag(name, _, _) | WindowKind::Lead(name, _, _) => {
+                Some(self.resolve_column(name)?)
+            }
+            WindowKind::Aggregate(function) if function.column() != "*" => {
+                Some(self.resolve_column(function.column())?)
+            }
+            _ => None,
+        };
 
-    // Read all records into a vector
-    let records: Vec<StringRecord> = reader.records().collect::<Result<Vec<_>, _>>()?;
+        let argument_type = match argument.map(|index| &self.columns[index].source) {
+            Some(ColumnSource::Field(Some(field))) => self.column_types.get(*field).copied(),
+            _ => None,
+        };
 
-    Ok((headers, records))
-}
+        Ok(WindowColumn {
+            column,
+            function: window.function.clone(),
+            partition_by,
+            ordering: RowOrdering { keys },
+            argument,
+            argument_type: argument_type.unwrap_or(ColumnType::Float),
+        })
+    }
 
-pub fn apply_query(
-    headers: &Vec<String>,
-    records: Vec<StringRecord>,
-    query: &SQLQuery,
-) -> (Vec<String>, Vec<Vec<String>>) {
-    // First, filter the records based on the WHERE clause
-    let filtered_records: Vec<StringRecord> = records
-        .into_iter()
-        .filter(|row| {
-            if let Some(condition) = &query.condition {
-                evaluate_condition(condition, row, headers)
-            } else {
-                true // No conditions means all rows pass
+    // Find the output column referred to by ORDER BY or HAVING, either by its header, the
+    // expression it was selected with, or failing that by adding a hidden column for it
+    fn resolve_column(&mut self, name: &str) -> Result<usize, Box<dyn Error>> {
+        let headers: Vec<String> = self.columns.iter().map(|c| c.header.clone()).collect();
+        if let Some(index) = column_index(&headers, name) {
+            return Ok(index);
+        }
+        if let Some(index) = self.columns.iter().position(|c| c.expression == name) {
+            return Ok(index);
+        }
+
+        let source = match parse_aggregate_function(name) {
+            Ok(("", function)) if self.aggregating => {
+                ColumnSource::Aggregate(self.add_aggregate(&function)?)
             }
-        })
-        .collect();
+            // Unknown names are suggested from both the selected columns and the input columns
+            _ => match column_index(self.headers, name) {
+                Some(index) => ColumnSource::Field(Some(index)),
+                None => {
+                    let mut candidates = headers;
+                    for header in self.headers {
+                        if !candidates.contains(header) {
+                            candidates.push(header.clone());
+                        }
+                    }
+                    return Err(unknown_column(&candidates, name));
+                }
+            },
+        };
+        self.add_column(name, name, source);
+        Ok(self.columns.len() - 1)
+    }
 
-    // Create a new header order for the result
-    let mut result_headers: Vec<String> = Vec::new();
+    // Process a single record, returning a result row if it can be output straight away
+    fn push(&mut self, record: &StringRecord) -> Option<Vec<String>> {
+        if !self.matches_where(record) {
+            return None;
+        }
 
-    // Add headers
-    for h in &query.columns {
-        if h == &"*" {
-            result_headers.extend(headers.clone());
-        } else {
-            result_headers.push(h.to_string());
+        if self.aggregating {
+            self.aggregate(record);
+            return None;
         }
+
+        let row = self.project(record);
+        self.accept(row)
     }
 
-    // Group records if GROUP BY is specified
-    let grouped_records: Vec<(String, Vec<&StringRecord>)> = if let Some(group_by) = &query.group_by
-    {
-        let mut groups: std::collections::HashMap<String, Vec<&StringRecord>> =
-            std::collections::HashMap::new();
+    // Process a batch of records on a worker thread, the results are passed to merge_batch
+    // on the executor the query is running on
+    fn process_batch(&mut self, records: &[StringRecord]) -> BatchResult {
+        if !self.aggregating {
+            return BatchResult::Rows(
+                records
+                    .iter()
+                    .filter(|record| self.matches_where(record))
+                    .map(|record| self.project(record))
+                    .collect(),
+            );
+        }
 
-        for record in filtered_records.iter() {
-            let group_key = group_by
-                .iter()
-                .map(|col| {
-                    let index = headers.iter().position(|h| h == col).unwrap();
-                    record.get(index).unwrap_or("").to_string()
-                })
-                .collect::<Vec<String>>()
-                .join(",");
+        for record in records {
+            if self.matches_where(record) {
+                self.aggregate(record);
+            }
+        }
+        let mut keys = vec![Vec::new(); self.groups.len()];
+        for (key, index) in self.group_index.drain() {
+            keys[index] = key;
+        }
+        BatchResult::Groups(keys.into_iter().zip(std::mem::take(&mut self.groups)).collect())
+    }
 
-            groups
-                .entry(group_key)
-                .or_insert_with(Vec::new)
-                .push(record);
+    // Add the results of a batch processed on a worker thread, returning any result rows
+    // that can be output straight away
+    fn merge_batch(&mut self, result: BatchResult) -> Vec<Vec<String>> {
+        match result {
+            BatchResult::Rows(rows) => rows.into_iter().filter_map(|row| self.accept(row)).collect(),
+            BatchResult::Groups(groups) => {
+                for (key, group) in groups {
+                    let index = self.group(key);
+                    let target = &mut self.groups[index];
+                    if target.values.is_none() {
+                        target.values = group.values;
+                    }
+                    for (accumulator, other) in target.accumulators.iter_mut().zip(group.accumulators) {
+                        accumulator.merge(other);
+                    }
+                }
+                Vec::new()
+            }
         }
+    }
 
-        groups.into_iter().collect() // Convert the HashMap to a Vec
-    } else {
-        // No grouping, create a single group with all filtered records
-        vec![("*".to_string(), filtered_records.iter().collect())] // Use a default key for no grouping
-    };
+    fn matches_where(&self, record: &StringRecord) -> bool {
+        match &self.query.condition {
+            Some(condition) => evaluate_condition(condition, &|name| self.record_value(record, name)),
+            None => true,
+        }
+    }
 
-    // Prepare the result rows
-    let mut result_rows = Vec::new();
-
-    // If there's an aggregate function, process it
-    if !query.aggregate_functions.is_empty() {
-        for (group_key, records) in &grouped_records {
-            let mut row = Vec::new();
-
-            for column_name in &query.columns {
-                let column_value =
-                    if let Some(index) = headers.iter().position(|h| h == column_name) {
-                        // Collect the values for the column across all records in the group
-                        records
-                            .iter()
-                            .map(|record| record.get(index).unwrap_or("").to_string())
-                            .next() // Take the first value (or aggregate if needed later)
-                    } else {
-                        None
-                    };
-                row.push(column_value.unwrap_or_default());
-            }
-
-            // Now, calculate the aggregate functions for the group
-            for aggregate_function in &query.aggregate_functions {
-                match aggregate_function {
-                    // COUNT function
-                    AggregateFunction::Count(column_name) => {
-                        if let Some(column_index) = headers.iter().position(|h| h == column_name) {
-                            let count = records
-                                .iter()
-                                .filter(|record| {
-                                    record
-                                        .get(column_index)
-                                        .map_or(false, |value| !value.is_empty())
-                                })
-                                .count();
-                            row.push(count.to_string()); // Add the count result to the row
-                        } else if column_name == "*" {
-                            row.push(records.len().to_string()); // Special case: count all records in the group
-                        } else {
-                            println!("Column '{}' not found in headers", column_name);
-                            row.push("0".to_string()); // Default to 0 if column not found
-                        }
-                    }
+    // Add a record to its group, updating each of the group's aggregates
+    fn aggregate(&mut self, record: &StringRecord) {
+        let key = self
+            .group_by
+            .iter()
+            .map(|index| field(record, *index).to_string())
+            .collect::<Vec<String>>();
+        let group_index = self.group(key);
+        if self.groups[group_index].values.is_none() {
+            self.groups[group_index].values = Some(self.project(record));
+        }
 
-                    // MIN function
-                    AggregateFunction::Min(column_name) => {
-                        if let Some(column_index) = headers.iter().position(|h| h == column_name) {
-                            if let Some(min_value) = records
-                                .iter()
-                                .filter_map(|record| record.get(column_index))
-                                .filter(|value| !value.is_empty())
-                                .min()
-                            {
-                                row.push(min_value.to_string());
-                            } else {
-                                row.push("NULL".to_string()); // Handle empty or missing values
-                            }
-                        } else {
-                            println!("Column '{}' not found in headers", column_name);
-                            row.push("NULL".to_string());
-                        }
-                    }
+        let group = &mut self.groups[group_index];
+        for (accumulator, (_, input)) in group.accumulators.iter_mut().zip(&self.aggregates) {
+            match input {
+                AggregateInput::AllRows => accumulator.update(None),
+                AggregateInput::Column(index) => accumulator.update(Some(field(record, Some(*index)))),
+            }
+        }
+    }
 
-                    // MAX function
-                    AggregateFunction::Max(column_name) => {
-                        if let Some(column_index) = headers.iter().position(|h| h == column_name) {
-                            if let Some(max_value) = records
-                                .iter()
-                                .filter_map(|record| record.get(column_index))
-                                .filter(|value| !value.is_empty())
-                                .max()
-                            {
-                                row.push(max_value.to_string());
-                            } else {
-                                row.push("NULL".to_string());
-                            }
-                        } else {
-                            println!("Column '{}' not found in headers", column_name);
-                            row.push("NULL".to_string());
-                        }
-                    }
+    // Pass a projected row through DISTINCT, ORDER BY, OFFSET and LIMIT, returning it if it
+    // can be output straight away
+    fn accept(&mut self, row: Vec<String>) -> Option<Vec<String>> {
+        if self.windowed {
+            if let SortBuffer::All(rows) = &mut self.sort_buffer {
+                rows.push(row);
+            }
+            return None;
+        }
 
-                    // SUM function
-                    AggregateFunction::Sum(column_name) => {
-                        if let Some(column_index) = headers.iter().position(|h| h == column_name) {
-                            let sum: f64 = records
-                                .iter()
-                                .filter_map(|record| record.get(column_index))
-                                .filter(|value| !value.is_empty())
-                                .filter_map(|value| value.parse::<f64>().ok())
-                                .sum();
-                            row.push(sum.to_string()); // Add the sum result to the row
-                        } else {
-                            println!("Column '{}' not found in headers", column_name);
-                            row.push("0".to_string());
-                        }
-                    }
+        // Only keep the first occurrence of each row when DISTINCT is specified
+        if self.query.distinct
+            && !self.distinct_rows.insert(row[..self.result_headers.len()].to_vec())
+        {
+            return None;
+        }
 
-                    // AVG function
-                    AggregateFunction::Avg(column_name) => {
-                        if let Some(column_index) = headers.iter().position(|h| h == column_name) {
-                            let sum: f64 = records
-                                .iter()
-                                .filter_map(|record| record.get(column_index))
-                                .filter(|value| !value.is_empty())
-                                .filter_map(|value| value.parse::<f64>().ok())
-                                .sum();
-
-                            let count: usize = records
-                                .iter()
-                                .filter(|record| {
-                                    record
-                                        .get(column_index)
-                                        .map_or(false, |value| !value.is_empty())
-                                })
-                                .count();
-
-                            if count > 0 {
-                                let avg = sum / (count as f64);
-                                row.push(avg.to_string());
-                            } else {
-                                row.push("NULL".to_string()); // Handle division by zero case
-                            }
-                        } else {
-                            println!("Column '{}' not found in headers", column_name);
-                            row.push("NULL".to_string());
-                        }
+        if let Some(ordering) = &self.ordering {
+            match &mut self.sort_buffer {
+                SortBuffer::All(rows) => rows.push(row),
+                SortBuffer::Top(heap, size) => {
+                    heap.push(RankedRow {
+                        row,
+                        sequence: self.sequence,
+                        ordering: ordering.clone(),
+                    });
+                    if heap.len() > *size {
+                        heap.pop();
                     }
                 }
             }
-            // After processing both columns and aggregates, push the row
-            result_rows.push(row);
+            self.sequence += 1;
+            return None;
         }
 
-        // Update result_headers to include the group key and aggregate function columns
-        for aggregate_function in &query.aggregate_functions {
-            match aggregate_function {
-                AggregateFunction::Count(column_name) => {
-                    result_headers.push(format!("COUNT({})", column_name));
-                }
-                AggregateFunction::Min(column_name) => {
-                    result_headers.push(format!("MIN({})", column_name));
-                }
-                AggregateFunction::Max(column_name) => {
-                    result_headers.push(format!("MAX({})", column_name));
+        if self.skipped < self.query.offset.unwrap_or(0) {
+            self.skipped += 1;
+            return None;
+        }
+
+        if self.is_complete() {
+            return None;
+        }
+
+        self.returned += 1;
+        Some(row)
+    }
+
+    // True once no more records can affect the result, i.e. the LIMIT has been reached
+    // on results that are being streamed
+    fn is_complete(&self) -> bool {
+        !self.aggregating
+            && !self.windowed
+            && self.ordering.is_none()
+            && self.query.limit.is_some_and(|limit| self.returned >= limit)
+    }
+
+    // Called once every record has been read, returns the remaining result rows
+    fn finish(mut self) -> Vec<Vec<String>> {
+        let mut result_rows: Vec<Vec<String>> = if self.aggregating {
+            let width = self.columns.len();
+            let mut rows = Vec::new();
+            for group in std::mem::take(&mut self.groups) {
+                let mut row = group.values.unwrap_or_else(|| vec![String::new(); width]);
+                for (value, column) in row.iter_mut().zip(&self.columns) {
+                    if let ColumnSource::Aggregate(index) = column.source {
+                        *value = group.accumulators[index].result();
+                    }
                 }
-                AggregateFunction::Sum(column_name) => {
-                    result_headers.push(format!("SUM({})", column_name));
+                for index in 0..width {
+                    if let ColumnSource::AggregateExpression(item) = self.columns[index].source {
+                        let expr = &self.query.columns[item].expr;
+                        let value = evaluate_expr(expr, &|name| self.row_value(&row, name));
+                        row[index] = output_field(value);
+                    }
                 }
-                AggregateFunction::Avg(column_name) => {
-                    result_headers.push(format!("AVG({})", column_name));
+                if self.matches_having(&row) {
+                    rows.push(row);
                 }
             }
-        }
-    } else {
-        if query.distinct {
-            let mut distinct_records = std::collections::HashSet::new();
-            for record in filtered_records {
-                // Create a unique key for the record based on the selected columns
-                let key = if query.columns.contains(&"*".to_string()) {
-                    // Create a unique key using all fields in the record
-                    record.iter().collect::<Vec<&str>>().join(",")
-                } else {
-                    query
-                        .columns
-                        .iter()
-                        .filter_map(|header| {
-                            headers
-                                .iter()
-                                .position(|h| h == header)
-                                .and_then(|index| record.get(index))
-                        })
-                        .map(|value| value.to_string())
-                        .collect::<Vec<String>>()
-                        .join(",")
-                };
+            rows
+        } else {
+            match std::mem::replace(&mut self.sort_buffer, SortBuffer::All(Vec::new())) {
+                SortBuffer::All(rows) => rows,
+                SortBuffer::Top(heap, _) => heap
+                    .into_sorted_vec()
+                    .into_iter()
+                    .map(|ranked| ranked.row)
+                    .collect(),
+            }
+        };
 
-                // Insert the key into the HashSet to ensure uniqueness
-                distinct_records.insert(key);
+        if self.windowed {
+            self.apply_windows(&mut result_rows);
+            result_rows.retain(|row| self.matches_qualify(row));
+
+            // DISTINCT applies to the rows with their window function results
+            if self.query.distinct && !self.aggregating {
+                let width = self.result_headers.len();
+                let mut distinct_rows = HashSet::new();
+                result_rows.retain(|row| distinct_rows.insert(row[..width].to_vec()));
             }
+        }
 
-            // Collect distinct results
-            for key in distinct_records {
-                // Create a row based on the unique key
-                let row: Vec<String> = key.split(',').map(|s| s.to_string()).collect();
-                result_rows.push(row);
+        // Sort the results if ORDER BY is specified after aggregation
+        if let Some(ordering) = &self.ordering {
+            result_rows.sort_by(|a, b| ordering.compare(a, b));
+        }
+
+        // Rows that were streamed have already had the OFFSET and LIMIT applied
+        if !self.aggregating && !self.windowed && self.ordering.is_none() {
+            return result_rows;
+        }
+
+        // Drop the hidden columns
+        let width = self.result_headers.len();
+        result_rows
+            .into_iter()
+            .skip(self.query.offset.unwrap_or(0))
+            .take(self.query.limit.unwrap_or(usize::MAX))
+            .map(|mut row| {
+                row.truncate(width);
+                row
+            })
+            .collect()
+    }
+
+    // Fill in the window function columns. Each window splits the rows into partitions which
+    // are sorted by its ORDER BY, rows that tie keep the order they were read in.
+    fn apply_windows(&self, rows: &mut [Vec<String>]) {
+        for window in &self.windows {
+            let mut partition_index: HashMap<Vec<String>, usize> = HashMap::new();
+            let mut partitions: Vec<Vec<usize>> = Vec::new();
+            for (index, row) in rows.iter().enumerate() {
+                let key: Vec<String> = window.partition_by.iter().map(|i| row[*i].clone()).collect();
+                let partition = *partition_index.entry(key).or_insert_with(|| {
+                    partitions.push(Vec::new());
+                    partitions.len() - 1
+                });
+                partitions[partition].push(index);
             }
-        } else {
-            // Process records based on specified columns
-            for record in filtered_records {
-                let row: Vec<String> = if query.columns.contains(&"*".to_string()) {
-                    record.iter().map(|value| value.to_string()).collect()
-                } else {
-                    query
-                        .columns
-                        .iter()
-                        .filter_map(|header| {
-                            if let Some(index) = headers.iter().position(|h| h == header) {
-                                Some(record.get(index).unwrap_or("").to_string())
-                            } else {
-                                None
-                            }
-                        })
-                        .collect()
-                };
-                result_rows.push(row);
+
+            for mut partition in partitions {
+                partition.sort_by(|a, b| window.ordering.compare(&rows[*a], &rows[*b]));
+                let values = window.evaluate(rows, &partition);
+                for (index, value) in partition.into_iter().zip(values) {
+                    rows[index][window.column] = value;
+                }
             }
         }
     }
 
-    // Create a mapping of column names to their respective indices
-    let column_index_map: std::collections::HashMap<_, _> = result_headers
-        .iter()
-        .enumerate()
-        .map(|(i, h)| (h.clone(), i))
-        .collect();
+    fn matches_qualify(&self, row: &[String]) -> bool {
+        let Some(qualify) = &self.query.qualify else {
+            return true;
+        };
 
-    // Sort the results if ORDER BY is specified after aggregation
-    if let Some(order_by) = &query.order_by {
-        if let Some(column_index) = column_index_map.get(&order_by.column) {
-            result_rows.sort_by(|a, b| {
-                let default = String::from("");
-                let a_value = a.get(*column_index).unwrap_or(&default);
-                let b_value = b.get(*column_index).unwrap_or(&default);
-
-                // Try to parse the values as numbers first
-                let a_parsed = a_value.parse::<f64>();
-                let b_parsed = b_value.parse::<f64>();
-
-                match (a_parsed, b_parsed) {
-                    (Ok(a_num), Ok(b_num)) => {
-                        // Both values are numeric
-                        match order_by.direction {
-                            SortDirection::Ascending => a_num.partial_cmp(&b_num).unwrap(),
-                            SortDirection::Descending => b_num.partial_cmp(&a_num).unwrap(),
-                        }
-                    }
-                    _ => {
-                        // Fallback to string comparison if either value is not a valid number
-                        match order_by.direction {
-                            SortDirection::Ascending => a_value.cmp(b_value),
-                            SortDirection::Descending => b_value.cmp(a_value),
-                        }
-                    }
-                }
-            });
+        evaluate_condition(qualify, &|name| self.row_value(row, name))
+    }
+
+    fn matches_having(&self, row: &[String]) -> bool {
+        let Some(having) = &self.query.having else {
+            return true;
+        };
+
+        evaluate_condition(having, &|name| self.row_value(row, name))
+    }
+
+    // Look up a column of a record, along with the type of the column
+    fn record_value<'r>(&self, record: &'r StringRecord, name: &str) -> Option<(&'r str, ColumnType)> {
+        column_index(self.headers, name).map(|index| {
+            let column_type = self.column_types.get(index).copied();
+            (field(record, Some(index)), column_type.unwrap_or(ColumnType::String))
+        })
+    }
+
+    // Look up a column of an aggregated row by a name used in HAVING or an aggregate
+    // expression. Aggregate results have their type inferred from the value itself.
+    fn row_value<'r>(&self, row: &'r [String], name: &str) -> Option<(&'r str, ColumnType)> {
+        self.row_columns.get(name).map(|index| {
+            let column_type = match self.columns[*index].source {
+                ColumnSource::Field(Some(field)) => self.column_types.get(field).copied(),
+                _ => None,
+            };
+            (row[*index].as_str(), column_type.unwrap_or(ColumnType::Float))
+        })
+    }
+
+    // Find the group for a key, creating it if this is the first record seen for the key
+    fn group(&mut self, key: Vec<String>) -> usize {
+        if let Some(index) = self.group_index.get(&key) {
+            return *index;
         }
+
+        let index = self.groups.len();
+        self.groups.push(Group {
+            values: None,
+            accumulators: self
+                .aggregates
+                .iter()
+                .map(|(function, input)| {
+                    let column_type = match input {
+                        AggregateInput::Column(index) => self.column_types.get(*index).copied(),
+                        AggregateInput::AllRows => None,
+                    };
+                    Accumulator::new(function, column_type.unwrap_or(ColumnType::String))
+                })
+                .collect(),
+        });
+        self.group_index.insert(key, index);
+        index
     }
 
-    (result_headers, result_rows)
+    fn project(&self, record: &StringRecord) -> Vec<String> {
+        self.columns
+            .iter()
+            .map(|column| match column.source {
+                ColumnSource::Field(index) => field(record, index).to_string(),
+                ColumnSource::Expression(item) => {
+                    let expr = &self.query.columns[item].expr;
+                    output_field(evaluate_expr(expr, &|name| self.record_value(record, name)))
+                }
+                ColumnSource::Aggregate(_)
+                | ColumnSource::AggregateExpression(_)
+                | ColumnSource::Window(_) => String::new(),
+            })
+            .collect()
+    }
 }
 
-fn evaluate_condition(condition: &Condition, row: &StringRecord, headers: &[String]) -> bool {
-    match condition {
-        Condition::GreaterThan(col, threshold) => {
-            if let Some(index) = headers.iter().position(|h| h == col) {
-                if let Some(value) = row.get(index) {
-                    if let Ok(num) = value.parse::<i32>() {
-                        return num > *threshold;
-                    }
-                }
+fn field(record: &StringRecord, index: Option<usize>) -> &str {
+    index.and_then(|i| record.get(i)).unwrap_or("")
+}
+
+// Compare values numerically when both are numbers, otherwise as strings
+fn compare_values(a: &str, b: &str) -> Ordering {
+    match (a.parse::<f64>(), b.parse::<f64>()) {
+        (Ok(a_num), Ok(b_num)) => a_num.partial_cmp(&b_num).unwrap_or(Ordering::Equal),
+        _ => a.cmp(b),
+    }
+}
+
+// Collect the names of the columns and aggregate functions an expression refers to
+fn expr_columns(expr: &Expr, columns: &mut Vec<String>) {
+    match expr {
+        Expr::Column(column) => columns.push(column.clone()),
+        Expr::Literal(_) => {}
+        Expr::Aggregate(function) => columns.push(function.to_string()),
+        Expr::Negate(inner) | Expr::Cast(inner, _) => expr_columns(inner, columns),
+        Expr::BinaryOp(left, _, right) => {
+            expr_columns(left, columns);
+            expr_columns(right, columns);
+        }
+        Expr::Function(_, args) => {
+            for arg in args {
+                expr_columns(arg, columns);
             }
-            false
         }
-        Condition::GreaterThanEqualTo(col, threshold) => {
-            if let Some(index) = headers.iter().position(|h| h == col) {
-                if let Some(value) = row.get(index) {
-                    if let Ok(num) = value.parse::<i32>() {
-                        return num >= *threshold;
-                    }
-                }
+        Expr::Case(branches, otherwise) => {
+            for (condition, result) in branches {
+                condition_columns(condition, columns);
+                expr_columns(result, columns);
+            }
+            if let Some(otherwise) = otherwise {
+                expr_columns(otherwise, columns);
             }
-            false
         }
-        Condition::LessThan(col, threshold) => {
-            if let Some(index) = headers.iter().position(|h| h == col) {
-                if let Some(value) = row.get(index) {
-                    if let Ok(num) = value.parse::<i32>() {
-                        return num < *threshold;
-                    }
+        // Window functions read from the result rows rather than the records
+        Expr::Window(_) => {}
+    }
+}
+
+// Window functions are calculated over every result row, so they can only be selected on their own
+fn contains_window(expr: &Expr) -> bool {
+    match expr {
+        Expr::Window(_) => true,
+        Expr::Negate(inner) | Expr::Cast(inner, _) => contains_window(inner),
+        Expr::BinaryOp(left, _, right) => contains_window(left) || contains_window(right),
+        Expr::Function(_, args) => args.iter().any(contains_window),
+        Expr::Case(branches, otherwise) => {
+            branches.iter().any(|(_, result)| contains_window(result))
+                || otherwise.as_deref().is_some_and(contains_window)
+        }
+        Expr::Column(_) | Expr::Literal(_) | Expr::Aggregate(_) => false,
+    }
+}
+
+fn contains_aggregate(expr: &Expr) -> bool {
+    let mut columns = Vec::new();
+    expr_columns(expr, &mut columns);
+    columns
+        .iter()
+        .any(|column| matches!(parse_aggregate_function(column), Ok(("", _))))
+}
+
+// Expressions that evaluate to NULL are output as empty fields
+fn output_field(value: Value) -> String {
+    value.as_text().unwrap_or_default()
+}
+
+// Evaluate an expression against a row, aggregate functions are looked up by name
+// as they've already been calculated
+fn evaluate_expr<'r>(expr: &Expr, lookup: &dyn Fn(&str) -> Option<(&'r str, ColumnType)>) -> Value {
+    let column_value = |name: &str| {
+        lookup(name).map_or(Value::Null, |(text, column_type)| Value::parse(text, column_type))
+    };
+
+    match expr {
+        Expr::Column(column) => column_value(column),
+        Expr::Literal(value) => value.clone(),
+        Expr::Aggregate(function) => column_value(&function.to_string()),
+        Expr::Negate(inner) => match evaluate_expr(inner, lookup).as_number() {
+            Some(Value::Integer(num)) => Value::Integer(-num),
+            Some(Value::Float(num)) => Value::Float(-num),
+            _ => Value::Null,
+        },
+        Expr::BinaryOp(left, op, right) => {
+            arithmetic(&evaluate_expr(left, lookup), *op, &evaluate_expr(right, lookup))
+        }
+        Expr::Function(function, args) => {
+            let args: Vec<Value> = args.iter().map(|arg| evaluate_expr(arg, lookup)).collect();
+            apply_function(*function, &args)
+        }
+        Expr::Case(branches, otherwise) => {
+            for (condition, result) in branches {
+                if evaluate_condition(condition, lookup) {
+                    return evaluate_expr(result, lookup);
                 }
             }
-            false
+            otherwise
+                .as_ref()
+                .map_or(Value::Null, |otherwise| evaluate_expr(otherwise, lookup))
         }
-        Condition::LessThanEqualTo(col, threshold) => {
-            if let Some(index) = headers.iter().position(|h| h == col) {
-                if let Some(value) = row.get(index) {
-                    if let Ok(num) = value.parse::<i32>() {
-                        return num <= *threshold;
-                    }
+        Expr::Cast(inner, column_type) => evaluate_expr(inner, lookup).cast(*column_type),
+        // Calculated once every row is known, see QueryExecutor::apply_windows
+        Expr::Window(_) => Value::Null,
+    }
+}
+
+// Integer arithmetic stays as integers unless it overflows, division always gives a float.
+// Anything that isn't a number, and division by zero, gives NULL.
+fn arithmetic(left: &Value, op: Op, right: &Value) -> Value {
+    let (Some(left), Some(right)) = (left.as_number(), right.as_number()) else {
+        return Value::Null;
+    };
+
+    if let (Value::Integer(a), Value::Integer(b)) = (&left, &right) {
+        let result = match op {
+            Op::Add => a.checked_add(*b),
+            Op::Sub => a.checked_sub(*b),
+            Op::Mul => a.checked_mul(*b),
+            Op::Mod => a.checked_rem(*b),
+            Op::Div => None,
+        };
+        if let Some(num) = result {
+            return Value::Integer(num);
+        }
+    }
+
+    let a = left.as_f64().unwrap_or(f64::NAN);
+    let b = right.as_f64().unwrap_or(f64::NAN);
+    match op {
+        Op::Add => Value::Float(a + b),
+        Op::Sub => Value::Float(a - b),
+        Op::Mul => Value::Float(a * b),
+        Op::Div | Op::Mod if b == 0.0 => Value::Null,
+        Op::Div => Value::Float(a / b),
+        Op::Mod => Value::Float(a % b),
+    }
+}
+
+// String functions return NULL when given NULL, except for CONCAT which skips NULLs
+fn apply_function(function: ScalarFunction, args: &[Value]) -> Value {
+    let text = |index: usize| args.get(index).and_then(|arg| arg.as_text());
+
+    match function {
+        ScalarFunction::Coalesce => args
+            .iter()
+            .find(|arg| **arg != Value::Null)
+            .cloned()
+            .unwrap_or(Value::Null),
+        ScalarFunction::Concat => {
+            Value::String(args.iter().filter_map(|arg| arg.as_text()).collect())
+        }
+        ScalarFunction::Upper => text(0).map_or(Value::Null, |s| Value::String(s.to_uppercase())),
+        ScalarFunction::Lower => text(0).map_or(Value::Null, |s| Value::String(s.to_lowercase())),
+        ScalarFunction::Trim => text(0).map_or(Value::Null, |s| Value::String(s.trim().to_string())),
+        ScalarFunction::Length => {
+            text(0).map_or(Value::Null, |s| Value::Integer(s.chars().count() as i64))
+        }
+        ScalarFunction::Replace => match (text(0), text(1), text(2)) {
+            (Some(s), Some(from), Some(to)) if !from.is_empty() => Value::String(s.replace(&from, &to)),
+            (Some(s), Some(_), Some(_)) => Value::String(s),
+            _ => Value::Null,
+        },
+        // SUBSTR(text, start, length) where start counts from 1
+        ScalarFunction::Substr => {
+            let Some(s) = text(0) else {
+                return Value::Null;
+            };
+            let start = match args.get(1).and_then(|arg| arg.as_number()) {
+                Some(Value::Integer(start)) => start,
+                Some(Value::Float(start)) => start as i64,
+                _ => return Value::Null,
+            };
+            let length = match args.get(2).map(|arg| arg.as_number()) {
+                None => usize::MAX,
+                Some(Some(Value::Integer(length))) => length.max(0) as usize,
+                Some(Some(Value::Float(length))) => length.max(0.0) as usize,
+                Some(_) => return Value::Null,
+            };
+            Value::String(s.chars().skip((start.max(1) - 1) as usize).take(length).collect())
+        }
+    }
+}
+
+// Collect the names of the columns a condition refers to
+fn condition_columns(condition: &Condition, columns: &mut Vec<String>) {
+    match condition {
+        Condition::GreaterThan(col, _)
+        | Condition::GreaterThanEqualTo(col, _)
+        | Condition::LessThan(col, _)
+        | Condition::LessThanEqualTo(col, _)
+        | Condition::Equal(col, _)
+        | Condition::NotEqual(col, _)
+        | Condition::Like(col, _)
+        | Condition::NotLike(col, _)
+        | Condition::Between(col, _, _)
+        | Condition::In(col, _)
+        | Condition::NotIn(col, _)
+        | Condition::InSubquery(col, _)
+        | Condition::NotInSubquery(col, _)
+        | Condition::IsNull(col)
+        | Condition::IsNotNull(col)
+        | Condition::CompareSubquery(col, _, _) => columns.push(col.clone()),
+        Condition::And(cond1, cond2) | Condition::Or(cond1, cond2) => {
+            condition_columns(cond1, columns);
+            condition_columns(cond2, columns);
+        }
+        Condition::Not(cond1) => condition_columns(cond1, columns),
+    }
+}
+
+// Run every subquery used by a query so their values are ready before any records are read
+fn run_subqueries(query: &SQLQuery, options: &CsvOptions) -> Result<(), Box<dyn Error>> {
+    let mut conditions: Vec<&Condition> = query
+        .condition
+        .iter()
+        .chain(&query.having)
+        .chain(&query.qualify)
+        .collect();
+    for item in &query.columns {
+        expr_conditions(&item.expr, &mut conditions);
+    }
+    run_condition_subqueries(conditions, options)
+}
+
+// Run the subqueries used by any of the conditions
+fn run_condition_subqueries(
+    mut conditions: Vec<&Condition>,
+    options: &CsvOptions,
+) -> Result<(), Box<dyn Error>> {
+    while let Some(condition) = conditions.pop() {
+        match condition {
+            Condition::InSubquery(_, subquery) | Condition::NotInSubquery(_, subquery) => {
+                subquery.run(options)?
+            }
+            // A subquery compared against has to return a single value
+            Condition::CompareSubquery(_, _, subquery) => {
+                subquery.run(options)?;
+                if subquery.values().len() > 1 {
+                    return Err(format!(
+                        "Subquery on '{}' returned more than one row",
+                        subquery.query.table
+                    )
+                    .into());
                 }
             }
-            false
+            Condition::And(cond1, cond2) | Condition::Or(cond1, cond2) => {
+                conditions.push(cond1);
+                conditions.push(cond2);
+            }
+            Condition::Not(cond1) => conditions.push(cond1),
+            _ => {}
         }
-        Condition::Equal(col, expected_value) => {
-            if let Some(index) = headers.iter().position(|h| h == col) {
-                if let Some(value) = row.get(index) {
-                    return value == expected_value;
-                }
+    }
+
+    Ok(())
+}
+
+// Collect the conditions used by CASE expressions
+fn expr_conditions<'e>(expr: &'e Expr, conditions: &mut Vec<&'e Condition>) {
+    match expr {
+        Expr::Negate(inner) | Expr::Cast(inner, _) => expr_conditions(inner, conditions),
+        Expr::BinaryOp(left, _, right) => {
+            expr_conditions(left, conditions);
+            expr_conditions(right, conditions);
+        }
+        Expr::Function(_, args) => {
+            for arg in args {
+                expr_conditions(arg, conditions);
             }
-            false
         }
-        Condition::Between(col, lower, upper) => {
-            if let Some(index) = headers.iter().position(|h| h == col) {
-                if let Some(value) = row.get(index) {
-                    if let Ok(num) = value.parse::<i32>() {
-                        return num >= *lower && num <= *upper; // Check if the value is within the range
-                    }
-                }
+        Expr::Case(branches, otherwise) => {
+            for (condition, result) in branches {
+                conditions.push(condition);
+                expr_conditions(result, conditions);
+            }
+            if let Some(otherwise) = otherwise {
+                expr_conditions(otherwise, conditions);
             }
-            false
         }
-        Condition::And(cond1, cond2) => {
-            evaluate_condition(cond1, row, headers) && evaluate_condition(cond2, row, headers)
+        Expr::Column(_) | Expr::Literal(_) | Expr::Aggregate(_) | Expr::Window(_) => {}
+    }
+}
+
+// Evaluate a condition against a row, the lookup returns the text of a column
+// in the row along with the type of the column. Only rows where the condition is
+// true match, not those where it's false or unknown.
+fn evaluate_condition<'r>(
+    condition: &Condition,
+    lookup: &dyn Fn(&str) -> Option<(&'r str, ColumnType)>,
+) -> bool {
+    evaluate_truth(condition, lookup) == Some(true)
+}
+
+// Evaluate a condition with SQL's three valued logic, None is unknown. Comparisons involving
+// NULL are unknown, so e.g. NOT b = 'x' doesn't match rows where b is empty.
+fn evaluate_truth<'r>(
+    condition: &Condition,
+    lookup: &dyn Fn(&str) -> Option<(&'r str, ColumnType)>,
+) -> Option<bool> {
+    // Read the typed value of a column
+    let column_value = |col: &String| {
+        lookup(col).map(|(text, column_type)| Value::parse(text, column_type))
+    };
+    let compare = |col: &String, value: &Value| column_value(col)?.compare(value);
+    let equals = |col: &String, value: &Value| column_value(col)?.equals(value);
+    // Like equals, NULL is neither in or not in a list of values
+    let contains = |col: &String, values: &[Value]| {
+        let value = column_value(col)?;
+        if value == Value::Null {
+            return None;
         }
+        Some(values.iter().any(|v| value.equals(v) == Some(true)))
+    };
+    let like = |col: &String, pattern: &regex::Regex| {
+        let (text, _) = lookup(col).filter(|(text, _)| !text.is_empty())?;
+        Some(pattern.is_match(text))
+    };
+
+    match condition {
+        Condition::GreaterThan(col, value) => compare(col, value).map(|o| o == Ordering::Greater),
+        Condition::GreaterThanEqualTo(col, value) => compare(col, value).map(|o| o != Ordering::Less),
+        Condition::LessThan(col, value) => compare(col, value).map(|o| o == Ordering::Less),
+        Condition::LessThanEqualTo(col, value) => compare(col, value).map(|o| o != Ordering::Greater),
+        Condition::Equal(col, value) => equals(col, value),
+        Condition::NotEqual(col, value) => equals(col, value).map(|equal| !equal),
+        Condition::Between(col, lower, upper) => and_truth(
+            compare(col, lower).map(|o| o != Ordering::Less),
+            compare(col, upper).map(|o| o != Ordering::Greater),
+        ),
+        Condition::And(cond1, cond2) => and_truth(evaluate_truth(cond1, lookup), evaluate_truth(cond2, lookup)),
         Condition::Or(cond1, cond2) => {
-            evaluate_condition(cond1, row, headers) || evaluate_condition(cond2, row, headers)
+            match (evaluate_truth(cond1, lookup), evaluate_truth(cond2, lookup)) {
+                (Some(true), _) | (_, Some(true)) => Some(true),
+                (Some(false), Some(false)) => Some(false),
+                _ => None,
+            }
         }
-        Condition::Not(cond1) => !evaluate_condition(cond1, row, headers),
-        Condition::Like(col, pattern) => {
-            if let Some(index) = headers.iter().position(|h| h == col) {
-                if let Some(value) = row.get(index) {
-                    return pattern.is_match(value);
-                }
+        Condition::Not(cond1) => evaluate_truth(cond1, lookup).map(|truth| !truth),
+        Condition::Like(col, pattern) => like(col, pattern),
+        Condition::NotLike(col, pattern) => like(col, pattern).map(|matched| !matched),
+        Condition::In(col, values) => contains(col, values),
+        Condition::NotIn(col, values) => contains(col, values).map(|found| !found),
+        Condition::InSubquery(col, subquery) => contains(col, subquery.values()),
+        Condition::NotInSubquery(col, subquery) => contains(col, subquery.values()).map(|found| !found),
+        Condition::IsNull(col) => Some(column_value(col).is_none_or(|value| value == Value::Null)),
+        Condition::IsNotNull(col) => Some(column_value(col).is_some_and(|value| value != Value::Null)),
+        Condition::CompareSubquery(col, operator, subquery) => {
+            evaluate_truth(&comparison(col.clone(), operator, subquery.scalar()), lookup)
+        }
+    }
+}
+
+// AND of two values in three valued logic, false if either is false
+fn and_truth(a: Option<bool>, b: Option<bool>) -> Option<bool> {
+    match (a, b) {
+        (Some(false), _) | (_, Some(false)) => Some(false),
+        (Some(true), Some(true)) => Some(true),
+        _ => None,
+    }
+}
+
+// Parser for a keyword in any case. The words of keywords like GROUP BY can be separated by
+// any whitespace, and the keyword can't run on into an identifier (e.g. ORDER in ORDERS).
+fn keyword<'a>(keyword: &'static str) -> impl FnMut(&'a str) -> IResult<&'a str, &'a str> {
+    move |input: &'a str| {
+        let mut rest = input;
+        for (i, word) in keyword.split(' ').enumerate() {
+            if i > 0 {
+                (rest, _) = multispace1(rest)?;
             }
-            false
+            (rest, _) = tag_no_case(word)(rest)?;
         }
+        let (rest, _) = not(peek(one_of(IDENTIFIER_CHARS)))(rest)?;
+        Ok((rest, &input[..input.len() - rest.len()]))
     }
 }
 
 // Function to parse comma followed by optional spaces
 fn comma_space(input: &str) -> IResult<&str, ()> {
-    let (input, _) = space0(input)?; // Consume any leading whitespace
+    let (input, _) = multispace0(input)?; // Consume any leading whitespace
     let (input, _) = tag(",")(input)?; // Consume the comma
-    let (input, _) = space0(input)?; // Consume any trailing whitespace
+    let (input, _) = multispace0(input)?; // Consume any trailing whitespace
     Ok((input, ())) // Return remaining input and unit value
 }
 
 fn parse_group_by(input: &str) -> IResult<&str, Vec<String>> {
     preceded(
-        tuple((space0, tag("GROUP BY"), space1)),
+        tuple((multispace0, keyword("GROUP BY"), multispace1)),
         separated_list1(comma_space, parse_column), // Parse column names
     )(input)
 }
 
-// Function to parse aggregate functions
+// Parser for aggregate functions e.g. COUNT(*), COUNT(DISTINCT Genre) or GROUP_CONCAT(Name, '; ')
 fn parse_aggregate_function(input: &str) -> IResult<&str, AggregateFunction> {
-    alt((
-        // Parser for COUNT function
-        preceded(
-            tuple((space0, tag("COUNT"))),
-            map(delimited(char('('), parse_column, char(')')), |col| {
-                AggregateFunction::Count(col)
-            }),
-        ),
-        preceded(
-            tuple((space0, tag("COUNT"))),
-            map(delimited(char('('), char('*'), char(')')), |col| {
-                AggregateFunction::Count(String::from("(*)"))
-            }),
-        ),
-        // Parser for MIN function
-        preceded(
-            tuple((space0, tag("MIN"))),
-            map(delimited(char('('), parse_column, char(')')), |col| {
-                AggregateFunction::Min(col)
-            }),
-        ),
-        preceded(
-            tuple((space0, tag("Min"))),
-            map(delimited(char('('), char('*'), char(')')), |col| {
-                AggregateFunction::Min(String::from("(*)"))
-            }),
-        ),
-        // Parser for MAX function
-        preceded(
-            tuple((space0, tag("MAX"))),
-            map(delimited(char('('), parse_column, char(')')), |col| {
-                AggregateFunction::Max(col)
-            }),
-        ),
-        preceded(
-            tuple((space0, tag("Max"))),
-            map(delimited(char('('), char('*'), char(')')), |col| {
-                AggregateFunction::Max(String::from("(*)"))
-            }),
-        ),
-        // Parser for SUM function
-        preceded(
-            tuple((space0, tag("SUM"))),
-            map(delimited(char('('), parse_column, char(')')), |col| {
-                AggregateFunction::Sum(col)
-            }),
-        ),
-        preceded(
-            tuple((space0, tag("Sum"))),
-            map(delimited(char('('), char('*'), char(')')), |col| {
-                AggregateFunction::Sum(String::from("(*)"))
-            }),
-        ),
-        // Parser for AVG function
-        preceded(
-            tuple((space0, tag("AVG"))),
-            map(delimited(char('('), parse_column, char(')')), |col| {
-                AggregateFunction::Avg(col)
-            }),
-        ),
-        preceded(
-            tuple((space0, tag("Avg"))),
-            map(delimited(char('('), char('*'), char(')')), |col| {
-                AggregateFunction::Avg(String::from("(*)"))
-            }),
-        ),
-    ))(input)
+    let (input, name) = preceded(
+        multispace0,
+        alt((
+            tag_no_case("COUNT"),
+            tag_no_case("MIN"),
+            tag_no_case("MAX"),
+            tag_no_case("SUM"),
+            tag_no_case("AVG"),
+            tag_no_case("MEDIAN"),
+            tag_no_case("STDDEV"),
+            tag_no_case("GROUP_CONCAT"),
+        )),
+    )(input)?;
+    let (input, _) = tuple((multispace0, char('('), multispace0))(input)?;
+    let (input, distinct) = opt(tuple((tag_no_case("DISTINCT"), multispace1)))(input)?;
+    let (input, column) = parse_column(input)?;
+    let (input, separator) = opt(preceded(
+        comma_space,
+        alt((value(String::new(), tag("''")), parse_quoted_string)),
+    ))(input)?;
+    let (remaining, _) = tuple((multispace0, char(')')))(input)?;
+
+    let function = match (name.to_uppercase().as_str(), distinct.is_some(), separator) {
+        ("COUNT", false, None) => AggregateFunction::Count(column),
+        ("COUNT", true, None) => AggregateFunction::CountDistinct(column),
+        ("MIN", false, None) => AggregateFunction::Min(column),
+        ("MAX", false, None) => AggregateFunction::Max(column),
+        ("SUM", false, None) => AggregateFunction::Sum(column),
+        ("AVG", false, None) => AggregateFunction::Avg(column),
+        ("MEDIAN", false, None) => AggregateFunction::Median(column),
+        ("STDDEV", false, None) => AggregateFunction::StdDev(column),
+        ("GROUP_CONCAT", false, separator) => {
+            AggregateFunction::GroupConcat(column, separator.unwrap_or_else(|| ",".to_string()))
+        }
+        // DISTINCT is only supported by COUNT and a separator only by GROUP_CONCAT
+        _ => {
+            return Err(nom::Err::Error(nom::error::Error::new(
+                input,
+                nom::error::ErrorKind::Verify,
+            )))
+        }
+    };
+    Ok((remaining, function))
 }
 
 fn parse_wildcard(input: &str) -> IResult<&str, String> {
@@ -564,121 +3803,401 @@ fn parse_quoted_string(input: &str) -> IResult<&str, String> {
         .map(|(next_input, result)| (next_input, result.to_string()))
 }
 
+const IDENTIFIER_CHARS: &str = "_abcdefghijklmnopqrstuvwxyzABCDEFGHIJKLMNOPQRSTUVWXYZ0123456789";
+
+// Parser for identifiers, either unquoted or quoted to allow any characters e.g. "Global Sales"
+fn parse_identifier(input: &str) -> IResult<&str, String> {
+    alt((parse_quoted_identifier, parse_bare_identifier))(input)
+}
+
+// Parser for identifiers in double quotes or backticks
+fn parse_quoted_identifier(input: &str) -> IResult<&str, String> {
+    map(
+        alt((
+            delimited(char('"'), is_not("\""), char('"')),
+            delimited(char('`'), is_not("`"), char('`')),
+        )),
+        |s: &str| s.to_string(),
+    )(input)
+}
+
 // Parser for unquoted identifiers (alphanumeric)
-fn parse_identifier(input: &str) -> IResult<&str, String> {
+fn parse_bare_identifier(input: &str) -> IResult<&str, String> {
     // Allow identifiers to consist of letters, digits, and underscores
     map(
-        many1(one_of(
-            "_abcdefghijklmnopqrstuvwxyzABCDEFGHIJKLMNOPQRSTUVWXYZ0123456789",
-        )),
+        many1(one_of(IDENTIFIER_CHARS)),
         |s: Vec<char>| s.into_iter().collect(), // Collect characters into a String
     )(input)
 }
 
+// Parser for an alias that isn't quoted, so can't be one of the reserved words
+fn parse_alias_identifier(input: &str) -> IResult<&str, String> {
+    alt((
+        parse_quoted_identifier,
+        verify(parse_bare_identifier, |alias: &str| {
+            !RESERVED_WORDS.contains(&alias.to_uppercase().as_str())
+        }),
+    ))(input)
+}
+
 fn parse_aggregate_function_name(input: &str) -> IResult<&str, String> {
-    let (input, func_name) =
-        alt((tag_no_case("COUNT"), tag_no_case("SUM"), tag_no_case("AVG")))(input)?;
-    let (input, _) = tuple((char('('), space0))(input)?;
-    let (input, column) = parse_column(input)?;
-    let (input, _) = tuple((space0, char(')')))(input)?;
-    Ok((input, format!("{}({})", func_name.to_uppercase(), column)))
+    map(parse_aggregate_function, |function| function.to_string())(input)
+}
+
+// Parser for identifiers optionally qualified by a table alias (e.g. v.Name or v.*),
+// nested JSON fields can have more parts (e.g. user.address.city)
+fn parse_qualified_identifier(input: &str) -> IResult<&str, String> {
+    map(
+        tuple((
+            parse_identifier,
+            many0(preceded(char('.'), parse_identifier)),
+            opt(preceded(char('.'), parse_wildcard)),
+        )),
+        |(first, rest, wildcard)| {
+            std::iter::once(first)
+                .chain(rest)
+                .chain(wildcard)
+                .collect::<Vec<String>>()
+                .join(".")
+        },
+    )(input)
 }
 
 // Combined parser for column names (either quoted strings or identifiers)
 fn parse_column(input: &str) -> IResult<&str, String> {
-    alt((parse_quoted_string, parse_identifier, parse_wildcard))(input)
+    alt((parse_quoted_string, parse_qualified_identifier, parse_wildcard))(input)
 }
 
 fn parse_order_by_column(input: &str) -> IResult<&str, String> {
     alt((
         parse_quoted_string,
         parse_aggregate_function_name,
-        parse_identifier,
+        parse_qualified_identifier,
         parse_wildcard,
     ))(input)
 }
 
-// Parser for the SELECT columns
-fn parse_columns(input: &str) -> IResult<&str, (bool, Vec<AggregateFunction>, Vec<String>)> {
-    // First, parse the SELECT keyword
-    let (input, _) = preceded(space0, tuple((tag("SELECT"), space1)))(input)?;
+// Parser for a column name given with AS, the AS can be left out (e.g. SELECT COUNT(*) total)
+fn parse_column_alias(input: &str) -> IResult<&str, String> {
+    alt((
+        preceded(
+            tuple((multispace1, keyword("AS"), multispace1)),
+            alt((parse_quoted_string, parse_identifier)),
+        ),
+        preceded(multispace1, parse_alias_identifier),
+    ))(input)
+}
 
-    // Check for DISTINCT (optional)
-    let (input, distinct) = opt(tuple((tag("DISTINCT"), space1)))(input)?;
-    let distinct_flag = distinct.is_some();
+// Parser for a column in the SELECT list, which is either * or an expression
+fn parse_select_item(input: &str) -> IResult<&str, SelectItem> {
+    let (input, (text, expr)) = alt((
+        consumed(map(parse_wildcard, Expr::Column)),
+        consumed(parse_expr),
+    ))(input)?;
+    let (input, alias) = opt(parse_column_alias)(input)?;
 
-    // Initialize the vectors to hold aggregate functions and columns
-    let mut aggregate_functions = Vec::new();
-    let mut columns = Vec::new();
+    // A quoted string on its own is a column name, e.g. SELECT 'first name'
+    let expr = match expr {
+        Expr::Literal(Value::String(column)) if text.starts_with('\'') => Expr::Column(column),
+        expr => expr,
+    };
 
-    // Function to parse the first item, either an aggregate function or a column
-    let (input, first_item) = alt((
-        map(parse_aggregate_function, |agg| {
-            aggregate_functions.push(agg);
-            input // Return the remaining input after parsing
-        }),
-        map(parse_column, |col| {
-            columns.push(col);
-            input // Return the remaining input after parsing
+    Ok((
+        input,
+        SelectItem {
+            expr,
+            alias,
+            text: text.trim().to_string(),
+        },
+    ))
+}
+
+// Parser for addition and subtraction, which have the lowest precedence
+fn parse_expr(input: &str) -> IResult<&str, Expr> {
+    let (mut input, mut left) = parse_term(input)?;
+
+    while let Ok((next_input, op)) = parse_add_sub_op(input) {
+        let (next_input, right) = preceded(multispace0, parse_term)(next_input)?;
+        left = Expr::BinaryOp(Box::new(left), op, Box::new(right));
+        input = next_input;
+    }
+
+    Ok((input, left))
+}
+
+// Parser for multiplication, division and modulo
+fn parse_term(input: &str) -> IResult<&str, Expr> {
+    let (mut input, mut left) = parse_factor(input)?;
+
+    while let Ok((next_input, op)) = parse_mul_div_op(input) {
+        let (next_input, right) = preceded(multispace0, parse_factor)(next_input)?;
+        left = Expr::BinaryOp(Box::new(left), op, Box::new(right));
+        input = next_input;
+    }
+
+    Ok((input, left))
+}
+
+fn parse_add_sub_op(input: &str) -> IResult<&str, Op> {
+    preceded(
+        multispace0,
+        alt((value(Op::Add, char('+')), value(Op::Sub, char('-')))),
+    )(input)
+}
+
+fn parse_mul_div_op(input: &str) -> IResult<&str, Op> {
+    preceded(
+        multispace0,
+        alt((
+            value(Op::Mul, char('*')),
+            value(Op::Div, char('/')),
+            value(Op::Mod, char('%')),
+        )),
+    )(input)
+}
+
+// Parser for the parts of an expression: brackets, functions, literals and columns
+fn parse_factor(input: &str) -> IResult<&str, Expr> {
+    alt((
+        delimited(
+            tuple((char('('), multispace0)),
+            parse_expr,
+            tuple((multispace0, char(')'))),
+        ),
+        parse_case,
+        parse_cast,
+        parse_window_function,
+        map(parse_aggregate_function, Expr::Aggregate),
+        parse_scalar_function,
+        value(
+            Expr::Literal(Value::Null),
+            terminated(keyword("NULL"), not(peek(parse_identifier))),
+        ),
+        map(parse_number, Expr::Literal),
+        value(Expr::Literal(Value::String(String::new())), tag("''")),
+        map(parse_quoted_string, |text| Expr::Literal(Value::String(text))),
+        map(preceded(tuple((char('-'), multispace0)), parse_factor), |expr| {
+            Expr::Negate(Box::new(expr))
         }),
+        map(parse_qualified_identifier, Expr::Column),
+    ))(input)
+}
+
+// Parser for a window function, which is a ranking function, LAG/LEAD or an aggregate function
+// followed by OVER ([PARTITION BY ...] [ORDER BY ...])
+fn parse_window_function(input: &str) -> IResult<&str, Expr> {
+    let ranking = terminated(
+        alt((
+            value(WindowKind::RowNumber, keyword("ROW_NUMBER")),
+            value(WindowKind::Rank, keyword("RANK")),
+            value(WindowKind::DenseRank, keyword("DENSE_RANK")),
+        )),
+        tuple((multispace0, char('('), multispace0, char(')'))),
+    );
+    let (input, function) = alt((
+        ranking,
+        parse_lag_lead,
+        map(parse_aggregate_function, WindowKind::Aggregate),
     ))(input)?;
 
-    // Parse any additional aggregate functions or columns, separated by commas
-    let (input, additional_items) = opt(preceded(
-        comma_space,
-        separated_list1(
-            comma_space,
-            alt((
-                map(parse_aggregate_function, |agg| {
-                    aggregate_functions.push(agg);
-                    input // Return the remaining input after parsing
-                }),
-                map(parse_column, |col| {
-                    columns.push(col);
-                    input // Return the remaining input after parsing
-                }),
-            )),
-        ),
+    let (input, _) = tuple((multispace0, keyword("OVER"), multispace0, char('('), multispace0))(input)?;
+    let (input, partition_by) = opt(preceded(
+        tuple((keyword("PARTITION BY"), multispace1)),
+        separated_list1(comma_space, parse_column),
     ))(input)?;
+    let (input, order_by) = opt(parse_order_by)(input)?;
+    let (input, _) = tuple((multispace0, char(')')))(input)?;
 
-    // Process additional items if they were found
-    if let Some(items) = additional_items {
-        for item in items {
-            // Since we pushed the items during parsing, we don't need to do anything else
-        }
+    Ok((
+        input,
+        Expr::Window(Box::new(WindowFunction {
+            function,
+            partition_by: partition_by.unwrap_or_default(),
+            order_by: order_by.unwrap_or_default(),
+        })),
+    ))
+}
+
+// Parser for LAG(column [, offset [, default]]) and LEAD(...), the offset defaults to one row
+fn parse_lag_lead(input: &str) -> IResult<&str, WindowKind> {
+    let (input, lag) = alt((value(true, keyword("LAG")), value(false, keyword("LEAD"))))(input)?;
+    let (input, _) = tuple((multispace0, char('('), multispace0))(input)?;
+    let (input, column) = parse_column(input)?;
+    let (input, offset) = opt(preceded(comma_space, parse_count))(input)?;
+    let (input, default) = match offset {
+        Some(_) => opt(preceded(comma_space, parse_literal))(input)?,
+        None => (input, None),
+    };
+    let (input, _) = tuple((multispace0, char(')')))(input)?;
+
+    let offset = offset.unwrap_or(1);
+    Ok((
+        input,
+        if lag {
+            WindowKind::Lag(column, offset, default)
+        } else {
+            WindowKind::Lead(column, offset, default)
+        },
+    ))
+}
+
+fn parse_scalar_function(input: &str) -> IResult<&str, Expr> {
+    let (input, function) = alt((
+        value(ScalarFunction::Upper, tag_no_case("UPPER")),
+        value(ScalarFunction::Lower, tag_no_case("LOWER")),
+        value(ScalarFunction::Length, tag_no_case("LENGTH")),
+        value(ScalarFunction::Trim, tag_no_case("TRIM")),
+        value(ScalarFunction::Substr, tag_no_case("SUBSTR")),
+        value(ScalarFunction::Replace, tag_no_case("REPLACE")),
+        value(ScalarFunction::Concat, tag_no_case("CONCAT")),
+        value(ScalarFunction::Coalesce, tag_no_case("COALESCE")),
+    ))(input)?;
+    let (input, args) = delimited(
+        tuple((multispace0, char('('), multispace0)),
+        separated_list1(comma_space, parse_expr),
+        tuple((multispace0, char(')'))),
+    )(input)?;
+
+    let (min, max) = function.arity();
+    if args.len() < min || args.len() > max {
+        return Err(nom::Err::Error(nom::error::Error::new(
+            input,
+            nom::error::ErrorKind::Verify,
+        )));
     }
+    Ok((input, Expr::Function(function, args)))
+}
+
+// Parser for CASE WHEN condition THEN result [WHEN ...] [ELSE result] END
+fn parse_case(input: &str) -> IResult<&str, Expr> {
+    let (input, _) = tuple((keyword("CASE"), multispace1))(input)?;
+    let (input, branches) = many1(map(
+        tuple((
+            keyword("WHEN"),
+            multispace1,
+            parse_or_condition,
+            multispace1,
+            keyword("THEN"),
+            multispace1,
+            parse_expr,
+            multispace1,
+        )),
+        |(_, _, condition, _, _, _, result, _)| (condition, result),
+    ))(input)?;
+    let (input, otherwise) = opt(delimited(tuple((keyword("ELSE"), multispace1)), parse_expr, multispace1))(input)?;
+    let (input, _) = keyword("END")(input)?;
+    Ok((input, Expr::Case(branches, otherwise.map(Box::new))))
+}
+
+// Parser for CAST(expression AS type)
+fn parse_cast(input: &str) -> IResult<&str, Expr> {
+    let (input, _) = tuple((tag_no_case("CAST"), multispace0, char('('), multispace0))(input)?;
+    let (input, expr) = parse_expr(input)?;
+    let (input, column_type) = preceded(
+        tuple((multispace1, tag_no_case("AS"), multispace1)),
+        alt((
+            value(ColumnType::Integer, alt((tag_no_case("INTEGER"), tag_no_case("INT")))),
+            value(
+                ColumnType::Float,
+                alt((
+                    tag_no_case("FLOAT"),
+                    tag_no_case("REAL"),
+                    tag_no_case("DOUBLE"),
+                    tag_no_case("DECIMAL"),
+                    tag_no_case("NUMERIC"),
+                )),
+            ),
+            value(ColumnType::Date, tag_no_case("DATE")),
+            value(
+                ColumnType::String,
+                alt((tag_no_case("TEXT"), tag_no_case("VARCHAR"), tag_no_case("STRING"))),
+            ),
+        )),
+    )(input)?;
+    let (input, _) = tuple((multispace0, char(')')))(input)?;
+    Ok((input, Expr::Cast(Box::new(expr), column_type)))
+}
+
+// Parser for the SELECT columns
+fn parse_columns(input: &str) -> IResult<&str, (bool, Vec<SelectItem>)> {
+    // First, parse the SELECT keyword
+    let (input, _) = preceded(multispace0, tuple((keyword("SELECT"), multispace1)))(input)?;
+
+    // Check for DISTINCT (optional)
+    let (input, distinct) = opt(tuple((keyword("DISTINCT"), multispace1)))(input)?;
 
-    Ok((input, (distinct_flag, aggregate_functions, columns)))
+    let (input, items) = separated_list1(comma_space, parse_select_item)(input)?;
+
+    Ok((input, (distinct.is_some(), items)))
 }
 
-// Function to parse a file path
+// Function to parse a file path. Paths containing spaces can be quoted e.g. 'my data/sales.csv'
 fn parse_file(input: &str) -> IResult<&str, String> {
-    // Define a valid character for file names (excluding newline)
-    let valid_filename_char = is_not("/\\ \n");
-
-    // Define a parser for a directory or filename
-    let component = map(valid_filename_char, |s: &str| s.to_string());
-
-    // Define a parser for a single directory component, allowing for optional leading "./" or "../"
-    let directory_component = preceded(
-        many0(alt((
-            map(char('.'), |_| ""),                         // Handle '.'
-            map(char('/'), |_| "/"),                        // Handle '/'
-            map(preceded(char('.'), char('.')), |_| "../"), // Handle '..'
-        ))),
-        component,
-    );
+    alt((
+        parse_quoted_string,
+        parse_quoted_identifier,
+        // Otherwise the path runs up to the next whitespace, or the end of a subquery
+        map(is_not(" \t\r\n)"), |s: &str| s.to_string()),
+    ))(input)
+}
 
-    // Combine the components into a full path
-    let mut path_parser = map(many0(directory_component), |components: Vec<String>| {
-        components.join("/") // Join components with '/'
-    });
+// Keywords that can follow a column or table name and so cannot be used as an alias
+const RESERVED_WORDS: [&str; 14] = [
+    "FROM", "WHERE", "GROUP", "HAVING", "QUALIFY", "ORDER", "INNER", "LEFT", "OUTER", "JOIN", "ON",
+    "AS", "LIMIT", "OFFSET",
+];
+
+fn parse_table_alias(input: &str) -> IResult<&str, String> {
+    preceded(
+        tuple((multispace1, opt(tuple((keyword("AS"), multispace1))))),
+        parse_alias_identifier,
+    )(input)
+}
 
-    path_parser(input)
+fn parse_table(input: &str) -> IResult<&str, (String, Option<String>)> {
+    preceded(
+        tuple((multispace0, keyword("FROM"), multispace1)),
+        tuple((parse_file, opt(parse_table_alias))),
+    )(input)
 }
 
-fn parse_table(input: &str) -> IResult<&str, String> {
-    preceded(tuple((space0, tag("FROM"), space1)), parse_file)(input)
+// Parse a join clause e.g. LEFT JOIN ./regions.csv r ON s.Region = r.Region
+fn parse_join(input: &str) -> IResult<&str, Join> {
+    let (input, join_type) = preceded(
+        multispace1,
+        alt((
+            value(JoinType::Inner, tuple((keyword("INNER"), multispace1, keyword("JOIN")))),
+            value(
+                JoinType::Left,
+                tuple((
+                    keyword("LEFT"),
+                    multispace1,
+                    opt(tuple((keyword("OUTER"), multispace1))),
+                    keyword("JOIN"),
+                )),
+            ),
+            value(JoinType::Inner, keyword("JOIN")),
+        )),
+    )(input)?;
+
+    let (input, (table, alias)) =
+        preceded(multispace1, tuple((parse_file, opt(parse_table_alias))))(input)?;
+
+    let (input, (left_column, _, _, _, right_column)) = preceded(
+        tuple((multispace1, keyword("ON"), multispace1)),
+        tuple((parse_column, multispace0, char('='), multispace0, parse_column)),
+    )(input)?;
+
+    Ok((
+        input,
+        Join {
+            join_type,
+            table,
+            alias,
+            left_column,
+            right_column,
+        },
+    ))
 }
 
 // Combine both parsers to accept either an identifier or a quoted string
@@ -686,80 +4205,179 @@ fn parse_value(input: &str) -> IResult<&str, String> {
     alt((parse_quoted_string, parse_identifier))(input)
 }
 
-// Update parse_single_condition to use parse_value
-fn parse_single_condition(input: &str) -> IResult<&str, Condition> {
+// Parser for numeric literals, which can't run on into an identifier (e.g. 3DS)
+fn parse_number(input: &str) -> IResult<&str, Value> {
+    map(
+        terminated(
+            recognize(tuple((opt(char('-')), digit1, opt(tuple((char('.'), digit1)))))),
+            not(peek(one_of(
+                "_abcdefghijklmnopqrstuvwxyzABCDEFGHIJKLMNOPQRSTUVWXYZ",
+            ))),
+        ),
+        |number: &str| match number.parse::<i64>() {
+            Ok(num) => Value::Integer(num),
+            Err(_) => Value::Float(number.parse::<f64>().unwrap_or(f64::NAN)),
+        },
+    )(input)
+}
+
+// Parser for literal values used in conditions. Quoted strings in date format are
+// read as dates, unquoted words are accepted as strings (e.g. Platform = Wii)
+fn parse_literal(input: &str) -> IResult<&str, Value> {
     alt((
-        map(
-            tuple((parse_column, space0, tag(">="), space0, digit1)),
-            |(col, _, _, _, value): (String, &str, &str, &str, &str)| {
-                Condition::GreaterThanEqualTo(col, value.parse::<i32>().unwrap())
-            },
+        value(Value::Null, keyword("NULL")),
+        parse_number,
+        value(Value::String(String::new()), tag("''")),
+        map(parse_quoted_string, |text| match parse_date(&text) {
+            Some(date) => Value::Date(date),
+            None => Value::String(text),
+        }),
+        map(parse_identifier, Value::String),
+    ))(input)
+}
+
+fn parse_comparison_operator(input: &str) -> IResult<&str, &str> {
+    alt((
+        tag(">="),
+        tag("<="),
+        tag("<>"),
+        tag("!="),
+        tag(">"),
+        tag("<"),
+        tag("="),
+    ))(input)
+}
+
+// Parser for a query in brackets used within a condition e.g. Name IN (SELECT Name FROM ./top.csv)
+fn parse_subquery(input: &str) -> IResult<&str, Subquery> {
+    map(
+        delimited(
+            tuple((char('('), multispace0)),
+            parse_sql,
+            tuple((multispace0, char(')'))),
         ),
+        Subquery::new,
+    )(input)
+}
+
+fn comparison(col: String, operator: &str, value: Value) -> Condition {
+    match operator {
+        ">=" => Condition::GreaterThanEqualTo(col, value),
+        ">" => Condition::GreaterThan(col, value),
+        "<=" => Condition::LessThanEqualTo(col, value),
+        "<" => Condition::LessThan(col, value),
+        "<>" | "!=" => Condition::NotEqual(col, value),
+        _ => Condition::Equal(col, value),
+    }
+}
+
+// Conditions in HAVING can also refer to aggregate functions
+fn parse_condition_column(input: &str) -> IResult<&str, String> {
+    alt((parse_aggregate_function_name, parse_column))(input)
+}
+
+fn parse_single_condition(input: &str) -> IResult<&str, Condition> {
+    alt((
         map(
-            tuple((parse_column, space0, tag(">"), space0, digit1)),
-            |(col, _, _, _, value): (String, &str, &str, &str, &str)| {
-                Condition::GreaterThan(col, value.parse::<i32>().unwrap())
-            },
+            tuple((
+                parse_condition_column,
+                multispace0,
+                parse_comparison_operator,
+                multispace0,
+                parse_literal,
+            )),
+            |(col, _, operator, _, value)| comparison(col, operator, value),
         ),
         map(
-            tuple((parse_column, space0, tag("<="), space0, digit1)),
-            |(col, _, _, _, value): (String, &str, &str, &str, &str)| {
-                Condition::LessThanEqualTo(col, value.parse::<i32>().unwrap())
+            tuple((
+                parse_condition_column,
+                multispace0,
+                parse_comparison_operator,
+                multispace0,
+                parse_subquery,
+            )),
+            |(col, _, operator, _, subquery)| {
+                Condition::CompareSubquery(col, operator.to_string(), subquery)
             },
         ),
         map(
-            tuple((parse_column, space0, tag("<"), space0, digit1)),
-            |(col, _, _, _, value): (String, &str, &str, &str, &str)| {
-                Condition::LessThan(col, value.parse::<i32>().unwrap())
+            tuple((
+                parse_condition_column,
+                multispace1,
+                opt(tuple((keyword("NOT"), multispace1))),
+                keyword("IN"),
+                multispace0,
+                alt((
+                    map(parse_subquery, |subquery| (Vec::new(), Some(subquery))),
+                    map(
+                        delimited(
+                            tuple((char('('), multispace0)),
+                            separated_list1(comma_space, parse_literal),
+                            tuple((multispace0, char(')'))),
+                        ),
+                        |values| (values, None),
+                    ),
+                )),
+            )),
+            |(col, _, not, _, _, values)| match (not.is_some(), values) {
+                (false, (_, Some(subquery))) => Condition::InSubquery(col, subquery),
+                (true, (_, Some(subquery))) => Condition::NotInSubquery(col, subquery),
+                (false, (values, None)) => Condition::In(col, values),
+                (true, (values, None)) => Condition::NotIn(col, values),
             },
         ),
         map(
-            tuple((parse_column, space0, tag("="), space0, parse_value)),
-            |(col, _, _, _, value): (String, &str, &str, &str, String)| {
-                Condition::Equal(col, value)
+            tuple((
+                parse_condition_column,
+                multispace1,
+                keyword("IS"),
+                multispace1,
+                opt(tuple((keyword("NOT"), multispace1))),
+                keyword("NULL"),
+            )),
+            |(col, _, _, _, not, _)| match not {
+                Some(_) => Condition::IsNotNull(col),
+                None => Condition::IsNull(col),
             },
         ),
         map(
-            tuple((parse_column, space0, tag("LIKE"), space0, parse_value)),
-            |(col, _, _, _, pattern): (String, &str, &str, &str, String)| {
+            tuple((
+                parse_condition_column,
+                multispace0,
+                opt(tuple((keyword("NOT"), multispace1))),
+                keyword("LIKE"),
+                multispace0,
+                parse_value,
+            )),
+            |(col, _, not, _, _, pattern)| {
                 // Convert the LIKE pattern into a regex
                 let regex_pattern = like_pattern_to_regex(&pattern); // Anchor the pattern
 
                 // Try to compile the regex
-                match Regex::new(&regex_pattern) {
-                    Ok(compiled_regex) => Condition::Like(col, compiled_regex),
+                let compiled_regex = match Regex::new(&regex_pattern) {
+                    Ok(compiled_regex) => compiled_regex,
                     Err(_) => panic!("Failed to compile regex for LIKE condition '{}'.", pattern), // In production, handle this more gracefully
+                };
+                match not {
+                    Some(_) => Condition::NotLike(col, compiled_regex),
+                    None => Condition::Like(col, compiled_regex),
                 }
             },
         ),
         map(
             tuple((
-                parse_column,
-                space0,
-                tag("BETWEEN"),
-                space0,
-                digit1,
-                space0,
-                tag("AND"),
-                space0,
-                digit1,
+                parse_condition_column,
+                multispace0,
+                keyword("BETWEEN"),
+                multispace0,
+                parse_literal,
+                multispace0,
+                keyword("AND"),
+                multispace0,
+                parse_literal,
             )),
-            |(col, _, _, _, lower_bound, _, _, _, upper_bound): (
-                String,
-                &str,
-                &str,
-                &str,
-                &str,
-                &str,
-                &str,
-                &str,
-                &str,
-            )| {
-                Condition::Between(
-                    col,
-                    lower_bound.parse::<i32>().unwrap(),
-                    upper_bound.parse::<i32>().unwrap(),
-                )
+            |(col, _, _, _, lower_bound, _, _, _, upper_bound)| {
+                Condition::Between(col, lower_bound, upper_bound)
             },
         ),
     ))(input)
@@ -772,16 +4390,24 @@ fn like_pattern_to_regex(pattern: &str) -> String {
 
 fn parse_condition(input: &str) -> IResult<&str, Condition> {
     preceded(
-        tuple((space0, tag("WHERE"), space1)),
+        tuple((multispace0, keyword("WHERE"), multispace1)),
         parse_or_condition, // Start with OR conditions (lowest precedence)
     )(input)
 }
 
+fn parse_having(input: &str) -> IResult<&str, Condition> {
+    preceded(tuple((multispace0, keyword("HAVING"), multispace1)), parse_or_condition)(input)
+}
+
+fn parse_qualify(input: &str) -> IResult<&str, Condition> {
+    preceded(tuple((multispace0, keyword("QUALIFY"), multispace1)), parse_or_condition)(input)
+}
+
 // Parse OR conditions (OR has lower precedence than AND)
 fn parse_or_condition(input: &str) -> IResult<&str, Condition> {
     let (input, first_condition) = parse_and_condition(input)?; // Parse first AND condition
     let (input, rest_conditions) = many0(preceded(
-        tuple((space0, tag("OR"), space1)),
+        tuple((multispace0, keyword("OR"), multispace1)),
         parse_and_condition, // Parse more AND conditions separated by OR
     ))(input)?;
 
@@ -799,7 +4425,7 @@ fn parse_or_condition(input: &str) -> IResult<&str, Condition> {
 fn parse_and_condition(input: &str) -> IResult<&str, Condition> {
     let (input, first_condition) = parse_not_condition(input)?; // Parse first NOT condition
     let (input, rest_conditions) = many0(preceded(
-        tuple((space0, tag("AND"), space1)),
+        tuple((multispace0, keyword("AND"), multispace1)),
         parse_not_condition, // Parse more NOT conditions separated by AND
     ))(input)?;
 
@@ -816,7 +4442,7 @@ fn parse_and_condition(input: &str) -> IResult<&str, Condition> {
 fn parse_not_condition(input: &str) -> IResult<&str, Condition> {
     // Check if the input starts with "NOT"
     let (input, negated) = opt(preceded(
-        tuple((tag("NOT"), space1)),
+        tuple((keyword("NOT"), multispace1)),
         parse_primary_condition,
     ))(input)?;
 
@@ -834,57 +4460,405 @@ fn parse_primary_condition(input: &str) -> IResult<&str, Condition> {
     alt((
         // Parse a grouped condition inside parentheses
         delimited(
-            tuple((char('('), space0)),
+            tuple((char('('), multispace0)),
             parse_or_condition, // Recursively parse OR conditions inside parentheses
-            tuple((space0, char(')'))),
+            tuple((multispace0, char(')'))),
         ),
         // Parse a single condition (e.g., age > 30)
         parse_single_condition, // Your existing single condition parser
     ))(input)
 }
-fn parse_order_by(input: &str) -> IResult<&str, OrderBy> {
+fn parse_order_by(input: &str) -> IResult<&str, Vec<OrderBy>> {
     preceded(
-        tuple((space0, tag("ORDER BY"), space1)),
-        // Parse the column name and the direction (ASC or DESC)
-        map(
-            tuple((
-                parse_order_by_column,
-                // Optional space followed by sort direction
-                opt(preceded(
-                    space0,
-                    alt((
-                        map(tag("ASC"), |_| SortDirection::Ascending),
-                        map(tag("DESC"), |_| SortDirection::Descending),
+        tuple((multispace0, keyword("ORDER BY"), multispace1)),
+        separated_list1(
+            comma_space,
+            // Parse the column name and the direction (ASC or DESC)
+            map(
+                tuple((
+                    parse_order_by_column,
+                    // Optional space followed by sort direction
+                    opt(preceded(
+                        multispace0,
+                        alt((
+                            map(keyword("ASC"), |_| SortDirection::Ascending),
+                            map(keyword("DESC"), |_| SortDirection::Descending),
+                        )),
                     )),
                 )),
-            )),
-            |(column, direction)| OrderBy {
-                column,
-                direction: direction.unwrap_or(SortDirection::Ascending), // Default to ascending if no direction specified
-            },
+                |(column, direction)| OrderBy {
+                    column,
+                    direction: direction.unwrap_or(SortDirection::Ascending), // Default to ascending if no direction specified
+                },
+            ),
         ),
     )(input)
 }
 
+fn parse_count(input: &str) -> IResult<&str, usize> {
+    map(digit1, |count: &str| count.parse::<usize>().unwrap_or(usize::MAX))(input)
+}
+
+fn parse_limit(input: &str) -> IResult<&str, usize> {
+    preceded(tuple((multispace0, keyword("LIMIT"), multispace1)), parse_count)(input)
+}
+
+fn parse_offset(input: &str) -> IResult<&str, usize> {
+    preceded(tuple((multispace0, keyword("OFFSET"), multispace1)), parse_count)(input)
+}
+
+// A query that couldn't be parsed, along with where the problem was found
+#[derive(Debug)]
+pub struct ParseError {
+    pub message: String,
+    // Byte offset into the query
+    pub position: usize,
+    sql: String,
+}
+
+impl ParseError {
+    // The rest of the input is a slice of the query, starting where the problem is
+    fn new(sql: &str, rest: &str, message: String) -> ParseError {
+        ParseError {
+            message,
+            position: rest.as_ptr() as usize - sql.as_ptr() as usize,
+            sql: sql.to_string(),
+        }
+    }
+}
+
+// Show the line of the query the problem is on with a caret pointing at it
+impl std::fmt::Display for ParseError {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        let line_start = self.sql[..self.position].rfind('\n').map_or(0, |i| i + 1);
+        let line_end = self.sql[self.position..]
+            .find('\n')
+            .map_or(self.sql.len(), |i| self.position + i);
+        let line_number = self.sql[..line_start].matches('\n').count() + 1;
+        let column = self.sql[line_start..self.position].chars().count();
+
+        let location = if line_number > 1 {
+            format!("line {}, column {}", line_number, column + 1)
+        } else {
+            format!("column {}", column + 1)
+        };
+        write!(
+            f,
+            "{} at {}\n  {}\n  {}^",
+            self.message,
+            location,
+            &self.sql[line_start..line_end],
+            " ".repeat(column)
+        )
+    }
+}
+
+impl Error for ParseError {}
+
+// Clauses that can follow the FROM clause, in the order they have to be written
+const CLAUSES: [&str; 9] = [
+    "INNER JOIN", "LEFT JOIN", "WHERE", "GROUP BY", "HAVING", "QUALIFY", "ORDER BY", "LIMIT",
+    "OFFSET",
+];
+
+// Parse a complete query. Unlike parse_sql, anything left over after the query is an error
+// and errors point at where the query went wrong.
+pub fn parse_query(sql: &str) -> Result<SQLQuery, ParseError> {
+    // A trailing semicolon is allowed, but would otherwise be read as part of a file name
+    parse_select(sql, sql.trim_end().trim_end_matches(';'))
+}
+
+// Parse the SELECT query in the body, a slice of the full sql that errors are reported against
+fn parse_select(sql: &str, body: &str) -> Result<SQLQuery, ParseError> {
+    let (rest, query) = match parse_sql(body) {
+        Ok(result) => result,
+        // Only the SELECT list and the FROM clause are required, so one of them is at fault
+        Err(_) => {
+            let rest = match parse_columns(body) {
+                // FROM straight after SELECT is read as a column named FROM
+                Ok((_, (_, items)))
+                    if items.first().is_some_and(|item| {
+                        matches!(&item.expr, Expr::Column(column) if column.eq_ignore_ascii_case("FROM"))
+                    }) =>
+                {
+                    let rest = after_keyword(body, "SELECT", body);
+                    let message =
+                        format!("Unexpected {}, expected a column or expression", token(rest));
+                    return Err(ParseError::new(sql, rest, message));
+                }
+                Ok((rest, _)) => rest,
+                Err(_) if strip_keyword(body.trim_start(), "SELECT").is_none() => {
+                    let rest = body.trim_start();
+                    let message = format!("Unexpected {}, expected SELECT", token(rest));
+                    return Err(ParseError::new(sql, rest, message));
+                }
+                Err(e) => {
+                    let rest = after_keyword(body, "SELECT", error_input(&e, body));
+                    let message =
+                        format!("Unexpected {}, expected a column or expression", token(rest));
+                    return Err(ParseError::new(sql, rest, message));
+                }
+            };
+
+            let rest = rest.trim_start();
+            return Err(match strip_keyword(rest, "FROM") {
+                Some(file) => ParseError::new(
+                    sql,
+                    file.trim_start(),
+                    format!("Unexpected {}, expected a file name", token(file)),
+                ),
+                None => ParseError::new(
+                    sql,
+                    rest,
+                    format!("Unexpected {}, expected ',' or FROM", token(rest)),
+                ),
+            });
+        }
+    };
+
+    let trailing = rest;
+    let rest = rest.trim_start();
+    if rest.is_empty() {
+        return Ok(query);
+    }
+
+    // A condition that ends with AND or OR is missing its last part
+    for keyword in ["AND", "OR"] {
+        if strip_keyword(rest, keyword).is_some() {
+            let rest = after_keyword(rest, keyword, rest);
+            let message = format!("Unexpected {}, expected a condition", token(rest));
+            return Err(ParseError::new(sql, rest, message));
+        }
+    }
+
+    // A clause that's been started but couldn't be parsed is reported from inside the clause
+    let clause_error = match CLAUSES.iter().find(|clause| strip_keyword(rest, clause).is_some()) {
+        Some(&clause @ ("INNER JOIN" | "LEFT JOIN")) => parse_join(trailing).err().map(|e| {
+            let position = after_keyword(rest, clause, error_input(&e, trailing));
+            (position, "<file> ON <column> = <column>")
+        }),
+        Some(&"WHERE") => parse_condition(rest).err().map(|e| (e, "a condition")).map(
+            |(e, expected)| (after_keyword(rest, "WHERE", error_input(&e, rest)), expected),
+        ),
+        Some(&"GROUP BY") => parse_group_by(rest)
+            .err()
+            .map(|e| (after_keyword(rest, "GROUP BY", error_input(&e, rest)), "a column")),
+        Some(&"HAVING") => parse_having(rest)
+            .err()
+            .map(|e| (after_keyword(rest, "HAVING", error_input(&e, rest)), "a condition")),
+        Some(&"QUALIFY") => parse_qualify(rest)
+            .err()
+            .map(|e| (after_keyword(rest, "QUALIFY", error_input(&e, rest)), "a condition")),
+        Some(&"ORDER BY") => parse_order_by(rest)
+            .err()
+            .map(|e| (after_keyword(rest, "ORDER BY", error_input(&e, rest)), "a column")),
+        Some(&"LIMIT") => parse_limit(rest)
+            .err()
+            .map(|e| (after_keyword(rest, "LIMIT", error_input(&e, rest)), "a number")),
+        Some(&"OFFSET") => parse_offset(rest)
+            .err()
+            .map(|e| (after_keyword(rest, "OFFSET", error_input(&e, rest)), "a number")),
+        _ => None,
+    };
+    if let Some((position, expected)) = clause_error {
+        let message = format!("Unexpected {}, expected {}", token(position), expected);
+        return Err(ParseError::new(sql, position, message));
+    }
+
+    // Otherwise the text doesn't belong here, e.g. a clause that's out of order
+    let last_clause = if query.offset.is_some() {
+        9
+    } else if query.limit.is_some() {
+        8
+    } else if !query.order_by.is_empty() {
+        7
+    } else if query.qualify.is_some() {
+        6
+    } else if query.having.is_some() {
+        5
+    } else if query.group_by.is_some() {
+        4
+    } else if query.condition.is_some() {
+        3
+    } else {
+        0
+    };
+    let message = match &CLAUSES[last_clause..] {
+        [] => format!("Unexpected {}, expected the end of the query", token(rest)),
+        clauses => format!(
+            "Unexpected {}, expected {} or the end of the query",
+            token(rest),
+            clauses.join(", ")
+        ),
+    };
+    Err(ParseError::new(sql, rest, message))
+}
+
+// Parse a statement, either a query or one that writes to a file
+pub fn parse_statement(sql: &str) -> Result<Statement, ParseError> {
+    let body = sql.trim_end().trim_end_matches(';');
+    let start = body.trim_start();
+
+    if let Some(rest) = strip_keyword(start, "CREATE TABLE") {
+        let (rest, table) = parse_target_file(sql, rest)?;
+        let rest = expect_keyword(sql, rest, "AS")?;
+        return parse_select(sql, rest).map(|query| Statement::CreateTableAs(table, query));
+    }
+    if let Some(rest) = strip_keyword(start, "INSERT INTO") {
+        let (rest, table) = parse_target_file(sql, rest)?;
+        return parse_select(sql, rest).map(|query| Statement::InsertInto(table, query));
+    }
+    if let Some(rest) = strip_keyword(start, "UPDATE") {
+        let (rest, table) = parse_target_file(sql, rest)?;
+        let rest = expect_keyword(sql, rest, "SET")?;
+        let (rest, assignments) =
+            preceded(multispace1, separated_list1(comma_space, parse_assignment))(rest).map_err(
+                |e| {
+                    let position = error_input(&e, rest).trim_start();
+                    let message =
+                        format!("Unexpected {}, expected <column> = <expression>", token(position));
+                    ParseError::new(sql, position, message)
+                },
+            )?;
+        let condition = parse_statement_condition(sql, rest)?;
+        return Ok(Statement::Update(table, assignments, condition));
+    }
+    if let Some(rest) = strip_keyword(start, "DELETE FROM") {
+        let (rest, table) = parse_target_file(sql, rest)?;
+        let condition = parse_statement_condition(sql, rest)?;
+        return Ok(Statement::Delete(table, condition));
+    }
+
+    if strip_keyword(start, "SELECT").is_none() {
+        let message = format!(
+            "Unexpected {}, expected SELECT, CREATE TABLE, INSERT INTO, UPDATE or DELETE FROM",
+            token(start)
+        );
+        return Err(ParseError::new(sql, start, message));
+    }
+    parse_select(sql, body).map(Statement::Select)
+}
+
+// The file a statement writes to, following the keyword that starts the statement
+fn parse_target_file<'a>(sql: &str, input: &'a str) -> Result<(&'a str, String), ParseError> {
+    preceded(multispace1, parse_file)(input).map_err(|_| {
+        let position = input.trim_start();
+        ParseError::new(sql, position, format!("Unexpected {}, expected a file name", token(position)))
+    })
+}
+
+fn expect_keyword<'a>(sql: &str, input: &'a str, word: &'static str) -> Result<&'a str, ParseError> {
+    preceded(multispace1, keyword(word))(input)
+        .map(|(rest, _)| rest)
+        .map_err(|_| {
+            let position = input.trim_start();
+            ParseError::new(sql, position, format!("Unexpected {}, expected {}", token(position), word))
+        })
+}
+
+// Parser for the assignments of an UPDATE e.g. Sales = Sales * 2
+fn parse_assignment(input: &str) -> IResult<&str, Assignment> {
+    let (input, column) = parse_column(input)?;
+    let (input, _) = tuple((multispace0, char('='), multispace0))(input)?;
+    let (input, expr) = parse_expr(input)?;
+    Ok((input, Assignment { column, expr }))
+}
+
+// The optional WHERE clause that ends an UPDATE or DELETE
+fn parse_statement_condition(sql: &str, input: &str) -> Result<Option<Condition>, ParseError> {
+    let rest = input.trim_start();
+    if rest.is_empty() {
+        return Ok(None);
+    }
+    if strip_keyword(rest, "WHERE").is_none() {
+        let message = format!("Unexpected {}, expected WHERE or the end of the query", token(rest));
+        return Err(ParseError::new(sql, rest, message));
+    }
+
+    let (after, condition) = parse_condition(input).map_err(|e| {
+        let position = after_keyword(rest, "WHERE", error_input(&e, input));
+        ParseError::new(sql, position, format!("Unexpected {}, expected a condition", token(position)))
+    })?;
+    let after = after.trim_start();
+    if after.is_empty() {
+        return Ok(Some(condition));
+    }
+    // A condition that ends with AND or OR is missing its last part
+    let (position, expected) = match ["AND", "OR"].iter().find(|word| strip_keyword(after, word).is_some()) {
+        Some(word) => (after_keyword(after, word, after), "a condition"),
+        None => (after, "the end of the query"),
+    };
+    let message = format!("Unexpected {}, expected {}", token(position), expected);
+    Err(ParseError::new(sql, position, message))
+}
+
+// Where a parser gave up, defaulting to the start of the input
+fn error_input<'a>(error: &nom::Err<nom::error::Error<&'a str>>, input: &'a str) -> &'a str {
+    match error {
+        nom::Err::Error(e) | nom::Err::Failure(e) => e.input,
+        nom::Err::Incomplete(_) => input,
+    }
+}
+
+// Errors that are reported at the keyword starting a clause are moved to just after it,
+// where the clause's contents were expected
+fn after_keyword<'a>(input: &'a str, keyword: &'static str, error: &'a str) -> &'a str {
+    match strip_keyword(input.trim_start(), keyword) {
+        Some(after) if error.len() >= after.len() => after.trim_start(),
+        _ => error,
+    }
+}
+
+// The input following a keyword it starts with
+fn strip_keyword<'a>(input: &'a str, word: &'static str) -> Option<&'a str> {
+    keyword(word)(input).ok().map(|(rest, _)| rest)
+}
+
+// Describe the token at the start of the input for an error message
+fn token(input: &str) -> String {
+    let input = input.trim_start();
+    let end = match input.chars().next() {
+        None => return "end of query".to_string(),
+        Some(quote @ ('\'' | '"' | '`')) => input[1..]
+            .find(quote)
+            .map_or(input.len(), |i| i + 2),
+        Some(c) if c.is_alphanumeric() || c == '_' => input
+            .find(|c: char| !(c.is_alphanumeric() || c == '_' || c == '.'))
+            .unwrap_or(input.len()),
+        Some(c) => c.len_utf8(),
+    };
+    format!("'{}'", &input[..end])
+}
+
 pub fn parse_sql(input: &str) -> IResult<&str, SQLQuery> {
-    // Call parse_columns to get the distinct flag, aggregate function, and columns
-    let (input, (distinct, aggregate_functions, columns)) = parse_columns(input)?;
+    // Call parse_columns to get the distinct flag and the selected columns
+    let (input, (distinct, columns)) = parse_columns(input)?;
 
     // Parse the rest of the SQL query
-    let (input, table) = parse_table(input)?;
+    let (input, (table, table_alias)) = parse_table(input)?;
+    let (input, joins) = many0(parse_join)(input)?;
     let (input, condition) = opt(parse_condition)(input)?;
     let (input, group_by) = opt(parse_group_by)(input)?;
+    let (input, having) = opt(parse_having)(input)?;
+    let (input, qualify) = opt(parse_qualify)(input)?;
     let (input, order_by) = opt(parse_order_by)(input)?;
+    let (input, limit) = opt(parse_limit)(input)?;
+    let (input, offset) = opt(parse_offset)(input)?;
 
     Ok((
         input,
         SQLQuery {
             columns,
             table,
+            table_alias,
+            joins,
             condition,
-            order_by,
+            order_by: order_by.unwrap_or_default(),
+            limit,
+            offset,
             group_by,
-            aggregate_functions,
+            having,
+            qualify,
             distinct,
         },
     ))
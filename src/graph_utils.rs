@@ -117,3 +117,26 @@ pub fn generate_points(start: f32, end: f32, step: f32, expression: &String) ->
 
     points
 }
+
+// Print a horizontal bar for each labelled count, scaled so the largest count fills the width
+pub fn plot_histogram(bins: &[(String, usize)], width: usize) {
+    let label_width = bins.iter().map(|(label, _)| label.len()).max().unwrap_or(0);
+    let max_count = bins.iter().map(|(_, count)| *count).max().unwrap_or(0);
+
+    for (label, count) in bins {
+        // Any non zero count gets at least one mark so it isn't mistaken for empty
+        let bar_length = (count * width)
+            .checked_div(max_count)
+            .map_or(0, |len| max(len, min(*count, 1)));
+        let bar = "#".repeat(bar_length);
+        let separator = if bar.is_empty() { "" } else { " " };
+        println!(
+            "{:>label_width$} | {}{}{}",
+            label,
+            bar,
+            separator,
+            count,
+            label_width = label_width
+        );
+    }
+}
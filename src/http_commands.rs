@@ -1,19 +1,46 @@
-use reqwest::Method;
+use reqwest::{redirect, Method, RequestBuilder};
 use tokio::fs::File;
 use tokio::io::{AsyncReadExt, AsyncWriteExt};
 use serde_derive::Deserialize;
 use serde_json::{from_str, Value};
-use reqwest::{Client, header::{HeaderMap, HeaderName, HeaderValue}};
-use core::str;
+use reqwest::{Client, header::{HeaderMap, HeaderName, HeaderValue, CONTENT_TYPE}};
+use std::path::{Path, PathBuf};
 use std::str::FromStr;
+use std::time::Duration;
 use tokio::net::TcpListener;
 
-#[derive(Deserialize, Debug)]
+use crate::http_utils::{self, FormPart, ServeMode};
+
+#[derive(Deserialize, Debug, Default)]
 struct HttpRequestConfig {
     method: Option<String>,
     url: Option<String>,
     headers: Option<Value>,
     body: Option<Value>,
+    query: Option<Value>,
+    form: Option<Value>,
+    multipart: Option<Value>,
+    user: Option<String>,
+    bearer: Option<String>,
+    timeout: Option<f64>,
+    max_redirects: Option<usize>,
+    insecure: Option<bool>,
+}
+
+// Options given on the command line, these are merged with and take precedence over the config file
+#[derive(Debug, Default)]
+pub struct RequestOptions {
+    pub headers: Vec<String>,
+    pub data: Option<String>,
+    pub data_urlencode: Vec<String>,
+    pub form: Vec<String>,
+    pub query: Vec<String>,
+    pub user: Option<String>,
+    pub bearer: Option<String>,
+    pub timeout: Option<f64>,
+    pub max_redirects: Option<usize>,
+    pub insecure: bool,
+    pub include: bool,
 }
 
 async fn read_config_file(file_path: &str) -> Result<HttpRequestConfig, String> {
@@ -37,6 +64,88 @@ fn build_headers(header_map: Option<Value>) -> Result<HeaderMap, String> {
     Ok(headers)
 }
 
+// Helper function to add 'Name: value' headers. These replace the config's headers of the same
+// name, while a header given more than once on the command line is sent with each value.
+fn add_headers(headers: &mut HeaderMap, args: &[String]) -> Result<(), String> {
+    let mut added = HeaderMap::new();
+    for arg in args {
+        let (key, value) = arg.split_once(':').ok_or(format!("Invalid header '{}', expected 'Name: value'", arg))?;
+        let header_key = HeaderName::from_str(key.trim()).map_err(|_| format!("Invalid header key: {}", key.trim()))?;
+        let header_value = HeaderValue::from_str(value.trim()).map_err(|_| format!("Invalid header value for '{}': {}", key.trim(), value.trim()))?;
+        added.append(header_key, header_value);
+    }
+    for key in added.keys() {
+        headers.remove(key);
+    }
+    for (key, value) in &added {
+        headers.append(key, value.clone());
+    }
+    Ok(())
+}
+
+// Helper function to read 'name=value' arguments
+fn parse_pairs(args: &[String]) -> Result<Vec<(String, String)>, String> {
+    args.iter()
+        .map(|arg| {
+            arg.split_once('=')
+                .map(|(name, value)| (name.to_string(), value.to_string()))
+                .ok_or(format!("Invalid field '{}', expected 'name=value'", arg))
+        })
+        .collect()
+}
+
+// Helper function to read the names and values of a JSON object from the config file
+fn config_pairs(object: &Option<Value>, field: &str) -> Result<Vec<(String, String)>, String> {
+    let Some(object) = object else {
+        return Ok(Vec::new());
+    };
+    let object = object.as_object().ok_or(format!("The config's {} must be an object", field))?;
+    Ok(object
+        .iter()
+        .map(|(name, value)| match value {
+            Value::String(value) => (name.clone(), value.clone()),
+            value => (name.clone(), value.to_string()),
+        })
+        .collect())
+}
+
+// Helper function to read a file for '@path' values, other values are used as they are
+async fn read_data(value: &str) -> Result<Vec<u8>, String> {
+    match value.strip_prefix('@') {
+        Some(path) => tokio::fs::read(path).await.map_err(|e| format!("Failed to read '{}': {}", path, e)),
+        None => Ok(value.as_bytes().to_vec()),
+    }
+}
+
+// Helper function to build multipart fields, 'name=@path;type=mime' uploads a file
+async fn build_form_parts(fields: Vec<(String, String)>) -> Result<Vec<FormPart>, String> {
+    let mut parts = Vec::new();
+    for (name, value) in fields {
+        let part = match value.strip_prefix('@') {
+            Some(file) => {
+                let (path, content_type) = match file.split_once(";type=") {
+                    Some((path, content_type)) => (path, content_type.to_string()),
+                    None => (file, http_utils::content_type(Path::new(file)).to_string()),
+                };
+                FormPart {
+                    name,
+                    file_name: Path::new(path).file_name().map(|name| name.to_string_lossy().into_owned()),
+                    content_type: Some(content_type),
+                    data: read_data(&format!("@{}", path)).await?,
+                }
+            }
+            None => FormPart {
+                name,
+                file_name: None,
+                content_type: None,
+                data: value.into_bytes(),
+            },
+        };
+        parts.push(part);
+    }
+    Ok(parts)
+}
+
 // Helper function to handle the request method and URL logic
 fn parse_method_and_url(config: &mut HttpRequestConfig, method: Option<String>, url: Option<String>) -> Result<(Method, String), String> {
     if let Some(u) = url {
@@ -59,68 +168,125 @@ fn parse_method_and_url(config: &mut HttpRequestConfig, method: Option<String>,
     Ok((method, url))
 }
 
-pub async fn http_request(
-    method: Option<String>, 
-    url: Option<String>, 
+// Build a request from the config file, if given, and the command line options
+pub async fn build_request(
+    method: Option<String>,
+    url: Option<String>,
     config_path: Option<String>,
-    output: Option<String>
-) {
-    let mut config = HttpRequestConfig {
-        method: None,
-        url: None,
-        headers: None,
-        body: None,
-    };
+    options: &RequestOptions,
+) -> Result<RequestBuilder, String> {
+    let mut config = HttpRequestConfig::default();
 
     // Load configuration from file, if provided
     if let Some(c) = config_path {
-        if let Err(e) = read_config_file(&c).await.map(|cfg| config = cfg) {
-            eprintln!("Error reading config: {}", e);
-            return;
-        }
+        config = read_config_file(&c).await.map_err(|e| format!("Error reading config: {}", e))?;
     }
 
     // Parse the method and URL
-    let (method, url) = match parse_method_and_url(&mut config, method, url) {
-        Ok((method, url)) => (method, url),
-        Err(e) => {
-            eprintln!("{}", e);
-            return;
-        }
-    };
+    let (method, url) = parse_method_and_url(&mut config, method, url)?;
 
-    // Build the request
-    let client = Client::new();
+    // Redirects are followed unless a limit of 0 is given
+    let mut client = Client::builder();
+    match options.max_redirects.or(config.max_redirects) {
+        Some(0) => client = client.redirect(redirect::Policy::none()),
+        Some(max_redirects) => client = client.redirect(redirect::Policy::limited(max_redirects)),
+        None => {}
+    }
+    if options.insecure || config.insecure.unwrap_or(false) {
+        client = client.danger_accept_invalid_certs(true);
+    }
+    let client = client.build().map_err(|e| format!("Failed to create the HTTP client: {}", e))?;
     let mut builder = client.request(method, &url);
 
-    // Add headers if provided
-    if let Ok(headers) = build_headers(config.headers) {
-        builder = builder.headers(headers);
-    } else {
-        eprintln!("Error building headers");
-        return;
+    // Query parameters are added to any already in the URL
+    let mut query = config_pairs(&config.query, "query")?;
+    query.extend(parse_pairs(&options.query)?);
+    if !query.is_empty() {
+        builder = builder.query(&query);
     }
 
-    // Add body if provided
-    if let Some(body) = config.body {
-        if let Ok(body_str) = serde_json::to_string(&body) {
-            builder = builder.body(body_str);
-        } else {
-            eprintln!("Failed to serialize body");
+    let mut headers = build_headers(config.headers).map_err(|e| format!("Error building headers: {}", e))?;
+    add_headers(&mut headers, &options.headers).map_err(|e| format!("Error building headers: {}", e))?;
+
+    match (options.user.as_ref().or(config.user.as_ref()), options.bearer.as_ref().or(config.bearer.as_ref())) {
+        (Some(_), Some(_)) => return Err("Only one of basic and bearer auth can be used.".to_string()),
+        (Some(user), None) => match user.split_once(':') {
+            Some((user, password)) => builder = builder.basic_auth(user, Some(password)),
+            None => builder = builder.basic_auth(user, None::<&str>),
+        },
+        (None, Some(token)) => builder = builder.bearer_auth(token),
+        (None, None) => {}
+    }
+
+    if let Some(timeout) = options.timeout.or(config.timeout) {
+        let timeout = Duration::try_from_secs_f64(timeout).map_err(|_| format!("Invalid timeout: {}", timeout))?;
+        builder = builder.timeout(timeout);
+    }
+
+    // A body given on the command line replaces the config's, form fields are combined with the config's
+    let has_body_option = options.data.is_some() || !options.data_urlencode.is_empty() || !options.form.is_empty();
+    if let Some(data) = &options.data {
+        builder = builder.body(read_data(data).await?);
+    } else if !options.form.is_empty() || (!has_body_option && config.multipart.is_some()) {
+        let mut fields = config_pairs(&config.multipart, "multipart")?;
+        fields.extend(parse_pairs(&options.form)?);
+        let (content_type, body) = http_utils::multipart_body(&build_form_parts(fields).await?);
+        headers.insert(CONTENT_TYPE, HeaderValue::from_str(&content_type).map_err(|e| e.to_string())?);
+        builder = builder.body(body);
+    } else if !options.data_urlencode.is_empty() || (!has_body_option && config.form.is_some()) {
+        let mut fields = config_pairs(&config.form, "form")?;
+        fields.extend(parse_pairs(&options.data_urlencode)?);
+        builder = builder.form(&fields);
+    } else if let Some(body) = config.body {
+        let body_str = serde_json::to_string(&body).map_err(|_| "Failed to serialize body".to_string())?;
+        builder = builder.body(body_str);
+    }
+
+    Ok(builder.headers(headers))
+}
+
+pub async fn http_request(
+    method: Option<String>, 
+    url: Option<String>, 
+    config_path: Option<String>,
+    output: Option<String>,
+    options: RequestOptions,
+) {
+    let builder = match build_request(method, url, config_path, &options).await {
+        Ok(builder) => builder,
+        Err(e) => {
+            eprintln!("{}", e);
             return;
         }
-    }
+    };
 
     // Send the request and handle the response
     match builder.send().await {
-        Ok(response) => handle_response(response, output).await,
+        Ok(response) => handle_response(response, output, options.include).await,
+        Err(e) if e.is_timeout() => eprintln!("Http request timed out: {}", e),
         Err(e) => eprintln!("Http request failed: {}", e),
     }
 }
 
+// Helper function to print a response body, formatting it if it's JSON
+fn print_body(text: &str) {
+    match from_str::<Value>(text) {
+        Ok(payload) => println!("{}", serde_json::to_string_pretty(&payload).unwrap()),
+        Err(_) => println!("{}", text),
+    }
+}
+
 // Helper function to handle HTTP response
-async fn handle_response(response: reqwest::Response, output: Option<String>) {
+async fn handle_response(response: reqwest::Response, output: Option<String>, include: bool) {
     let status = response.status();
+    if include {
+        println!("{:?} {}", response.version(), status);
+        for (name, value) in response.headers() {
+            println!("{}: {}", name, String::from_utf8_lossy(value.as_bytes()));
+        }
+        println!();
+    }
+
     if status.is_success() {
         if let Some(output) = output {
             if let Ok(bytes) = response.bytes().await {
@@ -133,9 +299,7 @@ async fn handle_response(response: reqwest::Response, output: Option<String>) {
             }
         } else {
             if let Ok(text) = response.text().await {
-                let payload = from_str(&text).unwrap_or(Value::Null);
-                let json_output = serde_json::to_string_pretty(&payload).unwrap();
-                println!("{}", json_output);
+                print_body(&text);
             } else {
                 eprintln!("Failed to read response.");
             }
@@ -145,9 +309,7 @@ async fn handle_response(response: reqwest::Response, output: Option<String>) {
 
         // Attempt to read and print the error body if available
         if let Ok(error_body) = response.text().await {
-            let payload = from_str(&error_body).unwrap_or(Value::Null);
-            let json_output = serde_json::to_string_pretty(&payload).unwrap();
-            println!("{}", json_output);
+            print_body(&error_body);
         } else {
             eprintln!("Failed to read the error response body.");
         }
@@ -155,45 +317,109 @@ async fn handle_response(response: reqwest::Response, output: Option<String>) {
 }
 
 
-pub async fn http_serve(port: u16) {
+pub async fn http_serve(port: u16, dir: Option<String>, routes: Option<String>, echo: bool, log: Option<String>) {
+    let mode = match (dir, routes) {
+        _ if echo => ServeMode::Echo,
+        (Some(dir), _) => {
+            if !Path::new(&dir).is_dir() {
+                eprintln!("'{}' is not a directory", dir);
+                return;
+            }
+            ServeMode::Static(PathBuf::from(dir))
+        }
+        (None, Some(routes)) => match http_utils::load_routes(&routes) {
+            Ok(routes) => ServeMode::Routes(routes),
+            Err(e) => {
+                eprintln!("Error reading routes: {}", e);
+                return;
+            }
+        },
+        (None, None) => ServeMode::Hello,
+    };
+
+    let log = match http_utils::RequestLog::new(log.as_deref()) {
+        Ok(log) => log,
+        Err(e) => {
+            eprintln!("Failed to open the request log: {}", e);
+            return;
+        }
+    };
+
     let addr = format!("0.0.0.0:{}", port);
-    let listener = TcpListener::bind(&addr).await.unwrap();
-    println!("Server listening on http://{}", addr);
-
-    // Accept incoming connections in a loop
-    loop {
-        let (mut stream, _) = listener.accept().await.unwrap();
-        tokio::spawn(async move {
-            handle_client(&mut stream).await;
-        });
-    }
-}
-
-async fn handle_client(stream: &mut tokio::net::TcpStream) {
-    let mut buffer = [0; 1024];
-    match stream.read(&mut buffer).await {
-        Ok(0) => return, // Connection closed
-        Ok(_) => {
-            if let Ok(request) = str::from_utf8(&buffer) {
-                println!("Received request: {}", request);
-                let response = "HTTP/1.1 200 OK\r\nContent-Type: text/plain\r\n\r\nHello, World!";
-                
-                if let Err(e) = stream.write_all(response.as_bytes()).await {
-                    eprintln!("Failed to send response: {}", e);
-                }
+    let listener = match TcpListener::bind(&addr).await {
+        Ok(listener) => listener,
+        Err(e) => {
+            eprintln!("Failed to listen on {}: {}", addr, e);
+            return;
+        }
+    };
+    match &mode {
+        ServeMode::Static(dir) => println!("Serving {} on http://{}", dir.display(), addr),
+        ServeMode::Routes(routes) => println!("Serving {} routes on http://{}", routes.len(), addr),
+        ServeMode::Echo => println!("Echoing requests on http://{}", addr),
+        ServeMode::Proxy(_) | ServeMode::Hello => println!("Server listening on http://{}", addr),
+    }
 
-                if let Err(e) = stream.flush().await {
-                    eprintln!("Failed to flush stream: {}", e);
-                }
+    http_utils::serve(listener, mode, log).await;
+}
+
+pub async fn http_proxy(listen: u16, upstream: Option<String>, record: Option<String>, replay: Option<String>, log: Option<String>) {
+    let recorded = match &replay {
+        Some(replay) => match http_utils::load_recording(replay) {
+            Ok(recorded) => recorded,
+            Err(e) => {
+                eprintln!("Error reading recording: {}", e);
+                return;
             }
+        },
+        None => Vec::new(),
+    };
+
+    let recorder = match &record {
+        Some(record) => match http_utils::Recorder::open(record) {
+            Ok(recorder) => Some(recorder),
+            Err(e) => {
+                eprintln!("Error opening recording: {}", e);
+                return;
+            }
+        },
+        None => None,
+    };
+
+    let proxy = match http_utils::Proxy::new(upstream.clone(), recorder, recorded) {
+        Ok(proxy) => proxy,
+        Err(e) => {
+            eprintln!("{}", e);
+            return;
         }
+    };
+
+    let log = match http_utils::RequestLog::new(log.as_deref()) {
+        Ok(log) => log,
         Err(e) => {
-            eprintln!("Failed to read from stream: {}", e);
+            eprintln!("Failed to open the request log: {}", e);
+            return;
         }
-    }
+    };
 
-    // Close the connection
-    if let Err(e) = stream.shutdown().await {
-        eprintln!("Failed to shutdown stream: {}", e);
+    let addr = format!("0.0.0.0:{}", listen);
+    let listener = match TcpListener::bind(&addr).await {
+        Ok(listener) => listener,
+        Err(e) => {
+            eprintln!("Failed to listen on {}: {}", addr, e);
+            return;
+        }
+    };
+    match &upstream {
+        Some(upstream) => println!("Proxying http://{} to {}", addr, upstream),
+        None => println!("Replaying recorded responses on http://{}", addr),
     }
+    if let Some(replay) = &replay {
+        println!("Replaying responses from {}", replay);
+    }
+    if let Some(record) = &record {
+        println!("Recording to {}", record);
+    }
+
+    http_utils::serve(listener, ServeMode::Proxy(Box::new(proxy)), log).await;
 }
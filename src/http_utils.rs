@@ -0,0 +1,1809 @@
+use std::collections::HashMap;
+use std::error::Error;
+use std::fmt;
+use std::io::Write;
+use std::net::SocketAddr;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+use base64::prelude::*;
+use chrono::{DateTime, SecondsFormat, Utc};
+use percent_encoding::{percent_decode_str, utf8_percent_encode, AsciiSet, CONTROLS};
+use reqwest::{redirect, Client, Method, Url};
+use serde_derive::{Deserialize, Serialize};
+use serde_json::Value;
+use tokio::fs::File;
+use tokio::io::{
+    AsyncBufRead, AsyncBufReadExt, AsyncReadExt, AsyncSeekExt, AsyncWrite, AsyncWriteExt,
+    BufReader, BufWriter,
+};
+use tokio::net::{TcpListener, TcpStream};
+
+// The most a request line and its headers can take up
+const MAX_HEADER_SIZE: u64 = 64 * 1024;
+// The largest request body that will be read
+pub const MAX_BODY_SIZE: u64 = 16 * 1024 * 1024;
+
+// A parsed HTTP/1.x request
+#[derive(Debug, Clone)]
+pub struct Request {
+    pub method: String,
+    // The request target as sent e.g. /files/a%20b.txt?download=1
+    pub target: String,
+    // The percent decoded path of the target, without the query string
+    pub path: String,
+    pub query: Option<String>,
+    pub version: String,
+    pub headers: Vec<(String, String)>,
+    pub body: Vec<u8>,
+    // The address of the client, set once the request has been read from a connection
+    pub remote: Option<SocketAddr>,
+}
+
+impl Request {
+    // The value of a header, header names aren't case sensitive
+    pub fn header(&self, name: &str) -> Option<&str> {
+        self.headers
+            .iter()
+            .find(|(header, _)| header.eq_ignore_ascii_case(name))
+            .map(|(_, value)| value.as_str())
+    }
+
+    // The percent decoded names and values of the query string, in order
+    pub fn query_params(&self) -> Vec<(String, String)> {
+        let decode = |text: &str| {
+            percent_decode_str(&text.replace('+', " "))
+                .decode_utf8_lossy()
+                .into_owned()
+        };
+        self.query
+            .iter()
+            .flat_map(|query| query.split('&'))
+            .filter(|pair| !pair.is_empty())
+            .map(|pair| match pair.split_once('=') {
+                Some((name, value)) => (decode(name), decode(value)),
+                None => (decode(pair), String::new()),
+            })
+            .collect()
+    }
+
+    // HTTP/1.1 connections stay open for more requests unless either side asks to close them
+    pub fn keep_alive(&self) -> bool {
+        match self
+            .header("Connection")
+            .map(|value| value.to_ascii_lowercase())
+        {
+            Some(value) if value.contains("close") => false,
+            Some(value) if value.contains("keep-alive") => true,
+            _ => self.version == "HTTP/1.1",
+        }
+    }
+}
+
+// A request that couldn't be read, answered with the given status
+#[derive(Debug)]
+pub struct HttpError {
+    pub status: u16,
+    pub message: String,
+}
+
+impl HttpError {
+    fn new(status: u16, message: &str) -> HttpError {
+        HttpError {
+            status,
+            message: message.to_string(),
+        }
+    }
+}
+
+impl fmt::Display for HttpError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} {}", self.status, self.message)
+    }
+}
+
+impl Error for HttpError {}
+
+// Read the next request from a connection, None once the client has closed it
+pub async fn read_request<R>(reader: &mut R) -> Result<Option<Request>, HttpError>
+where
+    R: AsyncBufRead + Unpin,
+{
+    let mut head = Vec::new();
+    loop {
+        let limit = MAX_HEADER_SIZE.saturating_sub(head.len() as u64);
+        let mut line = Vec::new();
+        let read = (&mut *reader)
+            .take(limit)
+            .read_until(b'\n', &mut line)
+            .await
+            .map_err(|e| HttpError::new(400, &e.to_string()))?;
+        if read == 0 {
+            if head.is_empty() {
+                return Ok(None);
+            }
+            return Err(HttpError::new(
+                400,
+                "The connection closed part way through the request",
+            ));
+        }
+        if !line.ends_with(b"\n") {
+            return Err(HttpError::new(431, "The request headers are too large"));
+        }
+        // Blank lines before the request line are allowed and ignored
+        if line == b"\r\n" || line == b"\n" {
+            if head.is_empty() {
+                continue;
+            }
+            break;
+        }
+        head.extend_from_slice(&line);
+    }
+
+    let head = String::from_utf8(head)
+        .map_err(|_| HttpError::new(400, "The request headers aren't valid UTF-8"))?;
+    let mut lines = head.lines();
+    let request_line = lines.next().unwrap_or_default();
+    let mut parts = request_line.split_whitespace();
+    let (Some(method), Some(target), Some(version), None) =
+        (parts.next(), parts.next(), parts.next(), parts.next())
+    else {
+        return Err(HttpError::new(400, "Malformed request line"));
+    };
+    if !version.starts_with("HTTP/1.") {
+        return Err(HttpError::new(
+            505,
+            "Only HTTP/1.0 and HTTP/1.1 are supported",
+        ));
+    }
+
+    let mut headers = Vec::new();
+    for line in lines {
+        let Some((name, value)) = line.split_once(':') else {
+            return Err(HttpError::new(400, &format!("Malformed header: {}", line)));
+        };
+        headers.push((name.trim().to_string(), value.trim().to_string()));
+    }
+
+    let (raw_path, query) = match target.split_once('?') {
+        Some((path, query)) => (path, Some(query.to_string())),
+        None => (target, None),
+    };
+    let path = percent_decode_str(raw_path)
+        .decode_utf8()
+        .map_err(|_| HttpError::new(400, "The request path isn't valid UTF-8"))?
+        .to_string();
+
+    let mut request = Request {
+        method: method.to_string(),
+        target: target.to_string(),
+        path,
+        query,
+        version: version.to_string(),
+        headers,
+        body: Vec::new(),
+        remote: None,
+    };
+
+    // A chunked body takes precedence over any Content-Length
+    let chunked = request.header("Transfer-Encoding").is_some_and(|encoding| {
+        encoding
+            .to_ascii_lowercase()
+            .split(',')
+            .any(|coding| coding.trim() == "chunked")
+    });
+    if chunked {
+        request.body = read_chunked_body(reader).await?;
+    } else if let Some(length) = request.header("Content-Length") {
+        let length: u64 = length
+            .parse()
+            .map_err(|_| HttpError::new(400, "Invalid Content-Length"))?;
+        if length > MAX_BODY_SIZE {
+            return Err(HttpError::new(413, "The request body is too large"));
+        }
+        let mut body = vec![0; length as usize];
+        reader.read_exact(&mut body).await.map_err(|_| {
+            HttpError::new(
+                400,
+                "The connection closed part way through the request body",
+            )
+        })?;
+        request.body = body;
+    }
+
+    Ok(Some(request))
+}
+
+// Read a body sent as a series of chunks, each preceded by its size in hex and ending with
+// an empty chunk followed by any trailing headers, which are ignored
+async fn read_chunked_body<R>(reader: &mut R) -> Result<Vec<u8>, HttpError>
+where
+    R: AsyncBufRead + Unpin,
+{
+    let mut body = Vec::new();
+    loop {
+        let line = read_line(reader).await?;
+        let size = line.split(';').next().unwrap_or_default().trim();
+        let size =
+            u64::from_str_radix(size, 16).map_err(|_| HttpError::new(400, "Invalid chunk size"))?;
+        if size == 0 {
+            break;
+        }
+        if body.len() as u64 + size > MAX_BODY_SIZE {
+            return Err(HttpError::new(413, "The request body is too large"));
+        }
+
+        let start = body.len();
+        body.resize(start + size as usize, 0);
+        reader.read_exact(&mut body[start..]).await.map_err(|_| {
+            HttpError::new(
+                400,
+                "The connection closed part way through the request body",
+            )
+        })?;
+        if !read_line(reader).await?.is_empty() {
+            return Err(HttpError::new(400, "A chunk is longer than its size"));
+        }
+    }
+
+    while !read_line(reader).await?.is_empty() {}
+    Ok(body)
+}
+
+// Read a line of a chunked body without its line ending
+async fn read_line<R>(reader: &mut R) -> Result<String, HttpError>
+where
+    R: AsyncBufRead + Unpin,
+{
+    let mut line = Vec::new();
+    (&mut *reader)
+        .take(MAX_HEADER_SIZE)
+        .read_until(b'\n', &mut line)
+        .await
+        .map_err(|e| HttpError::new(400, &e.to_string()))?;
+    if !line.ends_with(b"\n") {
+        return Err(HttpError::new(
+            400,
+            "The connection closed part way through the request body",
+        ));
+    }
+    let line = String::from_utf8(line).map_err(|_| HttpError::new(400, "Invalid chunk"))?;
+    Ok(line.trim_end_matches(['\r', '\n']).to_string())
+}
+
+// The body of a response, files are streamed rather than read into memory
+pub enum Body {
+    Empty,
+    Bytes(Vec<u8>),
+    // A file along with the offset and number of bytes to send from it
+    File(File, u64, u64),
+}
+
+pub struct Response {
+    pub status: u16,
+    pub headers: Vec<(String, String)>,
+    pub body: Body,
+}
+
+impl Response {
+    pub fn new(status: u16) -> Response {
+        Response {
+            status,
+            headers: Vec::new(),
+            body: Body::Empty,
+        }
+    }
+
+    pub fn text(status: u16, text: &str) -> Response {
+        Response::new(status)
+            .header("Content-Type", "text/plain; charset=utf-8")
+            .bytes(text.as_bytes().to_vec())
+    }
+
+    pub fn html(status: u16, html: String) -> Response {
+        Response::new(status)
+            .header("Content-Type", "text/html; charset=utf-8")
+            .bytes(html.into_bytes())
+    }
+
+    pub fn header(mut self, name: &str, value: &str) -> Response {
+        self.headers.push((name.to_string(), value.to_string()));
+        self
+    }
+
+    pub fn bytes(mut self, bytes: Vec<u8>) -> Response {
+        self.body = Body::Bytes(bytes);
+        self
+    }
+
+    // The number of bytes in the body
+    pub fn content_length(&self) -> u64 {
+        match &self.body {
+            Body::Empty => 0,
+            Body::Bytes(bytes) => bytes.len() as u64,
+            Body::File(_, _, length) => *length,
+        }
+    }
+
+    // Write the status line, headers and body. The body is left out for HEAD requests
+    // but its length is still given.
+    pub async fn write<W>(
+        self,
+        writer: &mut W,
+        head_only: bool,
+        keep_alive: bool,
+    ) -> Result<(), Box<dyn Error>>
+    where
+        W: AsyncWrite + Unpin,
+    {
+        let mut head = format!(
+            "HTTP/1.1 {} {}\r\n",
+            self.status,
+            reason_phrase(self.status)
+        );
+        for (name, value) in &self.headers {
+            head.push_str(&format!("{}: {}\r\n", name, value));
+        }
+        // 304 responses describe the body the client already has, so have no length of their own
+        if self.status != 304 {
+            head.push_str(&format!("Content-Length: {}\r\n", self.content_length()));
+        }
+        if !keep_alive {
+            head.push_str("Connection: close\r\n");
+        }
+        head.push_str("\r\n");
+
+        let mut writer = BufWriter::new(writer);
+        writer.write_all(head.as_bytes()).await?;
+        if !head_only {
+            match self.body {
+                Body::Empty => {}
+                Body::Bytes(bytes) => writer.write_all(&bytes).await?,
+                Body::File(mut file, offset, length) => {
+                    file.seek(std::io::SeekFrom::Start(offset)).await?;
+                    tokio::io::copy(&mut file.take(length), &mut writer).await?;
+                }
+            }
+        }
+        writer.flush().await?;
+        Ok(())
+    }
+}
+
+pub fn reason_phrase(status: u16) -> &'static str {
+    match status {
+        200 => "OK",
+        201 => "Created",
+        204 => "No Content",
+        206 => "Partial Content",
+        301 => "Moved Permanently",
+        302 => "Found",
+        304 => "Not Modified",
+        400 => "Bad Request",
+        401 => "Unauthorized",
+        403 => "Forbidden",
+        404 => "Not Found",
+        405 => "Method Not Allowed",
+        409 => "Conflict",
+        413 => "Content Too Large",
+        416 => "Range Not Satisfiable",
+        422 => "Unprocessable Content",
+        429 => "Too Many Requests",
+        431 => "Request Header Fields Too Large",
+        500 => "Internal Server Error",
+        501 => "Not Implemented",
+        502 => "Bad Gateway",
+        503 => "Service Unavailable",
+        504 => "Gateway Timeout",
+        505 => "HTTP Version Not Supported",
+        _ => "",
+    }
+}
+
+// How the server answers requests
+pub enum ServeMode {
+    // Every request gets the same greeting
+    Hello,
+    // Files are served from a directory
+    Static(PathBuf),
+    // Requests are answered by the first matching route, see load_routes
+    Routes(Vec<Route>),
+    // Requests are answered with a JSON description of themselves, which is also printed
+    Echo,
+    // Requests are forwarded to another server or answered from a recording
+    Proxy(Box<Proxy>),
+}
+
+impl ServeMode {
+    pub async fn respond(&self, request: &Request) -> Response {
+        match self {
+            ServeMode::Hello => Response::text(200, "Hello, World!"),
+            ServeMode::Static(root) => serve_static(root, request).await,
+            ServeMode::Routes(routes) => serve_routes(routes, request).await,
+            ServeMode::Echo => {
+                let description =
+                    serde_json::to_string_pretty(&describe_request(request)).unwrap_or_default();
+                println!("{}", description);
+                Response::new(200)
+                    .header("Content-Type", "application/json")
+                    .bytes(description.into_bytes())
+            }
+            ServeMode::Proxy(proxy) => proxy.respond(request).await,
+        }
+    }
+}
+
+// Describe a request as JSON. The body is included as text, or as base64 when it isn't
+// valid UTF-8, and is parsed when it's JSON.
+pub fn describe_request(request: &Request) -> Value {
+    let mut query = serde_json::Map::new();
+    for (name, value) in request.query_params() {
+        match query.get_mut(&name) {
+            // Repeated parameters are collected into an array
+            Some(Value::Array(values)) => values.push(Value::String(value)),
+            Some(existing) => *existing = Value::Array(vec![existing.take(), Value::String(value)]),
+            None => {
+                query.insert(name, Value::String(value));
+            }
+        }
+    }
+
+    let mut headers = serde_json::Map::new();
+    for (name, value) in &request.headers {
+        match headers.get_mut(name) {
+            Some(Value::String(existing)) => *existing = format!("{}, {}", existing, value),
+            _ => {
+                headers.insert(name.clone(), Value::String(value.clone()));
+            }
+        }
+    }
+
+    let mut description = serde_json::json!({
+        "method": request.method,
+        "target": request.target,
+        "path": request.path,
+        "query": query,
+        "version": request.version,
+        "remote": request.remote.map(|remote| remote.to_string()),
+        "headers": headers,
+        "body_size": request.body.len(),
+    });
+    match String::from_utf8(request.body.clone()) {
+        Ok(text) => {
+            if let Ok(json) = serde_json::from_str::<Value>(&text) {
+                description["json"] = json;
+            }
+            description["body"] = Value::String(text);
+        }
+        Err(_) => description["body_base64"] = Value::String(BASE64_STANDARD.encode(&request.body)),
+    }
+    description
+}
+
+// Each request is logged as a line on stdout and optionally appended to an NDJSON file
+pub struct RequestLog {
+    file: Option<Mutex<std::fs::File>>,
+}
+
+#[derive(Serialize)]
+struct LogEntry<'a> {
+    timestamp: String,
+    remote: Option<String>,
+    method: &'a str,
+    path: &'a str,
+    status: u16,
+    bytes: u64,
+    latency_ms: f64,
+}
+
+impl RequestLog {
+    pub fn new(ndjson_path: Option<&str>) -> Result<RequestLog, Box<dyn Error>> {
+        let file = match ndjson_path {
+            Some(path) => Some(Mutex::new(
+                std::fs::OpenOptions::new()
+                    .create(true)
+                    .append(true)
+                    .open(path)?,
+            )),
+            None => None,
+        };
+        Ok(RequestLog { file })
+    }
+
+    fn record(&self, entry: &LogEntry) {
+        println!(
+            "{} {} {} {} {} {}B {:.1}ms",
+            entry.timestamp,
+            entry.remote.as_deref().unwrap_or("-"),
+            entry.method,
+            entry.path,
+            entry.status,
+            entry.bytes,
+            entry.latency_ms
+        );
+
+        if let Some(file) = &self.file {
+            let Ok(line) = serde_json::to_string(entry) else {
+                return;
+            };
+            if let Ok(mut file) = file.lock() {
+                if let Err(e) = writeln!(file, "{}", line) {
+                    eprintln!("Failed to write to the request log: {}", e);
+                }
+            }
+        }
+    }
+}
+
+// Accept connections until the process is stopped, each one is handled on its own task
+pub async fn serve(listener: TcpListener, mode: ServeMode, log: RequestLog) {
+    let mode = Arc::new(mode);
+    let log = Arc::new(log);
+    loop {
+        let (stream, _) = match listener.accept().await {
+            Ok(connection) => connection,
+            Err(e) => {
+                eprintln!("Failed to accept connection: {}", e);
+                continue;
+            }
+        };
+        let (mode, log) = (mode.clone(), log.clone());
+        tokio::spawn(async move {
+            if let Err(e) = handle_connection(stream, &mode, &log).await {
+                eprintln!("Connection error: {}", e);
+            }
+        });
+    }
+}
+
+// Answer each request on a connection in turn until either side closes it
+async fn handle_connection(
+    stream: TcpStream,
+    mode: &ServeMode,
+    log: &RequestLog,
+) -> Result<(), Box<dyn Error>> {
+    let remote = stream.peer_addr().ok();
+    let (reader, mut writer) = stream.into_split();
+    let mut reader = BufReader::new(reader);
+
+    loop {
+        let start = Instant::now();
+        let mut entry = LogEntry {
+            timestamp: Utc::now().to_rfc3339_opts(SecondsFormat::Millis, true),
+            remote: remote.map(|remote| remote.to_string()),
+            method: "-",
+            path: "-",
+            status: 0,
+            bytes: 0,
+            latency_ms: 0.0,
+        };
+
+        let mut request = match read_request(&mut reader).await {
+            Ok(Some(request)) => request,
+            Ok(None) => break,
+            Err(e) => {
+                let response = Response::text(e.status, &e.message);
+                entry.status = e.status;
+                entry.bytes = response.content_length();
+                response.write(&mut writer, false, false).await?;
+                entry.latency_ms = start.elapsed().as_secs_f64() * 1000.0;
+                log.record(&entry);
+                break;
+            }
+        };
+        request.remote = remote;
+
+        let response = mode.respond(&request).await;
+        let keep_alive = request.keep_alive();
+        let head_only = request.method == "HEAD";
+        entry.status = response.status;
+        entry.bytes = if head_only {
+            0
+        } else {
+            response.content_length()
+        };
+        response.write(&mut writer, head_only, keep_alive).await?;
+
+        entry.method = &request.method;
+        entry.path = &request.target;
+        entry.latency_ms = start.elapsed().as_secs_f64() * 1000.0;
+        log.record(&entry);
+        if !keep_alive {
+            break;
+        }
+    }
+
+    writer.shutdown().await?;
+    Ok(())
+}
+
+// Characters escaped in the links of directory listings
+const PATH_SEGMENT: &AsciiSet = &CONTROLS
+    .add(b' ')
+    .add(b'"')
+    .add(b'#')
+    .add(b'%')
+    .add(b'<')
+    .add(b'>')
+    .add(b'?')
+    .add(b'`')
+    .add(b'{')
+    .add(b'}');
+
+// Serve a file from the root directory, or a listing of a directory without an index.html.
+// Paths that lead outside of the root, including through symlinks, are never served.
+pub async fn serve_static(root: &Path, request: &Request) -> Response {
+    if request.method != "GET" && request.method != "HEAD" {
+        return Response::text(405, "Method Not Allowed").header("Allow", "GET, HEAD");
+    }
+
+    let Some(path) = resolve_path(root, &request.path) else {
+        return Response::text(403, "Forbidden");
+    };
+    let Ok(metadata) = tokio::fs::metadata(&path).await else {
+        return Response::text(404, "Not Found");
+    };
+
+    if metadata.is_dir() {
+        // Relative links in the page only work when the directory ends in a /
+        if !request.path.ends_with('/') {
+            let mut location = format!("{}/", utf8_percent_encode(&request.path, PATH_SEGMENT));
+            if let Some(query) = &request.query {
+                location = format!("{}?{}", location, query);
+            }
+            return Response::new(301).header("Location", &location);
+        }
+
+        let index = path.join("index.html");
+        return match tokio::fs::metadata(&index).await {
+            Ok(metadata) if metadata.is_file() => serve_file(&index, &metadata, request).await,
+            _ => match directory_listing(&path, &request.path).await {
+                Ok(html) => Response::html(200, html),
+                Err(e) => Response::text(500, &format!("Failed to list directory: {}", e)),
+            },
+        };
+    }
+
+    serve_file(&path, &metadata, request).await
+}
+
+// Map a request path onto a file within the root, None when it would escape the root
+fn resolve_path(root: &Path, request_path: &str) -> Option<PathBuf> {
+    let mut path = root.to_path_buf();
+    for segment in request_path.split('/') {
+        match segment {
+            "" | "." => {}
+            ".." => return None,
+            segment if segment.contains('\\') || segment.contains('\0') => return None,
+            segment => path.push(segment),
+        }
+    }
+
+    // Symlinks are followed, as long as they point somewhere inside the root
+    match (path.canonicalize(), root.canonicalize()) {
+        (Ok(resolved), Ok(root)) if !resolved.starts_with(&root) => None,
+        _ => Some(path),
+    }
+}
+
+async fn serve_file(path: &Path, metadata: &std::fs::Metadata, request: &Request) -> Response {
+    let length = metadata.len();
+    let modified = metadata.modified().unwrap_or(UNIX_EPOCH);
+    let modified_secs = modified
+        .duration_since(UNIX_EPOCH)
+        .map(|duration| duration.as_secs())
+        .unwrap_or_default();
+    let etag = format!("\"{:x}-{:x}\"", modified_secs, length);
+    let last_modified = http_date(modified);
+
+    let response = Response::new(200)
+        .header("Content-Type", content_type(path))
+        .header("ETag", &etag)
+        .header("Last-Modified", &last_modified)
+        .header("Accept-Ranges", "bytes");
+
+    if is_not_modified(request, &etag, modified_secs) {
+        return Response {
+            status: 304,
+            ..response
+        };
+    }
+
+    // A range is ignored when If-Range names a different version of the file
+    let range = match request.header("If-Range") {
+        Some(if_range) if if_range != etag && if_range != last_modified => None,
+        _ => request.header("Range"),
+    };
+    let (status, offset, count) = match range.map(|range| parse_range(range, length)) {
+        Some(Some(Ok((start, end)))) => (206, start, end - start + 1),
+        Some(Some(Err(()))) => {
+            return Response::text(416, "Range Not Satisfiable")
+                .header("Content-Range", &format!("bytes */{}", length));
+        }
+        // Ranges that can't be understood, or more than one of them, get the whole file
+        _ => (200, 0, length),
+    };
+
+    let file = match File::open(path).await {
+        Ok(file) => file,
+        Err(e) => return Response::text(403, &format!("Failed to open file: {}", e)),
+    };
+
+    let mut response = Response {
+        status,
+        body: Body::File(file, offset, count),
+        ..response
+    };
+    if status == 206 {
+        response = response.header(
+            "Content-Range",
+            &format!("bytes {}-{}/{}", offset, offset + count - 1, length),
+        );
+    }
+    response
+}
+
+// True when the client's cached copy is still current. If-None-Match takes precedence over
+// If-Modified-Since when both are sent.
+fn is_not_modified(request: &Request, etag: &str, modified_secs: u64) -> bool {
+    if let Some(if_none_match) = request.header("If-None-Match") {
+        return if_none_match
+            .split(',')
+            .map(|tag| tag.trim().trim_start_matches("W/"))
+            .any(|tag| tag == "*" || tag == etag);
+    }
+    match request
+        .header("If-Modified-Since")
+        .and_then(|since| DateTime::parse_from_rfc2822(since).ok())
+    {
+        Some(since) => modified_secs as i64 <= since.timestamp(),
+        None => false,
+    }
+}
+
+// Parse a single byte range e.g. bytes=0-99, bytes=100- or bytes=-100 into the first and
+// last byte offsets. None when it should be ignored, Err when it's outside the file.
+pub fn parse_range(range: &str, length: u64) -> Option<Result<(u64, u64), ()>> {
+    let spec = range.trim().strip_prefix("bytes=")?.trim();
+    if spec.contains(',') {
+        return None;
+    }
+    let (start, end) = spec.split_once('-')?;
+    let (start, end) = (start.trim(), end.trim());
+
+    let range = if start.is_empty() {
+        // The last n bytes
+        let suffix: u64 = end.parse().ok()?;
+        if suffix == 0 || length == 0 {
+            return Some(Err(()));
+        }
+        (length.saturating_sub(suffix), length - 1)
+    } else {
+        let start: u64 = start.parse().ok()?;
+        let end = match end {
+            "" => length.saturating_sub(1),
+            end => end.parse::<u64>().ok()?.min(length.saturating_sub(1)),
+        };
+        if start >= length || end < start {
+            return Some(Err(()));
+        }
+        (start, end)
+    };
+    Some(Ok(range))
+}
+
+// Format a time the way HTTP headers expect e.g. Sun, 06 Nov 1994 08:49:37 GMT
+pub fn http_date(time: SystemTime) -> String {
+    DateTime::<Utc>::from(time)
+        .format("%a, %d %b %Y %H:%M:%S GMT")
+        .to_string()
+}
+
+// The Content-Type of a file from its extension
+pub fn content_type(path: &Path) -> &'static str {
+    let extension = path
+        .extension()
+        .map(|extension| extension.to_string_lossy().to_lowercase())
+        .unwrap_or_default();
+    match extension.as_str() {
+        "html" | "htm" => "text/html; charset=utf-8",
+        "css" => "text/css; charset=utf-8",
+        "js" | "mjs" => "text/javascript; charset=utf-8",
+        "json" | "map" => "application/json",
+        "txt" | "log" | "md" => "text/plain; charset=utf-8",
+        "csv" => "text/csv; charset=utf-8",
+        "xml" => "application/xml",
+        "svg" => "image/svg+xml",
+        "png" => "image/png",
+        "jpg" | "jpeg" => "image/jpeg",
+        "gif" => "image/gif",
+        "webp" => "image/webp",
+        "ico" => "image/x-icon",
+        "woff" => "font/woff",
+        "woff2" => "font/woff2",
+        "wasm" => "application/wasm",
+        "pdf" => "application/pdf",
+        "zip" => "application/zip",
+        "gz" | "tgz" => "application/gzip",
+        "tar" => "application/x-tar",
+        "mp4" => "video/mp4",
+        "mp3" => "audio/mpeg",
+        _ => "application/octet-stream",
+    }
+}
+
+// An HTML page linking to each entry of a directory, sub directories first
+async fn directory_listing(directory: &Path, request_path: &str) -> Result<String, Box<dyn Error>> {
+    let mut entries = Vec::new();
+    let mut reader = tokio::fs::read_dir(directory).await?;
+    while let Some(entry) = reader.next_entry().await? {
+        let metadata = entry.metadata().await?;
+        let name = entry.file_name().to_string_lossy().into_owned();
+        entries.push((
+            metadata.is_dir(),
+            name,
+            metadata.len(),
+            metadata.modified().ok(),
+        ));
+    }
+    entries.sort_by(|a, b| b.0.cmp(&a.0).then_with(|| a.1.cmp(&b.1)));
+
+    let title = escape_html(&format!("Index of {}", request_path));
+    let mut html = format!(
+        "<!DOCTYPE html>\n<html>\n<head><meta charset=\"utf-8\"><title>{0}</title></head>\n<body>\n<h1>{0}</h1>\n<table>\n<tr><th>Name</th><th>Size</th><th>Modified</th></tr>\n",
+        title
+    );
+    if request_path != "/" {
+        html.push_str("<tr><td><a href=\"../\">../</a></td><td></td><td></td></tr>\n");
+    }
+    for (is_dir, name, size, modified) in entries {
+        let suffix = if is_dir { "/" } else { "" };
+        let size = if is_dir {
+            String::new()
+        } else {
+            size.to_string()
+        };
+        let modified = modified.map(http_date).unwrap_or_default();
+        html.push_str(&format!(
+            "<tr><td><a href=\"{}{}\">{}{}</a></td><td>{}</td><td>{}</td></tr>\n",
+            utf8_percent_encode(&name, PATH_SEGMENT),
+            suffix,
+            escape_html(&name),
+            suffix,
+            size,
+            modified
+        ));
+    }
+    html.push_str("</table>\n</body>\n</html>\n");
+    Ok(html)
+}
+
+fn escape_html(text: &str) -> String {
+    text.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+}
+
+// A route in a routes file, in the same style as the config of aid http req e.g.
+// { "method": "GET", "path": "/users/:id", "status": 200, "headers": { "X-Mock": "true" },
+//   "body": { "id": "{{id}}" }, "delay_ms": 250 }
+#[derive(Deserialize, Debug)]
+#[serde(deny_unknown_fields)]
+struct RouteConfig {
+    method: Option<String>,
+    path: String,
+    status: Option<u16>,
+    headers: Option<Value>,
+    // A string is sent as text, anything else as JSON
+    body: Option<Value>,
+    // A file to send, relative to the routes file
+    file: Option<String>,
+    delay_ms: Option<u64>,
+}
+
+enum PathSegment {
+    Literal(String),
+    // :name matches any single segment, which templates can refer to as {{name}}
+    Param(String),
+    // A trailing * matches the rest of the path
+    Rest,
+}
+
+enum RouteBody {
+    Empty,
+    Text(String),
+    Json(Value),
+    File(PathBuf),
+}
+
+// A response to send for requests matching a method and path pattern
+pub struct Route {
+    // Matches any method when not given
+    method: Option<String>,
+    segments: Vec<PathSegment>,
+    status: u16,
+    headers: Vec<(String, String)>,
+    body: RouteBody,
+    delay: Option<Duration>,
+}
+
+impl Route {
+    // The values of the path's :params when the path matches the route
+    fn match_path(&self, path: &str) -> Option<Vec<(String, String)>> {
+        let mut parts = path.split('/').filter(|part| !part.is_empty());
+        let mut params = Vec::new();
+        for segment in &self.segments {
+            match segment {
+                PathSegment::Rest => return Some(params),
+                PathSegment::Literal(literal) => {
+                    if parts.next()? != literal {
+                        return None;
+                    }
+                }
+                PathSegment::Param(name) => params.push((name.clone(), parts.next()?.to_string())),
+            }
+        }
+        parts.next().is_none().then_some(params)
+    }
+
+    fn matches_method(&self, method: &str) -> bool {
+        // HEAD requests are answered by GET routes, without the body
+        self.method
+            .as_deref()
+            .is_none_or(|expected| expected == method || (expected == "GET" && method == "HEAD"))
+    }
+}
+
+// Load a JSON array of routes. Requests are matched against them in order.
+pub fn load_routes(file_path: &str) -> Result<Vec<Route>, String> {
+    let contents =
+        std::fs::read_to_string(file_path).map_err(|e| format!("Failed to open file: {}", e))?;
+    let configs: Vec<RouteConfig> = serde_json::from_str(&contents)
+        .map_err(|e| format!("Failed to deserialize JSON: {}", e))?;
+    let directory = Path::new(file_path).parent().unwrap_or(Path::new("."));
+
+    configs
+        .into_iter()
+        .map(|config| route_from_config(config, directory))
+        .collect()
+}
+
+fn route_from_config(config: RouteConfig, directory: &Path) -> Result<Route, String> {
+    let path = config.path;
+    if !path.starts_with('/') {
+        return Err(format!("Route path '{}' doesn't start with /", path));
+    }
+
+    let parts: Vec<&str> = path.split('/').filter(|part| !part.is_empty()).collect();
+    let mut segments = Vec::new();
+    for (index, part) in parts.iter().enumerate() {
+        segments.push(match *part {
+            "*" if index == parts.len() - 1 => PathSegment::Rest,
+            "*" => {
+                return Err(format!(
+                    "Route path '{}' can only have a * at the end",
+                    path
+                ))
+            }
+            part => match part.strip_prefix(':') {
+                Some("") => {
+                    return Err(format!(
+                        "Route path '{}' has a parameter without a name",
+                        path
+                    ))
+                }
+                Some(name) => PathSegment::Param(name.to_string()),
+                None => PathSegment::Literal(part.to_string()),
+            },
+        });
+    }
+
+    let status = config.status.unwrap_or(200);
+    if !(100..=999).contains(&status) {
+        return Err(format!("Invalid status {} for route '{}'", status, path));
+    }
+
+    let mut headers = Vec::new();
+    if let Some(config_headers) = config.headers {
+        let object = config_headers
+            .as_object()
+            .ok_or(format!("The headers of route '{}' aren't an object", path))?;
+        for (name, value) in object {
+            let value = value
+                .as_str()
+                .ok_or(format!("Header value for '{}' is not a string", name))?;
+            if name.is_empty()
+                || name.contains([':', ' ', '\r', '\n'])
+                || value.contains(['\r', '\n'])
+            {
+                return Err(format!("Invalid header '{}' for route '{}'", name, path));
+            }
+            headers.push((name.clone(), value.to_string()));
+        }
+    }
+
+    let body = match (config.body, config.file) {
+        (Some(_), Some(_)) => {
+            return Err(format!(
+                "Route '{}' can't have both a body and a file",
+                path
+            ))
+        }
+        (Some(Value::String(text)), None) => RouteBody::Text(text),
+        (Some(json), None) => RouteBody::Json(json),
+        (None, Some(file)) => {
+            let file = directory.join(file);
+            if !file.is_file() {
+                return Err(format!(
+                    "File '{}' for route '{}' doesn't exist",
+                    file.display(),
+                    path
+                ));
+            }
+            RouteBody::File(file)
+        }
+        (None, None) => RouteBody::Empty,
+    };
+
+    Ok(Route {
+        method: config.method.map(|method| method.to_uppercase()),
+        segments,
+        status,
+        headers,
+        body,
+        delay: config.delay_ms.map(Duration::from_millis),
+    })
+}
+
+// Answer a request with the first route matching its method and path
+async fn serve_routes(routes: &[Route], request: &Request) -> Response {
+    let mut allowed = Vec::new();
+    for route in routes {
+        let Some(params) = route.match_path(&request.path) else {
+            continue;
+        };
+        if !route.matches_method(&request.method) {
+            allowed.extend(route.method.clone());
+            continue;
+        }
+
+        if let Some(delay) = route.delay {
+            tokio::time::sleep(delay).await;
+        }
+        return route_response(route, request, &params).await;
+    }
+
+    let status = if allowed.is_empty() { 404 } else { 405 };
+    let error =
+        serde_json::json!({ "error": format!("No route for {} {}", request.method, request.path) });
+    let mut response = Response::new(status)
+        .header("Content-Type", "application/json")
+        .bytes(error.to_string().into_bytes());
+    if !allowed.is_empty() {
+        allowed.dedup();
+        response = response.header("Allow", &allowed.join(", "));
+    }
+    response
+}
+
+async fn route_response(route: &Route, request: &Request, params: &[(String, String)]) -> Response {
+    let mut response = Response::new(route.status);
+    let has_header = |name: &str| {
+        route
+            .headers
+            .iter()
+            .any(|(header, _)| header.eq_ignore_ascii_case(name))
+    };
+
+    response = match &route.body {
+        RouteBody::Empty => response,
+        RouteBody::Text(text) => {
+            if !has_header("Content-Type") {
+                response = response.header("Content-Type", "text/plain; charset=utf-8");
+            }
+            response.bytes(fill_template(text, request, params).into_bytes())
+        }
+        RouteBody::Json(json) => {
+            if !has_header("Content-Type") {
+                response = response.header("Content-Type", "application/json");
+            }
+            response.bytes(
+                fill_json_template(json, request, params)
+                    .to_string()
+                    .into_bytes(),
+            )
+        }
+        RouteBody::File(path) => {
+            let file = match File::open(path).await {
+                Ok(file) => file,
+                Err(e) => {
+                    return Response::text(
+                        500,
+                        &format!("Failed to open '{}': {}", path.display(), e),
+                    )
+                }
+            };
+            let length = file
+                .metadata()
+                .await
+                .map(|metadata| metadata.len())
+                .unwrap_or_default();
+            if !has_header("Content-Type") {
+                response = response.header("Content-Type", content_type(path));
+            }
+            Response {
+                body: Body::File(file, 0, length),
+                ..response
+            }
+        }
+    };
+
+    for (name, value) in &route.headers {
+        response = response.header(name, &fill_template(value, request, params));
+    }
+    response
+}
+
+// Replace each {{name}} in a template with a value from the request: a path :param, method,
+// path, body, query.<name> or header.<name>. Unknown names are left as they are.
+pub fn fill_template(template: &str, request: &Request, params: &[(String, String)]) -> String {
+    let mut filled = String::new();
+    let mut rest = template;
+    while let Some(start) = rest.find("{{") {
+        let Some(length) = rest[start..].find("}}") else {
+            break;
+        };
+        filled.push_str(&rest[..start]);
+        let placeholder = &rest[start..start + length + 2];
+        let name = placeholder[2..placeholder.len() - 2].trim();
+
+        let value = if let Some((_, value)) = params.iter().find(|(param, _)| param == name) {
+            Some(value.clone())
+        } else if let Some(query_name) = name.strip_prefix("query.") {
+            request
+                .query_params()
+                .into_iter()
+                .find(|(param, _)| param == query_name)
+                .map(|(_, value)| value)
+        } else if let Some(header_name) = name.strip_prefix("header.") {
+            request.header(header_name).map(|value| value.to_string())
+        } else {
+            match name {
+                "method" => Some(request.method.clone()),
+                "path" => Some(request.path.clone()),
+                "body" => Some(String::from_utf8_lossy(&request.body).into_owned()),
+                _ => None,
+            }
+        };
+        filled.push_str(value.as_deref().unwrap_or(placeholder));
+        rest = &rest[start + length + 2..];
+    }
+    filled.push_str(rest);
+    filled
+}
+
+// Fill the templates in each string of a JSON value
+fn fill_json_template(json: &Value, request: &Request, params: &[(String, String)]) -> Value {
+    match json {
+        Value::String(text) => Value::String(fill_template(text, request, params)),
+        Value::Array(values) => Value::Array(
+            values
+                .iter()
+                .map(|value| fill_json_template(value, request, params))
+                .collect(),
+        ),
+        Value::Object(object) => Value::Object(
+            object
+                .iter()
+                .map(|(key, value)| (key.clone(), fill_json_template(value, request, params)))
+                .collect(),
+        ),
+        value => value.clone(),
+    }
+}
+
+// A field of a multipart/form-data body, uploaded files also have a name and type
+pub struct FormPart {
+    pub name: String,
+    pub file_name: Option<String>,
+    pub content_type: Option<String>,
+    pub data: Vec<u8>,
+}
+
+// Build a multipart/form-data body, returning its Content-Type and contents
+pub fn multipart_body(parts: &[FormPart]) -> (String, Vec<u8>) {
+    let seed = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|elapsed| elapsed.as_nanos())
+        .unwrap_or_default();
+    // The boundary can't appear in any of the parts
+    let mut boundary = format!("aid-{:x}-{:x}", seed, std::process::id());
+    while parts.iter().any(|part| {
+        part.data
+            .windows(boundary.len())
+            .any(|window| window == boundary.as_bytes())
+    }) {
+        boundary.push('-');
+    }
+
+    // Quotes and line breaks in names are percent encoded, as browsers do
+    let quote = |value: &str| {
+        value
+            .replace('"', "%22")
+            .replace('\r', "%0D")
+            .replace('\n', "%0A")
+    };
+    let mut body = Vec::new();
+    for part in parts {
+        body.extend_from_slice(format!("--{}\r\n", boundary).as_bytes());
+        let mut disposition = format!(
+            "Content-Disposition: form-data; name=\"{}\"",
+            quote(&part.name)
+        );
+        if let Some(file_name) = &part.file_name {
+            disposition.push_str(&format!("; filename=\"{}\"", quote(file_name)));
+        }
+        body.extend_from_slice(disposition.as_bytes());
+        body.extend_from_slice(b"\r\n");
+        if let Some(content_type) = &part.content_type {
+            body.extend_from_slice(format!("Content-Type: {}\r\n", content_type).as_bytes());
+        }
+        body.extend_from_slice(b"\r\n");
+        body.extend_from_slice(&part.data);
+        body.extend_from_slice(b"\r\n");
+    }
+    body.extend_from_slice(format!("--{}--\r\n", boundary).as_bytes());
+
+    (format!("multipart/form-data; boundary={}", boundary), body)
+}
+
+// Headers that only apply to a single connection, so aren't passed on by the proxy
+const HOP_BY_HOP_HEADERS: [&str; 9] = [
+    "Connection",
+    "Keep-Alive",
+    "Proxy-Connection",
+    "Proxy-Authenticate",
+    "Proxy-Authorization",
+    "TE",
+    "Trailer",
+    "Transfer-Encoding",
+    "Upgrade",
+];
+
+fn is_hop_by_hop(name: &str) -> bool {
+    HOP_BY_HOP_HEADERS
+        .iter()
+        .any(|header| header.eq_ignore_ascii_case(name))
+}
+
+// A request forwarded by the proxy along with the response it got
+#[derive(Debug, Clone)]
+pub struct Exchange {
+    pub started: DateTime<Utc>,
+    pub duration_ms: f64,
+    pub method: String,
+    // The path and query the client asked for, which replayed requests are matched on
+    pub target: String,
+    pub url: String,
+    pub request_headers: Vec<(String, String)>,
+    pub request_body: Vec<u8>,
+    pub status: u16,
+    pub response_headers: Vec<(String, String)>,
+    pub response_body: Vec<u8>,
+}
+
+impl Exchange {
+    // One line of an NDJSON recording
+    pub fn to_ndjson(&self) -> Value {
+        let mut request = serde_json::json!({
+            "method": self.method,
+            "target": self.target,
+            "url": self.url,
+            "headers": headers_to_json(&self.request_headers),
+        });
+        add_body(&mut request, &self.request_body);
+        let mut response = serde_json::json!({
+            "status": self.status,
+            "headers": headers_to_json(&self.response_headers),
+        });
+        add_body(&mut response, &self.response_body);
+
+        serde_json::json!({
+            "started": self.started.to_rfc3339_opts(SecondsFormat::Millis, true),
+            "duration_ms": self.duration_ms,
+            "request": request,
+            "response": response,
+        })
+    }
+
+    pub fn from_ndjson(json: &Value) -> Result<Exchange, String> {
+        let request = &json["request"];
+        let response = &json["response"];
+        Ok(Exchange {
+            started: parse_started(&json["started"]),
+            duration_ms: json["duration_ms"].as_f64().unwrap_or_default(),
+            method: json_string(request, "method")?,
+            target: recorded_target(request, &request["target"])?,
+            url: json_string(request, "url")?,
+            request_headers: headers_from_json(&request["headers"]),
+            request_body: read_body(request)?,
+            status: json_status(&response["status"])?,
+            response_headers: headers_from_json(&response["headers"]),
+            response_body: read_body(response)?,
+        })
+    }
+
+    // An entry of a HAR 1.2 log, which browsers' developer tools can open
+    pub fn to_har_entry(&self) -> Value {
+        let header = |headers: &[(String, String)], name: &str| {
+            headers
+                .iter()
+                .find(|(header, _)| header.eq_ignore_ascii_case(name))
+                .map(|(_, value)| value.clone())
+                .unwrap_or_default()
+        };
+        let query_string: Vec<Value> = Url::parse(&self.url)
+            .map(|url| {
+                url.query_pairs()
+                    .map(|(name, value)| serde_json::json!({ "name": name, "value": value }))
+                    .collect()
+            })
+            .unwrap_or_default();
+
+        let mut request = serde_json::json!({
+            "method": self.method,
+            "url": self.url,
+            // Custom HAR fields start with an underscore
+            "_target": self.target,
+            "httpVersion": "HTTP/1.1",
+            "cookies": [],
+            "headers": headers_to_json(&self.request_headers),
+            "queryString": query_string,
+            "headersSize": -1,
+            "bodySize": self.request_body.len(),
+        });
+        if !self.request_body.is_empty() {
+            let mut post_data =
+                serde_json::json!({ "mimeType": header(&self.request_headers, "Content-Type") });
+            add_har_text(&mut post_data, &self.request_body);
+            request["postData"] = post_data;
+        }
+
+        let mut content = serde_json::json!({
+            "size": self.response_body.len(),
+            "mimeType": header(&self.response_headers, "Content-Type"),
+        });
+        add_har_text(&mut content, &self.response_body);
+
+        serde_json::json!({
+            "startedDateTime": self.started.to_rfc3339_opts(SecondsFormat::Millis, true),
+            "time": self.duration_ms,
+            "request": request,
+            "response": {
+                "status": self.status,
+                "statusText": reason_phrase(self.status),
+                "httpVersion": "HTTP/1.1",
+                "cookies": [],
+                "headers": headers_to_json(&self.response_headers),
+                "content": content,
+                "redirectURL": header(&self.response_headers, "Location"),
+                "headersSize": -1,
+                "bodySize": self.response_body.len(),
+            },
+            "cache": {},
+            "timings": { "send": 0, "wait": self.duration_ms, "receive": 0 },
+        })
+    }
+
+    pub fn from_har_entry(entry: &Value) -> Result<Exchange, String> {
+        let request = &entry["request"];
+        let response = &entry["response"];
+        Ok(Exchange {
+            started: parse_started(&entry["startedDateTime"]),
+            duration_ms: entry["time"].as_f64().unwrap_or_default(),
+            method: json_string(request, "method")?,
+            target: recorded_target(request, &request["_target"])?,
+            url: json_string(request, "url")?,
+            request_headers: headers_from_json(&request["headers"]),
+            request_body: read_har_text(&request["postData"])?,
+            status: json_status(&response["status"])?,
+            response_headers: headers_from_json(&response["headers"]),
+            response_body: read_har_text(&response["content"])?,
+        })
+    }
+}
+
+// The path and query string of a request target. Requests meant for a proxy name the whole
+// URL, e.g. from curl -x, of which only the path and query are kept.
+fn request_target(target: &str) -> String {
+    match Url::parse(target) {
+        Ok(url) => match url.query() {
+            Some(query) => format!("{}?{}", url.path(), query),
+            None => url.path().to_string(),
+        },
+        Err(_) => target.to_string(),
+    }
+}
+
+// The target a recorded request was made to, recordings without one use the path of its URL
+fn recorded_target(request: &Value, target: &Value) -> Result<String, String> {
+    match target.as_str() {
+        Some(target) => Ok(target.to_string()),
+        None => json_string(request, "url").map(|url| request_target(&url)),
+    }
+}
+
+// Headers are kept as a list of names and values, as a header can be sent more than once
+fn headers_to_json(headers: &[(String, String)]) -> Value {
+    Value::Array(
+        headers
+            .iter()
+            .map(|(name, value)| serde_json::json!({ "name": name, "value": value }))
+            .collect(),
+    )
+}
+
+fn headers_from_json(json: &Value) -> Vec<(String, String)> {
+    json.as_array()
+        .into_iter()
+        .flatten()
+        .filter_map(|header| {
+            Some((
+                header["name"].as_str()?.to_string(),
+                header["value"].as_str()?.to_string(),
+            ))
+        })
+        .collect()
+}
+
+// Bodies are stored as text, or as base64 when they aren't valid UTF-8
+fn add_body(json: &mut Value, body: &[u8]) {
+    if body.is_empty() {
+        return;
+    }
+    match std::str::from_utf8(body) {
+        Ok(text) => json["body"] = Value::String(text.to_string()),
+        Err(_) => json["body_base64"] = Value::String(BASE64_STANDARD.encode(body)),
+    }
+}
+
+fn read_body(json: &Value) -> Result<Vec<u8>, String> {
+    if let Some(text) = json["body"].as_str() {
+        return Ok(text.as_bytes().to_vec());
+    }
+    match json["body_base64"].as_str() {
+        Some(encoded) => BASE64_STANDARD
+            .decode(encoded)
+            .map_err(|e| format!("Invalid base64 body: {}", e)),
+        None => Ok(Vec::new()),
+    }
+}
+
+fn add_har_text(json: &mut Value, body: &[u8]) {
+    match std::str::from_utf8(body) {
+        Ok(text) => json["text"] = Value::String(text.to_string()),
+        Err(_) => {
+            json["text"] = Value::String(BASE64_STANDARD.encode(body));
+            json["encoding"] = Value::String("base64".to_string());
+        }
+    }
+}
+
+fn read_har_text(json: &Value) -> Result<Vec<u8>, String> {
+    let text = json["text"].as_str().unwrap_or_default();
+    if json["encoding"] == "base64" {
+        return BASE64_STANDARD
+            .decode(text)
+            .map_err(|e| format!("Invalid base64 body: {}", e));
+    }
+    Ok(text.as_bytes().to_vec())
+}
+
+fn json_string(json: &Value, field: &str) -> Result<String, String> {
+    json[field]
+        .as_str()
+        .map(|value| value.to_string())
+        .ok_or(format!("The recording is missing the request's {}", field))
+}
+
+fn json_status(json: &Value) -> Result<u16, String> {
+    json.as_u64()
+        .and_then(|status| u16::try_from(status).ok())
+        .ok_or("The recording is missing a response status".to_string())
+}
+
+fn parse_started(json: &Value) -> DateTime<Utc> {
+    json.as_str()
+        .and_then(|started| DateTime::parse_from_rfc3339(started).ok())
+        .map(|started| started.with_timezone(&Utc))
+        .unwrap_or_default()
+}
+
+fn is_har(path: &Path) -> bool {
+    path.extension()
+        .is_some_and(|extension| extension.eq_ignore_ascii_case("har"))
+}
+
+// Load the exchanges from a recording, a HAR file when it ends in .har and NDJSON otherwise
+pub fn load_recording(file_path: &str) -> Result<Vec<Exchange>, String> {
+    let contents =
+        std::fs::read_to_string(file_path).map_err(|e| format!("Failed to open file: {}", e))?;
+
+    if is_har(Path::new(file_path)) {
+        let har: Value = serde_json::from_str(&contents)
+            .map_err(|e| format!("Failed to deserialize JSON: {}", e))?;
+        return har["log"]["entries"]
+            .as_array()
+            .ok_or("The HAR file has no log entries".to_string())?
+            .iter()
+            .map(Exchange::from_har_entry)
+            .collect();
+    }
+
+    contents
+        .lines()
+        .enumerate()
+        .filter(|(_, line)| !line.trim().is_empty())
+        .map(|(index, line)| {
+            serde_json::from_str(line)
+                .map_err(|e| e.to_string())
+                .and_then(|json| Exchange::from_ndjson(&json))
+                .map_err(|e| format!("Line {}: {}", index + 1, e))
+        })
+        .collect()
+}
+
+enum RecordFormat {
+    // The whole log is written out again after each exchange, as a HAR file is a single document
+    Har(Mutex<Vec<Value>>),
+    Ndjson(Mutex<std::fs::File>),
+}
+
+// Writes each exchange through the proxy to a file
+pub struct Recorder {
+    path: PathBuf,
+    format: RecordFormat,
+}
+
+impl Recorder {
+    // Open a recording, adding to any exchanges already in it
+    pub fn open(file_path: &str) -> Result<Recorder, String> {
+        let path = PathBuf::from(file_path);
+        let format = if is_har(&path) {
+            let entries = match path.exists() {
+                true => load_recording(file_path)?
+                    .iter()
+                    .map(Exchange::to_har_entry)
+                    .collect(),
+                false => Vec::new(),
+            };
+            RecordFormat::Har(Mutex::new(entries))
+        } else {
+            let file = std::fs::OpenOptions::new()
+                .create(true)
+                .append(true)
+                .open(&path)
+                .map_err(|e| format!("Failed to open file: {}", e))?;
+            RecordFormat::Ndjson(Mutex::new(file))
+        };
+        Ok(Recorder { path, format })
+    }
+
+    fn record(&self, exchange: &Exchange) -> Result<(), Box<dyn Error>> {
+        match &self.format {
+            RecordFormat::Ndjson(file) => {
+                let mut file = file.lock().map_err(|_| "The recording is unavailable")?;
+                writeln!(file, "{}", exchange.to_ndjson())?;
+            }
+            RecordFormat::Har(entries) => {
+                let mut entries = entries.lock().map_err(|_| "The recording is unavailable")?;
+                entries.push(exchange.to_har_entry());
+                let har = serde_json::json!({
+                    "log": {
+                        "version": "1.2",
+                        "creator": { "name": "aid", "version": env!("CARGO_PKG_VERSION") },
+                        "entries": *entries,
+                    }
+                });
+
+                // Written alongside and renamed over the top so the file is never left half written
+                let file_name = self.path.file_name().unwrap_or_default().to_string_lossy();
+                let temp_path = self.path.with_file_name(format!(
+                    ".{}.aid-{}.tmp",
+                    file_name,
+                    std::process::id()
+                ));
+                std::fs::write(&temp_path, serde_json::to_string_pretty(&har)?)?;
+                std::fs::rename(&temp_path, &self.path)?;
+            }
+        }
+        Ok(())
+    }
+}
+
+// Forwards requests to an upstream server, recording each exchange, or answers them from
+// a previous recording. Recorded responses for the same request are replayed in order,
+// repeating the last one.
+pub struct Proxy {
+    client: Client,
+    upstream: Option<String>,
+    recorder: Option<Recorder>,
+    replay: Vec<Exchange>,
+    // How many times each method and target has been replayed
+    replayed: Mutex<HashMap<(String, String), usize>>,
+}
+
+impl Proxy {
+    pub fn new(
+        upstream: Option<String>,
+        recorder: Option<Recorder>,
+        replay: Vec<Exchange>,
+    ) -> Result<Proxy, String> {
+        if let Some(upstream) = &upstream {
+            let url = Url::parse(upstream)
+                .map_err(|e| format!("Invalid upstream URL '{}': {}", upstream, e))?;
+            if url.scheme() != "http" && url.scheme() != "https" {
+                return Err(format!(
+                    "The upstream URL '{}' must start with http:// or https://",
+                    upstream
+                ));
+            }
+        }
+
+        // Redirects are passed back to the client rather than followed
+        let client = Client::builder()
+            .redirect(redirect::Policy::none())
+            .build()
+            .map_err(|e| format!("Failed to create the HTTP client: {}", e))?;
+
+        Ok(Proxy {
+            client,
+            upstream: upstream.map(|upstream| upstream.trim_end_matches('/').to_string()),
+            recorder,
+            replay,
+            replayed: Mutex::new(HashMap::new()),
+        })
+    }
+
+    async fn respond(&self, request: &Request) -> Response {
+        let target = request_target(&request.target);
+        if let Some(exchange) = self.find_recorded(&request.method, &target) {
+            return proxied_response(
+                exchange.status,
+                &exchange.response_headers,
+                exchange.response_body.clone(),
+            );
+        }
+
+        let Some(upstream) = &self.upstream else {
+            let error = serde_json::json!({ "error": format!("No recording for {} {}", request.method, target) });
+            return Response::new(404)
+                .header("Content-Type", "application/json")
+                .bytes(error.to_string().into_bytes());
+        };
+
+        let url = format!("{}{}", upstream, target);
+        match self.forward(&url, target, request).await {
+            Ok(exchange) => {
+                let response = proxied_response(
+                    exchange.status,
+                    &exchange.response_headers,
+                    exchange.response_body.clone(),
+                );
+                if let Some(recorder) = &self.recorder {
+                    if let Err(e) = recorder.record(&exchange) {
+                        eprintln!(
+                            "Failed to record {} {}: {}",
+                            exchange.method, exchange.url, e
+                        );
+                    }
+                }
+                response
+            }
+            Err(e) if e.is_timeout() => {
+                Response::text(504, &format!("The upstream request timed out: {}", e))
+            }
+            Err(e) => Response::text(502, &format!("The upstream request failed: {}", e)),
+        }
+    }
+
+    async fn forward(
+        &self,
+        url: &str,
+        target: String,
+        request: &Request,
+    ) -> Result<Exchange, reqwest::Error> {
+        let method = Method::from_bytes(request.method.as_bytes()).unwrap_or(Method::GET);
+        let mut builder = self.client.request(method, url);
+
+        let mut request_headers = Vec::new();
+        let mut forwarded_for = request.remote.map(|remote| remote.ip().to_string());
+        for (name, value) in &request.headers {
+            if is_hop_by_hop(name)
+                || name.eq_ignore_ascii_case("Host")
+                || name.eq_ignore_ascii_case("Content-Length")
+            {
+                continue;
+            }
+            if name.eq_ignore_ascii_case("X-Forwarded-For") {
+                // Add the client to the end of the list of proxies the request has been through
+                forwarded_for = Some(match forwarded_for {
+                    Some(client) => format!("{}, {}", value, client),
+                    None => value.clone(),
+                });
+                continue;
+            }
+            request_headers.push((name.clone(), value.clone()));
+        }
+        if let Some(forwarded_for) = forwarded_for {
+            request_headers.push(("X-Forwarded-For".to_string(), forwarded_for));
+        }
+        for (name, value) in &request_headers {
+            builder = builder.header(name, value);
+        }
+        if !request.body.is_empty() {
+            builder = builder.body(request.body.clone());
+        }
+
+        let started = Utc::now();
+        let start = Instant::now();
+        let response = builder.send().await?;
+        let status = response.status().as_u16();
+        let response_headers = response
+            .headers()
+            .iter()
+            .map(|(name, value)| {
+                (
+                    name.to_string(),
+                    String::from_utf8_lossy(value.as_bytes()).into_owned(),
+                )
+            })
+            .collect();
+        let response_body = response.bytes().await?.to_vec();
+
+        Ok(Exchange {
+            started,
+            duration_ms: start.elapsed().as_secs_f64() * 1000.0,
+            method: request.method.clone(),
+            target,
+            url: url.to_string(),
+            request_headers,
+            request_body: request.body.clone(),
+            status,
+            response_headers,
+            response_body,
+        })
+    }
+
+    fn find_recorded(&self, method: &str, target: &str) -> Option<&Exchange> {
+        let recorded: Vec<&Exchange> = self
+            .replay
+            .iter()
+            .filter(|exchange| exchange.method == method && exchange.target == target)
+            .collect();
+        if recorded.is_empty() {
+            return None;
+        }
+
+        let mut replayed = self.replayed.lock().ok()?;
+        let count = replayed
+            .entry((method.to_string(), target.to_string()))
+            .or_default();
+        let exchange = recorded[(*count).min(recorded.len() - 1)];
+        *count += 1;
+        Some(exchange)
+    }
+}
+
+// A response passed back to the client, without the headers that only applied upstream
+fn proxied_response(status: u16, headers: &[(String, String)], body: Vec<u8>) -> Response {
+    let mut response = Response::new(status);
+    for (name, value) in headers {
+        if !is_hop_by_hop(name) && !name.eq_ignore_ascii_case("Content-Length") {
+            response = response.header(name, value);
+        }
+    }
+    response.bytes(body)
+}
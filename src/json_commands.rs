@@ -5,6 +5,9 @@ use base64::prelude::*;
 use std::str;
 use base64::engine::general_purpose::URL_SAFE;
 
+use crate::csv_commands;
+use crate::csv_utils::{CsvOptions, InputFormat};
+
 pub async fn json_extract(property: String) {
         let mut input = String::new();
         io::stdin().read_to_string(&mut input).expect("Failed to read from stdin");
@@ -17,6 +20,15 @@ pub async fn json_extract(property: String) {
         }
 }
 
+// Run a SQL query over JSON array or NDJSON files, FROM - reads from stdin
+pub async fn json_query(sql: String, output_path: Option<String>, format: Option<String>) {
+    let options = CsvOptions {
+        input_format: Some(InputFormat::Json),
+        ..CsvOptions::default()
+    };
+    csv_commands::run_search(sql, output_path, format, &options);
+}
+
 #[derive(Serialize)]
 struct JwtDecoded {
     header: Value,
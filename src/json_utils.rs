@@ -0,0 +1,149 @@
+use std::collections::HashMap;
+use std::error::Error;
+use std::io::BufRead;
+
+use csv::StringRecord;
+use serde_json::{Map, Value};
+
+use crate::csv_utils::{open_input, RecordStream, RowSource};
+
+// Number of NDJSON lines read up front to find the columns of the table
+const HEADER_SAMPLE_SIZE: usize = 1000;
+
+// A JSON array of objects, or a file with a JSON object on each line (NDJSON), read as a table.
+// Nested objects are flattened so {"user": {"name": "x"}} has a column user.name.
+// The columns are every field seen in the array, or in the first lines of an NDJSON file.
+pub struct JsonSource {
+    headers: Vec<String>,
+    records: RecordStream,
+}
+
+impl JsonSource {
+    pub fn open(file_path: &str) -> Result<JsonSource, Box<dyn Error>> {
+        let mut input = open_input(file_path)?;
+
+        // An array has to be read whole, objects on separate lines are streamed
+        if first_char(&mut input)? == Some(b'[') {
+            let values: Vec<Value> = serde_json::from_reader(input)?;
+            let rows: Vec<Vec<(String, String)>> = values.iter().map(flatten).collect();
+            let headers = collect_headers(&rows);
+            let record_headers = headers.clone();
+            let records = rows
+                .into_iter()
+                .map(move |row| Ok(to_record(&record_headers, row)));
+            return Ok(JsonSource {
+                headers,
+                records: Box::new(records),
+            });
+        }
+
+        let mut lines = input
+            .lines()
+            .enumerate()
+            .filter(|(_, line)| line.as_ref().map_or(true, |line| !line.trim().is_empty()))
+            .map(|(index, line)| -> Result<Vec<(String, String)>, Box<dyn Error>> {
+                let value: Value = serde_json::from_str(&line?)
+                    .map_err(|e| format!("Invalid JSON on line {}: {}", index + 1, e))?;
+                Ok(flatten(&value))
+            });
+
+        let sample = lines
+            .by_ref()
+            .take(HEADER_SAMPLE_SIZE)
+            .collect::<Result<Vec<_>, _>>()?;
+        let headers = collect_headers(&sample);
+        let record_headers = headers.clone();
+        let records = sample
+            .into_iter()
+            .map(Ok)
+            .chain(lines)
+            .map(move |row| row.map(|row| to_record(&record_headers, row)));
+
+        Ok(JsonSource {
+            headers,
+            records: Box::new(records),
+        })
+    }
+}
+
+impl RowSource for JsonSource {
+    fn headers(&self) -> &[String] {
+        &self.headers
+    }
+
+    fn into_records(self: Box<Self>) -> RecordStream {
+        self.records
+    }
+}
+
+// Find the first character that isn't whitespace without consuming it
+fn first_char(input: &mut dyn BufRead) -> Result<Option<u8>, Box<dyn Error>> {
+    loop {
+        let buffer = input.fill_buf()?;
+        if buffer.is_empty() {
+            return Ok(None);
+        }
+        if let Some(c) = buffer.iter().find(|c| !c.is_ascii_whitespace()) {
+            return Ok(Some(*c));
+        }
+        let length = buffer.len();
+        input.consume(length);
+    }
+}
+
+// Every field name in the order they're first seen
+fn collect_headers(rows: &[Vec<(String, String)>]) -> Vec<String> {
+    let mut headers: Vec<String> = Vec::new();
+    for row in rows {
+        for (name, _) in row {
+            if !headers.contains(name) {
+                headers.push(name.clone());
+            }
+        }
+    }
+    headers
+}
+
+// Fields missing from a row are empty, fields that aren't one of the headers are dropped
+fn to_record(headers: &[String], row: Vec<(String, String)>) -> StringRecord {
+    let mut fields: HashMap<String, String> = row.into_iter().collect();
+    StringRecord::from(
+        headers
+            .iter()
+            .map(|header| fields.remove(header).unwrap_or_default())
+            .collect::<Vec<String>>(),
+    )
+}
+
+// Flatten a JSON value into named fields, values that aren't objects have a single field called value
+pub fn flatten(value: &Value) -> Vec<(String, String)> {
+    let mut fields = Vec::new();
+    match value {
+        Value::Object(object) => flatten_object("", object, &mut fields),
+        value => fields.push(("value".to_string(), field_text(value))),
+    }
+    fields
+}
+
+fn flatten_object(prefix: &str, object: &Map<String, Value>, fields: &mut Vec<(String, String)>) {
+    for (key, value) in object {
+        let name = if prefix.is_empty() {
+            key.clone()
+        } else {
+            format!("{}.{}", prefix, key)
+        };
+        match value {
+            Value::Object(inner) => flatten_object(&name, inner, fields),
+            value => fields.push((name, field_text(value))),
+        }
+    }
+}
+
+// Strings are used as they are, null is an empty field and arrays are kept as JSON
+fn field_text(value: &Value) -> String {
+    match value {
+        Value::Null => String::new(),
+        Value::String(text) => text.clone(),
+        value => value.to_string(),
+    }
+}
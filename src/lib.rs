@@ -1,4 +1,5 @@
 pub mod csv_utils;
+pub mod json_utils;
 pub mod cpu_commands;
 pub mod format_utils;
 pub mod ip_commands;
@@ -8,6 +9,7 @@ pub mod port_commands;
 pub mod disk_commands;
 pub mod network_commands;
 pub mod http_commands;
+pub mod http_utils;
 pub mod json_commands;
 pub mod csv_commands;
 pub mod text_commands;
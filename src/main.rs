@@ -1,5 +1,5 @@
 use aid::{
-    bits_commands, cpu_commands, csv_commands, disk_commands, env_commands, file_commands,
+    bits_commands, cpu_commands, csv_commands, csv_utils, disk_commands, env_commands, file_commands,
     http_commands, input_utils, ip_commands, json_commands, math_commands, mem_commands,
     network_commands, port_commands, process_commands, text_commands, time_commands,
 };
@@ -188,6 +188,8 @@ enum NetworkCommands {
     },
 }
 
+// Parsed once from the command line, so the size of the req options doesn't matter
+#[allow(clippy::large_enum_variant)]
 #[derive(Subcommand, Debug, Clone)]
 enum HttpCommands {
     #[command(about = "Make a HTTP request")]
@@ -219,9 +221,84 @@ enum HttpCommands {
             help = "If specified saves http response body to a file at the given path."
         )]
         output: Option<String>,
+
+        #[arg(
+            short = 'H',
+            long = "header",
+            action = clap::ArgAction::Append,
+            help = "Add a header, e.g. -H 'Accept: application/json'. Can be repeated."
+        )]
+        headers: Vec<String>,
+
+        #[arg(
+            short = 'd',
+            long = "data",
+            conflicts_with_all = ["data_urlencode", "form"],
+            help = "Send a request body, or the contents of a file with @path."
+        )]
+        data: Option<String>,
+
+        #[arg(
+            long = "data-urlencode",
+            action = clap::ArgAction::Append,
+            conflicts_with = "form",
+            help = "Add a url encoded form field, e.g. --data-urlencode 'name=Bob Smith'. Can be repeated."
+        )]
+        data_urlencode: Vec<String>,
+
+        #[arg(
+            short = 'F',
+            long = "form",
+            action = clap::ArgAction::Append,
+            help = "Add a multipart form field, or upload a file with name=@path[;type=mime]. Can be repeated."
+        )]
+        form: Vec<String>,
+
+        #[arg(
+            short = 'q',
+            long = "query",
+            action = clap::ArgAction::Append,
+            help = "Add a query parameter, e.g. -q 'page=2'. Can be repeated."
+        )]
+        query: Vec<String>,
+
+        #[arg(
+            long = "user",
+            conflicts_with = "bearer",
+            help = "Use basic auth with user:password."
+        )]
+        user: Option<String>,
+
+        #[arg(long = "bearer", help = "Use bearer auth with the given token.")]
+        bearer: Option<String>,
+
+        #[arg(long = "timeout", help = "Give up on the request after the given number of seconds.")]
+        timeout: Option<f64>,
+
+        #[arg(
+            long = "max-redirects",
+            help = "The most redirects to follow, 0 to not follow them (default is 10)."
+        )]
+        max_redirects: Option<usize>,
+
+        #[arg(
+            short = 'k',
+            long = "insecure",
+            action = clap::ArgAction::SetTrue,
+            help = "Don't check the server's TLS certificate."
+        )]
+        insecure: bool,
+
+        #[arg(
+            short = 'i',
+            long = "include",
+            action = clap::ArgAction::SetTrue,
+            help = "Print the response status and headers before the body."
+        )]
+        include: bool,
     },
 
-    #[command(about = "Start a HTTP server (GET: 0.0.0.0:80 -> 'Hello, World!')")]
+    #[command(about = "Start a HTTP server that serves a directory (--dir), mock routes (--routes) or echoes requests (--echo), logging each request (--log). Answers 'Hello, World!' otherwise.")]
     Serve {
         #[arg(
             short = 'p',
@@ -230,6 +307,72 @@ enum HttpCommands {
             help = "Specify the port for the HTTP server (default is 80)."
         )]
         port: u16,
+
+        #[arg(
+            short = 'd',
+            long = "dir",
+            help = "Serve the files in a directory, with listings of directories that have no index.html."
+        )]
+        dir: Option<String>,
+
+        #[arg(
+            short = 'r',
+            long = "routes",
+            conflicts_with = "dir",
+            help = "Answer requests from a JSON file of mock routes. Specify: method, path, status, headers, body or file, delay_ms."
+        )]
+        routes: Option<String>,
+
+        #[arg(
+            short = 'e',
+            long = "echo",
+            action = clap::ArgAction::SetTrue,
+            conflicts_with_all = ["dir", "routes"],
+            help = "Answer each request with a JSON description of it, which is also printed."
+        )]
+        echo: bool,
+
+        #[arg(
+            short = 'l',
+            long = "log",
+            help = "Append a JSON line describing each request to a file."
+        )]
+        log: Option<String>,
+    },
+
+    #[command(about = "Forward requests to another server, recording or replaying the responses")]
+    Proxy {
+        #[arg(
+            short = 'l',
+            long = "listen",
+            default_value = "8080",
+            help = "Specify the port to listen on (default is 8080)."
+        )]
+        listen: u16,
+
+        #[arg(
+            short = 'u',
+            long = "upstream",
+            required_unless_present = "replay",
+            help = "The server to forward requests to, e.g. http://localhost:3000"
+        )]
+        upstream: Option<String>,
+
+        #[arg(
+            short = 'r',
+            long = "record",
+            help = "Record each request and response to a file, a HAR file when it ends in .har and JSON lines otherwise."
+        )]
+        record: Option<String>,
+
+        #[arg(
+            long = "replay",
+            help = "Answer requests from a recording, forwarding any that weren't recorded when an upstream is given."
+        )]
+        replay: Option<String>,
+
+        #[arg(long = "log", help = "Append a JSON line describing each request to a file.")]
+        log: Option<String>,
     },
 }
 
@@ -249,6 +392,19 @@ enum JsonCommands {
         #[arg(trailing_var_arg = true)]
         c_args: Vec<String>,
     },
+    #[command(about = "Sql search over JSON arrays / NDJSON")]
+    Query {
+        #[arg(short = 's', long = "sql", help = "Sql query, nested fields are flattened e.g. SELECT user.name FROM logs.ndjson.")]
+        sql: String,
+        #[arg(short = 'o', long = "output", help = "Output file path.")]
+        output: Option<String>,
+        #[arg(
+            short = 'f',
+            long = "format",
+            help = "Output format: csv, tsv, table, json, ndjson or markdown (defaults to the output file extension, or csv)."
+        )]
+        format: Option<String>,
+    },
 }
 
 #[derive(Subcommand, Debug, Clone)]
@@ -259,6 +415,205 @@ enum CsvCommands {
         sql: String,
         #[arg(short = 'o', long = "output", help = "Output file path.")]
         output: Option<String>,
+        #[arg(
+            short = 'f',
+            long = "format",
+            help = "Output format: csv, tsv, table, json, ndjson or markdown (defaults to the output file extension, or csv)."
+        )]
+        format: Option<String>,
+        #[arg(
+            short = 'd',
+            long = "delimiter",
+            help = "Field delimiter of the input files e.g. ';', '|' or 'tab' (detected from the file when not given)."
+        )]
+        delimiter: Option<String>,
+        #[arg(long = "no-header", action = clap::ArgAction::SetTrue,
+               help = "Input files have no header row, columns are named c1..cN.")]
+        no_header: bool,
+        #[arg(
+            short = 't',
+            long = "threads",
+            default_value_t = 1,
+            help = "Number of threads to filter and aggregate records on, 0 uses every core."
+        )]
+        threads: usize,
+    },
+    #[command(about = "Profile the columns of a csv file")]
+    Describe {
+        #[arg(help = "File to describe.")]
+        file: String,
+        #[arg(short = 'f', long = "format", help = "Output format: table or json (defaults to table).")]
+        format: Option<String>,
+        #[arg(short = 't', long = "top", default_value_t = 5, help = "Number of most common values to show.")]
+        top: usize,
+        #[arg(
+            short = 'd',
+            long = "delimiter",
+            help = "Field delimiter of the input file e.g. ';', '|' or 'tab' (detected from the file when not given)."
+        )]
+        delimiter: Option<String>,
+        #[arg(long = "no-header", action = clap::ArgAction::SetTrue,
+               help = "Input file has no header row, columns are named c1..cN.")]
+        no_header: bool,
+    },
+    #[command(about = "Interactive sql shell over csv files loaded into memory")]
+    Repl {
+        #[arg(required = true, help = "Files to load, each is queried by its name without the extension.")]
+        files: Vec<String>,
+        #[arg(
+            short = 'd',
+            long = "delimiter",
+            help = "Field delimiter of the input files e.g. ';', '|' or 'tab' (detected from the file when not given)."
+        )]
+        delimiter: Option<String>,
+        #[arg(long = "no-header", action = clap::ArgAction::SetTrue,
+               help = "Input files have no header row, columns are named c1..cN.")]
+        no_header: bool,
+    },
+    #[command(about = "Compare two versions of a csv file")]
+    Diff {
+        #[arg(help = "The old version of the file.")]
+        old: String,
+        #[arg(help = "The new version of the file.")]
+        new: String,
+        #[arg(short = 'k', long = "key", help = "Columns that identify a row in both files e.g. 'Rank' or 'Name,Platform'.")]
+        key: String,
+        #[arg(short = 'f', long = "format", help = "Output format: table or json (defaults to table).")]
+        format: Option<String>,
+        #[arg(
+            short = 'd',
+            long = "delimiter",
+            help = "Field delimiter of the input files e.g. ';', '|' or 'tab' (detected from the file when not given)."
+        )]
+        delimiter: Option<String>,
+        #[arg(long = "no-header", action = clap::ArgAction::SetTrue,
+               help = "Input files have no header row, columns are named c1..cN.")]
+        no_header: bool,
+    },
+    #[command(about = "Sort a csv file by one or more columns, spilling to disk for large files")]
+    Sort {
+        #[arg(help = "File to sort, - reads from stdin.")]
+        file: String,
+        #[arg(short = 'k', long = "key", help = "Columns to sort by e.g. 'Year:desc,Name'.")]
+        key: String,
+        #[arg(short = 'o', long = "output", help = "Output file path.")]
+        output: Option<String>,
+        #[arg(
+            short = 'f',
+            long = "format",
+            help = "Output format: csv, tsv, table, json, ndjson or markdown (defaults to the output file extension, or csv)."
+        )]
+        format: Option<String>,
+        #[arg(
+            long = "buffer-rows",
+            default_value_t = csv_utils::SORT_CHUNK_SIZE,
+            help = "Number of rows sorted in memory before they're written to a temporary file."
+        )]
+        buffer_rows: usize,
+        #[arg(
+            short = 'd',
+            long = "delimiter",
+            help = "Field delimiter of the input file e.g. ';', '|' or 'tab' (detected from the file when not given)."
+        )]
+        delimiter: Option<String>,
+        #[arg(long = "no-header", action = clap::ArgAction::SetTrue,
+               help = "Input file has no header row, columns are named c1..cN.")]
+        no_header: bool,
+    },
+    #[command(about = "Remove duplicate rows from a csv file")]
+    Dedupe {
+        #[arg(help = "File to dedupe, - reads from stdin.")]
+        file: String,
+        #[arg(short = 'k', long = "key", help = "Columns that identify a duplicate e.g. 'Name,Platform' (defaults to the whole row).")]
+        key: Option<String>,
+        #[arg(short = 'o', long = "output", help = "Output file path.")]
+        output: Option<String>,
+        #[arg(
+            short = 'f',
+            long = "format",
+            help = "Output format: csv, tsv, table, json, ndjson or markdown (defaults to the output file extension, or csv)."
+        )]
+        format: Option<String>,
+        #[arg(
+            short = 'd',
+            long = "delimiter",
+            help = "Field delimiter of the input file e.g. ';', '|' or 'tab' (detected from the file when not given)."
+        )]
+        delimiter: Option<String>,
+        #[arg(long = "no-header", action = clap::ArgAction::SetTrue,
+               help = "Input file has no header row, columns are named c1..cN.")]
+        no_header: bool,
+    },
+    #[command(about = "Split a csv file into several files by row count or column value")]
+    Split {
+        #[arg(help = "File to split, - reads from stdin.")]
+        file: String,
+        #[arg(short = 'r', long = "rows", conflicts_with = "by", required_unless_present = "by",
+              help = "Number of rows in each file.")]
+        rows: Option<usize>,
+        #[arg(short = 'b', long = "by", help = "Column whose values each get their own file.")]
+        by: Option<String>,
+        #[arg(short = 'o', long = "output-dir", help = "Directory to write the files to (defaults to the current directory).")]
+        output_dir: Option<String>,
+        #[arg(
+            short = 'd',
+            long = "delimiter",
+            help = "Field delimiter of the input file e.g. ';', '|' or 'tab' (detected from the file when not given)."
+        )]
+        delimiter: Option<String>,
+        #[arg(long = "no-header", action = clap::ArgAction::SetTrue,
+               help = "Input file has no header row, columns are named c1..cN.")]
+        no_header: bool,
+    },
+    #[command(about = "Concatenate csv files, lining up columns with the same name")]
+    Concat {
+        #[arg(required = true, help = "Files to concatenate.")]
+        files: Vec<String>,
+        #[arg(short = 'o', long = "output", help = "Output file path.")]
+        output: Option<String>,
+        #[arg(
+            short = 'f',
+            long = "format",
+            help = "Output format: csv, tsv, table, json, ndjson or markdown (defaults to the output file extension, or csv)."
+        )]
+        format: Option<String>,
+        #[arg(
+            short = 'd',
+            long = "delimiter",
+            help = "Field delimiter of the input files e.g. ';', '|' or 'tab' (detected from the file when not given)."
+        )]
+        delimiter: Option<String>,
+        #[arg(long = "no-header", action = clap::ArgAction::SetTrue,
+               help = "Input files have no header row, columns are named c1..cN.")]
+        no_header: bool,
+    },
+    #[command(about = "Convert between csv, tsv, json and ndjson")]
+    Convert {
+        #[arg(help = "File to convert, - reads from stdin.")]
+        file: String,
+        #[arg(short = 'o', long = "output", help = "Output file path.")]
+        output: Option<String>,
+        #[arg(
+            short = 'f',
+            long = "format",
+            help = "Output format: csv, tsv, table, json, ndjson or markdown (defaults to the output file extension, or csv)."
+        )]
+        format: Option<String>,
+        #[arg(
+            short = 'i',
+            long = "input-format",
+            help = "Input format: csv or json (defaults to the file extension, or csv)."
+        )]
+        input_format: Option<String>,
+        #[arg(
+            short = 'd',
+            long = "delimiter",
+            help = "Field delimiter of the input file e.g. ';', '|' or 'tab' (detected from the file when not given)."
+        )]
+        delimiter: Option<String>,
+        #[arg(long = "no-header", action = clap::ArgAction::SetTrue,
+               help = "Input file has no header row, columns are named c1..cN.")]
+        no_header: bool,
     },
 }
 
@@ -526,8 +881,47 @@ async fn main() {
                 url,
                 config,
                 output,
-            } => http_commands::http_request(method, url, config, output).await,
-            HttpCommands::Serve { port } => http_commands::http_serve(port).await,
+                headers,
+                data,
+                data_urlencode,
+                form,
+                query,
+                user,
+                bearer,
+                timeout,
+                max_redirects,
+                insecure,
+                include,
+            } => {
+                let options = http_commands::RequestOptions {
+                    headers,
+                    data,
+                    data_urlencode,
+                    form,
+                    query,
+                    user,
+                    bearer,
+                    timeout,
+                    max_redirects,
+                    insecure,
+                    include,
+                };
+                http_commands::http_request(method, url, config, output, options).await
+            }
+            HttpCommands::Serve {
+                port,
+                dir,
+                routes,
+                echo,
+                log,
+            } => http_commands::http_serve(port, dir, routes, echo, log).await,
+            HttpCommands::Proxy {
+                listen,
+                upstream,
+                record,
+                replay,
+                log,
+            } => http_commands::http_proxy(listen, upstream, record, replay, log).await,
         },
 
         Commands::Json(sub_command) => match sub_command {
@@ -535,10 +929,87 @@ async fn main() {
             JsonCommands::JwtDecode { c_args } => {
                 json_commands::json_decode_jwt(&input_utils::args_or_readline(c_args))
             }
+            JsonCommands::Query {
+                sql,
+                output,
+                format,
+            } => json_commands::json_query(sql, output, format).await,
         },
 
         Commands::Csv(sub_command) => match sub_command {
-            CsvCommands::Search { sql, output } => csv_commands::sql_search(sql, output).await,
+            CsvCommands::Search {
+                sql,
+                output,
+                format,
+                delimiter,
+                no_header,
+                threads,
+            } => csv_commands::sql_search(sql, output, format, delimiter, no_header, threads).await,
+            CsvCommands::Describe {
+                file,
+                format,
+                top,
+                delimiter,
+                no_header,
+            } => csv_commands::describe(file, format, top, delimiter, no_header).await,
+            CsvCommands::Repl {
+                files,
+                delimiter,
+                no_header,
+            } => csv_commands::repl(files, delimiter, no_header).await,
+            CsvCommands::Diff {
+                old,
+                new,
+                key,
+                format,
+                delimiter,
+                no_header,
+            } => csv_commands::diff(old, new, key, format, delimiter, no_header).await,
+            CsvCommands::Sort {
+                file,
+                key,
+                output,
+                format,
+                buffer_rows,
+                delimiter,
+                no_header,
+            } => {
+                csv_commands::sort(file, key, output, format, buffer_rows, delimiter, no_header).await
+            }
+            CsvCommands::Dedupe {
+                file,
+                key,
+                output,
+                format,
+                delimiter,
+                no_header,
+            } => csv_commands::dedupe(file, key, output, format, delimiter, no_header).await,
+            CsvCommands::Split {
+                file,
+                rows,
+                by,
+                output_dir,
+                delimiter,
+                no_header,
+            } => csv_commands::split(file, rows, by, output_dir, delimiter, no_header).await,
+            CsvCommands::Concat {
+                files,
+                output,
+                format,
+                delimiter,
+                no_header,
+            } => csv_commands::concat(files, output, format, delimiter, no_header).await,
+            CsvCommands::Convert {
+                file,
+                output,
+                format,
+                input_format,
+                delimiter,
+                no_header,
+            } => {
+                csv_commands::convert(file, output, format, input_format, delimiter, no_header)
+                    .await
+            }
         },
 
         Commands::Text(sub_command) => match sub_command {
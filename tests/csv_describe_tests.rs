@@ -0,0 +1,58 @@
+#[cfg(test)]
+mod tests {
+    use aid::csv_utils;
+
+    fn describe(file: &str) -> Vec<csv_utils::ColumnProfile> {
+        let (headers, records) =
+            csv_utils::load_table(file, &csv_utils::CsvOptions::default()).unwrap();
+        csv_utils::describe_table(&headers, &records, 2, 4)
+    }
+
+    #[test]
+    fn describe_column_types() {
+        let profiles = describe("./tests/data/members.csv");
+
+        let types: Vec<&str> = profiles.iter().map(|p| p.column_type.as_str()).collect();
+        assert_eq!(vec!["string", "int", "bool", "date", "float"], types);
+    }
+
+    #[test]
+    fn describe_numeric_column() {
+        let profiles = describe("./tests/data/members.csv");
+        let age = &profiles[1];
+
+        assert_eq!(1, age.nulls);
+        assert_eq!(3, age.distinct);
+        assert_eq!(Some("29".to_string()), age.min);
+        assert_eq!(Some("41".to_string()), age.max);
+        assert_eq!(Some(33.25), age.mean);
+        assert!((age.stddev.unwrap() - 5.6789).abs() < 0.0001);
+
+        // The most common value comes first, ties are broken by value
+        assert_eq!("29", age.top[0].value);
+        assert_eq!(2, age.top[0].count);
+        assert_eq!("34", age.top[1].value);
+
+        let counts: Vec<usize> = age.histogram.iter().map(|bin| bin.count).collect();
+        assert_eq!(vec![2, 1, 0, 1], counts);
+        assert_eq!(29.0, age.histogram[0].start);
+        assert_eq!(41.0, age.histogram[3].end);
+    }
+
+    #[test]
+    fn describe_other_columns() {
+        let profiles = describe("./tests/data/members.csv");
+
+        // Dates are ordered as dates, strings by their text
+        let joined = &profiles[3];
+        assert_eq!(Some("2019-11-30".to_string()), joined.min);
+        assert_eq!(Some("2022-06-01".to_string()), joined.max);
+        assert_eq!(None, joined.mean);
+        assert!(joined.histogram.is_empty());
+
+        let score = &profiles[4];
+        assert_eq!(1, score.nulls);
+        assert_eq!(Some("6".to_string()), score.min);
+        assert_eq!(Some("9.25".to_string()), score.max);
+    }
+}
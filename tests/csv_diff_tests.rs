@@ -0,0 +1,55 @@
+use aid::csv_utils::{self, CsvOptions, TableDiff};
+use std::error::Error;
+
+pub fn diff(old: &str, new: &str, keys: &[&str]) -> Result<TableDiff, Box<dyn Error>> {
+    let (old_headers, old_records) = csv_utils::load_table(old, &CsvOptions::default())?;
+    let (new_headers, new_records) = csv_utils::load_table(new, &CsvOptions::default())?;
+    let keys: Vec<String> = keys.iter().map(|key| key.to_string()).collect();
+    csv_utils::diff_tables(&old_headers, &old_records, &new_headers, &new_records, &keys)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn diff_added_removed_and_changed_rows() {
+        let diff = diff("./tests/data/orders.csv", "./tests/data/orders_new.csv", &["OrderId"]).unwrap();
+
+        assert_eq!(1, diff.summary.added);
+        assert_eq!(1, diff.summary.removed);
+        assert_eq!(2, diff.summary.changed);
+        // Rows 4 and 6 swapped places but are otherwise the same
+        assert_eq!(3, diff.summary.unchanged);
+        assert_eq!(vec!["Status"], diff.added_columns);
+        assert!(diff.removed_columns.is_empty());
+
+        assert_eq!("7", diff.added[0]["OrderId"]);
+        assert_eq!("pending", diff.added[0]["Status"]);
+        assert_eq!("Dave", diff.removed[0]["Customer"]);
+
+        let changed = &diff.changed[1];
+        assert_eq!("3", changed.key["OrderId"]);
+        let changes: Vec<(&str, &str, &str)> = changed
+            .changes
+            .iter()
+            .map(|change| (change.column.as_str(), change.old.as_str(), change.new.as_str()))
+            .collect();
+        assert_eq!(vec![("Amount", "89.99", "95.00"), ("Discount", "5", "7.5")], changes);
+    }
+
+    #[test]
+    fn diff_identical_files() {
+        let diff = diff("./vgsales.csv", "./vgsales.csv", &["Rank"]).unwrap();
+
+        assert_eq!(16598, diff.summary.unchanged);
+        assert!(diff.added.is_empty() && diff.removed.is_empty() && diff.changed.is_empty());
+    }
+
+    #[test]
+    fn diff_requires_unique_keys() {
+        let result = diff("./vgsales.csv", "./vgsales.csv", &["Platform"]);
+
+        assert!(result.unwrap_err().to_string().contains("Duplicate key 'Wii'"));
+    }
+}
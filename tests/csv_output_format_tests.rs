@@ -0,0 +1,98 @@
+use aid::csv_commands::{OutputFormat, ResultOutput};
+use aid::csv_utils::RowSink;
+use std::fs;
+
+pub fn write_results(format: OutputFormat, name: &str) -> String {
+    let path = std::env::temp_dir().join(format!("aid_csv_output_{}", name));
+    let path = path.to_string_lossy().to_string();
+
+    let mut output = ResultOutput::new(Some(path.clone()), format);
+    output
+        .write_headers(&["Name".to_string(), "Sales".to_string(), "Note".to_string()])
+        .unwrap();
+    output
+        .write_row(vec!["Hey You, Pikachu!".to_string(), "1.83".to_string(), "".to_string()])
+        .unwrap();
+    output
+        .write_row(vec!["Tetris".to_string(), "30.26".to_string(), "a|b".to_string()])
+        .unwrap();
+    output.finish().unwrap();
+
+    let contents = fs::read_to_string(&path).unwrap();
+    let _ = fs::remove_file(&path);
+    contents
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn format_from_name_and_path() {
+        assert_eq!(Some(OutputFormat::Ndjson), OutputFormat::parse("NDJSON"));
+        assert_eq!(Some(OutputFormat::Markdown), OutputFormat::parse("md"));
+        assert_eq!(None, OutputFormat::parse("xml"));
+        assert_eq!(OutputFormat::Json, OutputFormat::from_path(Some("out.json")));
+        assert_eq!(OutputFormat::Csv, OutputFormat::from_path(Some("out.txt")));
+        assert_eq!(OutputFormat::Csv, OutputFormat::from_path(None));
+    }
+
+    #[test]
+    fn csv_output_is_quoted() {
+        let contents = write_results(OutputFormat::Csv, "quoted.csv");
+        assert_eq!(
+            "Name,Sales,Note\n\"Hey You, Pikachu!\",1.83,\nTetris,30.26,a|b\n",
+            contents
+        );
+    }
+
+    #[test]
+    fn tsv_output() {
+        let contents = write_results(OutputFormat::Tsv, "out.tsv");
+        assert_eq!(
+            "Name\tSales\tNote\nHey You, Pikachu!\t1.83\t\nTetris\t30.26\ta|b\n",
+            contents
+        );
+    }
+
+    #[test]
+    fn json_output() {
+        let contents = write_results(OutputFormat::Json, "out.json");
+        let parsed: serde_json::Value = serde_json::from_str(&contents).unwrap();
+
+        assert_eq!("Hey You, Pikachu!", parsed[0]["Name"]);
+        assert_eq!(1.83, parsed[0]["Sales"]);
+        assert!(parsed[0]["Note"].is_null());
+        assert_eq!(2, parsed.as_array().unwrap().len());
+    }
+
+    #[test]
+    fn ndjson_output() {
+        let contents = write_results(OutputFormat::Ndjson, "out.ndjson");
+        let lines: Vec<&str> = contents.lines().collect();
+
+        assert_eq!(
+            vec![
+                "{\"Name\":\"Hey You, Pikachu!\",\"Sales\":1.83,\"Note\":null}",
+                "{\"Name\":\"Tetris\",\"Sales\":30.26,\"Note\":\"a|b\"}"
+            ],
+            lines
+        );
+    }
+
+    #[test]
+    fn markdown_output() {
+        let contents = write_results(OutputFormat::Markdown, "out.md");
+        assert_eq!(
+            "| Name | Sales | Note |\n| --- | --- | --- |\n| Hey You, Pikachu! | 1.83 |  |\n| Tetris | 30.26 | a\\|b |\n",
+            contents
+        );
+    }
+
+    #[test]
+    fn table_output() {
+        let contents = write_results(OutputFormat::Table, "out.txt");
+        assert!(contents.contains("| Name              | Sales | Note |"));
+        assert!(contents.contains("| Hey You, Pikachu! | 1.83  |      |"));
+    }
+}
@@ -0,0 +1,75 @@
+use aid::csv_utils::{self, CsvOptions, QueryResults};
+
+pub fn query(sql: &str, threads: usize) -> QueryResults {
+    let query = csv_utils::parse_query(sql).unwrap();
+    let options = CsvOptions {
+        threads,
+        ..CsvOptions::default()
+    };
+
+    let mut results = QueryResults::default();
+    csv_utils::execute_query(&query, &options, &mut results).unwrap();
+    results
+}
+
+// Run a query on a single thread and on several, the results should be the same
+pub fn assert_parallel_matches(sql: &str) -> QueryResults {
+    let single = query(sql, 1);
+    let parallel = query(sql, 4);
+
+    assert_eq!(single.headers, parallel.headers);
+    assert_eq!(single.rows, parallel.rows, "{}", sql);
+    parallel
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parallel_filter_keeps_record_order() {
+        let results = assert_parallel_matches("SELECT Rank, Name, Platform FROM vgsales.csv WHERE Genre = 'Sports' AND Year >= 2000");
+        assert_eq!(vec!["1", "Wii Sports", "Wii"], results.rows[0]);
+
+        let results = assert_parallel_matches("SELECT Name FROM vgsales.csv WHERE Platform = 'PS2' LIMIT 5 OFFSET 2000");
+        assert_eq!(5, results.rows.len());
+
+        assert_parallel_matches("SELECT DISTINCT Publisher FROM vgsales.csv WHERE Year > 2010");
+        assert_parallel_matches("SELECT Name, Global_Sales * 2 AS Double FROM vgsales.csv ORDER BY Global_Sales DESC, Name LIMIT 20");
+    }
+
+    #[test]
+    fn parallel_aggregates_merge_groups() {
+        let results = assert_parallel_matches(
+            "SELECT Platform, Name, COUNT(*), COUNT(DISTINCT Genre), MIN(Year), MAX(Name), MEDIAN(Rank), GROUP_CONCAT(Rank, ';') \
+             FROM vgsales.csv WHERE Year >= 2000 GROUP BY Platform HAVING COUNT(*) > 10",
+        );
+        assert_eq!("Wii", results.rows[0][0]);
+        assert_eq!("Wii Sports", results.rows[0][1]);
+
+        let results = assert_parallel_matches("SELECT COUNT(*) FROM vgsales.csv WHERE Genre = 'Not a genre'");
+        assert_eq!(vec![vec!["0"]], results.rows);
+
+        assert_parallel_matches(
+            "SELECT Genre, ROW_NUMBER() OVER (PARTITION BY Genre ORDER BY Year DESC) AS Latest FROM vgsales.csv \
+             WHERE Year > 2015 QUALIFY Latest = 1",
+        );
+    }
+
+    #[test]
+    fn parallel_sums_match_single_thread() {
+        let sql = "SELECT Genre, SUM(Global_Sales), AVG(NA_Sales), STDDEV(EU_Sales) FROM vgsales.csv GROUP BY Genre ORDER BY Genre";
+        let single = query(sql, 1);
+        let parallel = query(sql, 3);
+
+        assert_eq!(single.rows.len(), parallel.rows.len());
+        // Floating point sums can differ in the last digits as they're added in a different order
+        for (single, parallel) in single.rows.iter().zip(&parallel.rows) {
+            assert_eq!(single[0], parallel[0]);
+            for (a, b) in single[1..].iter().zip(&parallel[1..]) {
+                let (a, b) = (a.parse::<f64>().unwrap(), b.parse::<f64>().unwrap());
+                assert!((a - b).abs() < 1e-6, "{} != {}", a, b);
+            }
+        }
+    }
+}
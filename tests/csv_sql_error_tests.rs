@@ -0,0 +1,86 @@
+#[cfg(test)]
+mod tests {
+    use aid::csv_utils;
+
+    fn parse_error(sql: &str) -> csv_utils::ParseError {
+        match csv_utils::parse_query(sql) {
+            Ok(query) => panic!("Expected '{}' to fail, parsed {:?}", sql, query),
+            Err(e) => e,
+        }
+    }
+
+    fn query_error(sql: &str) -> String {
+        let query = csv_utils::parse_query(sql).unwrap();
+        let mut results = csv_utils::QueryResults::default();
+        match csv_utils::execute_query(&query, &csv_utils::CsvOptions::default(), &mut results) {
+            Ok(_) => panic!("Expected '{}' to fail", sql),
+            Err(e) => e.to_string(),
+        }
+    }
+
+    #[test]
+    fn parse_error_position() {
+        let error = parse_error("SELECT Name FROM vgsales.csv WHERE Year >");
+        assert_eq!("Unexpected '>', expected a condition", error.message);
+        assert_eq!(40, error.position);
+        assert_eq!(
+            "Unexpected '>', expected a condition at column 41\n  SELECT Name FROM vgsales.csv WHERE Year >\n                                          ^",
+            error.to_string()
+        );
+
+        let error = parse_error("SELECT Name,, Platform FROM vgsales.csv");
+        assert_eq!("Unexpected ',', expected ',' or FROM", error.message);
+        assert_eq!(11, error.position);
+
+        let error = parse_error("SELECT Name FROM vgsales.csv LIMIT ten");
+        assert_eq!("Unexpected 'ten', expected a number", error.message);
+
+        let error = parse_error("SELECT Name\nFROM vgsales.csv WHERE Year = 2000 AND");
+        assert!(error.to_string().starts_with(
+            "Unexpected end of query, expected a condition at line 2, column 39"
+        ));
+    }
+
+    #[test]
+    fn reject_trailing_input() {
+        let error = parse_error("SELECT Name FROM vgsales.csv LIMIT 5 ORDER BY Name");
+        assert_eq!(
+            "Unexpected 'ORDER', expected OFFSET or the end of the query",
+            error.message
+        );
+        assert_eq!(37, error.position);
+
+        let error = parse_error("SELECT Name FROM vgsales.csv WHERE Year = 2000 garbage");
+        assert_eq!(
+            "Unexpected 'garbage', expected GROUP BY, HAVING, QUALIFY, ORDER BY, LIMIT, OFFSET or the end of the query",
+            error.message
+        );
+
+        // A trailing semicolon is fine
+        assert!(csv_utils::parse_query("SELECT Name FROM vgsales.csv LIMIT 5;").is_ok());
+    }
+
+    #[test]
+    fn unknown_columns() {
+        assert_eq!(
+            "Unknown column 'Nmae', did you mean 'Name'?",
+            query_error("SELECT Nmae FROM vgsales.csv")
+        );
+        assert_eq!(
+            "Unknown column 'platfrom', did you mean 'Platform'?",
+            query_error("SELECT Name FROM vgsales.csv WHERE platfrom = 'Wii'")
+        );
+        assert_eq!(
+            "Unknown column 'Sales', the available columns are: OrderId, Date, Customer, Amount, Discount",
+            query_error("SELECT SUM(Sales) FROM ./tests/data/orders.csv")
+        );
+        assert_eq!(
+            "Unknown column 'Yaer', did you mean 'Year'?",
+            query_error("SELECT Name FROM vgsales.csv ORDER BY Yaer")
+        );
+        assert_eq!(
+            "Column 'Platform' is ambiguous, it could be any of: v.Platform, p.Platform",
+            query_error("SELECT Platform FROM vgsales.csv v INNER JOIN ./tests/data/platforms.csv p ON v.Platform = p.Platform")
+        );
+    }
+}
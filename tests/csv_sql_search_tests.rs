@@ -1,10 +1,18 @@
 use aid::csv_utils;
 use std::io;
+use std::sync::Arc;
 
 pub fn load_and_query(sql: &str) -> Result<(Vec<String>, Vec<Vec<String>>), io::Error> {
+    load_and_query_with_options(sql, &csv_utils::CsvOptions::default())
+}
+
+pub fn load_and_query_with_options(
+    sql: &str,
+    options: &csv_utils::CsvOptions,
+) -> Result<(Vec<String>, Vec<Vec<String>>), io::Error> {
     // Use the input SQL parameter instead of hardcoded SQL
-    let parsed = match csv_utils::parse_sql(sql) {
-        Ok(result) => result,
+    let query = match csv_utils::parse_query(sql) {
+        Ok(query) => query,
         Err(e) => {
             return Err(io::Error::new(
                 io::ErrorKind::InvalidData,
@@ -13,22 +21,13 @@ pub fn load_and_query(sql: &str) -> Result<(Vec<String>, Vec<Vec<String>>), io::
         }
     };
 
-    let query = parsed.1;
-
-    // Load the CSV file
-    let (headers, records) = match csv_utils::load_csv(&query.table) {
-        Ok(result) => result,
-        Err(e) => {
-            eprintln!();
-            return Err(io::Error::new(
-                io::ErrorKind::InvalidData,
-                format!("Failed to load CSV file '{}': {}", query.table, e),
-            ));
-        }
-    };
+    // Stream the CSV file(s) through the query
+    let mut results = csv_utils::QueryResults::default();
+    if let Err(e) = csv_utils::execute_query(&query, options, &mut results) {
+        return Err(io::Error::new(io::ErrorKind::InvalidData, e.to_string()));
+    }
 
-    // Apply the query
-    Ok(csv_utils::apply_query(&headers, records, &query))
+    Ok((results.headers, results.rows))
 }
 
 #[cfg(test)]
@@ -262,7 +261,7 @@ mod tests {
                     "3316",
                     "1751.1799999999691",
                     "0.01",
-                    "8.24",
+                    "21.4",
                     "0.5281001206272524"
                 ],
                 data[0]
@@ -281,4 +280,705 @@ mod tests {
             assert_eq!(12, data.len());
         }
     }
+
+    #[test]
+    fn select_min_max_compare_numbers() {
+        let sql = "SELECT MAX(Global_Sales), MIN(Global_Sales), MAX(Rank), MIN(Name) FROM vgsales.csv";
+        let expected = vec![vec!["82.74", "0.01", "16600", "'98 Koshien"]];
+        let (_, data) = load_and_query(sql).unwrap();
+        assert_eq!(expected, data);
+
+        let options = csv_utils::CsvOptions {
+            threads: 4,
+            ..csv_utils::CsvOptions::default()
+        };
+        let (_, data) = load_and_query_with_options(sql, &options).unwrap();
+        assert_eq!(expected, data);
+
+        let (_, data) = load_and_query(
+            "SELECT Name FROM vgsales.csv WHERE Global_Sales = (SELECT MAX(Global_Sales) FROM vgsales.csv)",
+        )
+        .unwrap();
+        assert_eq!(vec![vec!["Wii Sports"]], data);
+
+        let (_, data) =
+            load_and_query("SELECT Platform FROM vgsales.csv GROUP BY Platform HAVING MAX(Global_Sales) > 40 ORDER BY Platform")
+                .unwrap();
+        assert_eq!(vec![vec!["NES"], vec!["Wii"]], data);
+    }
+
+    #[test]
+    fn select_aggregates_skip_non_numeric_values() {
+        // Year has 'N/A' for some games, which shouldn't count towards the average
+        let (_, data) = load_and_query(
+            "SELECT AVG(Year), COUNT(Year) FROM vgsales.csv WHERE Platform = 'PS2'",
+        )
+        .unwrap();
+        let (_, numeric) = load_and_query(
+            "SELECT AVG(Year), COUNT(Year) FROM vgsales.csv WHERE Platform = 'PS2' AND Year <> 'N/A'",
+        )
+        .unwrap();
+        assert_eq!(numeric[0][0], data[0][0]);
+        assert!(data[0][1].parse::<usize>().unwrap() > numeric[0][1].parse::<usize>().unwrap());
+
+        let (_, data) = load_and_query("SELECT AVG(Year) FROM vgsales.csv").unwrap();
+        assert_eq!("2006.4064433147546", data[0][0]);
+    }
+
+    #[test]
+    fn select_inner_join() {
+        let result = load_and_query("SELECT COUNT(*) FROM ./vgsales.csv v INNER JOIN ./tests/data/platforms.csv p ON v.Platform = p.Platform");
+
+        if let Err(e) = &result {
+            eprint!("{}", e);
+            assert!(!result.is_err());
+        }
+
+        if let Ok((headers, data)) = result {
+            assert_eq!(vec!["COUNT(*)"], headers);
+            assert_eq!(vec!["16567"], data[0]);
+            assert_eq!(1, data.len());
+        }
+    }
+
+    #[test]
+    fn select_left_join() {
+        let result = load_and_query("SELECT v.Name, p.Manufacturer FROM ./vgsales.csv v LEFT JOIN ./tests/data/platforms.csv p ON v.Platform = p.Platform WHERE v.Platform = 'GG' OR v.Platform = 'PCFX'");
+
+        if let Err(e) = &result {
+            eprint!("{}", e);
+            assert!(!result.is_err());
+        }
+
+        if let Ok((headers, data)) = result {
+            assert_eq!(vec!["v.Name", "p.Manufacturer"], headers);
+            assert_eq!(vec!["Sonic the Hedgehog 2 (8-bit)", ""], data[0]);
+            assert_eq!(vec!["Blue Breaker: Ken Yorimo Hohoemi o", ""], data[1]);
+            assert_eq!(2, data.len());
+        }
+    }
+
+    #[test]
+    fn select_join_group_by() {
+        let result = load_and_query("SELECT Manufacturer, COUNT(v.Name) FROM ./vgsales.csv v JOIN ./tests/data/platforms.csv p ON p.Platform = v.Platform GROUP BY Manufacturer ORDER BY COUNT(v.Name) DESC");
+
+        if let Err(e) = &result {
+            eprint!("{}", e);
+            assert!(!result.is_err());
+        }
+
+        if let Ok((headers, data)) = result {
+            assert_eq!(vec!["Manufacturer", "COUNT(v.Name)"], headers);
+            assert_eq!(vec!["Sony", "6648"], data[0]);
+            assert_eq!(vec!["Atari", "133"], data[5]);
+            assert_eq!(6, data.len());
+        }
+    }
+
+    #[test]
+    fn apply_query_in_memory() {
+        let (_, query) = csv_utils::parse_sql(
+            "SELECT Platform, COUNT(Name) FROM vgsales.csv WHERE Year = 2015 GROUP BY Platform ORDER BY COUNT(Name) DESC",
+        )
+        .unwrap();
+        let (headers, records) = csv_utils::load_tables(&query, &csv_utils::CsvOptions::default()).unwrap();
+        let csv_utils::QueryResults {
+            headers: result_headers,
+            rows: data,
+        } = csv_utils::apply_query(&headers, records, &query).unwrap();
+
+        assert_eq!(vec!["Platform", "COUNT(Name)"], result_headers);
+        assert_eq!(
+            load_and_query("SELECT Platform, COUNT(Name) FROM vgsales.csv WHERE Year = 2015 GROUP BY Platform ORDER BY COUNT(Name) DESC")
+                .unwrap()
+                .1,
+            data
+        );
+        assert_eq!(vec!["PS4", "137"], data[0]);
+    }
+
+    #[test]
+    fn select_float_comparison() {
+        let result = load_and_query("SELECT Name FROM vgsales.csv WHERE Global_Sales > 30.5 AND Platform != 'Wii'");
+
+        if let Err(e) = &result {
+            eprint!("{}", e);
+            assert!(!result.is_err());
+        }
+
+        if let Ok((headers, data)) = result {
+            assert_eq!(vec!["Name"], headers);
+            assert_eq!(vec!["Super Mario Bros."], data[0]);
+            assert_eq!(vec!["Pokemon Red/Pokemon Blue"], data[1]);
+            assert_eq!(2, data.len());
+        }
+    }
+
+    #[test]
+    fn select_negative_and_null_comparison() {
+        let result = load_and_query("SELECT OrderId FROM ./tests/data/orders.csv WHERE Amount < 0 OR Discount <> 0");
+
+        if let Err(e) = &result {
+            eprint!("{}", e);
+            assert!(!result.is_err());
+        }
+
+        if let Ok((headers, data)) = result {
+            assert_eq!(vec!["OrderId"], headers);
+            // Empty discounts are NULL so are neither equal or unequal to 0
+            assert_eq!(vec![vec!["2"], vec!["3"], vec!["4"], vec!["6"]], data);
+        }
+    }
+
+    #[test]
+    fn select_not_of_null_comparison() {
+        // NOT of an unknown comparison is still unknown, so NOT matches the same rows as <>
+        let (_, not_equal) = load_and_query("SELECT OrderId FROM ./tests/data/orders.csv WHERE Discount <> 0").unwrap();
+        let (_, not) = load_and_query("SELECT OrderId FROM ./tests/data/orders.csv WHERE NOT Discount = 0").unwrap();
+        assert_eq!(vec![vec!["3"], vec!["4"], vec!["6"]], not_equal);
+        assert_eq!(not_equal, not);
+
+        // Unless the rest of the condition decides it, e.g. NULL AND false is false
+        let (_, data) = load_and_query(
+            "SELECT OrderId FROM ./tests/data/orders.csv WHERE NOT (Discount = 0 AND Amount > 0)",
+        )
+        .unwrap();
+        assert_eq!(vec![vec!["2"], vec!["3"], vec!["4"], vec!["6"]], data);
+    }
+
+    #[test]
+    fn select_string_comparison() {
+        let result = load_and_query("SELECT Customer FROM ./tests/data/orders.csv WHERE Customer >= 'B' AND Customer < 'E'");
+
+        if let Err(e) = &result {
+            eprint!("{}", e);
+            assert!(!result.is_err());
+        }
+
+        if let Ok((headers, data)) = result {
+            assert_eq!(vec!["Customer"], headers);
+            assert_eq!(vec![vec!["Bob"], vec!["Carol"], vec!["Dave"]], data);
+        }
+    }
+
+    #[test]
+    fn select_date_comparison() {
+        let result = load_and_query("SELECT OrderId FROM ./tests/data/orders.csv WHERE Date >= '2020-01-01' AND Date BETWEEN '2020-01-01' AND '2020-12-31'");
+
+        if let Err(e) = &result {
+            eprint!("{}", e);
+            assert!(!result.is_err());
+        }
+
+        if let Ok((headers, data)) = result {
+            assert_eq!(vec!["OrderId"], headers);
+            assert_eq!(vec![vec!["2"], vec!["3"], vec!["4"], vec!["5"]], data);
+        }
+    }
+
+    #[test]
+    fn select_limit_offset() {
+        let result = load_and_query("SELECT Rank FROM vgsales.csv LIMIT 2 OFFSET 5");
+
+        if let Err(e) = &result {
+            eprint!("{}", e);
+            assert!(!result.is_err());
+        }
+
+        if let Ok((headers, data)) = result {
+            assert_eq!(vec!["Rank"], headers);
+            assert_eq!(vec![vec!["6"], vec!["7"]], data);
+        }
+    }
+
+    #[test]
+    fn select_order_by_multiple_columns() {
+        let result = load_and_query("SELECT Name, Year FROM vgsales.csv WHERE Platform = 'GB' ORDER BY Year ASC, Name DESC LIMIT 3 OFFSET 2");
+
+        if let Err(e) = &result {
+            eprint!("{}", e);
+            assert!(!result.is_err());
+        }
+
+        if let Ok((headers, data)) = result {
+            assert_eq!(vec!["Name", "Year"], headers);
+            assert_eq!(vec!["The Final Fantasy Legend", "1989"], data[0]);
+            assert_eq!(vec!["Tetris", "1989"], data[1]);
+            assert_eq!(vec!["Tennis", "1989"], data[2]);
+            assert_eq!(3, data.len());
+        }
+    }
+
+    #[test]
+    fn select_order_by_limit_matches_full_sort() {
+        let limited = load_and_query("SELECT Name, Year FROM vgsales.csv ORDER BY Year DESC, Genre ASC LIMIT 25 OFFSET 10").unwrap();
+        let sorted = load_and_query("SELECT Name, Year FROM vgsales.csv ORDER BY Year DESC, Genre ASC").unwrap();
+
+        assert_eq!(sorted.1[10..35].to_vec(), limited.1);
+    }
+
+    #[test]
+    fn select_group_by_top_n() {
+        let result = load_and_query("SELECT Publisher, SUM(Global_Sales) FROM vgsales.csv GROUP BY Publisher ORDER BY SUM(Global_Sales) DESC, Publisher ASC LIMIT 3");
+
+        if let Err(e) = &result {
+            eprint!("{}", e);
+            assert!(!result.is_err());
+        }
+
+        if let Ok((headers, data)) = result {
+            assert_eq!(vec!["Publisher", "SUM(Global_Sales)"], headers);
+            assert_eq!("Nintendo", data[0][0]);
+            assert_eq!("Electronic Arts", data[1][0]);
+            assert_eq!("Activision", data[2][0]);
+            assert_eq!(3, data.len());
+        }
+    }
+
+    #[test]
+    fn select_group_by_having_with_aliases() {
+        let result = load_and_query("SELECT Genre, COUNT(*) AS games, SUM(Global_Sales) total FROM ./vgsales.csv GROUP BY Genre HAVING COUNT(*) > 1500 ORDER BY total DESC");
+
+        if let Err(e) = &result {
+            eprint!("{}", e);
+            assert!(!result.is_err());
+        }
+
+        if let Ok((headers, data)) = result {
+            assert_eq!(vec!["Genre", "games", "total"], headers);
+            assert_eq!(vec!["Action", "3316"], data[0][..2].to_vec());
+            assert_eq!(vec!["Sports", "2346"], data[1][..2].to_vec());
+            assert_eq!(vec!["Misc", "1739"], data[2][..2].to_vec());
+            assert_eq!(3, data.len());
+        }
+    }
+
+    #[test]
+    fn select_having_unselected_aggregate() {
+        let result = load_and_query("SELECT Genre FROM ./vgsales.csv GROUP BY Genre HAVING SUM(Global_Sales) > 1000 ORDER BY COUNT(*)");
+
+        if let Err(e) = &result {
+            eprint!("{}", e);
+            assert!(!result.is_err());
+        }
+
+        if let Ok((headers, data)) = result {
+            assert_eq!(vec!["Genre"], headers);
+            assert_eq!(vec![vec!["Shooter"], vec!["Sports"], vec!["Action"]], data);
+        }
+    }
+
+    #[test]
+    fn select_extended_aggregates() {
+        let result = load_and_query("SELECT Customer, COUNT(DISTINCT Customer), MEDIAN(Amount), STDDEV(Amount), GROUP_CONCAT(OrderId, ';') FROM ./tests/data/orders.csv");
+
+        if let Err(e) = &result {
+            eprint!("{}", e);
+            assert!(!result.is_err());
+        }
+
+        if let Ok((headers, data)) = result {
+            assert_eq!(
+                vec![
+                    "Customer",
+                    "COUNT(DISTINCT Customer)",
+                    "MEDIAN(Amount)",
+                    "STDDEV(Amount)",
+                    "GROUP_CONCAT(OrderId, ';')"
+                ],
+                headers
+            );
+            assert_eq!("6", data[0][1]);
+            assert_eq!("65.995", data[0][2]);
+            assert_eq!("1;2;3;4;5;6", data[0][4]);
+            assert_eq!(1, data.len());
+        }
+    }
+
+    #[test]
+    fn select_computed_columns() {
+        let result = load_and_query("SELECT Name, NA_Sales + EU_Sales AS west, UPPER(Platform), LENGTH(Name) len, SUBSTR(Name, 1, 5), CONCAT(Name, ' (', Year, ')') FROM ./vgsales.csv LIMIT 2");
+
+        if let Err(e) = &result {
+            eprint!("{}", e);
+            assert!(!result.is_err());
+        }
+
+        if let Ok((headers, data)) = result {
+            assert_eq!(
+                vec![
+                    "Name",
+                    "west",
+                    "UPPER(Platform)",
+                    "len",
+                    "SUBSTR(Name, 1, 5)",
+                    "CONCAT(Name, ' (', Year, ')')"
+                ],
+                headers
+            );
+            assert_eq!(
+                vec!["Wii Sports", "70.51", "WII", "10", "Wii S", "Wii Sports (2006)"],
+                data[0]
+            );
+            assert_eq!(2, data.len());
+        }
+    }
+
+    #[test]
+    fn select_case_coalesce_and_cast() {
+        let result = load_and_query("SELECT Customer, CASE WHEN Amount >= 100 THEN 'large' WHEN Amount < 0 THEN 'refund' ELSE 'small' END AS size, COALESCE(Discount, 0) discount, CAST(Amount AS INTEGER), Amount / 0 FROM ./tests/data/orders.csv");
+
+        if let Err(e) = &result {
+            eprint!("{}", e);
+            assert!(!result.is_err());
+        }
+
+        if let Ok((headers, data)) = result {
+            assert_eq!(
+                vec!["Customer", "size", "discount", "CAST(Amount AS INTEGER)", "Amount / 0"],
+                headers
+            );
+            assert_eq!(vec!["Alice", "large", "0", "120", ""], data[0]);
+            assert_eq!(vec!["Bob", "refund", "0", "-15", ""], data[1]);
+            assert_eq!(vec!["Carol", "small", "5", "89", ""], data[2]);
+            assert_eq!(6, data.len());
+        }
+    }
+
+    #[test]
+    fn select_aggregate_expression() {
+        let result = load_and_query("SELECT Genre, SUM(Global_Sales) / COUNT(*) AS average FROM ./vgsales.csv GROUP BY Genre ORDER BY average DESC LIMIT 2");
+
+        if let Err(e) = &result {
+            eprint!("{}", e);
+            assert!(!result.is_err());
+        }
+
+        if let Ok((headers, data)) = result {
+            assert_eq!(vec!["Genre", "average"], headers);
+            assert_eq!("Platform", data[0][0]);
+            assert_eq!("Shooter", data[1][0]);
+            assert_eq!(2, data.len());
+        }
+    }
+
+    #[test]
+    fn select_in_and_not_in() {
+        let included = load_and_query("SELECT COUNT(*) FROM ./vgsales.csv WHERE Platform IN ('Wii', PS4)").unwrap();
+        let excluded = load_and_query("SELECT COUNT(*) FROM ./vgsales.csv WHERE Platform NOT IN ('Wii', PS4)").unwrap();
+
+        assert_eq!(vec![vec!["1661"]], included.1);
+        assert_eq!(vec![vec!["14937"]], excluded.1);
+    }
+
+    #[test]
+    fn select_is_null_and_not_like() {
+        let result = load_and_query("SELECT Customer FROM ./tests/data/orders.csv WHERE Discount IS NULL OR (Discount IS NOT NULL AND Customer NOT LIKE 'E%')");
+
+        if let Err(e) = &result {
+            eprint!("{}", e);
+            assert!(!result.is_err());
+        }
+
+        if let Ok((headers, data)) = result {
+            assert_eq!(vec!["Customer"], headers);
+            assert_eq!(
+                vec![vec!["Alice"], vec!["Bob"], vec!["Carol"], vec!["alice"], vec!["Dave"]],
+                data
+            );
+        }
+    }
+
+    #[test]
+    fn select_in_subquery() {
+        let result = load_and_query("SELECT Platform, Manufacturer FROM ./tests/data/platforms.csv WHERE Platform IN (SELECT DISTINCT Platform FROM ./vgsales.csv WHERE Year = 2016) ORDER BY Platform");
+
+        if let Err(e) = &result {
+            eprint!("{}", e);
+            assert!(!result.is_err());
+        }
+
+        if let Ok((headers, data)) = result {
+            assert_eq!(vec!["Platform", "Manufacturer"], headers);
+            assert_eq!(vec!["3DS", "Nintendo"], data[0]);
+            assert_eq!(vec!["XOne", "Microsoft"], data[7]);
+            assert_eq!(8, data.len());
+        }
+    }
+
+    #[test]
+    fn select_scalar_subquery() {
+        let result = load_and_query("SELECT Name FROM ./vgsales.csv WHERE Global_Sales > (SELECT AVG(Global_Sales) FROM ./vgsales.csv) AND Year = 2016 LIMIT 2");
+
+        if let Err(e) = &result {
+            eprint!("{}", e);
+            assert!(!result.is_err());
+        }
+
+        if let Ok((_, data)) = result {
+            assert_eq!(vec![vec!["FIFA 17"], vec!["Uncharted 4: A Thief's End"]], data);
+        }
+
+        let result = load_and_query("SELECT Name FROM ./vgsales.csv WHERE Global_Sales > (SELECT Global_Sales FROM ./vgsales.csv)");
+        assert!(result.is_err());
+    }
+
+    #[test]
+    fn select_detected_delimiters() {
+        let expected = vec![vec!["Alice", "120.50"], vec!["Carol", "89.99"], vec!["alice", "1500"]];
+
+        let semicolon = load_and_query("SELECT Customer, Amount FROM ./tests/data/orders_semicolon.csv WHERE Amount > 50").unwrap();
+        assert_eq!(vec!["Customer", "Amount"], semicolon.0);
+        assert_eq!(expected, semicolon.1);
+
+        // Gzipped tab separated file, the delimiter comes from the extension before .gz
+        let gzipped = load_and_query("SELECT Customer, Amount FROM ./tests/data/orders.tsv.gz WHERE Amount > 50").unwrap();
+        assert_eq!(vec!["Customer", "Amount"], gzipped.0);
+        assert_eq!(expected, gzipped.1);
+    }
+
+    #[test]
+    fn select_without_header() {
+        let options = csv_utils::CsvOptions {
+            delimiter: Some(b'|'),
+            no_header: true,
+            ..csv_utils::CsvOptions::default()
+        };
+        let result = load_and_query_with_options("SELECT c3, c4 FROM ./tests/data/orders_noheader.psv WHERE c4 > 50", &options);
+
+        if let Err(e) = &result {
+            eprint!("{}", e);
+            assert!(!result.is_err());
+        }
+
+        if let Ok((headers, data)) = result {
+            assert_eq!(vec!["c3", "c4"], headers);
+            assert_eq!(vec!["Alice", "120.50"], data[0]);
+            assert_eq!(3, data.len());
+        }
+    }
+
+    #[test]
+    fn select_from_json() {
+        // NDJSON and JSON arrays are picked up by extension, nested fields are flattened
+        for file in ["./tests/data/events.ndjson", "./tests/data/events.json"] {
+            let sql = format!(
+                "SELECT user.name, COUNT(id), SUM(duration) FROM {} GROUP BY user.name ORDER BY user.name ASC",
+                file
+            );
+            let result = load_and_query(&sql);
+
+            if let Err(e) = &result {
+                eprint!("{}", e);
+                assert!(!result.is_err());
+            }
+
+            if let Ok((headers, data)) = result {
+                assert_eq!(vec!["user.name", "COUNT(id)", "SUM(duration)"], headers);
+                assert_eq!(vec!["Alice", "2", "37"], data[0]);
+                assert_eq!(vec!["Bob", "1", "40"], data[1]);
+                assert_eq!("Carol", data[2][0]);
+                assert_eq!(3, data.len());
+            }
+        }
+
+        // Fields missing from a record are NULL, arrays are kept as JSON text
+        let result = load_and_query(
+            "SELECT id, tags FROM ./tests/data/events.ndjson WHERE user.country IS NULL OR tags IS NOT NULL",
+        )
+        .unwrap();
+        assert_eq!(vec!["id", "tags"], result.0);
+        assert_eq!(vec!["3", "[\"sale\"]"], result.1[0]);
+        assert_eq!(vec!["4", ""], result.1[1]);
+    }
+
+    #[test]
+    fn select_from_memory_tables() {
+        // Tables loaded once are queried by name, including from joins
+        let mut options = csv_utils::CsvOptions::default();
+        for (name, file) in [("v", "./vgsales.csv"), ("platforms", "./tests/data/platforms.csv")] {
+            let table = csv_utils::MemoryTable::load(file, &options).unwrap();
+            options.tables.insert(name.to_string(), Arc::new(table));
+        }
+
+        let sql = "SELECT p.Manufacturer, COUNT(*) FROM v INNER JOIN platforms p ON v.Platform = p.Platform GROUP BY p.Manufacturer ORDER BY COUNT(*) DESC LIMIT 1";
+        for _ in 0..2 {
+            let result = load_and_query_with_options(sql, &options);
+
+            if let Err(e) = &result {
+                eprint!("{}", e);
+                assert!(!result.is_err());
+            }
+
+            if let Ok((headers, data)) = result {
+                assert_eq!(vec!["p.Manufacturer", "COUNT(*)"], headers);
+                assert_eq!("Sony", data[0][0]);
+                assert_eq!(1, data.len());
+            }
+        }
+    }
+
+    #[test]
+    fn select_case_insensitive_keywords() {
+        let result = load_and_query(
+            "select Platform, count(*) as games from vgsales.csv where Year between 2000 and 2001 and Genre not like 'Sport%' group by Platform having count(*) > 50 order by games desc limit 2 offset 1",
+        );
+
+        if let Err(e) = &result {
+            eprint!("{}", e);
+            assert!(!result.is_err());
+        }
+
+        if let Ok((headers, data)) = result {
+            assert_eq!(vec!["Platform", "games"], headers);
+            assert_eq!(
+                load_and_query("SELECT Platform, COUNT(*) AS games FROM vgsales.csv WHERE Year BETWEEN 2000 AND 2001 AND Genre NOT LIKE 'Sport%' GROUP BY Platform HAVING COUNT(*) > 50 ORDER BY games DESC LIMIT 2 OFFSET 1")
+                    .unwrap()
+                    .1,
+                data
+            );
+            assert_eq!(vec!["PS", "192"], data[0]);
+            assert_eq!(vec!["GBA", "93"], data[1]);
+        }
+    }
+
+    #[test]
+    fn select_quoted_identifiers_and_paths() {
+        // Double quotes and backticks allow headers with spaces or that are keywords
+        let result = load_and_query(
+            "SELECT Region, SUM(\"Global Sales\") AS `Total Sales` FROM './tests/data/sales report.csv' WHERE `Order` < 4 GROUP BY Region ORDER BY \"Total Sales\" DESC",
+        );
+
+        if let Err(e) = &result {
+            eprint!("{}", e);
+            assert!(!result.is_err());
+        }
+
+        if let Ok((headers, data)) = result {
+            assert_eq!(vec!["Region", "Total Sales"], headers);
+            assert_eq!(vec!["North", "160.75"], data[0]);
+            assert_eq!(vec!["South", "80"], data[1]);
+            assert_eq!(2, data.len());
+        }
+
+        // Quoted identifiers can be qualified, and quoted paths joined
+        let result = load_and_query(
+            "SELECT s.\"Global Sales\", o.Customer FROM \"./tests/data/sales report.csv\" s INNER JOIN ./tests/data/orders.csv o ON s.`Order` = o.OrderId WHERE s.\"Global Sales\" IS NOT NULL",
+        )
+        .unwrap();
+        assert_eq!(vec!["s.Global Sales", "o.Customer"], result.0);
+        assert_eq!(vec!["120.5", "Alice"], result.1[0]);
+        assert_eq!(3, result.1.len());
+    }
+
+    #[test]
+    fn select_top_n_per_group_with_row_number() {
+        let result = load_and_query(
+            "SELECT Platform, Name, ROW_NUMBER() OVER (PARTITION BY Platform ORDER BY Global_Sales DESC) AS rn FROM vgsales.csv WHERE Platform IN ('Wii', 'NES') QUALIFY rn <= 2 ORDER BY Platform, rn",
+        );
+
+        if let Err(e) = &result {
+            eprint!("{}", e);
+            assert!(!result.is_err());
+        }
+
+        if let Ok((headers, data)) = result {
+            assert_eq!(vec!["Platform", "Name", "rn"], headers);
+            assert_eq!(
+                vec![
+                    vec!["NES", "Super Mario Bros.", "1"],
+                    vec!["NES", "Duck Hunt", "2"],
+                    vec!["Wii", "Wii Sports", "1"],
+                    vec!["Wii", "Mario Kart Wii", "2"],
+                ],
+                data
+            );
+        }
+    }
+
+    #[test]
+    fn select_window_functions() {
+        let result = load_and_query(
+            "SELECT OrderId, RANK() OVER (ORDER BY Discount) AS r, DENSE_RANK() OVER (ORDER BY Discount) AS dr, LAG(Customer) OVER (ORDER BY OrderId) AS prev, LEAD(Amount, 2, 0) OVER (ORDER BY OrderId) AS next, SUM(Amount) OVER (ORDER BY Date) AS running, COUNT(*) OVER () AS total FROM ./tests/data/orders.csv",
+        );
+
+        if let Err(e) = &result {
+            eprint!("{}", e);
+            assert!(!result.is_err());
+        }
+
+        if let Ok((_, data)) = result {
+            // Rows stay in file order, empty discounts sort first and tie
+            assert_eq!(vec!["1", "3", "2", "", "89.99", "120.5", "6"], data[0]);
+            assert_eq!(vec!["2", "1", "1", "Alice", "1500", "105.25", "6"], data[1]);
+            assert_eq!(vec!["4", "6", "5", "Carol", "42", "1695.24", "6"], data[3]);
+            assert_eq!(vec!["5", "1", "1", "alice", "0", "1696.23", "6"], data[4]);
+            assert_eq!(vec!["6", "4", "3", "Dave", "0", "1738.23", "6"], data[5]);
+        }
+    }
+
+    #[test]
+    fn select_window_over_groups() {
+        let result = load_and_query(
+            "SELECT Platform, COUNT(*) AS games, RANK() OVER (ORDER BY COUNT(*) DESC) AS r FROM vgsales.csv GROUP BY Platform QUALIFY r <= 3 ORDER BY r",
+        )
+        .unwrap();
+
+        assert_eq!(vec!["Platform", "games", "r"], result.0);
+        assert_eq!(
+            vec![vec!["DS", "2163", "1"], vec!["PS2", "2161", "2"], vec!["PS3", "1329", "3"]],
+            result.1
+        );
+
+        let result = load_and_query("SELECT Name, RANK() OVER (ORDER BY Year) + 1 FROM vgsales.csv");
+        assert!(result.unwrap_err().to_string().contains("can't be used inside an expression"));
+    }
+
+    #[test]
+    fn select_from_directory() {
+        let (headers, data) = load_and_query("SELECT * FROM ./tests/data/exports WHERE DurationMs > 100").unwrap();
+
+        // The second file's columns are in a different order, _file isn't part of *
+        assert_eq!(vec!["Time", "Level", "Service", "DurationMs"], headers);
+        assert_eq!(
+            vec![
+                vec!["2026-10-01T08:00:00", "INFO", "api", "120"],
+                vec!["2026-10-01T08:05:00", "ERROR", "api", "950"],
+                vec!["2026-10-02T10:30:00", "ERROR", "billing", "1500"],
+            ],
+            data
+        );
+    }
+
+    #[test]
+    fn select_from_glob_by_file() {
+        let (_, data) = load_and_query(
+            "SELECT _file, COUNT(*), MIN(Time) FROM './tests/data/exports/2026-10-*.csv' GROUP BY _file ORDER BY _file",
+        )
+        .unwrap();
+        assert_eq!(
+            vec![
+                vec!["./tests/data/exports/2026-10-01.csv", "3", "2026-10-01T08:00:00"],
+                vec!["./tests/data/exports/2026-10-02.csv", "4", "2026-10-02T10:00:00"],
+            ],
+            data
+        );
+
+        let (_, data) = load_and_query(
+            "SELECT Service, _file FROM tests/data/export?/*-0[2-9].csv WHERE _file LIKE '%10-02%' AND Level = 'WARN'",
+        )
+        .unwrap();
+        assert_eq!(vec![vec!["billing", "tests/data/exports/2026-10-02.csv"]], data);
+    }
+
+    #[test]
+    fn select_from_glob_with_mismatched_headers() {
+        let error = load_and_query("SELECT * FROM ./tests/data/orders*.csv").unwrap_err().to_string();
+        assert!(error.contains(
+            "The columns of './tests/data/orders_new.csv' don't match './tests/data/orders.csv' (unexpected Status)"
+        ));
+
+        let error = load_and_query("SELECT * FROM ./tests/data/missing-*.csv").unwrap_err().to_string();
+        assert!(error.contains("No files match './tests/data/missing-*.csv'"));
+    }
 }
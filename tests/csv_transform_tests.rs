@@ -0,0 +1,127 @@
+use aid::csv_utils::{self, CsvOptions, OrderBy, QueryResults, RowSource, SortDirection, SplitBy};
+use std::fs;
+
+pub fn open(file: &str) -> Box<dyn RowSource> {
+    csv_utils::open_table(file, &CsvOptions::default()).unwrap()
+}
+
+pub fn sort(file: &str, keys: &[(&str, SortDirection)], chunk_size: usize) -> QueryResults {
+    let keys: Vec<OrderBy> = keys
+        .iter()
+        .map(|(column, direction)| OrderBy {
+            column: column.to_string(),
+            direction: direction.clone(),
+        })
+        .collect();
+
+    let mut results = QueryResults::default();
+    csv_utils::sort_table(open(file), &keys, chunk_size, &mut results).unwrap();
+    results
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn sort_by_several_columns() {
+        let results = sort(
+            "./tests/data/orders.csv",
+            &[("Discount", SortDirection::Descending), ("Amount", SortDirection::Ascending)],
+            csv_utils::SORT_CHUNK_SIZE,
+        );
+
+        let ids: Vec<&str> = results.rows.iter().map(|row| row[0].as_str()).collect();
+        assert_eq!(vec!["4", "3", "6", "1", "2", "5"], ids);
+    }
+
+    #[test]
+    fn sort_spilled_to_disk_matches_in_memory_sort() {
+        let keys = [("Year", SortDirection::Descending), ("Platform", SortDirection::Ascending)];
+        let in_memory = sort("./vgsales.csv", &keys, csv_utils::SORT_CHUNK_SIZE);
+        let merged = sort("./vgsales.csv", &keys, 1000);
+
+        assert_eq!(16598, merged.rows.len());
+        assert_eq!(in_memory.headers, merged.headers);
+        assert_eq!(in_memory.rows, merged.rows);
+        assert_eq!("N/A", merged.rows[0][3]);
+    }
+
+    #[test]
+    fn dedupe_by_key_columns() {
+        let mut results = QueryResults::default();
+        let (written, removed) = csv_utils::dedupe_table(
+            open("./vgsales.csv"),
+            &["Platform".to_string()],
+            &mut results,
+        )
+        .unwrap();
+
+        assert_eq!(31, written);
+        assert_eq!(16598 - 31, removed);
+        // The first row for each key is kept
+        assert_eq!("Wii Sports", results.rows[0][1]);
+    }
+
+    #[test]
+    fn concat_reconciles_headers() {
+        let mut results = QueryResults::default();
+        let rows = csv_utils::concat_tables(
+            vec![open("./tests/data/orders.csv"), open("./tests/data/platforms.csv")],
+            &mut results,
+        )
+        .unwrap();
+
+        assert_eq!(6 + 24, rows);
+        assert_eq!(
+            vec!["OrderId", "Date", "Customer", "Amount", "Discount", "Platform", "Manufacturer", "Generation"],
+            results.headers
+        );
+        assert_eq!(vec!["1", "2019-12-30", "Alice", "120.50", "0", "", "", ""], results.rows[0]);
+        assert_eq!(vec!["", "", "", "", "", "Wii", "Nintendo", "7"], results.rows[6]);
+    }
+
+    #[test]
+    fn split_by_rows_and_column() {
+        let output_dir = std::env::temp_dir().join("aid_csv_split_tests");
+        let _ = fs::remove_dir_all(&output_dir);
+        fs::create_dir_all(&output_dir).unwrap();
+
+        let files = csv_utils::split_table(
+            open("./tests/data/orders.csv"),
+            &SplitBy::Rows(4),
+            &output_dir,
+            "orders",
+        )
+        .unwrap();
+        let rows: Vec<usize> = files.iter().map(|(_, rows)| *rows).collect();
+        assert_eq!(vec![4, 2], rows);
+        assert_eq!(
+            "OrderId,Date,Customer,Amount,Discount\n5,2020-03-21,Dave,0.99,\n6,2021-07-09,Erin,42,2\n",
+            fs::read_to_string(output_dir.join("orders_2.csv")).unwrap()
+        );
+
+        let files = csv_utils::split_table(
+            open("./tests/data/platforms.csv"),
+            &SplitBy::Column("Manufacturer".to_string()),
+            &output_dir,
+            "platforms",
+        )
+        .unwrap();
+        assert!(files.iter().any(|(path, _)| path.ends_with("platforms_Nintendo.csv")));
+        let total: usize = files.iter().map(|(_, rows)| *rows).sum();
+        assert_eq!(24, total);
+
+        let _ = fs::remove_dir_all(&output_dir);
+    }
+
+    #[test]
+    fn convert_json_to_rows() {
+        let mut results = QueryResults::default();
+        let rows = csv_utils::copy_table(open("./tests/data/events.ndjson"), &mut results).unwrap();
+
+        assert_eq!(results.rows.len(), rows);
+        assert_eq!("user.name", results.headers[1]);
+        assert_eq!("Alice", results.rows[0][1]);
+    }
+}
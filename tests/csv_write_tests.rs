@@ -0,0 +1,134 @@
+use aid::csv_utils::{self, CsvOptions, Statement};
+use std::error::Error;
+use std::fs;
+use std::path::PathBuf;
+
+// Copy a test file into its own directory so each test can modify it
+pub fn scratch_copy(test: &str, file: &str) -> PathBuf {
+    let dir = std::env::temp_dir().join(format!("aid_csv_write_{}", test));
+    let _ = fs::remove_dir_all(&dir);
+    fs::create_dir_all(&dir).unwrap();
+    let path = dir.join(file);
+    fs::copy(format!("./tests/data/{}", file), &path).unwrap();
+    path
+}
+
+// Run a statement that writes to a file, returning the number of rows it affected
+pub fn execute(sql: &str) -> Result<usize, Box<dyn Error>> {
+    let options = CsvOptions::default();
+    match csv_utils::parse_statement(sql)? {
+        Statement::CreateTableAs(table, query) => csv_utils::create_table_as(&table, &query, &options),
+        Statement::InsertInto(table, query) => csv_utils::insert_into(&table, &query, &options),
+        Statement::Update(table, assignments, condition) => {
+            csv_utils::update_table(&table, &assignments, &condition, &options)
+        }
+        Statement::Delete(table, condition) => csv_utils::delete_from(&table, &condition, &options),
+        Statement::Select(_) => panic!("Expected '{}' to write to a file", sql),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn create_table_as_select() {
+        let orders = scratch_copy("create", "orders.csv");
+        let output = orders.with_file_name("big orders.csv");
+
+        let rows = execute(&format!(
+            "CREATE TABLE '{}' AS SELECT OrderId, Customer FROM '{}' WHERE Amount > 50",
+            output.display(),
+            orders.display()
+        ))
+        .unwrap();
+        assert_eq!(3, rows);
+        assert_eq!(
+            "OrderId,Customer\n1,Alice\n3,Carol\n4,alice\n",
+            fs::read_to_string(&output).unwrap()
+        );
+
+        // The file already exists now
+        let result = execute(&format!(
+            "create table '{}' as select OrderId, Customer from '{}'",
+            output.display(),
+            orders.display()
+        ));
+        assert!(result.unwrap_err().to_string().contains("already exists"));
+    }
+
+    #[test]
+    fn insert_into_checks_headers() {
+        let orders = scratch_copy("insert", "orders.csv");
+        let path = orders.display();
+
+        // Columns are matched up by name
+        let rows = execute(&format!(
+            "INSERT INTO '{}' SELECT Discount, Amount, Customer, Date, OrderId + 10 AS orderid FROM '{}' WHERE OrderId < 3",
+            path, path
+        ))
+        .unwrap();
+        assert_eq!(2, rows);
+        let contents = fs::read_to_string(&orders).unwrap();
+        assert!(contents.ends_with("6,2021-07-09,Erin,42,2\n11,2019-12-30,Alice,120.50,0\n12,2020-01-01,Bob,-15.25,\n"));
+
+        let result = execute(&format!("INSERT INTO '{}' SELECT OrderId, Date FROM '{}'", path, path));
+        assert_eq!(
+            format!(
+                "The columns of the query don't match '{}' (missing Customer, Amount, Discount), expected OrderId, Date, Customer, Amount, Discount",
+                path
+            ),
+            result.unwrap_err().to_string()
+        );
+        assert_eq!(contents, fs::read_to_string(&orders).unwrap());
+    }
+
+    #[test]
+    fn update_and_delete_rewrite_the_file() {
+        let orders = scratch_copy("update", "orders_semicolon.csv");
+        let path = orders.display();
+
+        let rows = execute(&format!(
+            "UPDATE '{}' SET Discount = 0, Amount = Amount * 2 WHERE Discount IS NULL",
+            path
+        ))
+        .unwrap();
+        assert_eq!(2, rows);
+        let rows = execute(&format!("DELETE FROM '{}' WHERE Customer LIKE 'a%' OR Amount > 100", path)).unwrap();
+        assert_eq!(2, rows);
+
+        // The delimiter is kept and no temporary files are left behind
+        assert_eq!(
+            "OrderId;Date;Customer;Amount;Discount\n2;2020-01-01;Bob;-30.5;0\n3;2020-01-14;Carol;89.99;5\n5;2020-03-21;Dave;1.98;0\n6;2021-07-09;Erin;42;2\n",
+            fs::read_to_string(&orders).unwrap()
+        );
+        assert_eq!(1, fs::read_dir(orders.parent().unwrap()).unwrap().count());
+
+        // Nothing is written when a column doesn't exist
+        let result = execute(&format!("UPDATE '{}' SET Amont = 1", path));
+        assert!(result.unwrap_err().to_string().contains("did you mean 'Amount'"));
+
+        // Even when no rows match the condition
+        let modified = fs::metadata(&orders).unwrap().modified().unwrap();
+        let result = execute(&format!("UPDATE '{}' SET Amont = 1 WHERE OrderId = 99", path));
+        assert!(result.unwrap_err().to_string().contains("did you mean 'Amount'"));
+        assert_eq!(modified, fs::metadata(&orders).unwrap().modified().unwrap());
+        assert_eq!(1, fs::read_dir(orders.parent().unwrap()).unwrap().count());
+    }
+
+    #[test]
+    fn parse_statement_errors() {
+        let error = csv_utils::parse_statement("DELETE FROM orders.csv LIMIT 1").unwrap_err();
+        assert_eq!("Unexpected 'LIMIT', expected WHERE or the end of the query", error.message);
+        assert_eq!(23, error.position);
+
+        let error = csv_utils::parse_statement("UPDATE orders.csv SET Amount WHERE OrderId = 1").unwrap_err();
+        assert_eq!("Unexpected 'WHERE', expected <column> = <expression>", error.message);
+
+        let error = csv_utils::parse_statement("CREATE TABLE out.csv SELECT * FROM orders.csv").unwrap_err();
+        assert_eq!("Unexpected 'SELECT', expected AS", error.message);
+
+        let error = csv_utils::parse_statement("INSERT INTO out.csv SELECT * FROM orders.csv WHERE").unwrap_err();
+        assert_eq!("Unexpected end of query, expected a condition", error.message);
+    }
+}
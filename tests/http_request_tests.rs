@@ -0,0 +1,168 @@
+use aid::http_commands::{self, RequestOptions};
+use aid::http_utils::{self, RequestLog, ServeMode};
+use serde_json::Value;
+use std::net::SocketAddr;
+use std::path::PathBuf;
+use tokio::net::TcpListener;
+
+// Start a server on a free port in the background
+pub async fn start(mode: ServeMode) -> SocketAddr {
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+    tokio::spawn(http_utils::serve(listener, mode, RequestLog::new(None).unwrap()));
+    addr
+}
+
+// Send a request to an echo server and return its description of what it received
+pub async fn echo(method: &str, config: Option<&str>, options: RequestOptions) -> Value {
+    let addr = start(ServeMode::Echo).await;
+    let url = format!("http://{}/echo", addr);
+    let builder = http_commands::build_request(Some(method.to_string()), Some(url), config.map(String::from), &options)
+        .await
+        .unwrap();
+    let text = builder.send().await.unwrap().text().await.unwrap();
+    serde_json::from_str(&text).unwrap()
+}
+
+pub fn strings(values: &[&str]) -> Vec<String> {
+    values.iter().map(|value| value.to_string()).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn request_merges_config_and_options() {
+        let options = RequestOptions {
+            headers: strings(&["Accept: application/json", "X-Trace:  abc ", "Accept: text/csv"]),
+            query: strings(&["q=a b"]),
+            ..RequestOptions::default()
+        };
+        // The method given on the command line replaces the config's
+        let echoed = echo("PUT", Some("./tests/data/request.json"), options).await;
+
+        assert_eq!("PUT", echoed["method"]);
+        assert_eq!("/echo?page=2&q=a+b", echoed["target"]);
+        assert_eq!("aid", echoed["headers"]["x-client"]);
+        // Headers repeated on the command line are all sent, replacing the config's
+        assert_eq!("application/json, text/csv", echoed["headers"]["accept"]);
+        assert_eq!("abc", echoed["headers"]["x-trace"]);
+        assert_eq!("Basic Ym9iOnNlY3JldA==", echoed["headers"]["authorization"]);
+        assert_eq!(r#"{"name":"Bob"}"#, echoed["body"]);
+
+        // A body on the command line replaces the config's
+        let options = RequestOptions {
+            data: Some("@./tests/data/public/hello.txt".to_string()),
+            bearer: Some("token".to_string()),
+            ..RequestOptions::default()
+        };
+        let echoed = echo("POST", None, options).await;
+        assert_eq!("Hello from aid\n", echoed["body"]);
+        assert_eq!("Bearer token", echoed["headers"]["authorization"]);
+    }
+
+    #[tokio::test]
+    async fn request_sends_forms() {
+        let options = RequestOptions {
+            data_urlencode: strings(&["name=Bob Smith", "tags=a&b"]),
+            ..RequestOptions::default()
+        };
+        let echoed = echo("POST", None, options).await;
+        assert_eq!("application/x-www-form-urlencoded", echoed["headers"]["content-type"]);
+        assert_eq!("name=Bob+Smith&tags=a%26b", echoed["body"]);
+
+        let options = RequestOptions {
+            form: strings(&["title=Notes", "file=@./tests/data/public/hello.txt", "raw=@./tests/data/public/data.json;type=text/plain"]),
+            ..RequestOptions::default()
+        };
+        let echoed = echo("POST", None, options).await;
+        let content_type = echoed["headers"]["content-type"].as_str().unwrap();
+        let boundary = content_type.strip_prefix("multipart/form-data; boundary=").unwrap();
+        let body = echoed["body"].as_str().unwrap();
+        assert!(body.starts_with(&format!("--{}\r\nContent-Disposition: form-data; name=\"title\"\r\n\r\nNotes\r\n", boundary)));
+        assert!(body.contains(
+            "Content-Disposition: form-data; name=\"file\"; filename=\"hello.txt\"\r\nContent-Type: text/plain; charset=utf-8\r\n\r\nHello from aid\n\r\n"
+        ));
+        assert!(body.contains("filename=\"data.json\"\r\nContent-Type: text/plain\r\n"));
+        assert!(body.ends_with(&format!("--{}--\r\n", boundary)));
+    }
+
+    #[tokio::test]
+    async fn request_redirects_and_timeouts() {
+        let addr = start(ServeMode::Static(PathBuf::from("./tests/data/public"))).await;
+        let url = format!("http://{}/site", addr);
+
+        let response = http_commands::build_request(Some("GET".to_string()), Some(url.clone()), None, &RequestOptions::default())
+            .await
+            .unwrap()
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(200, response.status().as_u16());
+
+        let options = RequestOptions {
+            max_redirects: Some(0),
+            ..RequestOptions::default()
+        };
+        let response = http_commands::build_request(Some("GET".to_string()), Some(url), None, &options)
+            .await
+            .unwrap()
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(301, response.status().as_u16());
+
+        let routes = http_utils::load_routes("./tests/data/routes.json").unwrap();
+        let addr = start(ServeMode::Routes(routes)).await;
+        let options = RequestOptions {
+            timeout: Some(0.05),
+            ..RequestOptions::default()
+        };
+        let error = http_commands::build_request(Some("GET".to_string()), Some(format!("http://{}/slow", addr)), None, &options)
+            .await
+            .unwrap()
+            .send()
+            .await
+            .unwrap_err();
+        assert!(error.is_timeout());
+    }
+
+    #[tokio::test]
+    async fn request_option_errors() {
+        let build = |options: RequestOptions| async move {
+            http_commands::build_request(Some("GET".to_string()), Some("http://localhost".to_string()), None, &options)
+                .await
+                .unwrap_err()
+        };
+
+        let error = build(RequestOptions {
+            headers: strings(&["Accept"]),
+            ..RequestOptions::default()
+        })
+        .await;
+        assert_eq!("Error building headers: Invalid header 'Accept', expected 'Name: value'", error);
+
+        let error = build(RequestOptions {
+            query: strings(&["page"]),
+            ..RequestOptions::default()
+        })
+        .await;
+        assert_eq!("Invalid field 'page', expected 'name=value'", error);
+
+        let error = build(RequestOptions {
+            user: Some("bob".to_string()),
+            bearer: Some("token".to_string()),
+            ..RequestOptions::default()
+        })
+        .await;
+        assert_eq!("Only one of basic and bearer auth can be used.", error);
+
+        let error = build(RequestOptions {
+            timeout: Some(-1.0),
+            ..RequestOptions::default()
+        })
+        .await;
+        assert_eq!("Invalid timeout: -1", error);
+    }
+}
@@ -0,0 +1,463 @@
+use aid::http_utils::{self, Proxy, Recorder, RequestLog, ServeMode};
+use std::net::SocketAddr;
+use std::path::PathBuf;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream};
+
+pub struct HttpResponse {
+    pub status: u16,
+    pub headers: Vec<(String, String)>,
+    pub body: Vec<u8>,
+}
+
+impl HttpResponse {
+    pub fn header(&self, name: &str) -> Option<&str> {
+        self.headers
+            .iter()
+            .find(|(header, _)| header.eq_ignore_ascii_case(name))
+            .map(|(_, value)| value.as_str())
+    }
+
+    pub fn text(&self) -> String {
+        String::from_utf8_lossy(&self.body).into_owned()
+    }
+}
+
+// Start a server on a free port in the background
+pub async fn start(mode: ServeMode) -> SocketAddr {
+    start_with_log(mode, RequestLog::new(None).unwrap()).await
+}
+
+pub async fn start_with_log(mode: ServeMode, log: RequestLog) -> SocketAddr {
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+    tokio::spawn(http_utils::serve(listener, mode, log));
+    addr
+}
+
+pub fn public_dir() -> ServeMode {
+    ServeMode::Static(PathBuf::from("./tests/data/public"))
+}
+
+// Send a raw request and read the response until the server closes the connection
+pub async fn send(addr: SocketAddr, request: &str) -> HttpResponse {
+    let mut stream = TcpStream::connect(addr).await.unwrap();
+    stream.write_all(request.as_bytes()).await.unwrap();
+
+    let mut response = Vec::new();
+    stream.read_to_end(&mut response).await.unwrap();
+    let split = response.windows(4).position(|window| window == b"\r\n\r\n").unwrap();
+    let head = String::from_utf8_lossy(&response[..split]).into_owned();
+
+    let mut lines = head.lines();
+    let status = lines.next().unwrap().split_whitespace().nth(1).unwrap().parse().unwrap();
+    let headers = lines
+        .filter_map(|line| line.split_once(':'))
+        .map(|(name, value)| (name.trim().to_string(), value.trim().to_string()))
+        .collect();
+    HttpResponse {
+        status,
+        headers,
+        body: response[split + 4..].to_vec(),
+    }
+}
+
+pub async fn get(addr: SocketAddr, path: &str, headers: &str) -> HttpResponse {
+    send(addr, &format!("GET {} HTTP/1.1\r\nHost: localhost\r\n{}Connection: close\r\n\r\n", path, headers)).await
+}
+
+pub fn temp_path(name: &str) -> String {
+    let path = std::env::temp_dir().join(format!("aid_{}_{}", std::process::id(), name));
+    let _ = std::fs::remove_file(&path);
+    path.to_string_lossy().into_owned()
+}
+
+// Start a proxy to the given server, recording to and replaying from the given files
+pub async fn start_proxy(upstream: Option<SocketAddr>, record: Option<&str>, replay: Option<&str>) -> SocketAddr {
+    let recorder = record.map(|record| Recorder::open(record).unwrap());
+    let recorded = replay.map(|replay| http_utils::load_recording(replay).unwrap()).unwrap_or_default();
+    let upstream = upstream.map(|upstream| format!("http://{}/", upstream));
+    let proxy = Proxy::new(upstream, recorder, recorded).unwrap();
+    start(ServeMode::Proxy(Box::new(proxy))).await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn serve_files_with_content_type() {
+        let addr = start(public_dir()).await;
+
+        let response = get(addr, "/hello.txt", "").await;
+        assert_eq!(200, response.status);
+        assert_eq!(Some("text/plain; charset=utf-8"), response.header("Content-Type"));
+        assert_eq!(Some("15"), response.header("Content-Length"));
+        assert_eq!("Hello from aid\n", response.text());
+
+        let response = get(addr, "/data.json", "").await;
+        assert_eq!(Some("application/json"), response.header("Content-Type"));
+
+        // Directories with an index.html serve it, others redirect to add the trailing /
+        let response = get(addr, "/site/", "").await;
+        assert!(response.text().contains("Site index"));
+        let response = get(addr, "/site?x=1", "").await;
+        assert_eq!(301, response.status);
+        assert_eq!(Some("/site/?x=1"), response.header("Location"));
+
+        let response = send(addr, "HEAD /hello.txt HTTP/1.1\r\nConnection: close\r\n\r\n").await;
+        assert_eq!(Some("15"), response.header("Content-Length"));
+        assert!(response.body.is_empty());
+
+        let response = send(addr, "DELETE /hello.txt HTTP/1.1\r\nConnection: close\r\n\r\n").await;
+        assert_eq!(405, response.status);
+        assert_eq!(404, get(addr, "/missing.txt", "").await.status);
+    }
+
+    #[tokio::test]
+    async fn serve_directory_listing() {
+        let addr = start(public_dir()).await;
+
+        let response = get(addr, "/", "").await;
+        assert_eq!(Some("text/html; charset=utf-8"), response.header("Content-Type"));
+        let html = response.text();
+        assert!(html.contains("<title>Index of /</title>"));
+        // Directories come first
+        assert!(html.find("docs/").unwrap() < html.find("data.json").unwrap());
+
+        // Names are escaped in the page and percent encoded in links
+        let html = get(addr, "/docs/", "").await.text();
+        assert!(html.contains("<a href=\"../\">"));
+        assert!(html.contains("<a href=\"guide%20&%20notes.md\">guide &amp; notes.md</a>"));
+        let response = get(addr, "/docs/guide%20&%20notes.md", "").await;
+        assert_eq!("# Guide\n\nSee <notes> & more.\n", response.text());
+    }
+
+    #[tokio::test]
+    async fn serve_ranges_and_conditional_requests() {
+        let addr = start(public_dir()).await;
+
+        let response = get(addr, "/hello.txt", "Range: bytes=6-9\r\n").await;
+        assert_eq!(206, response.status);
+        assert_eq!(Some("bytes 6-9/15"), response.header("Content-Range"));
+        assert_eq!("from", response.text());
+
+        assert_eq!("aid\n", get(addr, "/hello.txt", "Range: bytes=-4\r\n").await.text());
+        assert_eq!("aid\n", get(addr, "/hello.txt", "Range: bytes=11-\r\n").await.text());
+
+        let response = get(addr, "/hello.txt", "Range: bytes=20-30\r\n").await;
+        assert_eq!(416, response.status);
+        assert_eq!(Some("bytes */15"), response.header("Content-Range"));
+
+        let response = get(addr, "/hello.txt", "").await;
+        let etag = response.header("ETag").unwrap().to_string();
+        let last_modified = response.header("Last-Modified").unwrap().to_string();
+
+        let response = get(addr, "/hello.txt", &format!("If-None-Match: {}\r\n", etag)).await;
+        assert_eq!(304, response.status);
+        assert!(response.body.is_empty());
+        let response = get(addr, "/hello.txt", &format!("If-Modified-Since: {}\r\n", last_modified)).await;
+        assert_eq!(304, response.status);
+        let response = get(addr, "/hello.txt", "If-None-Match: \"other\"\r\n").await;
+        assert_eq!(200, response.status);
+
+        // A stale If-Range gets the whole file
+        let response = get(addr, "/hello.txt", "Range: bytes=0-4\r\nIf-Range: \"other\"\r\n").await;
+        assert_eq!(200, response.status);
+        assert_eq!(15, response.body.len());
+    }
+
+    #[tokio::test]
+    async fn serve_rejects_paths_outside_the_root() {
+        let addr = start(public_dir()).await;
+
+        assert_eq!(403, get(addr, "/../orders.csv", "").await.status);
+        assert_eq!(403, get(addr, "/docs/%2e%2e/%2e%2e/orders.csv", "").await.status);
+        assert_eq!(403, get(addr, "/..%5corders.csv", "").await.status);
+
+        // Symlinks can't lead outside the root either
+        let root = std::env::temp_dir().join("aid_http_serve_tests");
+        let _ = std::fs::remove_dir_all(&root);
+        std::fs::create_dir_all(&root).unwrap();
+        std::fs::write(root.join("inside.txt"), "inside").unwrap();
+        #[cfg(unix)]
+        std::os::unix::fs::symlink(std::fs::canonicalize("./tests/data/orders.csv").unwrap(), root.join("outside.csv")).unwrap();
+
+        let addr = start(ServeMode::Static(root.clone())).await;
+        assert_eq!("inside", get(addr, "/inside.txt", "").await.text());
+        #[cfg(unix)]
+        assert_eq!(403, get(addr, "/outside.csv", "").await.status);
+
+        let response = send(addr, "GET /inside.txt\r\n\r\n").await;
+        assert_eq!(400, response.status);
+
+        let _ = std::fs::remove_dir_all(&root);
+    }
+
+    #[tokio::test]
+    async fn serve_keeps_connections_alive() {
+        let addr = start(ServeMode::Hello).await;
+
+        // Two requests on one connection, the second asks for it to be closed
+        let response = send(
+            addr,
+            "GET / HTTP/1.1\r\nHost: localhost\r\n\r\nGET / HTTP/1.1\r\nHost: localhost\r\nConnection: close\r\n\r\n",
+        )
+        .await;
+        assert_eq!(200, response.status);
+        assert_eq!("Hello, World!HTTP/1.1 200 OK", &response.text()[..28]);
+    }
+
+    #[tokio::test]
+    async fn serve_mock_routes() {
+        let routes = http_utils::load_routes("./tests/data/routes.json").unwrap();
+        let addr = start(ServeMode::Routes(routes)).await;
+
+        let response = get(addr, "/users/7?fields=name%2Cid", "").await;
+        assert_eq!(200, response.status);
+        assert_eq!(Some("application/json"), response.header("Content-Type"));
+        assert_eq!(Some("7"), response.header("X-User"));
+        assert_eq!(
+            r#"{"id":"7","name":"User 7","fields":"name,id","roles":["reader"]}"#,
+            response.text()
+        );
+
+        let response = send(
+            addr,
+            "POST /users HTTP/1.1\r\nContent-Type: text/plain\r\nContent-Length: 5\r\nConnection: close\r\n\r\nAlice",
+        )
+        .await;
+        assert_eq!(201, response.status);
+        assert_eq!(Some("/users/42"), response.header("Location"));
+        assert_eq!("Created from text/plain: Alice", response.text());
+
+        let response = send(addr, "DELETE /users/7 HTTP/1.1\r\nConnection: close\r\n\r\n").await;
+        assert_eq!(204, response.status);
+
+        // Files are relative to the routes file
+        let response = get(addr, "/reports/2024/orders", "").await;
+        assert_eq!(Some("text/csv; charset=utf-8"), response.header("Content-Type"));
+        assert!(response.text().starts_with("OrderId,Date"));
+
+        let response = send(addr, "PUT /users/7 HTTP/1.1\r\nConnection: close\r\n\r\n").await;
+        assert_eq!(405, response.status);
+        assert_eq!(Some("GET, DELETE"), response.header("Allow"));
+        let response = get(addr, "/users/7/posts", "").await;
+        assert_eq!(404, response.status);
+        assert_eq!(r#"{"error":"No route for GET /users/7/posts"}"#, response.text());
+
+        let start = std::time::Instant::now();
+        assert_eq!("finally", get(addr, "/slow", "").await.text());
+        assert!(start.elapsed() >= std::time::Duration::from_millis(200));
+    }
+
+    #[test]
+    fn load_routes_errors() {
+        let dir = std::env::temp_dir().join("aid_http_routes_tests");
+        std::fs::create_dir_all(&dir).unwrap();
+        let load = |json: &str| {
+            let path = dir.join("routes.json");
+            std::fs::write(&path, json).unwrap();
+            http_utils::load_routes(path.to_str().unwrap()).err().unwrap()
+        };
+
+        assert!(load(r#"[{"path": "users"}]"#).contains("doesn't start with /"));
+        assert!(load(r#"[{"path": "/a/*/b"}]"#).contains("can only have a * at the end"));
+        assert!(load(r#"[{"path": "/a", "body": "x", "file": "y"}]"#).contains("both a body and a file"));
+        assert!(load(r#"[{"path": "/a", "file": "missing.txt"}]"#).contains("doesn't exist"));
+        assert!(load(r#"[{"path": "/a", "delay": 5}]"#).contains("unknown field `delay`"));
+
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+
+    #[tokio::test]
+    async fn echo_chunked_request() {
+        let addr = start(ServeMode::Echo).await;
+
+        let body = "{\"event\":\"push\",\"ref\":\"main\"}";
+        let (first, second) = body.split_at(10);
+        let response = send(
+            addr,
+            &format!(
+                "POST /hook?tag=a&tag=b&name=x+y HTTP/1.1\r\nHost: localhost\r\nX-Event: one\r\nX-Event: two\r\n\
+                 Transfer-Encoding: chunked\r\nConnection: close\r\n\r\n{:x};ext=1\r\n{}\r\n{:x}\r\n{}\r\n0\r\nTrailer: x\r\n\r\n",
+                first.len(),
+                first,
+                second.len(),
+                second
+            ),
+        )
+        .await;
+        assert_eq!(200, response.status);
+
+        let echo: serde_json::Value = serde_json::from_slice(&response.body).unwrap();
+        assert_eq!("POST", echo["method"]);
+        assert_eq!("/hook", echo["path"]);
+        assert_eq!(serde_json::json!({ "tag": ["a", "b"], "name": "x y" }), echo["query"]);
+        assert_eq!("one, two", echo["headers"]["X-Event"]);
+        assert_eq!(body, echo["body"]);
+        assert_eq!(body.len(), echo["body_size"]);
+        assert_eq!("push", echo["json"]["event"]);
+        assert!(echo["remote"].as_str().unwrap().starts_with("127.0.0.1:"));
+
+        // Bodies that aren't text are sent back as base64
+        let mut request = b"PUT /bin HTTP/1.1\r\nContent-Length: 3\r\nConnection: close\r\n\r\n".to_vec();
+        request.extend_from_slice(&[0xff, 0x00, 0x01]);
+        let mut stream = TcpStream::connect(addr).await.unwrap();
+        stream.write_all(&request).await.unwrap();
+        let mut response = Vec::new();
+        stream.read_to_end(&mut response).await.unwrap();
+        assert!(String::from_utf8_lossy(&response).contains("\"body_base64\": \"/wAB\""));
+
+        let response = send(addr, "POST / HTTP/1.1\r\nTransfer-Encoding: chunked\r\n\r\nzz\r\n").await;
+        assert_eq!(400, response.status);
+        assert_eq!("Invalid chunk size", response.text());
+    }
+
+    #[tokio::test]
+    async fn log_requests_as_ndjson() {
+        let path = std::env::temp_dir().join(format!("aid_http_log_{}.ndjson", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        let log = RequestLog::new(Some(path.to_str().unwrap())).unwrap();
+        let addr = start_with_log(public_dir(), log).await;
+
+        get(addr, "/hello.txt", "").await;
+        get(addr, "/missing.txt", "").await;
+        send(addr, "HEAD /hello.txt HTTP/1.1\r\nConnection: close\r\n\r\n").await;
+
+        let contents = std::fs::read_to_string(&path).unwrap();
+        let entries: Vec<serde_json::Value> = contents
+            .lines()
+            .map(|line| serde_json::from_str(line).unwrap())
+            .collect();
+        assert_eq!(3, entries.len());
+        assert_eq!("GET", entries[0]["method"]);
+        assert_eq!("/hello.txt", entries[0]["path"]);
+        assert_eq!(200, entries[0]["status"]);
+        assert_eq!(15, entries[0]["bytes"]);
+        assert!(entries[0]["latency_ms"].as_f64().unwrap() >= 0.0);
+        assert!(entries[0]["timestamp"].as_str().unwrap().ends_with('Z'));
+        assert_eq!(404, entries[1]["status"]);
+        assert_eq!(0, entries[2]["bytes"]);
+
+        let _ = std::fs::remove_file(&path);
+    }
+
+    #[tokio::test]
+    async fn proxy_records_and_replays_ndjson() {
+        let routes = http_utils::load_routes("./tests/data/routes.json").unwrap();
+        let upstream = start(ServeMode::Routes(routes)).await;
+        let recording = temp_path("proxy.ndjson");
+        let addr = start_proxy(Some(upstream), Some(&recording), None).await;
+
+        let response = get(addr, "/users/7?fields=name", "").await;
+        assert_eq!(200, response.status);
+        assert_eq!(Some("7"), response.header("X-User"));
+        let body: serde_json::Value = serde_json::from_slice(&response.body).unwrap();
+        assert_eq!("User 7", body["name"]);
+
+        let request = "POST /users HTTP/1.1\r\nContent-Type: text/plain\r\nContent-Length: 3\r\nConnection: close\r\n\r\nBob";
+        let response = send(addr, request).await;
+        assert_eq!(201, response.status);
+        assert_eq!(Some("/users/42"), response.header("Location"));
+        assert_eq!("Created from text/plain: Bob", response.text());
+        assert_eq!(404, get(addr, "/missing", "").await.status);
+
+        let recorded = http_utils::load_recording(&recording).unwrap();
+        assert_eq!(3, recorded.len());
+        assert_eq!("GET", recorded[0].method);
+        assert_eq!(format!("http://{}/users/7?fields=name", upstream), recorded[0].url);
+        assert_eq!(b"Bob".to_vec(), recorded[1].request_body);
+        assert_eq!(201, recorded[1].status);
+        assert_eq!(404, recorded[2].status);
+
+        // Without an upstream only recorded requests are answered
+        let replay = start_proxy(None, None, Some(&recording)).await;
+        let response = get(replay, "/users/7?fields=name", "").await;
+        assert_eq!(200, response.status);
+        assert_eq!(Some("7"), response.header("X-User"));
+        assert_eq!(body, serde_json::from_slice::<serde_json::Value>(&response.body).unwrap());
+        assert_eq!(201, send(replay, request).await.status);
+
+        let response = get(replay, "/users/8", "").await;
+        assert_eq!(404, response.status);
+        assert_eq!(r#"{"error":"No recording for GET /users/8"}"#, response.text());
+
+        let _ = std::fs::remove_file(&recording);
+    }
+
+    #[tokio::test]
+    async fn proxy_records_and_replays_har() {
+        let upstream = start(ServeMode::Echo).await;
+        let recording = temp_path("proxy.har");
+        let addr = start_proxy(Some(upstream), Some(&recording), None).await;
+
+        for body in ["one", "two"] {
+            let request = format!(
+                "PUT /echo HTTP/1.1\r\nX-Forwarded-For: 10.0.0.1\r\nContent-Length: 3\r\nConnection: close\r\n\r\n{}",
+                body
+            );
+            let response = send(addr, &request).await;
+            let echoed: serde_json::Value = serde_json::from_slice(&response.body).unwrap();
+            assert_eq!(body, echoed["body"]);
+            assert_eq!("10.0.0.1, 127.0.0.1", echoed["headers"]["x-forwarded-for"]);
+        }
+
+        let har: serde_json::Value = serde_json::from_str(&std::fs::read_to_string(&recording).unwrap()).unwrap();
+        assert_eq!("1.2", har["log"]["version"]);
+        let entries = har["log"]["entries"].as_array().unwrap();
+        assert_eq!(2, entries.len());
+        assert_eq!("PUT", entries[0]["request"]["method"]);
+        assert_eq!("one", entries[0]["request"]["postData"]["text"]);
+        assert_eq!("application/json", entries[0]["response"]["content"]["mimeType"]);
+
+        // Responses to the same request are replayed in order, then the last one repeats
+        let replay = start_proxy(None, None, Some(&recording)).await;
+        for expected in ["one", "two", "two"] {
+            let response = send(replay, "PUT /echo HTTP/1.1\r\nContent-Length: 0\r\nConnection: close\r\n\r\n").await;
+            let echoed: serde_json::Value = serde_json::from_slice(&response.body).unwrap();
+            assert_eq!(expected, echoed["body"]);
+        }
+
+        let _ = std::fs::remove_file(&recording);
+    }
+
+    #[tokio::test]
+    async fn proxy_replays_requests_to_an_upstream_path() {
+        let upstream = start(ServeMode::Echo).await;
+        let recording = temp_path("proxy_prefix.ndjson");
+        let proxy = Proxy::new(
+            Some(format!("http://{}/api", upstream)),
+            Some(Recorder::open(&recording).unwrap()),
+            Vec::new(),
+        )
+        .unwrap();
+        let addr = start(ServeMode::Proxy(Box::new(proxy))).await;
+
+        let response = get(addr, "/users/7?x=1", "").await;
+        let echoed: serde_json::Value = serde_json::from_slice(&response.body).unwrap();
+        assert_eq!("/api/users/7?x=1", echoed["target"]);
+
+        // Requests are replayed by the target the client asked for, including absolute URLs
+        let replay = start_proxy(None, None, Some(&recording)).await;
+        let replayed = get(replay, "/users/7?x=1", "").await;
+        assert_eq!(200, replayed.status);
+        assert_eq!(response.body, replayed.body);
+        let replayed = get(replay, "http://localhost:8080/users/7?x=1", "").await;
+        assert_eq!(response.body, replayed.body);
+        assert_eq!(404, get(replay, "/api/users/7?x=1", "").await.status);
+
+        let _ = std::fs::remove_file(&recording);
+    }
+
+    #[tokio::test]
+    async fn proxy_reports_unavailable_upstream() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let upstream = listener.local_addr().unwrap();
+        drop(listener);
+
+        let addr = start_proxy(Some(upstream), None, None).await;
+        assert_eq!(502, get(addr, "/", "").await.status);
+        assert!(Proxy::new(Some("ftp://localhost".to_string()), None, Vec::new()).is_err());
+    }
+}